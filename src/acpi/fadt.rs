@@ -0,0 +1,117 @@
+use super::{map_table, validate_table};
+use core::mem::size_of;
+
+// The acpi crate parses the FADT internally, but it doesn't give us access to any of the power
+// management fields, so we parse them ourselves once it has found the table.
+
+#[allow(dead_code)]
+#[derive(Clone, Copy)]
+#[repr(C, packed)]
+struct SdtHeader {
+    signature: [u8; 4],
+    length: u32,
+    revision: u8,
+    checksum: u8,
+    oem_id: [u8; 6],
+    oem_table_id: [u8; 8],
+    oem_revision: u32,
+    creator_id: u32,
+    creator_revision: u32,
+}
+
+#[allow(dead_code)]
+#[derive(Clone, Copy)]
+#[repr(C, packed)]
+struct RawFadt {
+    header: SdtHeader,
+    firmware_ctrl: u32,
+    dsdt_address: u32,
+    reserved: u8,
+    preferred_pm_profile: u8,
+    sci_interrupt: u16,
+    smi_command_port: u32,
+    acpi_enable: u8,
+    acpi_disable: u8,
+    s4bios_req: u8,
+    pstate_control: u8,
+    pm1a_event_block: u32,
+    pm1b_event_block: u32,
+    pm1a_control_block: u32,
+    pm1b_control_block: u32,
+    pm2_control_block: u32,
+    pm_timer_block: u32,
+    gpe0_block: u32,
+    gpe1_block: u32,
+    pm1_event_length: u8,
+    pm1_control_length: u8,
+    pm2_control_length: u8,
+    pm_timer_length: u8,
+    gpe0_block_length: u8,
+    gpe1_block_length: u8,
+    gpe1_base: u8,
+}
+
+/// The power management information from the FADT. Block addresses are IO ports, and a value of
+/// zero means the block is not supported.
+#[derive(Debug, Clone, Copy)]
+pub struct Fadt {
+    pub sci_interrupt: u16,
+    pub smi_command_port: u16,
+    pub acpi_enable: u8,
+    pub acpi_disable: u8,
+    pub pm1a_event_block: u16,
+    pub pm1b_event_block: u16,
+    pub pm1_event_length: u8,
+    pub pm1a_control_block: u16,
+    pub pm1b_control_block: u16,
+    pub pm_timer_block: u16,
+    pub gpe0_block: u16,
+    pub gpe0_block_length: u8,
+    pub gpe1_block: u16,
+    pub gpe1_block_length: u8,
+    pub gpe1_base: u8,
+}
+
+impl From<RawFadt> for Fadt {
+    fn from(raw: RawFadt) -> Self {
+        Self {
+            sci_interrupt: raw.sci_interrupt,
+            smi_command_port: raw.smi_command_port as u16,
+            acpi_enable: raw.acpi_enable,
+            acpi_disable: raw.acpi_disable,
+            pm1a_event_block: raw.pm1a_event_block as u16,
+            pm1b_event_block: raw.pm1b_event_block as u16,
+            pm1_event_length: raw.pm1_event_length,
+            pm1a_control_block: raw.pm1a_control_block as u16,
+            pm1b_control_block: raw.pm1b_control_block as u16,
+            pm_timer_block: raw.pm_timer_block as u16,
+            gpe0_block: raw.gpe0_block as u16,
+            gpe0_block_length: raw.gpe0_block_length,
+            gpe1_block: raw.gpe1_block as u16,
+            gpe1_block_length: raw.gpe1_block_length,
+            gpe1_base: raw.gpe1_base,
+        }
+    }
+}
+
+unsafe fn read_header(physical_address: usize) -> SdtHeader {
+    let mapping = map_table(physical_address, size_of::<SdtHeader>());
+    core::ptr::read_unaligned(mapping.as_ptr::<SdtHeader>())
+}
+
+/// Read the FADT at address, or return None if its checksum is wrong
+pub unsafe fn parse(address: usize) -> Option<Fadt> {
+    let length = read_header(address).length as usize;
+    let mapping = map_table(address, length);
+    if !validate_table(mapping.as_ptr()) {
+        return None;
+    }
+
+    // Everything we read was in the original ACPI 1.0 FADT, so any valid table is long enough
+    assert!(
+        length >= size_of::<RawFadt>(),
+        "FADT is too short ({} bytes)",
+        length
+    );
+    Some(core::ptr::read_unaligned(mapping.as_ptr::<RawFadt>()).into())
+}
@@ -1,9 +1,54 @@
-use crate::paging::phys_to_virt_addr;
+mod fadt;
+mod pm;
+
+pub use fadt::Fadt;
+pub use pm::{enable_sci, shutdown, spawn_event_task};
+
+use crate::devices::pcie;
+use crate::io_port::{Io, IoPort};
+use crate::paging::{self, MmioValue, PhysicalMappingFlags, Region};
+use crate::util;
 use acpi::{search_for_rsdp_bios, Acpi as AcpiContext, AcpiHandler, PhysicalMapping};
+use alloc::collections::btree_map::BTreeMap;
 use aml::{AmlContext, DebugVerbosity, Handler as AmlHandler};
 use core::marker::PhantomData;
+use core::sync::atomic::{AtomicUsize, Ordering};
 use spin::Mutex;
 
+// The acpi crate gives us no way to hang our own data off a PhysicalMapping, so we keep the regions
+// that back the mappings here, keyed by the virtual address that we handed out.
+static TABLE_MAPPINGS: Mutex<BTreeMap<usize, Region>> = Mutex::new(BTreeMap::new());
+
+// The acpi crate finds the FADT, checking the RSDP on the way, but keeps the power management
+// fields to itself. It maps every table it looks at through our handler, so we note where the FADT
+// is as it goes past and parse those fields ourselves.
+static FADT_ADDRESS: AtomicUsize = AtomicUsize::new(0);
+const FADT_SIGNATURE: &[u8; 4] = b"FACP";
+
+unsafe fn map_table(physical_address: usize, size: usize) -> Region {
+    // ACPI tables can live anywhere in physical memory, including above the identity mapped region,
+    // so we always map them explicitly.
+    paging::map_physical_memory(physical_address, size, PhysicalMappingFlags::READ_ONLY)
+        .expect("Failed to map ACPI table")
+}
+
+// AML operation regions are usually device registers, so every access gets its own uncached
+// mapping rather than going through the identity map.
+fn map_operation_region(address: usize, size: usize) -> Region {
+    unsafe {
+        paging::map_physical_memory(address, size, PhysicalMappingFlags::UNCACHED)
+            .expect("Failed to map AML operation region")
+    }
+}
+
+fn read_physical<T: MmioValue>(address: usize) -> T {
+    map_operation_region(address, core::mem::size_of::<T>()).read_volatile(0)
+}
+
+fn write_physical<T: MmioValue>(address: usize, value: T) {
+    map_operation_region(address, core::mem::size_of::<T>()).write_volatile(0, value)
+}
+
 pub struct HandlerImpl;
 
 impl AcpiHandler for HandlerImpl {
@@ -12,125 +57,154 @@ impl AcpiHandler for HandlerImpl {
         physical_address: usize,
         size: usize,
     ) -> PhysicalMapping<T> {
-        let virtual_addr = phys_to_virt_addr(physical_address, size);
+        let region = map_table(physical_address, size);
+        let virtual_addr = region.start();
+        let mapped_length = region.size();
+
+        if size >= TABLE_HEADER_SIZE
+            && core::slice::from_raw_parts(region.as_ptr::<u8>(), 4) == FADT_SIGNATURE
+        {
+            FADT_ADDRESS.store(physical_address, Ordering::SeqCst);
+        }
+
+        TABLE_MAPPINGS.lock().insert(virtual_addr, region);
 
         PhysicalMapping {
             physical_start: physical_address,
             virtual_start: core::ptr::NonNull::new(virtual_addr as *mut T).unwrap(),
             region_length: size,
-            mapped_length: size,
+            mapped_length,
         }
     }
 
-    fn unmap_physical_region<T>(&mut self, _region: PhysicalMapping<T>) {
-        // Nothing to do here because we only support identity mapped memory
+    fn unmap_physical_region<T>(&mut self, region: PhysicalMapping<T>) {
+        let virtual_addr = region.virtual_start.as_ptr() as usize;
+        let mapping = TABLE_MAPPINGS
+            .lock()
+            .remove(&virtual_addr)
+            .expect("Unmapping unknown ACPI region");
+
+        // Dropping the region tears down the mapping. We do it outside of the table lock.
+        core::mem::drop(mapping);
     }
 }
 
 impl AmlHandler for HandlerImpl {
-    fn read_u8(&self, _address: usize) -> u8 {
-        todo!()
+    fn read_u8(&self, address: usize) -> u8 {
+        read_physical(address)
     }
-    fn read_u16(&self, _address: usize) -> u16 {
-        todo!()
+    fn read_u16(&self, address: usize) -> u16 {
+        read_physical(address)
     }
-    fn read_u32(&self, _address: usize) -> u32 {
-        todo!()
+    fn read_u32(&self, address: usize) -> u32 {
+        read_physical(address)
     }
-    fn read_u64(&self, _address: usize) -> u64 {
-        todo!()
+    fn read_u64(&self, address: usize) -> u64 {
+        read_physical(address)
     }
-    fn write_u8(&mut self, _address: usize, _value: u8) {
-        todo!()
+    fn write_u8(&mut self, address: usize, value: u8) {
+        write_physical(address, value)
     }
-    fn write_u16(&mut self, _address: usize, _value: u16) {
-        todo!()
+    fn write_u16(&mut self, address: usize, value: u16) {
+        write_physical(address, value)
     }
-    fn write_u32(&mut self, _address: usize, _value: u32) {
-        todo!()
+    fn write_u32(&mut self, address: usize, value: u32) {
+        write_physical(address, value)
     }
-    fn write_u64(&mut self, _address: usize, _value: u64) {
-        todo!()
+    fn write_u64(&mut self, address: usize, value: u64) {
+        write_physical(address, value)
     }
-    fn read_io_u8(&self, _port: u16) -> u8 {
-        todo!()
+    fn read_io_u8(&self, port: u16) -> u8 {
+        IoPort::<u8>::new(port).read()
     }
-    fn read_io_u16(&self, _port: u16) -> u16 {
-        todo!()
+    fn read_io_u16(&self, port: u16) -> u16 {
+        IoPort::<u16>::new(port).read()
     }
-    fn read_io_u32(&self, _port: u16) -> u32 {
-        todo!()
+    fn read_io_u32(&self, port: u16) -> u32 {
+        IoPort::<u32>::new(port).read()
     }
-    fn write_io_u8(&self, _port: u16, _value: u8) {
-        todo!()
+    fn write_io_u8(&self, port: u16, value: u8) {
+        IoPort::<u8>::new(port).write(value)
     }
-    fn write_io_u16(&self, _port: u16, _value: u16) {
-        todo!()
+    fn write_io_u16(&self, port: u16, value: u16) {
+        IoPort::<u16>::new(port).write(value)
     }
-    fn write_io_u32(&self, _port: u16, _value: u32) {
-        todo!()
+    fn write_io_u32(&self, port: u16, value: u32) {
+        IoPort::<u32>::new(port).write(value)
     }
-    fn read_pci_u8(&self, _segment: u16, _bus: u8, _device: u8, _function: u8, _offset: u16) -> u8 {
-        todo!()
+    fn read_pci_u8(&self, segment: u16, bus: u8, device: u8, function: u8, offset: u16) -> u8 {
+        pcie::read_u8(segment, bus, device, function, offset)
     }
-    fn read_pci_u16(
-        &self,
-        _segment: u16,
-        _bus: u8,
-        _device: u8,
-        _function: u8,
-        _offset: u16,
-    ) -> u16 {
-        todo!()
-    }
-    fn read_pci_u32(
-        &self,
-        _segment: u16,
-        _bus: u8,
-        _device: u8,
-        _function: u8,
-        _offset: u16,
-    ) -> u32 {
-        todo!()
+    fn read_pci_u16(&self, segment: u16, bus: u8, device: u8, function: u8, offset: u16) -> u16 {
+        pcie::read_u16(segment, bus, device, function, offset)
+    }
+    fn read_pci_u32(&self, segment: u16, bus: u8, device: u8, function: u8, offset: u16) -> u32 {
+        pcie::read_u32(segment, bus, device, function, offset)
     }
     fn write_pci_u8(
         &self,
-        _segment: u16,
-        _bus: u8,
-        _device: u8,
-        _function: u8,
-        _offset: u16,
-        _value: u8,
+        segment: u16,
+        bus: u8,
+        device: u8,
+        function: u8,
+        offset: u16,
+        value: u8,
     ) {
-        todo!()
+        pcie::write_u8(segment, bus, device, function, offset, value)
     }
     fn write_pci_u16(
         &self,
-        _segment: u16,
-        _bus: u8,
-        _device: u8,
-        _function: u8,
-        _offset: u16,
-        _value: u16,
+        segment: u16,
+        bus: u8,
+        device: u8,
+        function: u8,
+        offset: u16,
+        value: u16,
     ) {
-        todo!()
+        pcie::write_u16(segment, bus, device, function, offset, value)
     }
     fn write_pci_u32(
         &self,
-        _segment: u16,
-        _bus: u8,
-        _device: u8,
-        _function: u8,
-        _offset: u16,
-        _value: u32,
+        segment: u16,
+        bus: u8,
+        device: u8,
+        function: u8,
+        offset: u16,
+        value: u32,
     ) {
-        todo!()
+        pcie::write_u32(segment, bus, device, function, offset, value)
     }
 }
 
+// Every ACPI table starts with the same header, with the length of the whole table at offset 4
+const TABLE_HEADER_SIZE: usize = 36;
+const TABLE_LENGTH_OFFSET: usize = 4;
+
+/// Check the checksum of the table that header points to. A table that fails is logged and
+/// shouldn't be used, as firmware bugs and corruption would otherwise get as far as the AML parser.
+pub unsafe fn validate_table(header: *const u8) -> bool {
+    let signature = core::str::from_utf8(core::slice::from_raw_parts(header, 4)).unwrap_or("????");
+    let length = (header.add(TABLE_LENGTH_OFFSET) as *const u32).read_unaligned() as usize;
+    if length < TABLE_HEADER_SIZE {
+        crate::warn!("ACPI table {} is too short at {} bytes", signature, length);
+        return false;
+    }
+
+    let sum = util::checksum8(core::slice::from_raw_parts(header, length));
+    if sum != 0 {
+        crate::warn!(
+            "ACPI table {} has a bad checksum, its bytes add up to {:#x}",
+            signature,
+            sum
+        );
+    }
+    sum == 0
+}
+
 pub struct Acpi<H: AmlHandler + AcpiHandler> {
     pub acpi_context: AcpiContext,
     pub aml_context: AmlContext,
+    pub fadt: Option<Fadt>,
     _marker: PhantomData<H>,
 }
 
@@ -147,30 +221,38 @@ impl<H: 'static + AmlHandler + AcpiHandler> Acpi<H> {
         let mut aml_context = AmlContext::new(handler, false, DebugVerbosity::Scopes);
 
         if let Some(dsdt) = &acpi_context.dsdt {
-            let dsdt_data = core::slice::from_raw_parts(
-                phys_to_virt_addr(dsdt.address, dsdt.length as usize) as *const u8,
-                dsdt.length as usize,
-            );
+            let dsdt_mapping = map_table(dsdt.address, dsdt.length as usize);
+            let dsdt_data =
+                core::slice::from_raw_parts(dsdt_mapping.as_ptr::<u8>(), dsdt.length as usize);
 
-            aml_context
-                .parse_table(dsdt_data)
-                .expect("Failed to parse DSDT");
+            if validate_table(dsdt_mapping.as_ptr()) {
+                aml_context
+                    .parse_table(dsdt_data)
+                    .expect("Failed to parse DSDT");
+            }
         }
 
         for ssdt in &acpi_context.ssdts {
-            let ssdt_data = core::slice::from_raw_parts(
-                phys_to_virt_addr(ssdt.address, ssdt.length as usize) as *const u8,
-                ssdt.length as usize,
-            );
+            let ssdt_mapping = map_table(ssdt.address, ssdt.length as usize);
+            let ssdt_data =
+                core::slice::from_raw_parts(ssdt_mapping.as_ptr::<u8>(), ssdt.length as usize);
 
-            aml_context
-                .parse_table(ssdt_data)
-                .expect("Failed to parse SSDT");
+            if validate_table(ssdt_mapping.as_ptr()) {
+                aml_context
+                    .parse_table(ssdt_data)
+                    .expect("Failed to parse SSDT");
+            }
         }
 
+        let fadt = match FADT_ADDRESS.load(Ordering::SeqCst) {
+            0 => None,
+            address => fadt::parse(address),
+        };
+
         Self {
             acpi_context,
             aml_context,
+            fadt,
             _marker: PhantomData,
         }
     }
@@ -181,3 +263,81 @@ pub static ACPI: Mutex<Option<Acpi<HandlerImpl>>> = Mutex::new(None);
 pub unsafe fn init_bsp() {
     *ACPI.lock() = Some(Acpi::new(HandlerImpl));
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloc::vec::Vec;
+
+    // A table with a header and a little AML, with its checksum filled in
+    fn test_table() -> Vec<u8> {
+        let mut table: Vec<u8> = b"SSDT".iter().copied().collect();
+        table.resize(TABLE_HEADER_SIZE + 8, 0x5a);
+        let length = table.len() as u32;
+        table[TABLE_LENGTH_OFFSET..TABLE_LENGTH_OFFSET + 4].copy_from_slice(&length.to_le_bytes());
+
+        const CHECKSUM_OFFSET: usize = 9;
+        table[CHECKSUM_OFFSET] = 0;
+        table[CHECKSUM_OFFSET] = 0u8.wrapping_sub(util::checksum8(&table));
+        table
+    }
+
+    #[test_case]
+    fn table_checksum_is_validated() {
+        let mut table = test_table();
+        assert!(unsafe { validate_table(table.as_ptr()) });
+
+        let last = table.len() - 1;
+        table[last] ^= 0x01;
+        let captured = crate::log::capture(|| assert!(!unsafe { validate_table(table.as_ptr()) }));
+        assert!(captured.contains("ACPI table SSDT has a bad checksum"));
+    }
+
+    #[test_case]
+    fn short_table_is_rejected() {
+        let mut table = test_table();
+        let length = (TABLE_HEADER_SIZE as u32 - 1).to_le_bytes();
+        table[TABLE_LENGTH_OFFSET..TABLE_LENGTH_OFFSET + 4].copy_from_slice(&length);
+        assert!(!unsafe { validate_table(table.as_ptr()) });
+    }
+
+    #[test_case]
+    fn map_and_unmap_table_above_identity_map() {
+        // Nothing needs to actually live at this address since we never read from it
+        let physical_address = paging::IDENTITY_MAP_SIZE + 0x1234_5678;
+
+        let mapping = unsafe {
+            HandlerImpl.map_physical_region::<u8>(physical_address, 2 * paging::PAGE_SIZE)
+        };
+        let virtual_addr = mapping.virtual_start.as_ptr() as usize;
+
+        assert!(
+            virtual_addr >= paging::KERNEL_HEAP_BASE && virtual_addr < paging::KERNEL_HEAP_LIMIT
+        );
+        assert_eq!(mapping.region_length, 2 * paging::PAGE_SIZE);
+        assert!(TABLE_MAPPINGS.lock().contains_key(&virtual_addr));
+
+        {
+            let page_table = unsafe { paging::lock_page_table() };
+            let pte = page_table
+                .get_pte_for_address(virtual_addr)
+                .and_then(|pte| pte.present().ok())
+                .expect("ACPI region is not mapped");
+            assert_eq!(
+                pte.frame().physical_address(),
+                paging::page_align_down(physical_address)
+            );
+        }
+
+        HandlerImpl.unmap_physical_region(mapping);
+
+        assert!(!TABLE_MAPPINGS.lock().contains_key(&virtual_addr));
+        {
+            let page_table = unsafe { paging::lock_page_table() };
+            assert!(!page_table
+                .get_pte_for_address(virtual_addr)
+                .map(|pte| pte.is_present())
+                .unwrap_or(false));
+        }
+    }
+}
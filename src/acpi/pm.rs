@@ -0,0 +1,525 @@
+use super::{Fadt, ACPI};
+use crate::devices::io_apic;
+use crate::interrupts::irq;
+use crate::io_port::{Io, IoPort};
+use crate::ipi::{ipi, IpiKind, IpiTarget};
+use crate::scheduler::{self, TaskReference};
+use crate::sync::IrqMutex;
+use crate::util::StackString;
+use alloc::vec::Vec;
+use aml::value::{AmlValue, Args};
+use aml::{AmlContext, AmlName};
+use bitflags::bitflags;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+bitflags! {
+    struct Pm1Event: u16 {
+        const TIMER = 1 << 0;
+        const BUS_MASTER = 1 << 4;
+        const GLOBAL = 1 << 5;
+        const POWER_BUTTON = 1 << 8;
+        const SLEEP_BUTTON = 1 << 9;
+        const RTC = 1 << 10;
+        const WAKE = 1 << 15;
+    }
+}
+
+bitflags! {
+    struct Pm1Control: u16 {
+        const SCI_ENABLE = 1 << 0;
+        const SLEEP_ENABLE = 1 << 13;
+    }
+}
+
+const SLEEP_TYPE_SHIFT: u16 = 10;
+const SLEEP_TYPE_MASK: u16 = 0b111 << SLEEP_TYPE_SHIFT;
+
+// How many times we poll for SCI_EN after asking the firmware to switch to ACPI mode
+const ACPI_ENABLE_RETRIES: usize = 1_000_000;
+
+#[derive(Debug, Clone, Copy)]
+struct PmState {
+    fadt: Fadt,
+
+    // SLP_TYPa and SLP_TYPb from \_S5. We look these up when the SCI is enabled so that shutting
+    // down doesn't need to evaluate AML, which would mean taking the ACPI lock.
+    s5_sleep_types: Option<(u16, u16)>,
+}
+
+// The SCI handler reads this
+static PM_STATE: IrqMutex<Option<PmState>> = IrqMutex::new(None);
+static SCI_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+// GPE numbers go up to gpe1_base plus the size of the second block, which fits in a byte
+const MAX_GPES: usize = 256;
+
+// What the SCI handler has seen that the event task hasn't dealt with yet. Evaluating AML can map
+// memory and allocate, which can't be done in an interrupt handler, so the handler only
+// acknowledges events and leaves the rest to the task.
+struct PendingEvents {
+    power_button: bool,
+    // One bit per GPE. Pending GPEs are disabled until their method has run, otherwise a level
+    // triggered GPE would keep raising the SCI.
+    gpes: [u64; MAX_GPES / 64],
+    waiter: Option<TaskReference>,
+}
+
+impl PendingEvents {
+    fn is_empty(&self) -> bool {
+        !self.power_button && self.gpes.iter().all(|bits| *bits == 0)
+    }
+
+    fn add_gpe(&mut self, gpe: u16) {
+        self.gpes[usize::from(gpe) / 64] |= 1 << (gpe % 64);
+    }
+
+    fn has_gpe(&self, gpe: u16) -> bool {
+        self.gpes[usize::from(gpe) / 64] & (1 << (gpe % 64)) != 0
+    }
+
+    fn take(&mut self) -> (bool, [u64; MAX_GPES / 64]) {
+        let events = (self.power_button, self.gpes);
+        self.power_button = false;
+        self.gpes = [0; MAX_GPES / 64];
+        events
+    }
+}
+
+static PENDING_EVENTS: IrqMutex<PendingEvents> = IrqMutex::new(PendingEvents {
+    power_button: false,
+    gpes: [0; MAX_GPES / 64],
+    waiter: None,
+});
+
+fn queue_events(add: impl FnOnce(&mut PendingEvents)) {
+    let mut pending = PENDING_EVENTS.lock();
+    add(&mut pending);
+    if !pending.is_empty() {
+        if let Some(waiter) = pending.waiter.take() {
+            waiter.wake();
+        }
+    }
+}
+
+fn pm1_event_blocks(fadt: &Fadt) -> impl Iterator<Item = u16> {
+    core::iter::once(fadt.pm1a_event_block)
+        .chain(core::iter::once(fadt.pm1b_event_block))
+        .filter(|block| *block != 0)
+}
+
+fn pm1_control_blocks(fadt: &Fadt) -> impl Iterator<Item = u16> {
+    core::iter::once(fadt.pm1a_control_block)
+        .chain(core::iter::once(fadt.pm1b_control_block))
+        .filter(|block| *block != 0)
+}
+
+// The PM1 event blocks are split in half, with the status register first and the enable register
+// second.
+fn read_pm1_status(fadt: &Fadt) -> Pm1Event {
+    pm1_event_blocks(fadt).fold(Pm1Event::empty(), |status, block| {
+        status | Pm1Event::from_bits_truncate(IoPort::<u16>::new(block).read())
+    })
+}
+
+fn clear_pm1_status(fadt: &Fadt, status: Pm1Event) {
+    // Status bits are cleared by writing a one to them
+    for block in pm1_event_blocks(fadt) {
+        IoPort::<u16>::new(block).write(status.bits());
+    }
+}
+
+fn read_pm1_enable(fadt: &Fadt) -> Pm1Event {
+    let offset = u16::from(fadt.pm1_event_length / 2);
+    pm1_event_blocks(fadt).fold(Pm1Event::empty(), |enable, block| {
+        enable | Pm1Event::from_bits_truncate(IoPort::<u16>::new(block + offset).read())
+    })
+}
+
+fn write_pm1_enable(fadt: &Fadt, enable: Pm1Event) {
+    let offset = u16::from(fadt.pm1_event_length / 2);
+    for block in pm1_event_blocks(fadt) {
+        IoPort::<u16>::new(block + offset).write(enable.bits());
+    }
+}
+
+fn read_pm1_control(fadt: &Fadt) -> Pm1Control {
+    pm1_control_blocks(fadt).fold(Pm1Control::empty(), |control, block| {
+        control | Pm1Control::from_bits_truncate(IoPort::<u16>::new(block).read())
+    })
+}
+
+#[derive(Debug, Clone, Copy)]
+struct GpeRegister {
+    status_port: u16,
+    enable_port: u16,
+    first_gpe: u16,
+}
+
+// Each GPE block is made up of byte wide registers covering eight GPEs each. Like the PM1 event
+// blocks, the first half of the block holds the status registers and the second half holds the
+// enable registers.
+fn gpe_registers(fadt: &Fadt) -> impl Iterator<Item = GpeRegister> {
+    let blocks = [
+        (fadt.gpe0_block, fadt.gpe0_block_length, 0),
+        (fadt.gpe1_block, fadt.gpe1_block_length, fadt.gpe1_base),
+    ];
+
+    (0..blocks.len())
+        .map(move |index| blocks[index])
+        .filter(|(block, length, _)| *block != 0 && *length != 0)
+        .flat_map(|(block, length, base)| {
+            let count = u16::from(length / 2);
+            (0..count).map(move |register| GpeRegister {
+                status_port: block + register,
+                enable_port: block + count + register,
+                first_gpe: u16::from(base) + (register * 8),
+            })
+        })
+}
+
+fn gpe_method(kind: char, gpe: u16) -> AmlName {
+    let name = StackString::<16>::from_args(format_args!("\\_GPE._{}{:02X}", kind, gpe));
+    AmlName::from_str(&name).expect("Invalid GPE method name")
+}
+
+fn has_method(aml_context: &AmlContext, name: &AmlName) -> bool {
+    aml_context.namespace.get_by_path(name).is_ok()
+}
+
+fn sleep_types(aml_context: &AmlContext, name: &str) -> Option<(u16, u16)> {
+    let value = AmlName::from_str(name)
+        .and_then(|path| aml_context.namespace.get_by_path(&path))
+        .ok()?;
+
+    match value {
+        AmlValue::Package(elements) => match elements.as_slice() {
+            [AmlValue::Integer(a), AmlValue::Integer(b), ..] => Some((*a as u16, *b as u16)),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+fn dispatch_gpe(aml_context: &mut AmlContext, gpe: u16, clear_status: impl FnOnce()) {
+    let edge_method = gpe_method('E', gpe);
+    let level_method = gpe_method('L', gpe);
+
+    // Edge triggered events are cleared before running the method so we don't miss a new edge,
+    // level triggered events are cleared afterwards, once the method has quietened the source.
+    let (method, clear_first) = if has_method(aml_context, &edge_method) {
+        (edge_method, true)
+    } else {
+        (level_method, false)
+    };
+
+    if clear_first {
+        clear_status();
+    }
+
+    if let Err(e) = aml_context.invoke_method(&method, Args::from_list(Vec::new())) {
+        crate::error!("Error running GPE method {:?}: {:?}", method, e);
+    }
+
+    if !clear_first {
+        clear_status();
+    }
+}
+
+// Disable the GPEs that are raising the SCI and queue them for the event task
+fn queue_gpes(fadt: &Fadt, pending: &mut PendingEvents) {
+    for register in gpe_registers(fadt) {
+        let status_port = IoPort::<u8>::new(register.status_port);
+        let mut enable_port = IoPort::<u8>::new(register.enable_port);
+
+        let enabled = enable_port.read();
+        let raised = status_port.read() & enabled;
+        if raised == 0 {
+            continue;
+        }
+
+        enable_port.write(enabled & !raised);
+        for bit in (0..8).filter(|bit| raised & (1 << bit) != 0) {
+            let gpe = register.first_gpe + bit;
+            if usize::from(gpe) < MAX_GPES {
+                pending.add_gpe(gpe);
+            } else {
+                crate::warn!("GPE {:#x} is out of range, leaving it disabled", gpe);
+            }
+        }
+    }
+}
+
+fn sci_handler() {
+    SCI_COUNT.fetch_add(1, Ordering::SeqCst);
+
+    let state = match *PM_STATE.lock() {
+        Some(state) => state,
+        None => return,
+    };
+
+    let status = read_pm1_status(&state.fadt) & read_pm1_enable(&state.fadt);
+    clear_pm1_status(&state.fadt, status);
+
+    queue_events(|pending| {
+        if status.contains(Pm1Event::POWER_BUTTON) {
+            pending.power_button = true;
+        }
+        queue_gpes(&state.fadt, pending);
+    });
+}
+
+fn wait_for_events() -> (bool, [u64; MAX_GPES / 64]) {
+    loop {
+        let events = PENDING_EVENTS.lock().take();
+        if events.0 || events.1.iter().any(|bits| *bits != 0) {
+            return events;
+        }
+
+        let task = scheduler::current_task();
+        scheduler::block_current(move || {
+            // Events could have come in since we looked, in which case the handler won't wake us
+            let mut pending = PENDING_EVENTS.lock();
+            if pending.is_empty() {
+                pending.waiter = Some(task);
+            } else {
+                task.wake();
+            }
+        });
+    }
+}
+
+#[cfg(not(test))]
+fn power_button_pressed() {
+    crate::info!("Power button pressed, shutting down");
+    shutdown();
+}
+
+// The tests can't let the machine turn off, so they count presses instead
+#[cfg(test)]
+static POWER_BUTTON_PRESSES: AtomicUsize = AtomicUsize::new(0);
+
+#[cfg(test)]
+fn power_button_pressed() {
+    POWER_BUTTON_PRESSES.fetch_add(1, Ordering::SeqCst);
+}
+
+fn event_task() -> ! {
+    loop {
+        let (power_button, gpes) = wait_for_events();
+        if power_button {
+            power_button_pressed();
+        }
+
+        let fadt = match *PM_STATE.lock() {
+            Some(state) => state.fadt,
+            None => continue,
+        };
+
+        let events = PendingEvents {
+            power_button,
+            gpes,
+            waiter: None,
+        };
+        for register in gpe_registers(&fadt) {
+            for bit in (0..8).filter(|bit| events.has_gpe(register.first_gpe + bit)) {
+                let gpe = register.first_gpe + bit;
+                let mut status_port = IoPort::<u8>::new(register.status_port);
+
+                {
+                    let mut acpi_lock = ACPI.lock();
+                    let acpi = acpi_lock.as_mut().unwrap();
+                    dispatch_gpe(&mut acpi.aml_context, gpe, || status_port.write(1 << bit));
+                }
+
+                // The handler disables GPEs with the lock held, so it can't race with this
+                let _pending = PENDING_EVENTS.lock();
+                let mut enable_port = IoPort::<u8>::new(register.enable_port);
+                enable_port.write(enable_port.read() | (1 << bit));
+            }
+        }
+    }
+}
+
+/// Start the task that runs the AML for ACPI events. Until it runs, events are queued.
+pub unsafe fn spawn_event_task() -> scheduler::Result<TaskReference> {
+    scheduler::spawn(None, event_task)
+}
+
+unsafe fn enter_acpi_mode(fadt: &Fadt) {
+    if read_pm1_control(fadt).contains(Pm1Control::SCI_ENABLE) {
+        return;
+    }
+
+    assert!(
+        fadt.smi_command_port != 0 && fadt.acpi_enable != 0,
+        "System is in legacy mode and cannot be switched to ACPI mode"
+    );
+
+    IoPort::<u8>::new(fadt.smi_command_port).write(fadt.acpi_enable);
+
+    for _ in 0..ACPI_ENABLE_RETRIES {
+        if read_pm1_control(fadt).contains(Pm1Control::SCI_ENABLE) {
+            return;
+        }
+        crate::interrupts::pause();
+    }
+
+    panic!("Firmware did not switch to ACPI mode");
+}
+
+/// Switch the system into ACPI mode and start handling the SCI. Power button presses shut the
+/// system down, and general purpose events are dispatched to their `_Lxx`/`_Exx` methods, once
+/// the event task is running.
+pub unsafe fn enable_sci() {
+    let (fadt, s5_sleep_types) = {
+        let mut acpi_lock = ACPI.lock();
+        let acpi = acpi_lock.as_mut().unwrap();
+
+        let fadt = match acpi.fadt {
+            Some(fadt) => fadt,
+            None => {
+                crate::warn!("No FADT, not enabling the ACPI SCI");
+                return;
+            }
+        };
+
+        enter_acpi_mode(&fadt);
+
+        // Only enable the GPEs that the firmware gave us a method for. Everything else stays off.
+        for register in gpe_registers(&fadt) {
+            let mut enable = 0u8;
+            for bit in 0..8 {
+                let gpe = register.first_gpe + bit;
+                if has_method(&acpi.aml_context, &gpe_method('L', gpe))
+                    || has_method(&acpi.aml_context, &gpe_method('E', gpe))
+                {
+                    enable |= 1 << bit;
+                }
+            }
+
+            IoPort::<u8>::new(register.enable_port).write(enable);
+            IoPort::<u8>::new(register.status_port).write(0xff);
+        }
+
+        (fadt, sleep_types(&acpi.aml_context, "\\_S5"))
+    };
+
+    *PM_STATE.lock() = Some(PmState {
+        fadt,
+        s5_sleep_types,
+    });
+
+    write_pm1_enable(&fadt, Pm1Event::POWER_BUTTON);
+    clear_pm1_status(&fadt, Pm1Event::all());
+
+    let sci_irq = fadt.sci_interrupt as u8;
+    irq::register_legacy_handler(sci_irq, sci_handler);
+    io_apic::map_sci(sci_irq);
+
+    crate::info!("ACPI SCI enabled on IRQ {}", sci_irq);
+}
+
+/// Put the system into the S5 (soft off) state. If that isn't possible we just halt.
+pub fn shutdown() -> ! {
+    ipi(IpiKind::Halt, IpiTarget::Other);
+
+    let state = *PM_STATE.lock();
+    if let Some(PmState {
+        fadt,
+        s5_sleep_types: Some((sleep_type_a, sleep_type_b)),
+    }) = state
+    {
+        unsafe { crate::interrupts::disable() };
+
+        let blocks = [
+            (fadt.pm1a_control_block, sleep_type_a),
+            (fadt.pm1b_control_block, sleep_type_b),
+        ];
+        for (block, sleep_type) in blocks.iter().filter(|(block, _)| *block != 0) {
+            let mut port = IoPort::<u16>::new(*block);
+            let control = port.read() & !(SLEEP_TYPE_MASK | Pm1Control::SLEEP_ENABLE.bits());
+            port.write(
+                control
+                    | ((sleep_type << SLEEP_TYPE_SHIFT) & SLEEP_TYPE_MASK)
+                    | Pm1Control::SLEEP_ENABLE.bits(),
+            );
+        }
+    }
+
+    crate::error!("ACPI shutdown failed, halting");
+    crate::interrupts::disable_and_halt()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::devices::local_apic::local_apic_access;
+
+    #[test_case]
+    fn sci_is_enabled_and_handler_fires() {
+        let state = match *PM_STATE.lock() {
+            Some(state) => state,
+            None => {
+                crate::skip_test("no FADT, so no SCI");
+                return;
+            }
+        };
+
+        assert!(read_pm1_control(&state.fadt).contains(Pm1Control::SCI_ENABLE));
+        assert!(read_pm1_enable(&state.fadt).contains(Pm1Event::POWER_BUTTON));
+
+        // There's no way to press the power button from inside the guest, so we raise the SCI
+        // vector on the BSP with an IPI instead. No status bits are set, so the handler just
+        // acknowledges it and queues nothing.
+        let bsp_apic_id = {
+            let acpi_lock = ACPI.lock();
+            let acpi = acpi_lock.as_ref().unwrap();
+            acpi.acpi_context
+                .boot_processor
+                .as_ref()
+                .unwrap()
+                .local_apic_id
+        };
+        let vector = 32 + state.fadt.sci_interrupt as u32;
+
+        let before = SCI_COUNT.load(Ordering::SeqCst);
+        local_apic_access().set_icr(bsp_apic_id.into(), 1 << 14 | vector);
+
+        let were_enabled = crate::interrupts::are_enabled();
+        unsafe { crate::interrupts::enable() };
+        for _ in 0..ACPI_ENABLE_RETRIES {
+            if SCI_COUNT.load(Ordering::SeqCst) != before {
+                break;
+            }
+            crate::interrupts::pause();
+        }
+        if !were_enabled {
+            unsafe { crate::interrupts::disable() };
+        }
+
+        assert_ne!(SCI_COUNT.load(Ordering::SeqCst), before);
+        assert!(PENDING_EVENTS.lock().is_empty());
+    }
+
+    #[test_case]
+    fn power_button_events_reach_the_event_task() {
+        if PM_STATE.lock().is_none() {
+            crate::skip_test("no FADT, so no SCI");
+            return;
+        }
+
+        // This is what the SCI handler queues when the power button status bit is set
+        let before = POWER_BUTTON_PRESSES.load(Ordering::SeqCst);
+        queue_events(|pending| pending.power_button = true);
+
+        for _ in 0..1000 {
+            if POWER_BUTTON_PRESSES.load(Ordering::SeqCst) != before {
+                break;
+            }
+            crate::scheduler::sleep(core::time::Duration::from_millis(1));
+        }
+
+        assert_eq!(POWER_BUTTON_PRESSES.load(Ordering::SeqCst), before + 1);
+        assert!(!PENDING_EVENTS.lock().power_button);
+    }
+}
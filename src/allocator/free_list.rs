@@ -1,5 +1,6 @@
 use super::align_up;
 use core::alloc::Layout;
+use core::fmt;
 use core::mem::{align_of, size_of};
 use core::ptr::NonNull;
 
@@ -18,10 +19,83 @@ struct Allocation {
 
 pub(super) struct FreeList {
     head: FreeNode,
+    // The space the list was made with, for the integrity check
+    start: usize,
+    limit: usize,
     allocated_space: usize,
     free_space: usize,
 }
 
+/// The first problem check_integrity found in a free list
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HeapCorruption {
+    MisalignedNode {
+        addr: usize,
+    },
+    NodeOutsideList {
+        addr: usize,
+    },
+    BadNodeSize {
+        addr: usize,
+        size: usize,
+    },
+    OverlappingNodes {
+        addr: usize,
+        next: usize,
+    },
+    UncoalescedNodes {
+        addr: usize,
+        next: usize,
+    },
+    FreeSpaceMismatch {
+        counted: usize,
+        recorded: usize,
+    },
+    SpaceMismatch {
+        free: usize,
+        allocated: usize,
+        size: usize,
+    },
+}
+
+impl fmt::Display for HeapCorruption {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::MisalignedNode { addr } => write!(f, "Free node at {:#x} is misaligned", addr),
+            Self::NodeOutsideList { addr } => {
+                write!(f, "Free node at {:#x} is outside its list", addr)
+            }
+            Self::BadNodeSize { addr, size } => {
+                write!(f, "Free node at {:#x} has bad size {:#x}", addr, size)
+            }
+            Self::OverlappingNodes { addr, next } => write!(
+                f,
+                "Free node at {:#x} overlaps or is after the next one at {:#x}",
+                addr, next
+            ),
+            Self::UncoalescedNodes { addr, next } => write!(
+                f,
+                "Free node at {:#x} touches the next one at {:#x}",
+                addr, next
+            ),
+            Self::FreeSpaceMismatch { counted, recorded } => write!(
+                f,
+                "Free nodes add up to {:#x} bytes but {:#x} are recorded as free",
+                counted, recorded
+            ),
+            Self::SpaceMismatch {
+                free,
+                allocated,
+                size,
+            } => write!(
+                f,
+                "{:#x} bytes free and {:#x} allocated in a list of {:#x} bytes",
+                free, allocated, size
+            ),
+        }
+    }
+}
+
 #[repr(transparent)]
 #[derive(Debug, Clone, Copy)]
 pub(super) struct AlignedLayout(Layout);
@@ -95,6 +169,8 @@ impl FreeList {
                 size: 0,
                 next: Some(&mut *ptr),
             },
+            start: aligned_start,
+            limit,
             allocated_space: 0,
             free_space: size,
         }
@@ -136,6 +212,73 @@ impl FreeList {
         self.allocated_space
     }
 
+    /// Walk the nodes checking that they are in address order, inside the list, aligned, and
+    /// coalesced, and that the space adds up. Each node is checked before it is read, so a corrupt
+    /// next pointer is reported rather than followed.
+    pub fn check_integrity(&self) -> Result<(), HeapCorruption> {
+        let mut counted_free_space = 0;
+        let mut previous: Option<HoleInfo> = None;
+        let mut node = &self.head;
+        while let Some(next) = node.next.as_ref() {
+            let addr = &**next as *const FreeNode as usize;
+            if addr % Self::min_alignment() != 0 {
+                return Err(HeapCorruption::MisalignedNode { addr });
+            }
+
+            if addr < self.start || addr > self.limit.saturating_sub(size_of::<FreeNode>()) {
+                return Err(HeapCorruption::NodeOutsideList { addr });
+            }
+
+            let info = next.info();
+            if info.size < Self::min_alloc_size() || info.size % Self::min_alignment() != 0 {
+                return Err(HeapCorruption::BadNodeSize {
+                    addr,
+                    size: info.size,
+                });
+            }
+
+            if info.size > self.limit - addr {
+                return Err(HeapCorruption::NodeOutsideList { addr });
+            }
+
+            if let Some(previous) = previous {
+                let previous_end = previous.addr + previous.size;
+                if addr < previous_end {
+                    return Err(HeapCorruption::OverlappingNodes {
+                        addr: previous.addr,
+                        next: addr,
+                    });
+                } else if addr == previous_end {
+                    return Err(HeapCorruption::UncoalescedNodes {
+                        addr: previous.addr,
+                        next: addr,
+                    });
+                }
+            }
+
+            counted_free_space += info.size;
+            previous = Some(info);
+            node = next;
+        }
+
+        if counted_free_space != self.free_space {
+            return Err(HeapCorruption::FreeSpaceMismatch {
+                counted: counted_free_space,
+                recorded: self.free_space,
+            });
+        }
+
+        if self.free_space + self.allocated_space != self.limit - self.start {
+            return Err(HeapCorruption::SpaceMismatch {
+                free: self.free_space,
+                allocated: self.allocated_space,
+                size: self.limit - self.start,
+            });
+        }
+
+        Ok(())
+    }
+
     #[cfg(test)]
     pub fn node_count(&self) -> usize {
         let mut prev_node = &self.head;
@@ -440,4 +583,76 @@ mod test {
             align *= 2;
         }
     }
+
+    #[test_case]
+    fn integrity_check_finds_corruption() {
+        let mut t = make_free_list(1024, FreeList::min_alignment());
+        let layout = FreeList::align_layout(Layout::from_size_align(64, 8).unwrap()).unwrap();
+
+        let allocations = [
+            t.free_list.allocate(layout).unwrap(),
+            t.free_list.allocate(layout).unwrap(),
+            t.free_list.allocate(layout).unwrap(),
+        ];
+        t.free_list.deallocate(allocations[1], layout);
+        assert_eq!(t.free_list.node_count(), 2);
+        assert_eq!(t.free_list.check_integrity(), Ok(()));
+
+        // The free nodes are the middle allocation and everything after the last one
+        let addr = allocations[1].as_ptr() as usize;
+        let next = allocations[2].as_ptr() as usize + 64;
+        let node = unsafe { &mut *(addr as *mut FreeNode) };
+
+        node.size = 192;
+        assert_eq!(
+            t.free_list.check_integrity(),
+            Err(HeapCorruption::OverlappingNodes { addr, next })
+        );
+
+        node.size = 128;
+        assert_eq!(
+            t.free_list.check_integrity(),
+            Err(HeapCorruption::UncoalescedNodes { addr, next })
+        );
+
+        node.size = 60;
+        assert_eq!(
+            t.free_list.check_integrity(),
+            Err(HeapCorruption::BadNodeSize { addr, size: 60 })
+        );
+        node.size = 64;
+
+        let outside = t.aligned_storage + 2048;
+        let saved_next = node.next.take();
+        node.next = Some(unsafe { &mut *(outside as *mut FreeNode) });
+        assert_eq!(
+            t.free_list.check_integrity(),
+            Err(HeapCorruption::NodeOutsideList { addr: outside })
+        );
+
+        // A node at the very top of the address space mustn't make the bounds check overflow
+        let top = !(FreeList::min_alignment() - 1);
+        node.next = Some(unsafe { &mut *(top as *mut FreeNode) });
+        assert_eq!(
+            t.free_list.check_integrity(),
+            Err(HeapCorruption::NodeOutsideList { addr: top })
+        );
+        node.next = saved_next;
+
+        t.free_list.allocated_space += 8;
+        assert_eq!(
+            t.free_list.check_integrity(),
+            Err(HeapCorruption::SpaceMismatch {
+                free: 1024 - 128,
+                allocated: 136,
+                size: 1024
+            })
+        );
+        t.free_list.allocated_space -= 8;
+
+        assert_eq!(t.free_list.check_integrity(), Ok(()));
+        t.free_list.deallocate(allocations[0], layout);
+        t.free_list.deallocate(allocations[2], layout);
+        assert_eq!(t.free_list.check_integrity(), Ok(()));
+    }
 }
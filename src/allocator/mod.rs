@@ -1,7 +1,14 @@
-use crate::init_mutex::InitMutex;
+use crate::gdt;
+use crate::init_mutex::{InitMutex, InitMutexGuard};
+use crate::scheduler::{self, TaskReference};
 use core::alloc::{GlobalAlloc, Layout};
+use core::fmt;
+use core::ops::{Deref, DerefMut};
+use core::time::Duration;
 use simple_allocator::SimpleAllocator;
 
+pub use free_list::HeapCorruption;
+
 mod free_list;
 mod simple_allocator;
 
@@ -29,16 +36,169 @@ pub unsafe fn init() {
 
 pub struct Allocator;
 
+// Lets tests make heap allocations fail on demand
+#[cfg(test)]
+static INJECTED_FAILURES: core::sync::atomic::AtomicUsize = core::sync::atomic::AtomicUsize::new(0);
+
+/// Make the next n heap allocations fail
+#[cfg(test)]
+pub fn fail_next_n_allocations(n: usize) {
+    INJECTED_FAILURES.store(n, core::sync::atomic::Ordering::SeqCst);
+}
+
+// Set while this CPU is inside the allocator. The allocator lock doesn't know which CPU holds it,
+// so without this anything under it that allocates would spin on it forever.
+#[thread_local]
+static mut IN_ALLOCATOR: bool = false;
+
+#[derive(Debug)]
+pub struct RecursiveAllocation {
+    cpu_id: usize,
+}
+
+impl fmt::Display for RecursiveAllocation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Heap allocation from inside the allocator on CPU {}",
+            self.cpu_id
+        )
+    }
+}
+
+// The allocator locked by this CPU. The flag is only set once the lock is held, so an interrupt
+// that allocates while this CPU waits for the lock doesn't mistake the wait for recursion.
+struct AllocatorGuard<'a> {
+    guard: Option<InitMutexGuard<'a, SimpleAllocator>>,
+    tls_ready: bool,
+}
+
+impl<'a> AllocatorGuard<'a> {
+    fn lock() -> Result<Self, RecursiveAllocation> {
+        // The heap is up before this CPU has any thread local storage. Nothing can interrupt the
+        // allocator that early, so there is nothing to check.
+        let tls_ready = gdt::tls_ready();
+
+        unsafe {
+            if tls_ready && IN_ALLOCATOR {
+                return Err(RecursiveAllocation {
+                    cpu_id: crate::cpu_id(),
+                });
+            }
+        }
+
+        let guard = ALLOCATOR_IMPL.lock();
+        if tls_ready {
+            unsafe { IN_ALLOCATOR = true };
+        }
+
+        Ok(Self {
+            guard: Some(guard),
+            tls_ready,
+        })
+    }
+}
+
+impl<'a> Deref for AllocatorGuard<'a> {
+    type Target = SimpleAllocator;
+    fn deref(&self) -> &Self::Target {
+        self.guard.as_ref().unwrap()
+    }
+}
+
+impl<'a> DerefMut for AllocatorGuard<'a> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.guard.as_mut().unwrap()
+    }
+}
+
+impl<'a> Drop for AllocatorGuard<'a> {
+    fn drop(&mut self) {
+        // Unlock first, for the same reason the flag is set last
+        self.guard = None;
+        if self.tls_ready {
+            unsafe { IN_ALLOCATOR = false };
+        }
+    }
+}
+
+impl Allocator {
+    unsafe fn try_alloc(&self, layout: Layout) -> Result<*mut u8, RecursiveAllocation> {
+        Ok(AllocatorGuard::lock()?.alloc(layout))
+    }
+
+    unsafe fn try_dealloc(&self, ptr: *mut u8, layout: Layout) -> Result<(), RecursiveAllocation> {
+        AllocatorGuard::lock()?.dealloc(ptr, layout);
+        Ok(())
+    }
+}
+
 unsafe impl GlobalAlloc for Allocator {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        ALLOCATOR_IMPL.lock().alloc(layout)
+        // The free list would round this up to a whole node. Nothing can be read or written
+        // through it, so any suitably aligned address will do.
+        if layout.size() == 0 {
+            return layout.align() as *mut u8;
+        }
+
+        #[cfg(test)]
+        {
+            use core::sync::atomic::Ordering;
+            if INJECTED_FAILURES
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |count| {
+                    count.checked_sub(1)
+                })
+                .is_ok()
+            {
+                return core::ptr::null_mut();
+            }
+        }
+
+        self.try_alloc(layout)
+            .unwrap_or_else(|err| panic!("{}", err))
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
-        ALLOCATOR_IMPL.lock().dealloc(ptr, layout);
+        if layout.size() == 0 {
+            return;
+        }
+
+        self.try_dealloc(ptr, layout)
+            .unwrap_or_else(|err| panic!("{}", err))
+    }
+}
+
+/// Give the pages of the initial heap buffer back to physmem once nothing is allocated in it. This
+/// must wait until physmem is fully initialized. Returns the number of frames recovered now; if
+/// the buffer is still in use, it is reclaimed when it empties.
+pub unsafe fn reclaim_initial_region() -> usize {
+    ALLOCATOR_IMPL.lock().reclaim_initial_region()
+}
+
+/// Unmap the heap regions that have emptied since the last call. dealloc only sets them aside,
+/// because unmapping takes the page table lock and shoots down the other CPUs' TLBs. Returns the
+/// number of regions released.
+pub unsafe fn release_empty_regions() -> usize {
+    // The regions are released without the heap locked, so whatever that frees can use the heap
+    let empty_regions = ALLOCATOR_IMPL.lock().take_empty_regions();
+    empty_regions.release()
+}
+
+// How often the heap task gives empty regions back
+const RELEASE_INTERVAL: Duration = Duration::from_millis(100);
+
+fn release_task() -> ! {
+    loop {
+        scheduler::sleep(RELEASE_INTERVAL);
+        unsafe { release_empty_regions() };
     }
 }
 
+/// Start the task that gives empty heap regions back
+pub unsafe fn spawn_release_task() -> scheduler::Result<TaskReference> {
+    scheduler::spawn(None, release_task)
+}
+
 pub fn allocated_space() -> usize {
     ALLOCATOR_IMPL.lock().allocated_space()
 }
@@ -46,3 +206,111 @@ pub fn allocated_space() -> usize {
 pub fn free_space() -> usize {
     ALLOCATOR_IMPL.lock().free_space()
 }
+
+/// Check every free list in the heap, for tracking down corruption. Returns the first problem
+/// found.
+pub fn check_integrity() -> Result<(), HeapCorruption> {
+    ALLOCATOR_IMPL.lock().check_integrity()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::interrupts::without_interrupts;
+
+    #[test_case]
+    fn injected_heap_failures_return_null() {
+        let layout = Layout::from_size_align(64, 8).unwrap();
+
+        fail_next_n_allocations(2);
+        unsafe {
+            assert!(alloc::alloc::alloc(layout).is_null());
+            assert!(alloc::alloc::alloc(layout).is_null());
+
+            let ptr = alloc::alloc::alloc(layout);
+            assert!(!ptr.is_null());
+            alloc::alloc::dealloc(ptr, layout);
+        }
+    }
+
+    #[test_case]
+    fn heap_passes_integrity_check() {
+        let buffers: alloc::vec::Vec<_> = (0..32).map(|i| alloc::vec![i as u8; i * 24]).collect();
+        assert_eq!(check_integrity(), Ok(()));
+
+        // Leave holes between the ones that are still allocated
+        let remaining: alloc::vec::Vec<_> = buffers.into_iter().step_by(2).collect();
+        assert_eq!(check_integrity(), Ok(()));
+        core::mem::drop(remaining);
+        assert_eq!(check_integrity(), Ok(()));
+    }
+
+    #[test_case]
+    fn zero_sized_allocations_use_no_space() {
+        const ALIGNS: [usize; 3] = [1, 8, 4096];
+
+        // The heap stays locked throughout, so other CPUs can't change the counts, and a zero
+        // sized allocation that went near the heap would be caught as a recursive one. Nothing is
+        // checked until it is unlocked, since a failed check allocates.
+        let (pointers, before, after) = without_interrupts(|| {
+            let allocator = AllocatorGuard::lock().unwrap();
+            let before = (allocator.allocated_space(), allocator.free_space());
+
+            let mut pointers = [core::ptr::null_mut(); ALIGNS.len()];
+            for (ptr, align) in pointers.iter_mut().zip(ALIGNS.iter()) {
+                let layout = Layout::from_size_align(0, *align).unwrap();
+                unsafe {
+                    *ptr = Allocator.alloc(layout);
+                    Allocator.dealloc(*ptr, layout);
+                }
+            }
+
+            let after = (allocator.allocated_space(), allocator.free_space());
+            (pointers, before, after)
+        });
+
+        for (ptr, align) in pointers.iter().zip(ALIGNS.iter()) {
+            assert!(!ptr.is_null());
+            assert_eq!(*ptr as usize % align, 0);
+        }
+        assert_eq!(after, before);
+    }
+
+    // Stands in for allocator code that allocates while it is inside the allocator. This really
+    // holds the heap lock, so interrupts are kept off while it does.
+    struct ReentrantAllocator;
+
+    unsafe impl GlobalAlloc for ReentrantAllocator {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            without_interrupts(|| {
+                let _allocator = AllocatorGuard::lock().unwrap();
+                Allocator.try_alloc(layout).unwrap_or(core::ptr::null_mut())
+            })
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            Allocator.dealloc(ptr, layout);
+        }
+    }
+
+    #[test_case]
+    fn recursive_allocation_is_caught() {
+        let layout = Layout::from_size_align(64, 8).unwrap();
+
+        unsafe {
+            assert!(ReentrantAllocator.alloc(layout).is_null());
+
+            // Leaving the allocator clears the flag again
+            let ptr = Allocator.alloc(layout);
+            assert!(!ptr.is_null());
+
+            let caught = without_interrupts(|| {
+                let _allocator = AllocatorGuard::lock().unwrap();
+                Allocator.try_dealloc(ptr, layout).is_err()
+            });
+            assert!(caught);
+
+            assert!(Allocator.try_dealloc(ptr, layout).is_ok());
+        }
+    }
+}
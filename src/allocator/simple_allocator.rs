@@ -1,8 +1,8 @@
 use super::{
     align_up,
-    free_list::{AlignedLayout, FreeList},
+    free_list::{AlignedLayout, FreeList, HeapCorruption},
 };
-use crate::paging::{allocate_region, Region, PAGE_SIZE};
+use crate::paging::{allocate_region, release_kernel_image_pages, Region, PAGE_SIZE};
 use core::alloc::{GlobalAlloc, Layout};
 use core::mem::{align_of, size_of};
 use core::ptr::{null_mut, NonNull};
@@ -14,8 +14,43 @@ const MINIMUM_HEAP_REGION_SIZE: usize = MINIMUM_HEAP_REGION_PAGES * PAGE_SIZE;
 // When we have an empty region, we don't release it back if our free space is less than this
 const HEAP_RESERVE_LIMIT: usize = 128; // * 1024;
 
+// Allocations at least this big, or aligned to a page or more, get a region of their own. Carving
+// them out of the shared regions would mostly add padding, and they would keep a whole region
+// alive after everything else in it had been freed.
+const DEDICATED_REGION_THRESHOLD: usize = MINIMUM_HEAP_REGION_SIZE;
+
 struct HeapRegionList {
     head: HeapRegion,
+    // Set once the static buffer regions should be given back as soon as they are empty
+    reclaim_buffers: bool,
+    // Empty regions waiting to be given back. Unmapping takes the page table lock and a TLB
+    // shootdown, which is too much for dealloc, so it happens later in release_empty_regions.
+    empty: Option<&'static mut HeapRegion>,
+}
+
+/// Heap regions that have been taken off the heap, to be unmapped once the heap lock is dropped
+pub struct EmptyRegions(Option<&'static mut HeapRegion>);
+
+impl EmptyRegions {
+    /// Unmap the regions. Returns how many there were.
+    pub unsafe fn release(self) -> usize {
+        let mut released = 0;
+        let mut next = self.0;
+        while let Some(region) = next {
+            // The link lives inside the region, so it has to be read first
+            next = region.next.take();
+            if region.is_buffer() {
+                HeapRegionList::release_buffer_region(region);
+            } else {
+                // We have to move the payload out of the region that it is held in before we drop it, otherwise we end up with
+                // the memory going away part way through the drop which is weird.
+                core::mem::drop((region as *mut HeapRegion).read());
+            }
+            released += 1;
+        }
+
+        released
+    }
 }
 
 impl HeapRegionList {
@@ -25,16 +60,90 @@ impl HeapRegionList {
                 payload: None,
                 next: None,
             },
+            reclaim_buffers: false,
+            empty: None,
+        }
+    }
+
+    // Make a list with a single region carved out of a static buffer. Buffer regions can't be
+    // freed like the page backed ones, only reclaimed.
+    unsafe fn with_buffer(buffer: &'static mut [u8]) -> Self {
+        let region_start = buffer.as_mut_ptr() as usize;
+        let region_end = region_start + buffer.len();
+
+        let aligned_start = align_up(region_start, align_of::<HeapRegion>());
+        let size = region_end.saturating_sub(aligned_start);
+        assert!(size >= size_of::<HeapRegion>());
+
+        let ptr = aligned_start as *mut HeapRegion;
+        ptr.write(HeapRegion {
+            payload: Some(HeapRegionPayload {
+                alloc_region: PayloadRegionAlloc::from_slice(buffer),
+                can_free: false,
+                dedicated: false,
+                free_list: FreeList::new(aligned_start + size_of::<HeapRegion>(), region_end),
+            }),
+            next: None,
+        });
+
+        Self {
+            head: HeapRegion {
+                payload: None,
+                next: Some(&mut *ptr),
+            },
+            reclaim_buffers: false,
+            empty: None,
+        }
+    }
+
+    /// Give back the pages of any buffer regions. Regions that are in use are given back when
+    /// their last allocation is freed. Returns the number of frames recovered now.
+    pub unsafe fn reclaim_buffers(&mut self) -> usize {
+        self.reclaim_buffers = true;
+
+        let mut recovered = 0;
+        let mut prev_region = &mut self.head;
+        while prev_region.next.is_some() {
+            let this_region = prev_region.next.as_ref().unwrap();
+            if this_region.is_buffer() && this_region.allocated_space() == 0 {
+                let removed_region = prev_region.next.take().unwrap();
+                prev_region.next = removed_region.next.take();
+                recovered += Self::release_buffer_region(removed_region);
+            } else {
+                prev_region = prev_region.next.as_mut().unwrap();
+            }
+        }
+
+        recovered
+    }
+
+    // The region header lives inside the buffer, so the payload has to be moved out before the
+    // pages are released
+    unsafe fn release_buffer_region(region: &'static mut HeapRegion) -> usize {
+        match (region as *mut HeapRegion).read().payload {
+            Some(HeapRegionPayload {
+                alloc_region: PayloadRegionAlloc::Buffer(buffer),
+                ..
+            }) => release_kernel_image_pages(buffer.as_ptr() as usize, buffer.len()),
+            _ => panic!("Region is not a buffer region"),
         }
     }
 
     pub unsafe fn alloc(&mut self, original_layout: Layout) -> Option<NonNull<u8>> {
         FreeList::align_layout(original_layout).and_then(|aligned_layout| {
-            Self::do_allocate(&mut self.head, aligned_layout)
-                .or_else(|| self.expand_and_allocate(aligned_layout))
+            if Self::needs_dedicated_region(aligned_layout) {
+                self.allocate_dedicated(aligned_layout)
+            } else {
+                Self::do_allocate(&mut self.head, aligned_layout)
+                    .or_else(|| self.expand_and_allocate(aligned_layout))
+            }
         })
     }
 
+    fn needs_dedicated_region(layout: AlignedLayout) -> bool {
+        layout.size() >= DEDICATED_REGION_THRESHOLD || layout.align() >= PAGE_SIZE
+    }
+
     unsafe fn do_allocate(
         mut prev_region: &mut HeapRegion,
         layout: AlignedLayout,
@@ -60,29 +169,49 @@ impl HeapRegionList {
             if let Some(mut removed_region_list) =
                 Self::do_deallocate(&mut self.head, ptr, aligned_layout)
             {
-                let (removed_region_can_free, removed_region_free_space) = {
+                let (
+                    removed_region_can_free,
+                    removed_region_dedicated,
+                    removed_region_free_space,
+                ) = {
                     let removed_region = removed_region_list.next.as_ref().unwrap();
-                    (removed_region.can_free(), removed_region.free_space())
+                    (
+                        removed_region.can_free(),
+                        removed_region.is_dedicated(),
+                        removed_region.free_space(),
+                    )
                 };
 
                 // If we have enough free space, then we do not need to keep this region around and we can drop it.
                 // But, we don't want to keep really big regions around, so if the regions free space is larger than
-                // the default space we always drop it
-                if !removed_region_can_free
-                    || (removed_region_free_space < MINIMUM_HEAP_REGION_SIZE
+                // the default space we always drop it. Dedicated regions only ever hold the one
+                // allocation, so they are always dropped too. Dropped regions are only set aside
+                // here, and are unmapped by the next release_empty_regions.
+                if removed_region_list.next.as_ref().unwrap().is_buffer() && self.reclaim_buffers {
+                    self.set_aside(removed_region_list.next.take().unwrap());
+                } else if !removed_region_can_free
+                    || (!removed_region_dedicated
+                        && removed_region_free_space < MINIMUM_HEAP_REGION_SIZE
                         && self.free_space() < HEAP_RESERVE_LIMIT)
                 {
                     removed_region_list.next.as_mut().unwrap().next = self.head.next.take();
                     self.head.next = removed_region_list.next.take();
                 } else {
-                    // We have to move the payload out of the region that it is held in before we drop it, otherwise we end up with
-                    // the memory going away part way through the drop which is weird.
-                    core::mem::drop((removed_region_list.next.unwrap() as *mut HeapRegion).read());
+                    self.set_aside(removed_region_list.next.take().unwrap());
                 }
             }
         });
     }
 
+    fn set_aside(&mut self, region: &'static mut HeapRegion) {
+        region.next = self.empty.take();
+        self.empty = Some(region);
+    }
+
+    pub fn take_empty_regions(&mut self) -> EmptyRegions {
+        EmptyRegions(self.empty.take())
+    }
+
     unsafe fn do_deallocate(
         mut prev_region: &mut HeapRegion,
         ptr: NonNull<u8>,
@@ -147,6 +276,16 @@ impl HeapRegionList {
         }
     }
 
+    pub fn check_integrity(&self) -> Result<(), HeapCorruption> {
+        let mut prev_region = &self.head;
+        while let Some(region) = prev_region.next.as_ref() {
+            region.check_integrity()?;
+            prev_region = region;
+        }
+
+        Ok(())
+    }
+
     unsafe fn expand_and_allocate(&mut self, layout: AlignedLayout) -> Option<NonNull<u8>> {
         // The smallest possible region that this could fit in is the size of a region
         // header, plus whatever padding needed to get to alignment, plus the size of the
@@ -193,6 +332,7 @@ impl HeapRegionList {
                 payload: Some(HeapRegionPayload {
                     alloc_region: PayloadRegionAlloc::from_region(region),
                     can_free: true,
+                    dedicated: false,
                     free_list: FreeList::new(aligned_start + size_of::<HeapRegion>(), limit),
                 }),
                 next: self.head.next.take(),
@@ -208,6 +348,45 @@ impl HeapRegionList {
                 .expect("Couldn't make allocation from new region")
         })
     }
+
+    // Make a region that holds nothing but this allocation. The allocation goes at the start of the
+    // region, which is already page aligned, and the header goes after it so that it doesn't push
+    // the allocation onto another page.
+    unsafe fn allocate_dedicated(&mut self, layout: AlignedLayout) -> Option<NonNull<u8>> {
+        // Regions are only page aligned, so anything aligned further may have to move up
+        let alignment_slack = layout.align().saturating_sub(PAGE_SIZE);
+        let region_size = alignment_slack
+            + align_up(layout.size(), align_of::<HeapRegion>())
+            + size_of::<HeapRegion>();
+        let allocation_pages = align_up(region_size, PAGE_SIZE) / PAGE_SIZE;
+
+        allocate_region(allocation_pages).ok().map(|region| {
+            let allocation_start = align_up(region.start(), layout.align());
+            let allocation_limit = allocation_start + layout.size();
+            let header_start = align_up(allocation_limit, align_of::<HeapRegion>());
+            assert!(header_start + size_of::<HeapRegion>() <= region.limit());
+
+            let ptr = header_start as *mut HeapRegion;
+            ptr.write(HeapRegion {
+                payload: Some(HeapRegionPayload {
+                    alloc_region: PayloadRegionAlloc::from_region(region),
+                    can_free: true,
+                    dedicated: true,
+                    free_list: FreeList::new(allocation_start, allocation_limit),
+                }),
+                next: self.head.next.take(),
+            });
+
+            self.head.next = Some(&mut *ptr);
+
+            self.head
+                .next
+                .as_mut()
+                .unwrap()
+                .allocate(layout)
+                .expect("Couldn't make allocation from dedicated region")
+        })
+    }
 }
 
 enum PayloadRegionAlloc {
@@ -244,6 +423,8 @@ impl PayloadRegionAlloc {
 struct HeapRegionPayload {
     alloc_region: PayloadRegionAlloc,
     can_free: bool,
+    // Made for a single allocation, and dropped as soon as it is freed
+    dedicated: bool,
     free_list: FreeList,
 }
 
@@ -276,6 +457,14 @@ impl HeapRegionPayload {
     pub fn can_free(&self) -> bool {
         self.can_free
     }
+
+    pub fn is_dedicated(&self) -> bool {
+        self.dedicated
+    }
+
+    pub fn check_integrity(&self) -> Result<(), HeapCorruption> {
+        self.free_list.check_integrity()
+    }
 }
 
 struct HeapRegion {
@@ -316,6 +505,29 @@ impl HeapRegion {
             .map(|payload| payload.can_free())
             .unwrap_or(false)
     }
+
+    pub fn is_dedicated(&self) -> bool {
+        self.payload
+            .as_ref()
+            .map(|payload| payload.is_dedicated())
+            .unwrap_or(false)
+    }
+
+    pub fn check_integrity(&self) -> Result<(), HeapCorruption> {
+        self.payload
+            .as_ref()
+            .map_or(Ok(()), |payload| payload.check_integrity())
+    }
+
+    pub fn is_buffer(&self) -> bool {
+        match self.payload {
+            Some(HeapRegionPayload {
+                alloc_region: PayloadRegionAlloc::Buffer(_),
+                ..
+            }) => true,
+            _ => false,
+        }
+    }
 }
 
 pub struct SimpleAllocator {
@@ -345,39 +557,26 @@ impl SimpleAllocator {
             static mut INITIAL_HEAP_REGION: InitialHeapBuffer =
                 InitialHeapBuffer([0; INITIAL_HEAP_REGION_SIZE]);
 
-            let region_start = unsafe { (&mut INITIAL_HEAP_REGION.0[0] as *mut u8) as usize };
-            let region_end = region_start + INITIAL_HEAP_REGION_SIZE;
-
-            let aligned_start = align_up(region_start, align_of::<HeapRegion>());
-            let size = region_end.saturating_sub(aligned_start);
-            assert!(size >= size_of::<HeapRegion>());
-
-            let ptr = aligned_start as *mut HeapRegion;
-            unsafe {
-                ptr.write(HeapRegion {
-                    payload: Some(HeapRegionPayload {
-                        alloc_region: PayloadRegionAlloc::from_slice(&mut INITIAL_HEAP_REGION.0),
-                        can_free: false,
-                        free_list: FreeList::new(
-                            aligned_start + size_of::<HeapRegion>(),
-                            region_end,
-                        ),
-                    }),
-                    next: None,
-                })
-            }
-
             Self {
-                head_region: Mutex::new(HeapRegionList {
-                    head: HeapRegion {
-                        payload: None,
-                        next: Some(unsafe { &mut *ptr }),
-                    },
+                head_region: Mutex::new(unsafe {
+                    HeapRegionList::with_buffer(&mut INITIAL_HEAP_REGION.0)
                 }),
             }
         }
     }
 
+    /// Hand the pages of the initial heap region back to physmem once it is empty. Returns the
+    /// number of frames recovered straight away.
+    pub unsafe fn reclaim_initial_region(&self) -> usize {
+        self.head_region.lock().reclaim_buffers()
+    }
+
+    /// Take the regions that have emptied since the last call. They are only unmapped when the
+    /// result is released, which should be done without any allocator locks held.
+    pub fn take_empty_regions(&self) -> EmptyRegions {
+        self.head_region.lock().take_empty_regions()
+    }
+
     pub fn allocated_space(&self) -> usize {
         self.head_region.lock().allocated_space()
     }
@@ -385,6 +584,10 @@ impl SimpleAllocator {
     pub fn free_space(&self) -> usize {
         self.head_region.lock().free_space()
     }
+
+    pub fn check_integrity(&self) -> Result<(), HeapCorruption> {
+        self.head_region.lock().check_integrity()
+    }
 }
 
 unsafe impl GlobalAlloc for SimpleAllocator {
@@ -401,3 +604,112 @@ unsafe impl GlobalAlloc for SimpleAllocator {
             .deallocate(NonNull::new(ptr).unwrap(), layout);
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::paging::lock_page_table;
+    use crate::physmem;
+
+    const TEST_BUFFER_SIZE: usize = 4 * PAGE_SIZE;
+
+    #[repr(align(4096))]
+    struct TestBuffer([u8; TEST_BUFFER_SIZE]);
+
+    // This has to be in the BSS like the real initial region, because reclaiming unmaps it
+    static mut TEST_BUFFER: TestBuffer = TestBuffer([0; TEST_BUFFER_SIZE]);
+
+    #[test_case]
+    fn empty_buffer_region_is_reclaimed() {
+        let layout = Layout::from_size_align(256, 8).unwrap();
+        let buffer_start = unsafe { TEST_BUFFER.0.as_ptr() as usize };
+        let free_frames = physmem::free_frames();
+
+        unsafe {
+            let mut list = HeapRegionList::with_buffer(&mut TEST_BUFFER.0);
+
+            let allocations = [
+                list.alloc(layout).unwrap(),
+                list.alloc(layout).unwrap(),
+                list.alloc(layout).unwrap(),
+            ];
+            for allocation in allocations.iter() {
+                let addr = allocation.as_ptr() as usize;
+                assert!(addr >= buffer_start && addr < buffer_start + TEST_BUFFER_SIZE);
+            }
+
+            // The region is still in use, so reclaiming it has to wait
+            assert_eq!(list.reclaim_buffers(), 0);
+            assert!(list.head.next.is_some());
+
+            for allocation in allocations.iter() {
+                list.deallocate(*allocation, layout);
+            }
+
+            // Nothing is unmapped until the empty region is released
+            assert!(list.head.next.is_none());
+            assert_eq!(physmem::free_frames(), free_frames);
+            assert_eq!(list.take_empty_regions().release(), 1);
+        }
+
+        assert_eq!(
+            physmem::free_frames(),
+            free_frames + TEST_BUFFER_SIZE / PAGE_SIZE
+        );
+
+        let page_table = unsafe { lock_page_table() };
+        for page in (buffer_start..buffer_start + TEST_BUFFER_SIZE).step_by(PAGE_SIZE) {
+            assert!(page_table
+                .get_pte_for_address(page)
+                .map_or(true, |pte| pte.is_unused()));
+        }
+    }
+
+    fn count_regions(mut next: Option<&HeapRegion>) -> usize {
+        let mut count = 0;
+        while let Some(region) = next {
+            count += 1;
+            next = region.next.as_deref();
+        }
+        count
+    }
+
+    #[test_case]
+    fn large_aligned_allocations_get_their_own_region() {
+        let layout = Layout::from_size_align(64 * 1024, PAGE_SIZE).unwrap();
+
+        let (start, limit) = unsafe {
+            let mut list = HeapRegionList::empty();
+            let allocation = list.alloc(layout).unwrap();
+            let addr = allocation.as_ptr() as usize;
+            assert_eq!(addr % PAGE_SIZE, 0);
+            core::ptr::write_bytes(allocation.as_ptr(), 0xa5, layout.size());
+
+            // The region is just big enough for the allocation and the header after it
+            assert_eq!(count_regions(list.head.next.as_deref()), 1);
+            let region = list.head.next.as_ref().unwrap();
+            assert!(region.is_dedicated());
+            assert_eq!(region.free_space(), 0);
+            let bounds = match &region.payload.as_ref().unwrap().alloc_region {
+                PayloadRegionAlloc::Region(region) => (region.start(), region.limit()),
+                PayloadRegionAlloc::Buffer(_) => panic!("Dedicated region is a buffer"),
+            };
+            assert_eq!(bounds, (addr, addr + layout.size() + PAGE_SIZE));
+
+            // Freeing it sets the whole region aside straight away, however little else is free,
+            // but it stays mapped until the empty regions are released
+            list.deallocate(allocation, layout);
+            assert_eq!(count_regions(list.head.next.as_deref()), 0);
+            assert_eq!(count_regions(list.empty.as_deref()), 1);
+            assert_eq!(list.take_empty_regions().release(), 1);
+            bounds
+        };
+
+        let page_table = unsafe { lock_page_table() };
+        for page in (start..limit).step_by(PAGE_SIZE) {
+            assert!(page_table
+                .get_pte_for_address(page)
+                .map_or(true, |pte| pte.is_unused()));
+        }
+    }
+}
@@ -78,7 +78,11 @@ long_mode_ap:
     mov rdi, [trampoline.startup_data]
 
     mov rax, [trampoline.code]
-    mov qword [trampoline.ready], 1
+
+    ; The trampoline page is read only, so the ready flag lives in the kernel. Writing the entry
+    ; point back lets the BSP check that we read what it wrote.
+    mov rcx, [trampoline.ready]
+    mov qword [rcx], rax
     jmp rax
 
 
@@ -151,7 +155,9 @@ istruc GDTEntry
     at GDTEntry.limitl, dw 0
     at GDTEntry.basel, dw 0
     at GDTEntry.basem, db 0
-    at GDTEntry.attribute, db attrib.present | attrib.user | attrib.code
+    ; The accessed bits are already set so that loading the descriptors doesn't write to the
+    ; trampoline page, which is read only by then
+    at GDTEntry.attribute, db attrib.present | attrib.user | attrib.code | attrib.accessed
     at GDTEntry.flags__limith, db flags.long_mode
     at GDTEntry.baseh, db 0
 iend
@@ -162,7 +168,7 @@ istruc GDTEntry
     at GDTEntry.basel, dw 0
     at GDTEntry.basem, db 0
 ; AMD System Programming Manual states that the writeable bit is ignored in long mode, but ss can not be set to this descriptor without it
-    at GDTEntry.attribute, db attrib.present | attrib.user | attrib.writable
+    at GDTEntry.attribute, db attrib.present | attrib.user | attrib.writable | attrib.accessed
     at GDTEntry.flags__limith, db 0
     at GDTEntry.baseh, db 0
 iend
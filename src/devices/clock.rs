@@ -0,0 +1,79 @@
+// The kernel's clock and periodic tick. Both come from the HPET when there is one, and from the
+// PIT when there isn't. Whichever is picked drives IRQ 0, and the rest of the kernel only asks
+// this module for the time.
+
+use super::{hpet, pit};
+use crate::init_mutex::Once;
+
+/// The tick frequency the kernel runs its timer at
+pub const DEFAULT_TICK_HZ: u32 = 1000;
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum TimerSource {
+    Hpet,
+    Pit,
+}
+
+static TIMER_SOURCE: Once<TimerSource> = Once::new();
+
+/// Start the best timer there is ticking at hz. The HPET is left alone, even if there is one, unless
+/// use_hpet is set.
+pub unsafe fn init(hz: u32, use_hpet: bool) {
+    let source = if use_hpet && hpet::init(hz) {
+        TimerSource::Hpet
+    } else {
+        assert!(pit::init(hz), "PIT can't tick at {}Hz", hz);
+        TimerSource::Pit
+    };
+
+    crate::info!("Using the {:?} as the clock", source);
+    TIMER_SOURCE.set(source);
+}
+
+/// Which timer is the clock, or None if there isn't one yet
+pub fn timer_source() -> Option<TimerSource> {
+    TIMER_SOURCE.get().copied()
+}
+
+/// Called from the IRQ 0 handler
+pub fn tick_interrupt() {
+    match timer_source() {
+        Some(TimerSource::Hpet) => hpet::handle_interrupt(),
+        Some(TimerSource::Pit) => pit::handle_interrupt(),
+        None => (),
+    }
+}
+
+/// Read the monotonic clock, or None if there is no clock yet
+pub fn monotonic_ns() -> Option<u64> {
+    match timer_source()? {
+        TimerSource::Hpet => hpet::monotonic_ns(),
+        TimerSource::Pit => pit::monotonic_ns(),
+    }
+}
+
+/// Spin until at least ns nanoseconds have passed. Returns immediately if there is no clock yet.
+pub fn spin_wait_ns(ns: u64) {
+    match timer_source() {
+        Some(TimerSource::Hpet) => hpet::spin_wait_ns(ns),
+        Some(TimerSource::Pit) => pit::spin_wait_ns(ns),
+        None => (),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn the_clock_runs_whatever_the_source() {
+        let source = timer_source().expect("No timer was picked");
+        assert_eq!(source == TimerSource::Hpet, hpet::monotonic_ns().is_some());
+
+        // Sleep rather than spin, since the PIT's clock needs the BSP to take its interrupts
+        let start = monotonic_ns().unwrap();
+        crate::scheduler::sleep(core::time::Duration::from_millis(2));
+        assert!(monotonic_ns().unwrap() >= start + 2_000_000);
+        assert!(crate::devices::now_ns().is_some());
+    }
+}
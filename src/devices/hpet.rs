@@ -1,6 +1,8 @@
+use super::mmio::Mmio;
+use super::time::{register_clocksource, Clocksource};
 use crate::acpi::ACPI;
 use crate::init_mutex::InitMutex;
-use crate::paging::{self, Region};
+use core::sync::atomic::{AtomicU64, Ordering};
 
 static LEG_RT_CNF: u64 = 2;
 static ENABLE_CNF: u64 = 1;
@@ -11,7 +13,7 @@ static TN_INT_ENB_CNF: u64 = 0x04;
 
 static CAPABILITY_OFFSET: u16 = 0x00;
 static GENERAL_CONFIG_OFFSET: u16 = 0x10;
-// static GENERAL_INTERRUPT_OFFSET: usize = 0x20;
+static GENERAL_INTERRUPT_OFFSET: u16 = 0x20;
 // static MAIN_COUNTER_OFFSET: usize = 0xF0;
 // static NUM_TIMER_CAP_MASK: u64 = 0x0f00;
 static LEG_RT_CAP: u64 = 0x8000;
@@ -19,9 +21,49 @@ static T0_CONFIG_CAPABILITY_OFFSET: u16 = 0x100;
 static T0_COMPARATOR_OFFSET: u16 = 0x108;
 
 static PER_INT_CAP: u64 = 0x10;
+static SIZE_CAP: u64 = 0x20;
+static T0_INT_STS: u64 = 0x01;
+
+const FS_PER_SECOND: u64 = 1_000_000_000_000_000;
+const NS_PER_SECOND: u64 = 1_000_000_000;
+
+// How many timer 0 interrupts there have been
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+// How long timer 0 is currently set to interrupt after, or 0 before it is set up
+static TICK_NS: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum HpetError {
+    /// The counter is too slow to tick this often
+    FrequencyTooHigh(u32),
+    /// Timer 0 can't count this long between ticks
+    FrequencyTooLow(u32),
+}
+
+/// How many counter periods each tick at hz lasts, for a counter that counts every
+/// counter_clk_period_fs femtoseconds. A 32 bit comparator limits how long a tick can be.
+pub fn counter_periods_per_tick(
+    counter_clk_period_fs: u64,
+    hz: u32,
+    wide_comparator: bool,
+) -> Result<u64, HpetError> {
+    if hz == 0 {
+        return Err(HpetError::FrequencyTooLow(hz));
+    }
+
+    let periods = FS_PER_SECOND / u64::from(hz) / counter_clk_period_fs;
+    if periods == 0 {
+        Err(HpetError::FrequencyTooHigh(hz))
+    } else if !wide_comparator && periods > u64::from(u32::MAX) {
+        Err(HpetError::FrequencyTooLow(hz))
+    } else {
+        Ok(periods)
+    }
+}
 
 struct HpetAccess {
-    mapping: Region,
+    registers: Mmio,
 }
 
 impl HpetAccess {
@@ -31,19 +73,21 @@ impl HpetAccess {
         _hpet_number: u8,
         _clock_tick_unit: u16,
     ) -> Option<Self> {
-        paging::map_physical_memory(base_address, 1024, paging::PhysicalMappingFlags::UNCACHED)
-            .map(|mapping| Self { mapping })
+        Mmio::map(base_address, 1024)
+            .map(|registers| Self { registers })
             .ok()
     }
 
     pub unsafe fn read(&self, register: u16) -> u64 {
-        use core::intrinsics::volatile_load;
-        volatile_load(self.mapping.as_ptr_offset(register.into()))
+        self.registers.read(register.into())
     }
 
     pub unsafe fn write(&mut self, register: u16, value: u64) {
-        use core::intrinsics::volatile_store;
-        volatile_store(self.mapping.as_mut_ptr_offset(register.into()), value);
+        self.registers.write(register.into(), value);
+    }
+
+    pub unsafe fn modify(&mut self, register: u16, f: impl FnOnce(u64) -> u64) {
+        self.registers.modify(register.into(), f);
     }
 
     pub fn current(&self) -> u64 {
@@ -53,66 +97,267 @@ impl HpetAccess {
 
 pub struct Hpet {
     access: HpetAccess,
+    counter_clk_period_fs: u64,
 }
 
 impl Hpet {
-    unsafe fn new(access: HpetAccess) -> Self {
-        let mut ret = Self { access };
-
-        let capability = ret.access.read(CAPABILITY_OFFSET);
+    unsafe fn new(access: HpetAccess, hz: u32) -> Result<Self, HpetError> {
+        let capability = access.read(CAPABILITY_OFFSET);
         if capability & LEG_RT_CAP == 0 {
             panic!("HPET cannot perform legacy replacement")
         }
 
         let counter_clk_period_fs = capability >> 32;
-        let desired_fs_period: u64 = 2_250_286 * 1_000_000;
-
-        let clk_periods_per_kernel_tick: u64 = desired_fs_period / counter_clk_period_fs;
+        let mut ret = Self {
+            access,
+            counter_clk_period_fs,
+        };
 
         let t0_capabilities = ret.access.read(T0_CONFIG_CAPABILITY_OFFSET);
         if t0_capabilities & PER_INT_CAP == 0 {
             panic!("HPET timer 0 does not support periodic mode");
         }
 
+        ret.set_frequency(hz)?;
+
+        ret.access.modify(GENERAL_CONFIG_OFFSET, |config| {
+            config | LEG_RT_CNF | ENABLE_CNF
+        });
+        // Enable interrupts from the HPET
+
+        Ok(ret)
+    }
+
+    /// Make timer 0 interrupt hz times a second. The main counter keeps running, so the monotonic
+    /// clock is not disturbed.
+    pub unsafe fn set_frequency(&mut self, hz: u32) -> Result<(), HpetError> {
+        let t0_capabilities = self.access.read(T0_CONFIG_CAPABILITY_OFFSET);
+        let clk_periods_per_kernel_tick = counter_periods_per_tick(
+            self.counter_clk_period_fs,
+            hz,
+            t0_capabilities & SIZE_CAP != 0,
+        )?;
+
         let t0_config_word: u64 = TN_VAL_SET_CNF | TN_TYPE_CNF | TN_INT_ENB_CNF;
-        ret.access
+        self.access
             .write(T0_CONFIG_CAPABILITY_OFFSET, t0_config_word);
-        ret.access.write(
+        self.access.write(
             T0_COMPARATOR_OFFSET,
-            ret.access.current() + clk_periods_per_kernel_tick,
+            self.access.current() + clk_periods_per_kernel_tick,
         );
         // set accumulator value
-        ret.access
+        self.access
             .write(T0_COMPARATOR_OFFSET, clk_periods_per_kernel_tick);
         // set interval
 
-        let enable_word: u64 = ret.access.read(GENERAL_CONFIG_OFFSET) | LEG_RT_CNF | ENABLE_CNF;
-        ret.access.write(GENERAL_CONFIG_OFFSET, enable_word);
-        // Enable interrupts from the HPET
+        TICK_NS.store(NS_PER_SECOND / u64::from(hz), Ordering::SeqCst);
+        Ok(())
+    }
 
-        ret
+    // The status bit only latches for level triggered interrupts, but clearing it is harmless
+    // otherwise and keeps the interrupt from getting stuck if the routing ever changes
+    unsafe fn acknowledge(&mut self) {
+        if self.access.read(GENERAL_INTERRUPT_OFFSET) & T0_INT_STS != 0 {
+            self.access.write(GENERAL_INTERRUPT_OFFSET, T0_INT_STS);
+        }
+    }
+
+    /// Nanoseconds since the main counter started, which it did when the HPET was initialized
+    pub fn monotonic_ns(&self) -> u64 {
+        let femtoseconds =
+            u128::from(self.access.current()) * u128::from(self.counter_clk_period_fs);
+        (femtoseconds / 1_000_000) as u64
     }
 }
 
 pub static HPET: InitMutex<Hpet> = InitMutex::new();
 
-pub unsafe fn init() {
+struct HpetClocksource;
+
+impl Clocksource for HpetClocksource {
+    fn name(&self) -> &'static str {
+        "hpet"
+    }
+
+    fn now_ns(&self) -> Option<u64> {
+        monotonic_ns()
+    }
+
+    fn resolution_ns(&self) -> u64 {
+        HPET.try_lock().map_or(u64::MAX, |hpet| {
+            (hpet.counter_clk_period_fs + 999_999) / 1_000_000
+        })
+    }
+
+    fn stable(&self) -> bool {
+        true
+    }
+}
+
+static HPET_CLOCKSOURCE: HpetClocksource = HpetClocksource;
+
+/// Start the HPET ticking at hz. Returns false if the firmware doesn't describe one.
+pub unsafe fn init(hz: u32) -> bool {
     let mut acpi_lock = ACPI.lock();
     let acpi = acpi_lock.as_mut().unwrap();
 
-    HPET.init(
-        acpi.acpi_context
-            .hpet
-            .as_ref()
-            .and_then(|hpet| {
-                HpetAccess::new(
-                    hpet.event_timer_block_id,
-                    hpet.base_address,
-                    hpet.hpet_number,
-                    hpet.clock_tick_unit,
-                )
-            })
-            .map(|access| Hpet::new(access))
-            .expect("Failed to locate HPET"),
-    );
+    let access = acpi.acpi_context.hpet.as_ref().and_then(|hpet| {
+        HpetAccess::new(
+            hpet.event_timer_block_id,
+            hpet.base_address,
+            hpet.hpet_number,
+            hpet.clock_tick_unit,
+        )
+    });
+
+    match access {
+        Some(access) => {
+            HPET.init(Hpet::new(access, hz).expect("Failed to set HPET tick frequency"));
+            register_clocksource(&HPET_CLOCKSOURCE);
+            true
+        }
+        None => false,
+    }
+}
+
+/// Acknowledge a timer 0 interrupt and count the tick. Legacy replacement routes timer 0 to IRQ 0,
+/// whose handler calls this.
+pub fn handle_interrupt() {
+    if let Some(mut hpet) = HPET.try_lock() {
+        unsafe { hpet.acknowledge() };
+    }
+    TICKS.fetch_add(1, Ordering::SeqCst);
+}
+
+/// The number of timer 0 interrupts so far, one every tick_ns
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::SeqCst)
+}
+
+/// How many nanoseconds apart timer 0 interrupts, or 0 if the HPET hasn't been initialized yet
+pub fn tick_ns() -> u64 {
+    TICK_NS.load(Ordering::SeqCst)
+}
+
+/// Read the monotonic clock, or None if the HPET hasn't been initialized yet
+pub fn monotonic_ns() -> Option<u64> {
+    HPET.try_lock().map(|hpet| hpet.monotonic_ns())
+}
+
+/// Spin until at least ns nanoseconds have passed. Returns immediately if there is no clock yet.
+pub fn spin_wait_ns(ns: u64) {
+    if let Some(start) = monotonic_ns() {
+        while monotonic_ns().unwrap() - start < ns {
+            crate::interrupts::pause();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use core::time::Duration;
+
+    #[test_case]
+    fn test_timing_measures_a_busy_wait() {
+        const WAIT_US: u64 = 10_000;
+
+        let elapsed_us =
+            crate::time_us(|| spin_wait_ns(WAIT_US * 1000)).expect("HPET has no clock");
+
+        // Emulators can be slow, so only check that it is roughly right
+        assert!(elapsed_us >= WAIT_US, "Waited {}us", elapsed_us);
+        assert!(elapsed_us < WAIT_US * 100, "Waited {}us", elapsed_us);
+    }
+
+    // Sleep until the next tick, and return the time it came at
+    fn wait_for_tick() -> u64 {
+        let start = ticks();
+        while ticks() == start {
+            crate::scheduler::sleep(Duration::from_micros(100));
+        }
+        monotonic_ns().unwrap()
+    }
+
+    #[test_case]
+    fn tick_periods_are_checked() {
+        // A 10MHz counter, which is 100ns a period
+        let period_fs = 100_000_000;
+        assert_eq!(counter_periods_per_tick(period_fs, 1000, false), Ok(10_000));
+        assert_eq!(counter_periods_per_tick(period_fs, 250, false), Ok(40_000));
+        assert_eq!(
+            counter_periods_per_tick(period_fs, 20_000_000, true),
+            Err(HpetError::FrequencyTooHigh(20_000_000))
+        );
+        assert_eq!(
+            counter_periods_per_tick(period_fs, 0, true),
+            Err(HpetError::FrequencyTooLow(0))
+        );
+
+        // A fast counter runs out of 32 bit comparator well within a second
+        let fast_fs = 1_000;
+        assert_eq!(
+            counter_periods_per_tick(fast_fs, 1, false),
+            Err(HpetError::FrequencyTooLow(1))
+        );
+        assert!(counter_periods_per_tick(fast_fs, 1, true).is_ok());
+    }
+
+    #[test_case]
+    fn ticks_come_at_the_requested_frequency() {
+        // Not the default rate, so that the ticks only come at it if the HPET was reprogrammed
+        const TEST_HZ: u32 = 250;
+        const TEST_TICK_NS: u64 = NS_PER_SECOND / TEST_HZ as u64;
+
+        if monotonic_ns().is_none() {
+            crate::skip_test("no hpet");
+            return;
+        }
+
+        let old_hz = (NS_PER_SECOND / tick_ns()) as u32;
+        assert_ne!(old_hz, TEST_HZ);
+        unsafe { HPET.lock().set_frequency(TEST_HZ) }.expect("Failed to set HPET frequency");
+
+        // Average over a few ticks, since any of them can be noticed late
+        let start_ns = wait_for_tick();
+        let start_ticks = ticks();
+        while ticks() - start_ticks < 20 {
+            wait_for_tick();
+        }
+        let end_ns = monotonic_ns().unwrap();
+        let per_tick_ns = (end_ns - start_ns) / (ticks() - start_ticks);
+
+        unsafe { HPET.lock().set_frequency(old_hz) }.expect("Failed to restore HPET frequency");
+
+        assert_eq!(tick_ns(), NS_PER_SECOND / u64::from(old_hz));
+        assert!(
+            per_tick_ns >= TEST_TICK_NS * 9 / 10 && per_tick_ns <= TEST_TICK_NS * 2,
+            "Ticks were {}ns apart",
+            per_tick_ns
+        );
+    }
+
+    #[test_case]
+    fn ticks_advance_at_the_timer_rate() {
+        let start_ticks = ticks();
+        let start_ns = match monotonic_ns() {
+            Some(start_ns) => start_ns,
+            None => {
+                crate::skip_test("no hpet");
+                return;
+            }
+        };
+
+        crate::scheduler::sleep(Duration::from_millis(100));
+
+        let counted = ticks() - start_ticks;
+        let expected = (monotonic_ns().unwrap() - start_ns) / tick_ns();
+        // Ticks are only lost, never gained, if the BSP has interrupts disabled for a while
+        assert!(
+            counted >= expected / 2 && counted <= expected + 1,
+            "Counted {} ticks, expected about {}",
+            counted,
+            expected
+        );
+    }
 }
@@ -1,47 +1,42 @@
+use super::local_apic;
+use super::mmio::Mmio;
 use crate::acpi::ACPI;
-use crate::paging;
+use crate::init_mutex::Once;
 use acpi::interrupt::InterruptModel;
 use alloc::vec::Vec;
 use core::fmt;
 use spin::Mutex;
 
+// The IO APIC is programmed through a register select and a data window
+const IOREGSEL: usize = 0x00;
+const IOWIN: usize = 0x10;
+const REGISTERS_SIZE: usize = IOWIN + 4;
+
 struct IoApicRegisters {
-    mapping: paging::Region,
+    registers: Mmio,
 }
 
 impl IoApicRegisters {
     pub unsafe fn new(address: usize) -> Option<Self> {
-        paging::map_physical_memory(address, 8, paging::PhysicalMappingFlags::UNCACHED)
+        Mmio::map(address, REGISTERS_SIZE)
             .ok()
-            .map(|mapping| Self { mapping })
-    }
-
-    fn ioregsel(&self) -> *mut u32 {
-        self.mapping.as_ptr_offset::<u32>(0) as *mut u32
-    }
-
-    fn iowin(&self) -> *mut u32 {
-        self.mapping.as_ptr_offset::<u32>(0x10) as *mut u32
+            .map(|registers| Self { registers })
     }
 
     /*fn read_ioregsel(&self) -> u32 {
-        unsafe { core::ptr::read_volatile(self.ioregsel()) }
+        self.registers.read(IOREGSEL)
     }*/
 
     fn write_ioregsel(&self, value: u32) {
-        unsafe {
-            core::ptr::write_volatile(self.ioregsel(), value);
-        }
+        self.registers.write(IOREGSEL, value);
     }
 
     fn read_iowin(&self) -> u32 {
-        unsafe { core::ptr::read_volatile(self.iowin()) }
+        self.registers.read(IOWIN)
     }
 
     fn write_iowin(&self, value: u32) {
-        unsafe {
-            core::ptr::write_volatile(self.iowin(), value);
-        }
+        self.registers.write(IOWIN, value);
     }
 
     fn read_reg(&self, reg: u8) -> u32 {
@@ -118,6 +113,37 @@ impl IoApic {
     pub fn map(&self, idx: u8, info: MapInfo) {
         self.registers.lock().write_ioredtbl(idx, info.as_raw())
     }
+    /// Mask every redirection entry. This is for when the CPU holding the lock might never let it
+    /// go, so it gives up and returns false rather than wait for it.
+    pub fn try_mask_all(&self) -> bool {
+        match self.registers.try_lock() {
+            Some(mut guard) => {
+                for idx in 0..=self.count {
+                    let reg = guard.read_ioredtbl(idx);
+                    guard.write_ioredtbl(idx, reg | 1 << 16);
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    #[cfg(test)]
+    pub(crate) fn redirection_table(&self) -> Vec<u64> {
+        let mut guard = self.registers.lock();
+        (0..=self.count)
+            .map(|idx| guard.read_ioredtbl(idx))
+            .collect()
+    }
+
+    #[cfg(test)]
+    pub(crate) fn restore_redirection_table(&self, table: &[u64]) {
+        let mut guard = self.registers.lock();
+        for (idx, reg) in table.iter().enumerate() {
+            guard.write_ioredtbl(idx as u8, *reg);
+        }
+    }
+
     pub fn set_mask(&self, global_system_interrupt: u32, mask: bool) {
         let idx = (global_system_interrupt - self.global_system_interrupt_base) as u8;
         let mut guard = self.registers.lock();
@@ -240,6 +266,25 @@ pub enum DeliveryMode {
     ExtInt = 0b111,
 }
 
+/// Which CPUs an interrupt goes to
+#[derive(Clone, Copy, Debug)]
+pub enum Destination {
+    /// The CPU with this local APIC ID
+    Physical(u8),
+    /// Every CPU whose logical destination bit is in the mask
+    Logical(u8),
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum RouteError {
+    /// None of the IO APICs handle this GSI
+    NoIoApic(u32),
+    /// Vectors below 0x20 are exceptions, and 0xff is the spurious vector
+    InvalidVector(u8),
+    /// x2APIC mode has no flat logical destinations, so a logical route would reach nothing
+    NoLogicalDestinations,
+}
+
 #[derive(Clone, Copy, Debug)]
 pub struct MapInfo {
     pub dest: u8,
@@ -268,8 +313,8 @@ impl MapInfo {
     }
 }
 
-static mut IOAPICS: Option<Vec<IoApic>> = None;
-static mut SRC_OVERRIDES: Option<Vec<Override>> = None;
+static IOAPICS: Once<Vec<IoApic>> = Once::new();
+static SRC_OVERRIDES: Once<Vec<Override>> = Once::new();
 
 pub unsafe fn init() {
     let bsp_apic_id = x86::cpuid::CpuId::new()
@@ -285,19 +330,22 @@ pub unsafe fn init() {
         _ => panic!("Unsupported interrupt model"),
     };
 
-    for io_apic in interrupt_model.io_apics.iter() {
-        if let Some(io_apic) = IoApic::new(
-            io_apic.address as usize,
-            io_apic.id,
-            io_apic.global_system_interrupt_base,
-        ) {
-            IOAPICS.get_or_insert_with(Vec::new).push(io_apic);
-        } else {
-            panic!("Failed to initialize io_apic id {:#x}", io_apic.id);
-        }
-    }
+    IOAPICS.set(
+        interrupt_model
+            .io_apics
+            .iter()
+            .map(|io_apic| {
+                IoApic::new(
+                    io_apic.address as usize,
+                    io_apic.id,
+                    io_apic.global_system_interrupt_base,
+                )
+                .unwrap_or_else(|| panic!("Failed to initialize io_apic id {:#x}", io_apic.id))
+            })
+            .collect(),
+    );
 
-    SRC_OVERRIDES = Some(
+    SRC_OVERRIDES.set(
         interrupt_model
             .interrupt_source_overrides
             .iter()
@@ -308,61 +356,13 @@ pub unsafe fn init() {
     // map the legacy PC-compatible IRQs (0-15) to 32-47, just like we did with 8259 PIC (if it
     // wouldn't have been disabled due to this I/O APIC)
     for legacy_irq in 0..=15 {
-        let (global_system_interrupt, trigger_mode, polarity) = match get_src_override(legacy_irq) {
-            Some(over) => (
-                over.global_system_interrupt,
-                over.trigger_mode,
-                over.polarity,
-            ),
-            None => {
-                if src_overrides().iter().any(|over| {
-                    over.global_system_interrupt == u32::from(legacy_irq)
-                        && over.isa_source != legacy_irq
-                }) && !src_overrides()
-                    .iter()
-                    .any(|over| over.isa_source == legacy_irq)
-                {
-                    // there's an IRQ conflict, making this legacy IRQ inaccessible.
-                    continue;
-                }
-                (
-                    legacy_irq.into(),
-                    TriggerMode::SameAsBus,
-                    Polarity::SameAsBus,
-                )
-            }
-        };
-
-        let apic = match find_ioapic(global_system_interrupt) {
-            Some(ioapic) => ioapic,
-            None => {
-                crate::println!("Unable to find a suitable APIC for legacy IRQ {} (GSI {}). It will not be mapped.", legacy_irq, global_system_interrupt);
-                continue;
-            }
-        };
-
-        let redir_tbl_index = (global_system_interrupt - apic.global_system_interrupt_base) as u8;
-
-        let map_info = MapInfo {
-            // only send to the BSP
-            dest: bsp_apic_id,
-            dest_mode: DestinationMode::Physical,
-            delivery_mode: DeliveryMode::Fixed,
-            mask: false,
-            polarity: match polarity {
-                Polarity::ActiveHigh => ApicPolarity::ActiveHigh,
-                Polarity::ActiveLow => ApicPolarity::ActiveLow,
-                Polarity::SameAsBus => ApicPolarity::ActiveHigh,
-            },
-            trigger_mode: match trigger_mode {
-                TriggerMode::Edge => ApicTriggerMode::Edge,
-                TriggerMode::Level => ApicTriggerMode::Level,
-                TriggerMode::SameAsBus => ApicTriggerMode::Edge,
-            },
-            vector: 32 + legacy_irq,
-        };
-
-        apic.map(redir_tbl_index, map_info);
+        // only send to the BSP
+        map_legacy_irq(
+            bsp_apic_id,
+            legacy_irq,
+            ApicTriggerMode::Edge,
+            ApicPolarity::ActiveHigh,
+        );
     }
 
     // Now that we've set up the IOAPIC we need to tell the firmware what we did
@@ -378,21 +378,263 @@ pub unsafe fn init() {
     }
 }
 
-pub fn io_apics<'a>() -> &'a [IoApic] {
-    unsafe { IOAPICS.as_ref().map_or(&[], |vector| &vector[..]) }
+/// Route a legacy IRQ to vector 32 + irq on the given processor. The trigger mode and polarity are
+/// only used if there is no interrupt source override for the IRQ; ISA interrupts are edge
+/// triggered and active high, but some legacy IRQs (like the ACPI SCI) are not.
+fn map_legacy_irq(
+    dest: u8,
+    legacy_irq: u8,
+    bus_trigger_mode: ApicTriggerMode,
+    bus_polarity: ApicPolarity,
+) {
+    let (global_system_interrupt, trigger_mode, polarity) = match get_src_override(legacy_irq) {
+        Some(over) => (
+            over.global_system_interrupt,
+            over.trigger_mode,
+            over.polarity,
+        ),
+        None => {
+            if src_overrides().iter().any(|over| {
+                over.global_system_interrupt == u32::from(legacy_irq)
+                    && over.isa_source != legacy_irq
+            }) && !src_overrides()
+                .iter()
+                .any(|over| over.isa_source == legacy_irq)
+            {
+                // there's an IRQ conflict, making this legacy IRQ inaccessible.
+                return;
+            }
+            (
+                legacy_irq.into(),
+                TriggerMode::SameAsBus,
+                Polarity::SameAsBus,
+            )
+        }
+    };
+
+    let polarity = match polarity {
+        Polarity::ActiveHigh => ApicPolarity::ActiveHigh,
+        Polarity::ActiveLow => ApicPolarity::ActiveLow,
+        Polarity::SameAsBus => bus_polarity,
+    };
+    let trigger_mode = match trigger_mode {
+        TriggerMode::Edge => ApicTriggerMode::Edge,
+        TriggerMode::Level => ApicTriggerMode::Level,
+        TriggerMode::SameAsBus => bus_trigger_mode,
+    };
+
+    if let Err(e) = route(
+        global_system_interrupt,
+        32 + legacy_irq,
+        Destination::Physical(dest),
+        trigger_mode,
+        polarity,
+        false,
+    ) {
+        crate::warn!(
+            "Unable to route legacy IRQ {} (GSI {}): {:?}. It will not be mapped.",
+            legacy_irq,
+            global_system_interrupt,
+            e
+        );
+    }
+}
+
+/// Send a GSI to vector on the CPUs dest picks out. Fixed delivery is used, so with a logical
+/// destination every CPU in the mask gets the interrupt.
+pub fn route(
+    global_system_interrupt: u32,
+    vector: u8,
+    dest: Destination,
+    trigger_mode: ApicTriggerMode,
+    polarity: ApicPolarity,
+    mask: bool,
+) -> Result<(), RouteError> {
+    if vector < 0x20 || vector == 0xff {
+        return Err(RouteError::InvalidVector(vector));
+    }
+
+    let apic = find_ioapic(global_system_interrupt)
+        .ok_or(RouteError::NoIoApic(global_system_interrupt))?;
+    let redir_tbl_index = (global_system_interrupt - apic.global_system_interrupt_base) as u8;
+
+    let (dest, dest_mode) = match dest {
+        Destination::Physical(apic_id) => (apic_id, DestinationMode::Physical),
+        Destination::Logical(_) if local_apic::x2apic_enabled() => {
+            return Err(RouteError::NoLogicalDestinations)
+        }
+        Destination::Logical(mask) => (mask, DestinationMode::Logical),
+    };
+
+    apic.map(
+        redir_tbl_index,
+        MapInfo {
+            dest,
+            dest_mode,
+            delivery_mode: DeliveryMode::Fixed,
+            mask,
+            polarity,
+            trigger_mode,
+            vector,
+        },
+    );
+    Ok(())
+}
+
+/// The GSI a legacy IRQ arrives on, which is the IRQ itself unless the MADT overrides it
+pub fn legacy_irq_gsi(legacy_irq: u8) -> u32 {
+    get_src_override(legacy_irq).map_or(legacy_irq.into(), |over| over.global_system_interrupt)
+}
+
+/// The ACPI SCI is a shareable, level triggered, active low interrupt unless the MADT says
+/// otherwise. Like the other legacy IRQs it is only sent to the BSP.
+pub unsafe fn map_sci(sci_irq: u8) {
+    assert!(sci_irq < 16, "SCI on GSI {} is not supported", sci_irq);
+
+    let bsp_apic_id = x86::cpuid::CpuId::new()
+        .get_feature_info()
+        .unwrap()
+        .initial_local_apic_id();
+
+    map_legacy_irq(
+        bsp_apic_id,
+        sci_irq,
+        ApicTriggerMode::Level,
+        ApicPolarity::ActiveLow,
+    );
 }
 
-pub fn src_overrides<'a>() -> &'a [Override] {
-    unsafe { SRC_OVERRIDES.as_ref().map_or(&[], |vector| &vector[..]) }
+pub fn io_apics() -> &'static [IoApic] {
+    IOAPICS.get().map_or(&[], |vector| &vector[..])
 }
 
-fn get_src_override<'a>(irq: u8) -> Option<&'a Override> {
+pub fn src_overrides() -> &'static [Override] {
+    SRC_OVERRIDES.get().map_or(&[], |vector| &vector[..])
+}
+
+fn get_src_override(irq: u8) -> Option<&'static Override> {
     src_overrides().iter().find(|o| o.isa_source == irq)
 }
 
-fn find_ioapic<'a>(global_system_interrupt: u32) -> Option<&'a IoApic> {
+fn find_ioapic(global_system_interrupt: u32) -> Option<&'static IoApic> {
     io_apics().iter().find(|apic| {
         global_system_interrupt >= apic.global_system_interrupt_base
             && global_system_interrupt < apic.global_system_interrupt_base + u32::from(apic.count)
     })
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::devices::rtc::{self, REGISTER_B, REGISTER_C};
+    use crate::interrupts::irq;
+    use crate::scheduler::{self, sleep};
+    use core::sync::atomic::{AtomicUsize, Ordering};
+    use core::time::Duration;
+
+    // The RTC can be made to interrupt periodically on IRQ 8, which makes it a source we can trigger
+    const RTC_IRQ: u8 = 8;
+    const RTC_PERIODIC_INTERRUPT: u8 = 0x40;
+
+    const NO_CPU: usize = usize::MAX;
+    static RTC_CPU: AtomicUsize = AtomicUsize::new(NO_CPU);
+
+    fn rtc_interrupt() {
+        RTC_CPU.store(crate::cpu_id(), Ordering::SeqCst);
+        // The RTC doesn't interrupt again until register C has been read
+        rtc::read_register(REGISTER_C);
+    }
+
+    fn route_rtc(dest: Destination) {
+        route(
+            legacy_irq_gsi(RTC_IRQ),
+            32 + RTC_IRQ,
+            dest,
+            ApicTriggerMode::Edge,
+            ApicPolarity::ActiveHigh,
+            false,
+        )
+        .expect("Failed to route RTC interrupt");
+    }
+
+    // Legacy IRQs, the RTC included, are normally routed to the BSP
+    fn bsp_apic_id() -> u8 {
+        x86::cpuid::CpuId::new()
+            .get_feature_info()
+            .unwrap()
+            .initial_local_apic_id()
+    }
+
+    #[test_case]
+    fn routed_interrupts_arrive_on_their_cpu() {
+        let ap = match (1..scheduler::MAX_CPUS).find(|cpu_id| crate::init::cpu_online(*cpu_id)) {
+            Some(ap) => ap,
+            None => return,
+        };
+
+        let previous_handler = irq::replace_legacy_handler(RTC_IRQ, Some(rtc_interrupt));
+        route_rtc(Destination::Physical(ap as u8));
+
+        RTC_CPU.store(NO_CPU, Ordering::SeqCst);
+        rtc::read_register(REGISTER_C);
+        rtc::write_register(
+            REGISTER_B,
+            rtc::read_register(REGISTER_B) | RTC_PERIODIC_INTERRUPT,
+        );
+        for _ in 0..100 {
+            if RTC_CPU.load(Ordering::SeqCst) != NO_CPU {
+                break;
+            }
+            sleep(Duration::from_millis(1));
+        }
+        rtc::write_register(
+            REGISTER_B,
+            rtc::read_register(REGISTER_B) & !RTC_PERIODIC_INTERRUPT,
+        );
+        route_rtc(Destination::Physical(bsp_apic_id()));
+        irq::replace_legacy_handler(RTC_IRQ, previous_handler);
+
+        assert_eq!(RTC_CPU.load(Ordering::SeqCst), ap);
+    }
+
+    #[test_case]
+    fn routes_need_an_io_apic_and_a_usable_vector() {
+        let masked_route = |gsi, vector| {
+            route(
+                gsi,
+                vector,
+                Destination::Physical(0),
+                ApicTriggerMode::Edge,
+                ApicPolarity::ActiveHigh,
+                true,
+            )
+        };
+
+        assert_eq!(masked_route(0, 0x10), Err(RouteError::InvalidVector(0x10)));
+        assert_eq!(masked_route(0, 0xff), Err(RouteError::InvalidVector(0xff)));
+        assert_eq!(
+            masked_route(u32::MAX, 0x40),
+            Err(RouteError::NoIoApic(u32::MAX))
+        );
+    }
+
+    #[test_case]
+    fn logical_routes_need_flat_destinations() {
+        let gsi = legacy_irq_gsi(RTC_IRQ);
+        let logical_route = route(
+            gsi,
+            32 + RTC_IRQ,
+            Destination::Logical(1),
+            ApicTriggerMode::Edge,
+            ApicPolarity::ActiveHigh,
+            true,
+        );
+
+        if local_apic::x2apic_enabled() {
+            assert_eq!(logical_route, Err(RouteError::NoLogicalDestinations));
+        } else {
+            assert_eq!(logical_route, Ok(()));
+        }
+        route_rtc(Destination::Physical(bsp_apic_id()));
+    }
+}
@@ -1,71 +1,298 @@
+use super::mmio::Mmio;
+use crate::init_mutex::Once;
 use crate::paging;
+use core::sync::atomic::{fence, AtomicU32, Ordering};
+use x86::cpuid::CpuId;
+use x86::msr::{rdmsr, wrmsr, IA32_APIC_BASE, IA32_TSC_DEADLINE};
+
+const VERSION: u16 = 0x30;
+const LVT_CMCI: u16 = 0x2f0;
+const LVT_TIMER: u16 = 0x320;
+const LVT_THERMAL: u16 = 0x330;
+const LVT_PERFORMANCE_COUNTERS: u16 = 0x340;
+const LVT_LINT0: u16 = 0x350;
+const LVT_LINT1: u16 = 0x360;
+const LVT_ERROR: u16 = 0x370;
+const TIMER_INITIAL_COUNT: u16 = 0x380;
+const TIMER_CURRENT_COUNT: u16 = 0x390;
+const TIMER_DIVIDE_CONFIG: u16 = 0x3e0;
+const LOGICAL_DESTINATION: u16 = 0xd0;
+const DESTINATION_FORMAT: u16 = 0xe0;
+
+const DESTINATION_FORMAT_FLAT: u32 = 0xffff_ffff;
+
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+const APIC_BASE_X2APIC: u64 = 1 << 10;
+
+// In x2APIC mode each register is an MSR, and the ICR is a single 64 bit one
+const X2APIC_MSR_BASE: u32 = 0x800;
+const X2APIC_ICR: u32 = 0x830;
+
+const ICR_LOW: u16 = 0x300;
+const ICR_HIGH: u16 = 0x310;
+const ICR_DELIVERY_PENDING: u32 = 1 << 12;
+const IN_SERVICE: u16 = 0x100;
+
+const LVT_MASKED: u32 = 1 << 16;
+const LVT_TIMER_PERIODIC: u32 = 1 << 17;
+const LVT_TIMER_TSC_DEADLINE: u32 = 2 << 17;
+const TIMER_DIVIDE_BY_16: u32 = 0x3;
+
+/// The vector of the local APIC timer
+pub const LOCAL_TIMER_VECTOR: u8 = 0xfc;
+
+// How long to count the local APIC timer against the clock for
+const TIMER_CALIBRATION_NS: u64 = 10_000_000;
+
+// How many times the local APIC timer counts down in a millisecond, which is the same on every CPU
+static TIMER_TICKS_PER_MS: AtomicU32 = AtomicU32::new(0);
+
+/// The local vector table entries, in the order the version register counts them. Every local
+/// APIC has the first four, and the rest are optional.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Lvt {
+    Timer,
+    Lint0,
+    Lint1,
+    Error,
+    PerformanceCounters,
+    Thermal,
+    Cmci,
+}
+
+impl Lvt {
+    fn index(self) -> usize {
+        self as usize
+    }
+
+    fn offset(self) -> u16 {
+        match self {
+            Self::Timer => LVT_TIMER,
+            Self::Lint0 => LVT_LINT0,
+            Self::Lint1 => LVT_LINT1,
+            Self::Error => LVT_ERROR,
+            Self::PerformanceCounters => LVT_PERFORMANCE_COUNTERS,
+            Self::Thermal => LVT_THERMAL,
+            Self::Cmci => LVT_CMCI,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum LvtError {
+    /// This local APIC doesn't have the entry
+    NotPresent(Lvt),
+}
+
+/// Whether the CPU can run its local APIC in x2APIC mode
+pub fn x2apic_supported() -> bool {
+    CpuId::new()
+        .get_feature_info()
+        .map_or(false, |info| info.has_x2apic())
+}
+
+/// Whether the local APICs have been put in x2APIC mode
+pub fn x2apic_enabled() -> bool {
+    local_apic_access_safe().map_or(false, LocalApicAccess::x2apic)
+}
+
+/// Whether the local APIC timer can fire when the TSC reaches a deadline
+pub fn tsc_deadline_supported() -> bool {
+    CpuId::new()
+        .get_feature_info()
+        .map_or(false, |info| info.has_tsc_deadline())
+}
+
+// Every CPU uses the same interface, because the BSP picks it before any of the APs start
+enum Interface {
+    XApic(Mmio),
+    X2Apic,
+}
 
 pub struct LocalApicAccess {
-    mapping: paging::Region,
+    interface: Interface,
 }
 
 impl LocalApicAccess {
     pub unsafe fn new() -> Self {
-        use x86::msr::*;
+        if x2apic_supported() {
+            enable_x2apic();
+            return Self {
+                interface: Interface::X2Apic,
+            };
+        }
 
         let physical_address = rdmsr(IA32_APIC_BASE) as usize & 0xffff_0000;
-        let mapping = paging::map_physical_memory(
-            physical_address,
-            paging::PAGE_SIZE,
-            paging::PhysicalMappingFlags::UNCACHED,
-        )
-        .expect("Failed to map local apic");
+        let registers =
+            Mmio::map(physical_address, paging::PAGE_SIZE).expect("Failed to map local apic");
 
-        Self { mapping }
+        Self {
+            interface: Interface::XApic(registers),
+        }
+    }
+
+    pub fn x2apic(&self) -> bool {
+        match self.interface {
+            Interface::XApic(_) => false,
+            Interface::X2Apic => true,
+        }
     }
 
     pub unsafe fn read(&self, offset: u16) -> u32 {
-        core::intrinsics::volatile_load(self.mapping.as_ptr_offset(offset.into()))
+        match &self.interface {
+            Interface::XApic(registers) => registers.read(offset.into()),
+            Interface::X2Apic => rdmsr(x2apic_msr(offset)) as u32,
+        }
     }
 
-    unsafe fn write(&mut self, offset: u16, value: u32) {
-        core::intrinsics::volatile_store(self.mapping.as_mut_ptr_offset(offset.into()), value)
+    unsafe fn write(&self, offset: u16, value: u32) {
+        match &self.interface {
+            Interface::XApic(registers) => registers.write(offset.into(), value),
+            Interface::X2Apic => wrmsr(x2apic_msr(offset), value.into()),
+        }
     }
 
+    /// This CPU's local APIC ID. It is only eight bits wide unless the APIC is in x2APIC mode.
     pub fn id(&self) -> u32 {
-        unsafe { self.read(0x20) }
+        let id = unsafe { self.read(0x20) };
+        if self.x2apic() {
+            id
+        } else {
+            id >> 24
+        }
+    }
+
+    pub fn version(&self) -> u32 {
+        unsafe { self.read(VERSION) }
+    }
+
+    /// How many LVT entries this local APIC has. The version register holds one less than this.
+    pub fn max_lvt_entries(&self) -> usize {
+        ((self.version() >> 16) & 0xff) as usize + 1
+    }
+
+    fn lvt_offset(&self, lvt: Lvt) -> Result<u16, LvtError> {
+        if lvt.index() < self.max_lvt_entries() {
+            Ok(lvt.offset())
+        } else {
+            Err(LvtError::NotPresent(lvt))
+        }
+    }
+
+    pub fn lvt_masked(&self, lvt: Lvt) -> Result<bool, LvtError> {
+        let offset = self.lvt_offset(lvt)?;
+        Ok(unsafe { self.read(offset) } & LVT_MASKED != 0)
     }
 
-    pub fn set_icr(&mut self, value: u64) {
+    /// Mask or unmask an LVT entry, leaving the rest of it as it is
+    pub fn set_lvt_masked(&self, lvt: Lvt, masked: bool) -> Result<(), LvtError> {
+        let offset = self.lvt_offset(lvt)?;
         unsafe {
-            while self.read(0x300) & 1 << 12 == 1 << 12 {}
-            self.write(0x310, (value >> 32) as u32);
-            self.write(0x300, value as u32);
-            while self.read(0x300) & 1 << 12 == 1 << 12 {}
+            let value = self.read(offset) & !LVT_MASKED;
+            self.write(offset, if masked { value | LVT_MASKED } else { value });
         }
+        Ok(())
     }
 
-    pub fn eoi(&mut self) {
+    /// Send an IPI to the local APIC with ID dest, or to the destinations picked by the
+    /// shorthand in command. The ID has to fit in eight bits unless the APIC is in x2APIC mode.
+    pub fn set_icr(&self, dest: u32, command: u32) {
+        unsafe {
+            match &self.interface {
+                Interface::XApic(_) => {
+                    assert!(dest <= 0xff, "APIC ID {:#x} needs x2APIC mode", dest);
+                    while self.read(ICR_LOW) & ICR_DELIVERY_PENDING != 0 {}
+                    self.write(ICR_HIGH, dest << 24);
+                    self.write(ICR_LOW, command);
+                    while self.read(ICR_LOW) & ICR_DELIVERY_PENDING != 0 {}
+                }
+                // There is no delivery status to wait for in x2APIC mode
+                Interface::X2Apic => wrmsr(X2APIC_ICR, u64::from(dest) << 32 | u64::from(command)),
+            }
+        }
+    }
+
+    /// Whether vector has been delivered and is waiting for its EOI. Software interrupts never are.
+    pub fn in_service(&self, vector: u8) -> bool {
+        let register = unsafe { self.read(IN_SERVICE + u16::from(vector / 32) * 0x10) };
+        register & (1 << (vector % 32)) != 0
+    }
+
+    pub fn eoi(&self) {
         unsafe {
             self.write(0xB0, 0);
         }
     }
+
+    /// Fire LOCAL_TIMER_VECTOR on this CPU once its TSC reaches tsc_value. The CPU must support
+    /// TSC deadline mode.
+    pub fn arm_deadline(&self, tsc_value: u64) {
+        unsafe {
+            self.write(
+                LVT_TIMER,
+                LVT_TIMER_TSC_DEADLINE | u32::from(LOCAL_TIMER_VECTOR),
+            );
+
+            // The deadline is ignored if the MSR write overtakes the switch to deadline mode
+            fence(Ordering::SeqCst);
+            wrmsr(IA32_TSC_DEADLINE, tsc_value);
+        }
+    }
+
+    /// Fire LOCAL_TIMER_VECTOR on this CPU every period_ms milliseconds. Returns false if the timer
+    /// hasn't been calibrated.
+    pub fn arm_periodic(&self, period_ms: u32) -> bool {
+        let count = TIMER_TICKS_PER_MS
+            .load(Ordering::SeqCst)
+            .saturating_mul(period_ms);
+        if count == 0 {
+            return false;
+        }
+
+        unsafe {
+            self.write(TIMER_DIVIDE_CONFIG, TIMER_DIVIDE_BY_16);
+            self.write(
+                LVT_TIMER,
+                LVT_TIMER_PERIODIC | u32::from(LOCAL_TIMER_VECTOR),
+            );
+            self.write(TIMER_INITIAL_COUNT, count);
+        }
+        true
+    }
+
+    /// Stop this CPU's timer, whichever mode it is in
+    pub fn stop_timer(&self) {
+        unsafe {
+            self.write(LVT_TIMER, LVT_MASKED | u32::from(LOCAL_TIMER_VECTOR));
+            self.write(TIMER_INITIAL_COUNT, 0);
+            if tsc_deadline_supported() {
+                wrmsr(IA32_TSC_DEADLINE, 0);
+            }
+        }
+    }
 }
 
-static mut LOCAL_APIC_ACCESS: Option<LocalApicAccess> = None;
+static LOCAL_APIC_ACCESS: Once<LocalApicAccess> = Once::new();
 
-pub fn local_apic_access<'a>() -> &'a mut LocalApicAccess {
-    unsafe { LOCAL_APIC_ACCESS.as_mut().unwrap() }
+pub fn local_apic_access() -> &'static LocalApicAccess {
+    LOCAL_APIC_ACCESS
+        .get()
+        .expect("Local APIC has not been initialized")
 }
 
-pub fn local_apic_access_safe<'a>() -> Option<&'a mut LocalApicAccess> {
-    unsafe { LOCAL_APIC_ACCESS.as_mut() }
+pub fn local_apic_access_safe() -> Option<&'static LocalApicAccess> {
+    LOCAL_APIC_ACCESS.get()
 }
 
 fn disable_pic() {
-    use crate::io_port::{Io, IoPort};
+    use crate::io_port::{Io, Port};
 
     // We have to disable the PIC. We never want to hear from it. But, to be safe, we configure it
     // first, then disable it.
-    let mut master_cmd: IoPort<u8> = IoPort::new(0x20);
-    let mut master_data: IoPort<u8> = IoPort::new(0x21);
-    let mut slave_cmd: IoPort<u8> = IoPort::new(0xa0);
-    let mut slave_data: IoPort<u8> = IoPort::new(0xa1);
+    let mut master_cmd = Port::<u8, 0x20>::new();
+    let mut master_data = Port::<u8, 0x21>::new();
+    let mut slave_cmd = Port::<u8, 0xa0>::new();
+    let mut slave_data = Port::<u8, 0xa1>::new();
 
     // Start initialization
     master_cmd.write(0x11);
@@ -98,13 +325,178 @@ pub unsafe fn init_bsp() {
 
     // Set up the local apic access object. This does not need to be per core because
     // the mechanics of accessing the local apic do not change between cores.
-    LOCAL_APIC_ACCESS = Some(LocalApicAccess::new());
+    let local_apic = LOCAL_APIC_ACCESS.set(LocalApicAccess::new());
 
     // Set the spurious interrupt register to 0xff and enable the local APIC
-    local_apic_access().write(0xf0, 0x1ff);
+    local_apic.write(0xf0, 0x1ff);
+    set_logical_destination(0);
 }
 
-pub unsafe fn init_ap() {
+/// Measure how fast the local APIC timer counts against the clock, which must already be
+/// initialized
+pub unsafe fn calibrate_timer() {
+    let local_apic = local_apic_access();
+    local_apic.write(TIMER_DIVIDE_CONFIG, TIMER_DIVIDE_BY_16);
+    local_apic.write(LVT_TIMER, LVT_MASKED | u32::from(LOCAL_TIMER_VECTOR));
+    local_apic.write(TIMER_INITIAL_COUNT, u32::MAX);
+
+    super::clock::spin_wait_ns(TIMER_CALIBRATION_NS);
+
+    let counted = u32::MAX - local_apic.read(TIMER_CURRENT_COUNT);
+    local_apic.write(TIMER_INITIAL_COUNT, 0);
+
+    let ticks_per_ms = u64::from(counted) * 1_000_000 / TIMER_CALIBRATION_NS;
+    TIMER_TICKS_PER_MS.store(ticks_per_ms as u32, Ordering::SeqCst);
+}
+
+fn x2apic_msr(offset: u16) -> u32 {
+    X2APIC_MSR_BASE + u32::from(offset >> 4)
+}
+
+// Going from xAPIC to x2APIC mode has to be done with the APIC enabled
+unsafe fn enable_x2apic() {
+    let base = rdmsr(IA32_APIC_BASE);
+    wrmsr(IA32_APIC_BASE, base | APIC_BASE_ENABLE);
+    wrmsr(IA32_APIC_BASE, base | APIC_BASE_ENABLE | APIC_BASE_X2APIC);
+}
+
+pub unsafe fn init_ap(cpu_id: usize) {
+    // APs start in xAPIC mode, so they have to switch to whatever the BSP picked
+    let local_apic = local_apic_access();
+    if local_apic.x2apic() {
+        enable_x2apic();
+    }
+
     // Set the spurious interrupt register to 0xff and enable the local APIC
-    local_apic_access().write(0xf0, 0x1ff);
+    local_apic.write(0xf0, 0x1ff);
+    set_logical_destination(cpu_id);
+}
+
+/// The logical destination bit of a CPU. Logical destinations use the flat model, where each of
+/// the first eight CPUs has a bit of its own, so an interrupt can go to any set of them. x2APIC
+/// mode only has cluster mode, so there are no flat destinations at all then.
+pub fn logical_destination(cpu_id: usize) -> Option<u8> {
+    if x2apic_enabled() {
+        None
+    } else if cpu_id < 8 {
+        Some(1 << cpu_id)
+    } else {
+        None
+    }
+}
+
+unsafe fn set_logical_destination(cpu_id: usize) {
+    // The logical destination is read only in x2APIC mode, and there is no format register
+    let local_apic = local_apic_access();
+    if local_apic.x2apic() {
+        return;
+    }
+
+    local_apic.write(DESTINATION_FORMAT, DESTINATION_FORMAT_FLAT);
+    local_apic.write(
+        LOGICAL_DESTINATION,
+        u32::from(logical_destination(cpu_id).unwrap_or(0)) << 24,
+    );
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::devices::tsc;
+    use crate::idt;
+    use crate::interrupts::{self, irq, pause};
+    use core::sync::atomic::AtomicU64;
+    use x86::time::rdtsc;
+
+    static FIRED_AT: AtomicU64 = AtomicU64::new(0);
+
+    #[test_case]
+    fn local_apic_id_matches_cpuid() {
+        let local_apic = local_apic_access();
+        let cpuid = CpuId::new();
+        assert_eq!(local_apic.x2apic(), x2apic_supported());
+
+        if local_apic.x2apic() {
+            let base = unsafe { rdmsr(IA32_APIC_BASE) };
+            assert_ne!(base & APIC_BASE_X2APIC, 0);
+
+            let x2apic_id = cpuid
+                .get_extended_topology_info()
+                .and_then(|mut levels| levels.next())
+                .map(|level| level.x2apic_id());
+            let id = unsafe { rdmsr(x2apic_msr(0x20)) } as u32;
+            assert_eq!(Some(id), x2apic_id);
+            assert_eq!(local_apic.id(), id);
+        } else {
+            let initial_id = cpuid.get_feature_info().unwrap().initial_local_apic_id();
+            assert_eq!(local_apic.id(), u32::from(initial_id));
+        }
+        assert_eq!(local_apic.id() as usize, crate::cpu_id());
+    }
+
+    #[test_case]
+    fn lvt_entries_match_the_version() {
+        let local_apic = local_apic_access();
+
+        // QEMU emulates an integrated APIC with six LVT entries, so no CMCI
+        let version = local_apic.version() & 0xff;
+        assert!(version >= 0x10 && version <= 0x15, "Version {:#x}", version);
+        assert_eq!(local_apic.max_lvt_entries(), 6);
+        assert_eq!(
+            local_apic.set_lvt_masked(Lvt::Cmci, true),
+            Err(LvtError::NotPresent(Lvt::Cmci))
+        );
+
+        // Nothing has set up a vector for thermal interrupts, so it is only ever masked here
+        let was_masked = local_apic.lvt_masked(Lvt::Thermal).unwrap();
+        local_apic.set_lvt_masked(Lvt::Thermal, true).unwrap();
+        assert_eq!(local_apic.lvt_masked(Lvt::Thermal), Ok(true));
+        local_apic.set_lvt_masked(Lvt::Thermal, was_masked).unwrap();
+        assert_eq!(local_apic.lvt_masked(Lvt::Thermal), Ok(was_masked));
+    }
+
+    crate::interrupt!(deadline_test_interrupt, || {
+        FIRED_AT.store(rdtsc(), Ordering::SeqCst);
+        local_apic_access().eoi();
+    });
+
+    #[test_case]
+    fn deadline_timer_fires_at_its_deadline() {
+        const DELAY_NS: u64 = 2_000_000;
+        const TOLERANCE_NS: u64 = 1_000_000;
+
+        let (delay, tolerance) = match (tsc::ns_to_ticks(DELAY_NS), tsc::ns_to_ticks(TOLERANCE_NS))
+        {
+            (Some(delay), Some(tolerance)) if tsc_deadline_supported() => (delay, tolerance),
+            _ => return,
+        };
+
+        let were_enabled = interrupts::are_enabled();
+        FIRED_AT.store(0, Ordering::SeqCst);
+        unsafe { idt::set_handler(LOCAL_TIMER_VECTOR, Some(deadline_test_interrupt)) };
+
+        let deadline = unsafe { rdtsc() } + delay;
+        local_apic_access().arm_deadline(deadline);
+        unsafe { interrupts::enable() };
+        while FIRED_AT.load(Ordering::SeqCst) == 0 && unsafe { rdtsc() } < deadline + 100 * delay {
+            pause();
+        }
+
+        unsafe {
+            interrupts::disable();
+            idt::set_handler(LOCAL_TIMER_VECTOR, Some(irq::local_timer));
+            if were_enabled {
+                interrupts::enable();
+            }
+        }
+
+        let fired_at = FIRED_AT.load(Ordering::SeqCst);
+        assert_ne!(fired_at, 0, "Deadline timer didn't fire");
+        assert!(fired_at >= deadline);
+        assert!(
+            fired_at - deadline < tolerance,
+            "Fired {} ticks late",
+            fired_at - deadline
+        );
+    }
 }
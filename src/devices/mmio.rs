@@ -0,0 +1,98 @@
+use crate::paging::{self, MmioValue, Region};
+
+/// A block of memory mapped device registers. Every access is volatile and bounds checked against
+/// the mapping, so drivers only have to know their register offsets.
+pub struct Mmio {
+    region: Region,
+}
+
+impl Mmio {
+    pub fn new(region: Region) -> Self {
+        Self { region }
+    }
+
+    /// Map size bytes of registers at physical_address, uncached
+    pub unsafe fn map(physical_address: usize, size: usize) -> paging::Result<Self> {
+        paging::map_physical_memory(
+            physical_address,
+            size,
+            paging::PhysicalMappingFlags::UNCACHED,
+        )
+        .map(Self::new)
+    }
+
+    pub fn read<T: MmioValue>(&self, offset: usize) -> T {
+        self.region.read_volatile(offset)
+    }
+
+    // The registers are MMIO, so writing them doesn't need a mutable reference
+    pub fn write<T: MmioValue>(&self, offset: usize, value: T) {
+        self.region.write_volatile(offset, value)
+    }
+
+    /// Read a register, then write back whatever f makes of it. Returns the value written. This
+    /// is not atomic, so callers sharing the registers need their own lock.
+    pub fn modify<T: MmioValue>(&self, offset: usize, f: impl FnOnce(T) -> T) -> T {
+        let value = f(self.read(offset));
+        self.write(offset, value);
+        value
+    }
+
+    pub fn size(&self) -> usize {
+        self.region.size()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn ram_registers() -> Mmio {
+        let registers = Mmio::new(paging::allocate_region(1).expect("Failed to allocate region"));
+        for offset in (0..registers.size()).step_by(8) {
+            registers.write(offset, 0u64);
+        }
+        registers
+    }
+
+    #[test_case]
+    fn writes_are_read_back() {
+        let registers = ram_registers();
+
+        registers.write(0x10, 0x1234_5678u32);
+        registers.write(0x18, 0xdead_beef_cafe_f00du64);
+        registers.write(0x21, 0xa5u8);
+
+        assert_eq!(registers.read::<u32>(0x10), 0x1234_5678);
+        assert_eq!(registers.read::<u16>(0x10), 0x5678);
+        assert_eq!(registers.read::<u64>(0x18), 0xdead_beef_cafe_f00d);
+        assert_eq!(registers.read::<u8>(0x21), 0xa5);
+        assert_eq!(registers.read::<u8>(0x20), 0);
+    }
+
+    #[test_case]
+    fn modify_writes_back_the_new_value() {
+        let registers = ram_registers();
+        registers.write(0xf0, 0x0fu32);
+
+        let mut seen = 0;
+        let written = registers.modify(0xf0, |value: u32| {
+            seen = value;
+            value | 0x100
+        });
+
+        assert_eq!(seen, 0x0f);
+        assert_eq!(written, 0x10f);
+        assert_eq!(registers.read::<u32>(0xf0), 0x10f);
+        assert_eq!(registers.read::<u32>(0xf4), 0);
+    }
+
+    #[test_case]
+    fn last_register_in_the_region_is_usable() {
+        let registers = ram_registers();
+        let last = registers.size() - 4;
+
+        registers.write(last, u32::MAX);
+        assert_eq!(registers.modify(last, |value: u32| value - 1), u32::MAX - 1);
+    }
+}
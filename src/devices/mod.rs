@@ -1,26 +1,76 @@
-use crate::init::AP_READY;
+use crate::init::{self, BootOptions};
 use crate::paging::{self, PAGE_SIZE};
 use crate::physmem::Frame;
-use core::sync::atomic::Ordering;
+use core::sync::atomic::{AtomicU64, AtomicU8, Ordering};
 
+pub mod clock;
 pub mod hpet;
 pub mod io_apic;
 pub mod local_apic;
+pub mod mmio;
+pub mod pcie;
+pub mod pit;
+pub mod ps2_keyboard;
+pub mod rtc;
+pub mod time;
+pub mod timer;
+pub mod tsc;
 
-pub unsafe fn init_bsp() {
+pub use rtc::wall_clock_ns;
+pub use time::now_ns;
+
+pub unsafe fn init_bsp(options: &BootOptions) {
     local_apic::init_bsp();
     io_apic::init();
-    hpet::init();
+    clock::init(clock::DEFAULT_TICK_HZ, !options.ignore_hpet);
+    tsc::init_bsp();
+    rtc::init();
+    local_apic::calibrate_timer();
+    pcie::init();
+    ps2_keyboard::init();
 }
 
-pub unsafe fn init_ap(_cpu_id: usize) {
-    local_apic::init_ap();
+pub unsafe fn init_ap(cpu_id: usize) {
+    local_apic::init_ap(cpu_id);
+    tsc::init_ap();
+}
+
+/// Stop the APICs interrupting this CPU, for when it is about to stop for good. Whatever hasn't
+/// been set up yet is left alone, and nothing waits for a lock, so this is safe to call from a
+/// panic at any point.
+pub fn quiesce_interrupts() {
+    for io_apic in io_apic::io_apics() {
+        io_apic.try_mask_all();
+    }
+
+    if let Some(local_apic) = local_apic::local_apic_access_safe() {
+        local_apic.stop_timer();
+    }
 }
 
 const TRAMPOLINE_P4: usize = 0x7000;
 const TRAMPOLINE: usize = 0x8000;
 static TRAMPOLINE_DATA: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/trampoline"));
 
+// The words at the start of the trampoline that tell the AP where to go, after the initial jump
+const HANDOFF_OFFSET: usize = 8;
+const HANDOFF_READY: usize = 0;
+const HANDOFF_STACK: usize = 1;
+const HANDOFF_STARTUP_DATA: usize = 2;
+const HANDOFF_CODE: usize = 3;
+const HANDOFF_WORDS: usize = 4;
+
+// The trampoline stores the entry point it read from HANDOFF_CODE here once it has finished with
+// the trampoline page
+static TRAMPOLINE_DONE: AtomicU64 = AtomicU64::new(0);
+
+// View memory that an AP reads as atomics, rather than as a &mut slice. The stores are then real
+// stores that the AP can see, and we never claim exclusive access to memory another CPU is using.
+unsafe fn atomic_view<'a, T>(addr: *mut u8, count: usize) -> &'a [T] {
+    assert_eq!(addr as usize % core::mem::align_of::<T>(), 0);
+    core::slice::from_raw_parts(addr as *const T, count)
+}
+
 #[derive(Debug)]
 struct ApStartupData {
     kernel_stack: paging::KernelStack,
@@ -33,14 +83,15 @@ pub unsafe fn start_aps() {
     let acpi = acpi_lock.as_mut().unwrap();
 
     // First thing we have to do is to identity map the trampoline. We do this because
-    // when the trampoline enables paging, it needs to be able to continue running
+    // when the trampoline enables paging, it needs to be able to continue running. It starts out
+    // writable so that we can copy the code in.
     {
         let mut page_table = paging::lock_page_table();
         let flush = page_table
             .map_to(
                 TRAMPOLINE,
                 Frame::containing_address(TRAMPOLINE),
-                paging::PresentPageFlags::WRITABLE,
+                paging::PresentPageFlags::WRITABLE | paging::PresentPageFlags::NO_EXECUTE,
             )
             .expect("Failed to map trampoline");
         flush.flush(&page_table);
@@ -48,27 +99,48 @@ pub unsafe fn start_aps() {
 
     let mut mapping = paging::map_physical_memory(
         TRAMPOLINE_P4,
-        2 * PAGE_SIZE,
+        PAGE_SIZE,
         paging::PhysicalMappingFlags::empty(),
     )
-    .expect("Failed to map trampoline");
-    let trampoline_p4 =
-        core::slice::from_raw_parts_mut(mapping.as_mut_ptr_offset::<u64>(0), PAGE_SIZE / 8);
-    let trampoline =
-        core::slice::from_raw_parts_mut(mapping.as_mut_ptr_offset::<u8>(PAGE_SIZE), PAGE_SIZE);
+    .expect("Failed to map trampoline page table");
+    let trampoline_p4 = atomic_view::<AtomicU64>(mapping.as_mut_ptr_offset(0), PAGE_SIZE / 8);
 
     let kernel_page_table = paging::phys_to_virt_addr(x86::controlregs::cr3() as usize, PAGE_SIZE);
     let page_table = core::slice::from_raw_parts(kernel_page_table as *const u64, PAGE_SIZE / 8);
-    for i in 0..512 {
-        core::intrinsics::atomic_store(&mut trampoline_p4[i] as *mut _, page_table[i]);
+    for (entry, kernel_entry) in trampoline_p4.iter().zip(page_table.iter()) {
+        entry.store(*kernel_entry, Ordering::SeqCst);
     }
 
     // Copy the trampoline into the memory block we use for it
-    for i in 0..TRAMPOLINE_DATA.len() {
-        core::intrinsics::atomic_store(&mut trampoline[i] as *mut _, TRAMPOLINE_DATA[i]);
+    let trampoline_code = atomic_view::<AtomicU8>(TRAMPOLINE as *mut u8, TRAMPOLINE_DATA.len());
+    for (byte, data) in trampoline_code.iter().zip(TRAMPOLINE_DATA.iter()) {
+        byte.store(*data, Ordering::SeqCst);
+    }
+
+    // The APs only read and execute the trampoline page, so make it read only before any of them
+    // start running it
+    {
+        let mut page_table = paging::lock_page_table();
+        page_table
+            .remap(TRAMPOLINE, paging::PresentPageFlags::empty())
+            .expect("Failed to remap trampoline")
+            .flush(&page_table);
     }
 
-    for ap in acpi.acpi_context.application_processors.iter() {
+    // Tests keep the last AP back, so that they can start it themselves
+    let ap_count = acpi.acpi_context.application_processors.len();
+    let boot_aps = if cfg!(test) {
+        ap_count.saturating_sub(1)
+    } else {
+        ap_count
+    };
+
+    for ap in acpi
+        .acpi_context
+        .application_processors
+        .iter()
+        .take(boot_aps)
+    {
         if ap.state != acpi::ProcessorState::WaitingForSipi {
             continue;
         }
@@ -79,66 +151,83 @@ pub unsafe fn start_aps() {
             "BSP listed in ASP list"
         );
 
-        let (startup_data, stack) = {
-            let kernel_stack = paging::allocate_kernel_stack(paging::DEFAULT_KERNEL_STACK_PAGES)
-                .expect("Failed to allocate kernel stack for AP");
-            let cr3 = x86::controlregs::cr3() as usize;
-            let stack = kernel_stack.stack_top();
-            let cpu_id = ap.local_apic_id.into();
-            let startup_data = box ApStartupData {
-                kernel_stack,
-                cpu_id,
-                cr3,
-            };
-
-            (alloc::boxed::Box::into_raw(startup_data), stack)
-        };
-
-        crate::println!("Starting AP: {:?}", ap);
-
-        let ap_ready = trampoline.as_ptr().offset(8) as *mut u64;
-        let ap_stack = ap_ready.offset(1);
-        let ap_startup_data = ap_ready.offset(2);
-        let ap_code = ap_ready.offset(3);
-        AP_READY.store(false, Ordering::SeqCst);
+        crate::info!("Starting AP: {:?}", ap);
+        if start_ap(ap.local_apic_id.into()) {
+            crate::debug!("AP started");
+        } else {
+            crate::error!("AP {} failed to start", ap.local_apic_id);
+        }
+    }
+}
 
-        use core::intrinsics::{atomic_load, atomic_store};
-        atomic_store(ap_ready, 0);
-        atomic_store(ap_stack, stack as u64);
-        atomic_store(ap_startup_data, startup_data as u64);
-        atomic_store(ap_code, enter_ap as u64);
+/// Start the AP with this local APIC id, and wait until it is ready or has failed. The trampoline
+/// must already be set up by start_aps. An AP that failed can be started again.
+pub unsafe fn start_ap(cpu_id: usize) -> bool {
+    let (startup_data, stack) = {
+        let kernel_stack = paging::allocate_kernel_stack(paging::DEFAULT_KERNEL_STACK_PAGES)
+            .expect("Failed to allocate kernel stack for AP");
+        let cr3 = x86::controlregs::cr3() as usize;
+        let stack = kernel_stack.stack_top();
+        let startup_data = box ApStartupData {
+            kernel_stack,
+            cpu_id,
+            cr3,
+        };
 
-        {
-            let mut icr = 0x4500;
-            icr |= (ap.local_apic_id as u64) << 56;
+        (alloc::boxed::Box::into_raw(startup_data), stack)
+    };
 
-            crate::println!("Sending init IPI");
-            local_apic::local_apic_access().set_icr(icr);
-        }
+    init::begin_ap_startup(cpu_id);
 
-        {
-            let ap_segment = (TRAMPOLINE >> 12) & 0xFF;
-            let mut icr = 0x4600 | ap_segment as u64;
+    // The handoff words are written through a physical mapping, because the trampoline page itself
+    // is read only. The SIPI is sent after these stores, so the AP sees them.
+    let mut mapping =
+        paging::map_physical_memory(TRAMPOLINE, PAGE_SIZE, paging::PhysicalMappingFlags::empty())
+            .expect("Failed to map trampoline");
+    let handoff =
+        atomic_view::<AtomicU64>(mapping.as_mut_ptr_offset(HANDOFF_OFFSET), HANDOFF_WORDS);
+    TRAMPOLINE_DONE.store(0, Ordering::SeqCst);
+    handoff[HANDOFF_READY].store(
+        &TRAMPOLINE_DONE as *const AtomicU64 as u64,
+        Ordering::SeqCst,
+    );
+    handoff[HANDOFF_STACK].store(stack as u64, Ordering::SeqCst);
+    handoff[HANDOFF_STARTUP_DATA].store(startup_data as u64, Ordering::SeqCst);
+    handoff[HANDOFF_CODE].store(enter_ap as u64, Ordering::SeqCst);
 
-            icr |= (ap.local_apic_id as u64) << 56;
+    {
+        crate::trace!("Sending init IPI");
+        local_apic::local_apic_access().set_icr(cpu_id as u32, 0x4500);
+    }
 
-            crate::println!("Sending start IPI");
-            local_apic::local_apic_access().set_icr(icr);
-        }
+    {
+        let ap_segment = (TRAMPOLINE >> 12) & 0xFF;
 
-        // Wait for trampoline ready
-        crate::println!("Waiting for trampoline ready signal");
-        while atomic_load(ap_ready) == 0 {
-            crate::interrupts::pause();
-        }
+        crate::trace!("Sending start IPI");
+        local_apic::local_apic_access().set_icr(cpu_id as u32, 0x4600 | ap_segment as u32);
+    }
 
-        crate::println!("Waiting for processor startup");
-        while !AP_READY.load(Ordering::SeqCst) {
-            crate::interrupts::pause();
+    // Wait for trampoline ready. The AP signals it by writing back the entry point it read.
+    crate::trace!("Waiting for trampoline ready signal");
+    let ap_code = loop {
+        match TRAMPOLINE_DONE.load(Ordering::SeqCst) {
+            0 => crate::interrupts::pause(),
+            ap_code => break ap_code,
         }
+    };
+    assert_eq!(
+        ap_code, enter_ap as u64,
+        "AP {} read the wrong entry point",
+        cpu_id
+    );
 
-        crate::println!("AP started");
+    crate::trace!("Waiting for processor startup");
+    while !init::ap_ready(cpu_id) && !init::ap_failed(cpu_id) {
+        crate::interrupts::pause();
     }
+    init::end_ap_startup();
+
+    init::ap_ready(cpu_id)
 }
 
 unsafe extern "C" fn enter_ap(startup_data: *mut ApStartupData) -> ! {
@@ -149,3 +238,105 @@ unsafe extern "C" fn enter_ap(startup_data: *mut ApStartupData) -> ! {
 
     crate::init::kstart_ap(startup_data.cpu_id, startup_data.kernel_stack)
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::idt;
+    use crate::interrupts::{self, irq};
+    use alloc::vec::Vec;
+    use core::sync::atomic::AtomicUsize;
+
+    static LATE_INTERRUPTS: AtomicUsize = AtomicUsize::new(0);
+
+    crate::interrupt!(late_timer_interrupt, || {
+        LATE_INTERRUPTS.fetch_add(1, Ordering::SeqCst);
+        local_apic::local_apic_access().eoi();
+    });
+
+    #[test_case]
+    fn quiesced_interrupts_stay_quiet() {
+        let saved: Vec<Vec<u64>> = io_apic::io_apics()
+            .iter()
+            .map(|io_apic| io_apic.redirection_table())
+            .collect();
+        let were_enabled = interrupts::are_enabled();
+
+        LATE_INTERRUPTS.store(0, Ordering::SeqCst);
+        unsafe {
+            interrupts::disable();
+            idt::set_handler(local_apic::LOCAL_TIMER_VECTOR, Some(late_timer_interrupt));
+        }
+        let armed = local_apic::local_apic_access().arm_periodic(1);
+
+        // The timer is armed, but hasn't had the chance to go off yet
+        quiesce_interrupts();
+        let masked = io_apic::io_apics().iter().all(|io_apic| {
+            io_apic
+                .redirection_table()
+                .iter()
+                .all(|reg| reg & 1 << 16 != 0)
+        });
+
+        unsafe { interrupts::enable() };
+        clock::spin_wait_ns(5_000_000);
+
+        unsafe {
+            interrupts::disable();
+            for (io_apic, table) in io_apic::io_apics().iter().zip(saved.iter()) {
+                io_apic.restore_redirection_table(table);
+            }
+            idt::set_handler(local_apic::LOCAL_TIMER_VECTOR, Some(irq::local_timer));
+            if were_enabled {
+                interrupts::enable();
+            }
+        }
+
+        assert!(armed, "Local APIC timer is not calibrated");
+        assert!(masked, "IO APIC entries were left unmasked");
+        assert_eq!(LATE_INTERRUPTS.load(Ordering::SeqCst), 0);
+    }
+
+    #[test_case]
+    fn trampoline_is_read_only_and_executable() {
+        let page_table = unsafe { paging::lock_page_table() };
+        let flags = page_table
+            .get_pte_for_address(TRAMPOLINE)
+            .and_then(|pte| pte.present().ok())
+            .expect("Trampoline is not mapped")
+            .flags();
+
+        assert!(!flags.contains(paging::PresentPageFlags::WRITABLE));
+        assert!(!flags.contains(paging::PresentPageFlags::NO_EXECUTE));
+    }
+
+    #[test_case]
+    fn aps_read_the_handoff_words() {
+        let trampoline = unsafe { core::slice::from_raw_parts(TRAMPOLINE as *const u8, PAGE_SIZE) };
+        let handoff_end = HANDOFF_OFFSET + HANDOFF_WORDS * 8;
+
+        // The code around the handoff words is what was copied in
+        assert_eq!(
+            &trampoline[..HANDOFF_OFFSET],
+            &TRAMPOLINE_DATA[..HANDOFF_OFFSET]
+        );
+        assert_eq!(
+            &trampoline[handoff_end..TRAMPOLINE_DATA.len()],
+            &TRAMPOLINE_DATA[handoff_end..]
+        );
+
+        // The handoff words are what the last AP to start was given, and it wrote back the entry
+        // point it jumped to
+        let handoff = unsafe {
+            core::slice::from_raw_parts((TRAMPOLINE + HANDOFF_OFFSET) as *const u64, HANDOFF_WORDS)
+        };
+        assert_eq!(
+            handoff[HANDOFF_READY],
+            &TRAMPOLINE_DONE as *const AtomicU64 as u64
+        );
+        assert_ne!(handoff[HANDOFF_STACK], 0);
+        assert_ne!(handoff[HANDOFF_STARTUP_DATA], 0);
+        assert_eq!(handoff[HANDOFF_CODE], enter_ap as u64);
+        assert_eq!(TRAMPOLINE_DONE.load(Ordering::SeqCst), enter_ap as u64);
+    }
+}
@@ -0,0 +1,252 @@
+use crate::acpi::ACPI;
+use crate::io_port::{Io, IoPort};
+use crate::paging::{self, PhysicalMappingFlags, Region};
+use acpi::PciConfigRegions;
+use alloc::vec::Vec;
+use spin::Mutex;
+
+// With ECAM every function gets a full 4KiB of configuration space, whereas the legacy port based
+// mechanism can only reach the first 256 bytes.
+pub const CONFIG_SPACE_SIZE: usize = 4096;
+pub const LEGACY_CONFIG_SPACE_SIZE: usize = 256;
+
+// The configuration space of a whole bus is contiguous: 32 devices of 8 functions each
+const FUNCTIONS_PER_BUS: usize = 32 * 8;
+const BUS_CONFIG_SPACE_SIZE: usize = FUNCTIONS_PER_BUS * CONFIG_SPACE_SIZE;
+
+const CONFIG_ADDRESS_PORT: u16 = 0xcf8;
+const CONFIG_DATA_PORT: u16 = 0xcfc;
+
+// We take the MCFG information out of the ACPI context at init time. Config space accesses happen
+// from inside AML evaluation, when the ACPI lock is already held, so we can't go back to it.
+static ECAM_REGIONS: Mutex<Option<PciConfigRegions>> = Mutex::new(None);
+static LEGACY_LOCK: Mutex<()> = Mutex::new(());
+
+// Buses are mapped the first time they are used and then kept, because mapping and unmapping for
+// every access would mean a TLB shootdown each time
+struct MappedBus {
+    segment: u16,
+    bus: u8,
+    region: Region,
+}
+
+static MAPPED_BUSES: Mutex<Vec<MappedBus>> = Mutex::new(Vec::new());
+
+pub unsafe fn init() {
+    let mut acpi_lock = ACPI.lock();
+    let acpi = acpi_lock.as_mut().unwrap();
+
+    *ECAM_REGIONS.lock() = acpi.acpi_context.pci_config_regions.take();
+}
+
+pub fn has_ecam() -> bool {
+    ECAM_REGIONS.lock().is_some()
+}
+
+fn bus_address(segment: u16, bus: u8) -> Option<usize> {
+    ECAM_REGIONS
+        .lock()
+        .as_ref()
+        .and_then(|regions| regions.physical_address(segment, bus, 0, 0))
+        .map(|address| address as usize)
+}
+
+/// Run func on the ECAM configuration space of a bus, mapping it if this is the first access.
+/// Function f of device d starts at (d * 8 + f) * CONFIG_SPACE_SIZE. Returns None if there is no
+/// MCFG, or if it does not cover the requested segment and bus.
+fn with_bus_config_space<R>(segment: u16, bus: u8, func: impl FnOnce(&Region) -> R) -> Option<R> {
+    let mut mapped_buses = MAPPED_BUSES.lock();
+    let position = mapped_buses
+        .iter()
+        .position(|mapped| mapped.segment == segment && mapped.bus == bus);
+
+    let mapped = match position {
+        Some(position) => &mapped_buses[position],
+        None => {
+            let region = bus_address(segment, bus).and_then(|address| unsafe {
+                paging::map_physical_memory(
+                    address,
+                    BUS_CONFIG_SPACE_SIZE,
+                    PhysicalMappingFlags::UNCACHED,
+                )
+                .ok()
+            })?;
+
+            mapped_buses.push(MappedBus {
+                segment,
+                bus,
+                region,
+            });
+            mapped_buses.last().unwrap()
+        }
+    };
+
+    Some(func(&mapped.region))
+}
+
+// Where function f of device d starts in its bus's configuration space
+fn function_offset(device: u8, function: u8) -> usize {
+    assert!(device < 32, "Invalid PCI device {}", device);
+    assert!(function < 8, "Invalid PCI function {}", function);
+
+    (usize::from(device) * 8 + usize::from(function)) * CONFIG_SPACE_SIZE
+}
+
+/// The ECAM configuration space of a single function, CONFIG_SPACE_SIZE bytes of it. This is a view
+/// of its bus's mapping, which is kept for good, so dropping it unmaps nothing. Returns None if
+/// there is no MCFG, or if it does not cover the requested segment and bus.
+pub fn config_space(segment: u16, bus: u8, device: u8, function: u8) -> Option<Region> {
+    let offset = function_offset(device, function);
+    with_bus_config_space(segment, bus, |region| unsafe {
+        region.view(offset, CONFIG_SPACE_SIZE)
+    })
+}
+
+fn legacy_address(bus: u8, device: u8, function: u8, offset: u16) -> u32 {
+    assert!(device < 32, "Invalid PCI device {}", device);
+    assert!(function < 8, "Invalid PCI function {}", function);
+
+    0x8000_0000
+        | (u32::from(bus) << 16)
+        | (u32::from(device) << 11)
+        | (u32::from(function) << 8)
+        | u32::from(offset & 0xfc)
+}
+
+fn legacy_access<R>(
+    segment: u16,
+    bus: u8,
+    device: u8,
+    function: u8,
+    offset: u16,
+    func: impl FnOnce(u16) -> R,
+) -> R {
+    assert_eq!(
+        segment, 0,
+        "Legacy PCI configuration only supports segment 0"
+    );
+    assert!(
+        (offset as usize) < LEGACY_CONFIG_SPACE_SIZE,
+        "Legacy PCI configuration offset {:#x} out of range",
+        offset
+    );
+
+    let _guard = LEGACY_LOCK.lock();
+    IoPort::<u32>::new(CONFIG_ADDRESS_PORT).write(legacy_address(bus, device, function, offset));
+    func(CONFIG_DATA_PORT + (offset & 3))
+}
+
+fn ecam_access<R>(
+    segment: u16,
+    bus: u8,
+    device: u8,
+    function: u8,
+    offset: u16,
+    size: usize,
+    func: impl FnOnce(&Region, usize) -> R,
+) -> Option<R> {
+    assert!(
+        offset as usize + size <= CONFIG_SPACE_SIZE,
+        "PCI configuration offset {:#x} out of range",
+        offset
+    );
+
+    let function_offset = function_offset(device, function);
+    with_bus_config_space(segment, bus, |region| {
+        func(region, function_offset + usize::from(offset))
+    })
+}
+
+macro_rules! config_accessors {
+    ($read:ident, $write:ident, $t:ty) => {
+        pub fn $read(segment: u16, bus: u8, device: u8, function: u8, offset: u16) -> $t {
+            ecam_access(
+                segment,
+                bus,
+                device,
+                function,
+                offset,
+                core::mem::size_of::<$t>(),
+                |region, offset| region.read_volatile::<$t>(offset),
+            )
+            .unwrap_or_else(|| {
+                legacy_access(segment, bus, device, function, offset, |port| {
+                    IoPort::<$t>::new(port).read()
+                })
+            })
+        }
+
+        pub fn $write(segment: u16, bus: u8, device: u8, function: u8, offset: u16, value: $t) {
+            ecam_access(
+                segment,
+                bus,
+                device,
+                function,
+                offset,
+                core::mem::size_of::<$t>(),
+                |region, offset| region.write_volatile(offset, value),
+            )
+            .unwrap_or_else(|| {
+                legacy_access(segment, bus, device, function, offset, |port| {
+                    IoPort::<$t>::new(port).write(value)
+                })
+            })
+        }
+    };
+}
+
+config_accessors!(read_u8, write_u8, u8);
+config_accessors!(read_u16, write_u16, u16);
+config_accessors!(read_u32, write_u32, u32);
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn read_ecam_u32(device: u8, offset: u16) -> u32 {
+        ecam_access(0, 0, device, 0, offset, 4, |region, offset| {
+            region.read_volatile::<u32>(offset)
+        })
+        .expect("ECAM region not available")
+    }
+
+    #[test_case]
+    fn ecam_matches_legacy_and_reaches_extended_space() {
+        if !has_ecam() {
+            crate::skip_test("no MCFG table, ECAM needs a q35 machine");
+            return;
+        }
+
+        for device in 0..32 {
+            let legacy_id =
+                legacy_access(0, 0, device, 0, 0, |port| IoPort::<u32>::new(port).read());
+            if legacy_id & 0xffff == 0xffff {
+                continue;
+            }
+
+            assert_eq!(read_ecam_u32(device, 0), legacy_id);
+
+            // The extended capability list starts at 0x100. An empty list is a zero header, but
+            // a function that is present never reads back as all ones.
+            let extended = read_ecam_u32(device, 0x100);
+            assert_ne!(extended, 0xffff_ffff);
+            assert_eq!(read_u32(0, 0, device, 0, 0x100), extended);
+
+            let config = config_space(0, 0, device, 0).expect("ECAM region not available");
+            assert_eq!(config.size(), CONFIG_SPACE_SIZE);
+            assert_eq!(config.read_volatile::<u32>(0), legacy_id);
+            assert_eq!(config.read_volatile::<u32>(0x100), extended);
+        }
+
+        // The whole bus shares one mapping, which dropping the views above left in place
+        let mapped_buses = MAPPED_BUSES.lock();
+        assert_eq!(
+            mapped_buses
+                .iter()
+                .filter(|mapped| mapped.segment == 0 && mapped.bus == 0)
+                .count(),
+            1
+        );
+        assert_eq!(mapped_buses[0].region.size(), BUS_CONFIG_SPACE_SIZE);
+    }
+}
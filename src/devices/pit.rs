@@ -0,0 +1,282 @@
+// The 8254 PIT, for machines with no HPET. Channel 0 gives the periodic tick on IRQ 0, and the
+// clock is the number of ticks plus how far channel 0 has counted into the current one. That is
+// only as good as the BSP is at taking its interrupts, so the clock can stall while it is busy,
+// but it never goes backwards. Spin waits use channel 2 one shots instead, which are polled and
+// don't need interrupts at all.
+
+use super::time::{register_clocksource, Clocksource};
+use crate::io_port::{Io, Port};
+use crate::sync::IrqMutex;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+/// How fast every PIT channel counts
+pub const PIT_HZ: u64 = 1_193_182;
+
+const NS_PER_SECOND: u64 = 1_000_000_000;
+
+const SELECT_CHANNEL0: u8 = 0x00;
+const SELECT_CHANNEL2: u8 = 0x80;
+const ACCESS_LATCH: u8 = 0x00;
+const ACCESS_LOW_HIGH: u8 = 0x30;
+const MODE_INTERRUPT_ON_COUNT: u8 = 0x00;
+const MODE_RATE_GENERATOR: u8 = 0x04;
+
+const GATE_CHANNEL2: u8 = 0x01;
+const SPEAKER_ENABLE: u8 = 0x02;
+const CHANNEL2_OUTPUT: u8 = 0x20;
+
+// Spin waits are done a bit at a time, because interrupts are off on this CPU while the PIT is
+// locked, and more than one tick in that time would be lost
+const MAX_ONE_SHOT_NS: u64 = 1_000_000;
+
+struct Pit {
+    channel0: Port<u8, 0x40>,
+    channel2: Port<u8, 0x42>,
+    command: Port<u8, 0x43>,
+    gate: Port<u8, 0x61>,
+}
+
+impl Pit {
+    fn write_count(channel: &mut impl Io<Value = u8>, count: u16) {
+        channel.write(count as u8);
+        channel.write((count >> 8) as u8);
+    }
+
+    fn read_channel0(&mut self) -> u16 {
+        self.command.write(SELECT_CHANNEL0 | ACCESS_LATCH);
+        let low = self.channel0.read();
+        let high = self.channel0.read();
+        u16::from(low) | u16::from(high) << 8
+    }
+
+    fn read_channel2(&mut self) -> u16 {
+        self.command.write(SELECT_CHANNEL2 | ACCESS_LATCH);
+        let low = self.channel2.read();
+        let high = self.channel2.read();
+        u16::from(low) | u16::from(high) << 8
+    }
+
+    // Start channel 2 counting down from count, with the speaker off, and return the gate bits to
+    // put back once it is done with
+    fn start_channel2(&mut self, count: u16) -> u8 {
+        let gate = self.gate.read() & !(GATE_CHANNEL2 | SPEAKER_ENABLE);
+        self.gate.write(gate);
+        self.command
+            .write(SELECT_CHANNEL2 | ACCESS_LOW_HIGH | MODE_INTERRUPT_ON_COUNT);
+        Self::write_count(&mut self.channel2, count);
+
+        // Counting starts when the gate goes high
+        self.gate.write(gate | GATE_CHANNEL2);
+        gate
+    }
+
+    fn one_shot(&mut self, count: u16) {
+        let gate = self.start_channel2(count);
+
+        // The output goes high when it reaches zero
+        while self.gate.read() & CHANNEL2_OUTPUT == 0 {
+            crate::interrupts::pause();
+        }
+        self.gate.write(gate);
+    }
+}
+
+static PIT: IrqMutex<Pit> = IrqMutex::new(Pit {
+    channel0: Port::new(),
+    channel2: Port::new(),
+    command: Port::new(),
+    gate: Port::new(),
+});
+
+// What channel 0 counts down from each tick, or 0 if it isn't ticking
+static RELOAD: AtomicU64 = AtomicU64::new(0);
+static TICKS: AtomicU64 = AtomicU64::new(0);
+static LAST_NS: AtomicU64 = AtomicU64::new(0);
+
+// The counts and ticks when channel 0 was last reprogrammed, which the clock carries on from
+static BASE_COUNTS: AtomicU64 = AtomicU64::new(0);
+static BASE_TICKS: AtomicU64 = AtomicU64::new(0);
+
+fn counts_to_ns(counts: u64) -> u64 {
+    (u128::from(counts) * u128::from(NS_PER_SECOND) / u128::from(PIT_HZ)) as u64
+}
+
+fn ns_to_counts(ns: u64) -> u64 {
+    (u128::from(ns) * u128::from(PIT_HZ) / u128::from(NS_PER_SECOND)) as u64
+}
+
+/// What channel 0 has to count down from to tick at hz, if it can
+pub fn reload_for(hz: u32) -> Option<u16> {
+    match PIT_HZ.checked_div(hz.into()) {
+        // A reload of zero means 65536 to the PIT, but there is no need to go that slow
+        Some(reload) if reload > 0 && reload <= u64::from(u16::MAX) => Some(reload as u16),
+        _ => None,
+    }
+}
+
+struct PitClocksource;
+
+impl Clocksource for PitClocksource {
+    fn name(&self) -> &'static str {
+        "pit"
+    }
+
+    fn now_ns(&self) -> Option<u64> {
+        monotonic_ns()
+    }
+
+    fn resolution_ns(&self) -> u64 {
+        counts_to_ns(1)
+    }
+
+    // It stalls while the BSP isn't taking interrupts
+    fn stable(&self) -> bool {
+        false
+    }
+}
+
+static PIT_CLOCKSOURCE: PitClocksource = PitClocksource;
+
+// How far channel 0 has counted since it started, with the PIT locked
+fn counts(pit: &mut Pit, reload: u64) -> u64 {
+    let ticks = TICKS.load(Ordering::SeqCst) - BASE_TICKS.load(Ordering::SeqCst);
+    BASE_COUNTS.load(Ordering::SeqCst) + ticks * reload + (reload - u64::from(pit.read_channel0()))
+}
+
+/// Start channel 0 ticking hz times a second, and make it the clock. Returns false if the PIT
+/// can't tick at that rate.
+pub unsafe fn init(hz: u32) -> bool {
+    if !set_frequency(hz) {
+        return false;
+    }
+
+    register_clocksource(&PIT_CLOCKSOURCE);
+    true
+}
+
+/// Make channel 0 tick hz times a second. The clock carries on from where it was. Returns false if
+/// the PIT can't tick at that rate.
+pub unsafe fn set_frequency(hz: u32) -> bool {
+    let reload = match reload_for(hz) {
+        Some(reload) => reload,
+        None => return false,
+    };
+
+    let mut pit = PIT.lock();
+    let base_counts = match RELOAD.load(Ordering::SeqCst) {
+        0 => 0,
+        old_reload => counts(&mut pit, old_reload),
+    };
+    BASE_COUNTS.store(base_counts, Ordering::SeqCst);
+    BASE_TICKS.store(TICKS.load(Ordering::SeqCst), Ordering::SeqCst);
+
+    pit.command
+        .write(SELECT_CHANNEL0 | ACCESS_LOW_HIGH | MODE_RATE_GENERATOR);
+    Pit::write_count(&mut pit.channel0, reload);
+    RELOAD.store(reload.into(), Ordering::SeqCst);
+    true
+}
+
+/// Count a channel 0 interrupt. The IO APIC routes IRQ 0 to vector 32, whose handler calls this
+/// when the PIT is the clock.
+pub fn handle_interrupt() {
+    TICKS.fetch_add(1, Ordering::SeqCst);
+}
+
+/// The number of channel 0 interrupts so far
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::SeqCst)
+}
+
+/// How many nanoseconds apart channel 0 ticks, or 0 if it isn't ticking
+pub fn tick_ns() -> u64 {
+    counts_to_ns(RELOAD.load(Ordering::SeqCst))
+}
+
+/// Nanoseconds since channel 0 started ticking, or None if it hasn't
+pub fn monotonic_ns() -> Option<u64> {
+    let reload = match RELOAD.load(Ordering::SeqCst) {
+        0 => return None,
+        reload => reload,
+    };
+
+    let count = counts(&mut PIT.lock(), reload);
+
+    // If the counter went round again before its interrupt was counted, this reads as earlier than
+    // the last time, so never hand out less than that
+    let ns = counts_to_ns(count);
+    Some(LAST_NS.fetch_max(ns, Ordering::SeqCst).max(ns))
+}
+
+/// Spin for at least ns nanoseconds, polling channel 2. This works with interrupts disabled.
+pub fn spin_wait_ns(mut ns: u64) {
+    while ns > 0 {
+        let chunk = ns.min(MAX_ONE_SHOT_NS);
+        PIT.lock().one_shot(ns_to_counts(chunk).max(1) as u16);
+        ns -= chunk;
+    }
+}
+
+/// Spin for at least ns nanoseconds, and measure how long that really was on channel 2. sample is
+/// called as the wait starts and as it ends, and what it returns then comes back along with the
+/// measured time. This is polled, so it works with interrupts disabled, and it holds the PIT the
+/// whole time.
+pub fn measure_wait_ns<T>(ns: u64, mut sample: impl FnMut() -> T) -> (T, T, u64) {
+    let mut pit = PIT.lock();
+
+    // Channel 2 goes round and round from 65536, and is read far more often than it takes to go
+    // round once. The count is only loaded on the next PIT clock, so wait until it has been.
+    let gate = pit.start_channel2(0);
+    let unloaded = pit.read_channel2();
+    while pit.read_channel2() == unloaded {
+        crate::interrupts::pause();
+    }
+
+    let target = ns_to_counts(ns);
+    let mut last = pit.read_channel2();
+    let start = sample();
+    let mut counted = 0;
+    while counted < target {
+        let count = pit.read_channel2();
+        counted += u64::from(last.wrapping_sub(count));
+        last = count;
+    }
+    let end = sample();
+
+    pit.gate.write(gate);
+    (start, end, counts_to_ns(counted))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::devices::hpet;
+
+    #[test_case]
+    fn reloads_fit_the_counter() {
+        assert_eq!(reload_for(1000), Some(1193));
+        assert_eq!(reload_for(100), Some(11931));
+        assert_eq!(reload_for(0), None);
+        assert_eq!(reload_for(18), None);
+        assert_eq!(reload_for(2_000_000), None);
+    }
+
+    #[test_case]
+    fn spin_waits_agree_with_the_hpet() {
+        const WAIT_NS: u64 = 20_000_000;
+
+        let start = match hpet::monotonic_ns() {
+            Some(start) => start,
+            None => return,
+        };
+        spin_wait_ns(WAIT_NS);
+        let waited = hpet::monotonic_ns().unwrap() - start;
+
+        assert!(
+            waited >= WAIT_NS * 9 / 10 && waited < WAIT_NS * 2,
+            "Waited {}ns for {}ns",
+            waited,
+            WAIT_NS
+        );
+    }
+}
@@ -0,0 +1,447 @@
+// The keyboard on the 8042 PS/2 controller. The controller translates whatever the keyboard sends
+// into scancode set 1, which the IRQ 1 handler decodes into key events and queues for whoever is
+// reading the keyboard. Events are dropped if nobody reads them fast enough.
+
+use crate::interrupts::irq;
+use crate::io_port::{Io, Port};
+use crate::scheduler::{self, TaskReference};
+use crate::sync::IrqMutex;
+
+const KEYBOARD_IRQ: u8 = 1;
+
+const STATUS_OUTPUT_FULL: u8 = 0x01;
+const STATUS_INPUT_FULL: u8 = 0x02;
+
+const COMMAND_READ_CONFIG: u8 = 0x20;
+const COMMAND_WRITE_CONFIG: u8 = 0x60;
+const COMMAND_DISABLE_SECOND_PORT: u8 = 0xa7;
+const COMMAND_SELF_TEST: u8 = 0xaa;
+const COMMAND_DISABLE_FIRST_PORT: u8 = 0xad;
+const COMMAND_ENABLE_FIRST_PORT: u8 = 0xae;
+
+const SELF_TEST_PASSED: u8 = 0x55;
+
+const CONFIG_FIRST_PORT_INTERRUPT: u8 = 0x01;
+const CONFIG_SECOND_PORT_INTERRUPT: u8 = 0x02;
+const CONFIG_TRANSLATION: u8 = 0x40;
+
+// How many times to poll the controller before giving up on it
+const CONTROLLER_RETRIES: usize = 100_000;
+
+const EXTENDED_PREFIX: u8 = 0xe0;
+const RELEASED: u8 = 0x80;
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Key {
+    Char(char),
+    Escape,
+    Backspace,
+    Tab,
+    Enter,
+    LeftShift,
+    RightShift,
+    LeftCtrl,
+    RightCtrl,
+    LeftAlt,
+    RightAlt,
+    CapsLock,
+    Function(u8),
+    Up,
+    Down,
+    Left,
+    Right,
+    Home,
+    End,
+    PageUp,
+    PageDown,
+    Insert,
+    Delete,
+    /// A key with no meaning here, by its scancode, with 0xe0 in the high byte if it was extended
+    Unknown(u16),
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct KeyEvent {
+    pub key: Key,
+    pub pressed: bool,
+}
+
+// The characters for set 1 scancodes up to the space bar, with zero for keys that aren't characters
+const UNSHIFTED: &[u8; 0x3a] =
+    b"\0\x001234567890-=\0\0qwertyuiop[]\0\0asdfghjkl;'`\0\\zxcvbnm,./\0*\0 ";
+const SHIFTED: &[u8; 0x3a] =
+    b"\0\0!@#$%^&*()_+\0\0QWERTYUIOP{}\0\0ASDFGHJKL:\"~\0|ZXCVBNM<>?\0*\0 ";
+
+/// Turns set 1 scancodes into key events, keeping track of the modifiers as it goes
+pub struct Decoder {
+    extended: bool,
+    left_shift: bool,
+    right_shift: bool,
+    caps_lock: bool,
+}
+
+impl Decoder {
+    pub const fn new() -> Self {
+        Self {
+            extended: false,
+            left_shift: false,
+            right_shift: false,
+            caps_lock: false,
+        }
+    }
+
+    /// Decode the next scancode. Returns None for prefixes and for the fake shifts some keys send.
+    pub fn feed(&mut self, scancode: u8) -> Option<KeyEvent> {
+        if scancode == EXTENDED_PREFIX {
+            self.extended = true;
+            return None;
+        }
+
+        let extended = core::mem::replace(&mut self.extended, false);
+        let pressed = scancode & RELEASED == 0;
+        let code = scancode & !RELEASED;
+
+        let key = if extended {
+            match code {
+                // Print screen and friends pretend to press shift as well
+                0x2a | 0x36 => return None,
+                0x1c => Key::Enter,
+                0x1d => Key::RightCtrl,
+                0x35 => Key::Char('/'),
+                0x38 => Key::RightAlt,
+                0x47 => Key::Home,
+                0x48 => Key::Up,
+                0x49 => Key::PageUp,
+                0x4b => Key::Left,
+                0x4d => Key::Right,
+                0x4f => Key::End,
+                0x50 => Key::Down,
+                0x51 => Key::PageDown,
+                0x52 => Key::Insert,
+                0x53 => Key::Delete,
+                _ => Key::Unknown(u16::from(EXTENDED_PREFIX) << 8 | u16::from(code)),
+            }
+        } else {
+            match code {
+                0x01 => Key::Escape,
+                0x0e => Key::Backspace,
+                0x0f => Key::Tab,
+                0x1c => Key::Enter,
+                0x1d => Key::LeftCtrl,
+                0x2a => Key::LeftShift,
+                0x36 => Key::RightShift,
+                0x38 => Key::LeftAlt,
+                0x3a => Key::CapsLock,
+                0x3b..=0x44 => Key::Function(code - 0x3a),
+                0x57 => Key::Function(11),
+                0x58 => Key::Function(12),
+                _ => self.char_key(code),
+            }
+        };
+
+        match key {
+            Key::LeftShift => self.left_shift = pressed,
+            Key::RightShift => self.right_shift = pressed,
+            Key::CapsLock if pressed => self.caps_lock = !self.caps_lock,
+            _ => (),
+        }
+
+        Some(KeyEvent { key, pressed })
+    }
+
+    fn char_key(&self, code: u8) -> Key {
+        let index = usize::from(code);
+        let (unshifted, shifted) = match (UNSHIFTED.get(index), SHIFTED.get(index)) {
+            (Some(&unshifted), Some(&shifted)) if unshifted != 0 => (unshifted, shifted),
+            _ => return Key::Unknown(code.into()),
+        };
+
+        // Caps lock only applies to letters, and shift undoes it
+        let mut shift = self.left_shift || self.right_shift;
+        if unshifted.is_ascii_alphabetic() {
+            shift ^= self.caps_lock;
+        }
+
+        let character = if shift { shifted } else { unshifted };
+        Key::Char(character.into())
+    }
+}
+
+const QUEUE_SIZE: usize = 64;
+
+struct EventQueue {
+    events: [Option<KeyEvent>; QUEUE_SIZE],
+    head: usize,
+    len: usize,
+}
+
+impl EventQueue {
+    const fn new() -> Self {
+        Self {
+            events: [None; QUEUE_SIZE],
+            head: 0,
+            len: 0,
+        }
+    }
+
+    // Returns false if the queue is full, in which case the event is dropped
+    fn push(&mut self, event: KeyEvent) -> bool {
+        if self.len == QUEUE_SIZE {
+            return false;
+        }
+
+        self.events[(self.head + self.len) % QUEUE_SIZE] = Some(event);
+        self.len += 1;
+        true
+    }
+
+    fn pop(&mut self) -> Option<KeyEvent> {
+        if self.len == 0 {
+            return None;
+        }
+
+        let event = self.events[self.head].take();
+        self.head = (self.head + 1) % QUEUE_SIZE;
+        self.len -= 1;
+        event
+    }
+}
+
+struct Keyboard {
+    decoder: Decoder,
+    events: EventQueue,
+    // A task blocked in wait_event, to be woken when there is something to read
+    waiter: Option<TaskReference>,
+}
+
+static KEYBOARD: IrqMutex<Keyboard> = IrqMutex::new(Keyboard {
+    decoder: Decoder::new(),
+    events: EventQueue::new(),
+    waiter: None,
+});
+
+/// Take the oldest key event that hasn't been read yet
+pub fn read_event() -> Option<KeyEvent> {
+    KEYBOARD.lock().events.pop()
+}
+
+/// Take the oldest key event, blocking until there is one
+pub fn wait_event() -> KeyEvent {
+    loop {
+        if let Some(event) = read_event() {
+            return event;
+        }
+
+        let task = scheduler::current_task();
+        scheduler::block_current(move || {
+            // An event could have come in since the queue was looked at, in which case nothing is
+            // going to wake the task, so it wakes itself
+            let mut keyboard = KEYBOARD.lock();
+            if keyboard.events.len > 0 {
+                task.wake();
+            } else {
+                keyboard.waiter = Some(task);
+            }
+        });
+    }
+}
+
+fn keyboard_interrupt() {
+    let scancode = Port::<u8, 0x60>::new().read();
+
+    let mut keyboard = KEYBOARD.lock();
+    if let Some(event) = keyboard.decoder.feed(scancode) {
+        if !keyboard.events.push(event) {
+            crate::warn!("Keyboard queue is full, dropping {:?}", event);
+        }
+
+        if let Some(waiter) = keyboard.waiter.take() {
+            waiter.wake();
+        }
+    }
+}
+
+struct Controller {
+    data: Port<u8, 0x60>,
+    status_command: Port<u8, 0x64>,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum ControllerError {
+    /// The controller stopped responding
+    Timeout,
+    /// The controller's self test returned this instead of passing
+    SelfTestFailed(u8),
+}
+
+impl Controller {
+    fn wait_for(&self, mask: u8, set: bool) -> Result<(), ControllerError> {
+        for _ in 0..CONTROLLER_RETRIES {
+            if (self.status_command.read() & mask != 0) == set {
+                return Ok(());
+            }
+            crate::interrupts::pause();
+        }
+        Err(ControllerError::Timeout)
+    }
+
+    fn command(&mut self, command: u8) -> Result<(), ControllerError> {
+        self.wait_for(STATUS_INPUT_FULL, false)?;
+        self.status_command.write(command);
+        Ok(())
+    }
+
+    fn write_data(&mut self, value: u8) -> Result<(), ControllerError> {
+        self.wait_for(STATUS_INPUT_FULL, false)?;
+        self.data.write(value);
+        Ok(())
+    }
+
+    fn read_data(&self) -> Result<u8, ControllerError> {
+        self.wait_for(STATUS_OUTPUT_FULL, true)?;
+        Ok(self.data.read())
+    }
+
+    fn flush(&self) {
+        while self.status_command.read() & STATUS_OUTPUT_FULL != 0 {
+            self.data.read();
+        }
+    }
+
+    // Set the controller up to translate to set 1 and interrupt for the keyboard only
+    fn init(&mut self) -> Result<(), ControllerError> {
+        self.command(COMMAND_DISABLE_FIRST_PORT)?;
+        self.command(COMMAND_DISABLE_SECOND_PORT)?;
+        self.flush();
+
+        self.command(COMMAND_READ_CONFIG)?;
+        let config = (self.read_data()? | CONFIG_TRANSLATION)
+            & !(CONFIG_FIRST_PORT_INTERRUPT | CONFIG_SECOND_PORT_INTERRUPT);
+        self.command(COMMAND_WRITE_CONFIG)?;
+        self.write_data(config)?;
+
+        // Some controllers reset themselves in the self test, so the configuration goes back in after
+        self.command(COMMAND_SELF_TEST)?;
+        match self.read_data()? {
+            SELF_TEST_PASSED => (),
+            result => return Err(ControllerError::SelfTestFailed(result)),
+        }
+
+        self.command(COMMAND_WRITE_CONFIG)?;
+        self.write_data(config | CONFIG_FIRST_PORT_INTERRUPT)?;
+        self.command(COMMAND_ENABLE_FIRST_PORT)
+    }
+}
+
+/// Set up the PS/2 controller and start taking keyboard interrupts. A machine without a working
+/// controller just has no keyboard.
+pub unsafe fn init() {
+    let mut controller = Controller {
+        data: Port::new(),
+        status_command: Port::new(),
+    };
+
+    match controller.init() {
+        Ok(()) => {
+            irq::register_legacy_handler(KEYBOARD_IRQ, keyboard_interrupt);
+            crate::info!("PS/2 keyboard enabled on IRQ {}", KEYBOARD_IRQ);
+        }
+        Err(e) => crate::warn!("No PS/2 keyboard: {:?}", e),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloc::vec::Vec;
+
+    fn decode(scancodes: &[u8]) -> Vec<KeyEvent> {
+        let mut decoder = Decoder::new();
+        scancodes
+            .iter()
+            .filter_map(|scancode| decoder.feed(*scancode))
+            .collect()
+    }
+
+    fn press(key: Key) -> KeyEvent {
+        KeyEvent { key, pressed: true }
+    }
+
+    fn release(key: Key) -> KeyEvent {
+        KeyEvent {
+            key,
+            pressed: false,
+        }
+    }
+
+    #[test_case]
+    fn scancodes_decode_to_keys() {
+        assert_eq!(
+            decode(&[0x1e, 0x9e, 0x02, 0x39, 0x1c, 0x3b]),
+            [
+                press(Key::Char('a')),
+                release(Key::Char('a')),
+                press(Key::Char('1')),
+                press(Key::Char(' ')),
+                press(Key::Enter),
+                press(Key::Function(1)),
+            ]
+        );
+    }
+
+    #[test_case]
+    fn shift_and_caps_lock_change_characters() {
+        // Shift, a, 1, release shift, a
+        assert_eq!(
+            decode(&[0x2a, 0x1e, 0x02, 0xaa, 0x1e]),
+            [
+                press(Key::LeftShift),
+                press(Key::Char('A')),
+                press(Key::Char('!')),
+                release(Key::LeftShift),
+                press(Key::Char('a')),
+            ]
+        );
+
+        // Caps lock, a, 1, then right shift and a
+        assert_eq!(
+            decode(&[0x3a, 0xba, 0x1e, 0x02, 0x36, 0x1e]),
+            [
+                press(Key::CapsLock),
+                release(Key::CapsLock),
+                press(Key::Char('A')),
+                press(Key::Char('1')),
+                press(Key::RightShift),
+                press(Key::Char('a')),
+            ]
+        );
+    }
+
+    #[test_case]
+    fn extended_scancodes_decode_to_their_own_keys() {
+        // Up, released, then right ctrl, and the fake shift print screen sends
+        assert_eq!(
+            decode(&[0xe0, 0x48, 0xe0, 0xc8, 0xe0, 0x1d, 0xe0, 0x2a, 0xe0, 0x6f]),
+            [
+                press(Key::Up),
+                release(Key::Up),
+                press(Key::RightCtrl),
+                press(Key::Unknown(0xe06f)),
+            ]
+        );
+    }
+
+    #[test_case]
+    fn full_queue_drops_new_events() {
+        let mut queue = EventQueue::new();
+        for index in 0..QUEUE_SIZE {
+            assert!(queue.push(press(Key::Function(index as u8))));
+        }
+        assert!(!queue.push(press(Key::Escape)));
+
+        for index in 0..QUEUE_SIZE {
+            assert_eq!(queue.pop(), Some(press(Key::Function(index as u8))));
+        }
+        assert_eq!(queue.pop(), None);
+    }
+}
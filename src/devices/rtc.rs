@@ -0,0 +1,273 @@
+// The CMOS real time clock, for the date and time. It only counts whole seconds, so it is read
+// once at boot, and the wall clock is that plus however long the monotonic clock says it has been
+// since.
+
+use crate::io_port::{Io, Port};
+use crate::sync::IrqMutex;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+const REGISTER_SECONDS: u8 = 0x00;
+const REGISTER_MINUTES: u8 = 0x02;
+const REGISTER_HOURS: u8 = 0x04;
+const REGISTER_DAY: u8 = 0x07;
+const REGISTER_MONTH: u8 = 0x08;
+const REGISTER_YEAR: u8 = 0x09;
+const REGISTER_A: u8 = 0x0a;
+pub const REGISTER_B: u8 = 0x0b;
+pub const REGISTER_C: u8 = 0x0c;
+
+const UPDATE_IN_PROGRESS: u8 = 0x80;
+const HOURS_24: u8 = 0x02;
+const BINARY: u8 = 0x04;
+const HOUR_PM: u8 = 0x80;
+
+// Selecting a register with this bit set also keeps NMIs off while it is being accessed. The bit
+// stays set until the index is written again, so each access selects the register again without it
+// afterwards.
+const NMI_DISABLE: u8 = 0x80;
+
+const NS_PER_SECOND: u64 = 1_000_000_000;
+
+struct Cmos {
+    index: Port<u8, 0x70>,
+    data: Port<u8, 0x71>,
+}
+
+static CMOS: IrqMutex<Cmos> = IrqMutex::new(Cmos {
+    index: Port::new(),
+    data: Port::new(),
+});
+
+/// Read a CMOS register. The index and data ports are used as a pair, so go through here rather
+/// than the ports.
+pub fn read_register(register: u8) -> u8 {
+    let mut cmos = CMOS.lock();
+    cmos.index.write(NMI_DISABLE | register);
+    let value = cmos.data.read();
+    cmos.index.write(register);
+    value
+}
+
+pub fn write_register(register: u8, value: u8) {
+    let mut cmos = CMOS.lock();
+    cmos.index.write(NMI_DISABLE | register);
+    cmos.data.write(value);
+    cmos.index.write(register);
+}
+
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl DateTime {
+    /// Seconds since 1970-01-01 00:00:00
+    pub fn unix_seconds(&self) -> u64 {
+        // Count years from March, so the leap day is the last day of the year
+        let (year, month) = if self.month > 2 {
+            (u64::from(self.year), u64::from(self.month) - 3)
+        } else {
+            (u64::from(self.year) - 1, u64::from(self.month) + 9)
+        };
+        let day_of_year = (153 * month + 2) / 5 + u64::from(self.day) - 1;
+        let days = year * 365 + year / 4 - year / 100 + year / 400 + day_of_year;
+
+        // 1970-01-01 is day 719468 counting the same way
+        let days = days - 719_468;
+        ((days * 24 + u64::from(self.hour)) * 60 + u64::from(self.minute)) * 60
+            + u64::from(self.second)
+    }
+}
+
+// The time registers as the RTC has them, in whatever format register B says
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+struct RawTime {
+    second: u8,
+    minute: u8,
+    hour: u8,
+    day: u8,
+    month: u8,
+    year: u8,
+}
+
+impl RawTime {
+    fn read() -> Self {
+        Self {
+            second: read_register(REGISTER_SECONDS),
+            minute: read_register(REGISTER_MINUTES),
+            hour: read_register(REGISTER_HOURS),
+            day: read_register(REGISTER_DAY),
+            month: read_register(REGISTER_MONTH),
+            year: read_register(REGISTER_YEAR),
+        }
+    }
+
+    fn decode(&self, register_b: u8) -> DateTime {
+        let number = |value: u8| {
+            if register_b & BINARY != 0 {
+                value
+            } else {
+                (value >> 4) * 10 + (value & 0x0f)
+            }
+        };
+
+        // In 12 hour mode the top bit marks the afternoon, and midnight and noon are both 12
+        let hour = if register_b & HOURS_24 != 0 {
+            number(self.hour)
+        } else {
+            let hour = number(self.hour & !HOUR_PM) % 12;
+            if self.hour & HOUR_PM != 0 {
+                hour + 12
+            } else {
+                hour
+            }
+        };
+
+        // There's no standard place for the century, but this kernel won't see the last one
+        DateTime {
+            year: 2000 + u16::from(number(self.year)),
+            month: number(self.month),
+            day: number(self.day),
+            hour,
+            minute: number(self.minute),
+            second: number(self.second),
+        }
+    }
+}
+
+/// Read the date and time from the RTC
+pub fn read() -> DateTime {
+    // The registers are garbage while the RTC is updating them, and it can start an update while
+    // they are being read, so keep going until two reads in a row agree
+    let read_settled = || {
+        while read_register(REGISTER_A) & UPDATE_IN_PROGRESS != 0 {
+            crate::interrupts::pause();
+        }
+        RawTime::read()
+    };
+
+    let mut raw = read_settled();
+    loop {
+        let again = read_settled();
+        if again == raw {
+            break;
+        }
+        raw = again;
+    }
+
+    raw.decode(read_register(REGISTER_B))
+}
+
+// The wall clock time when the monotonic clock read zero, or 0 before the RTC has been read
+static BOOT_WALL_CLOCK_NS: AtomicU64 = AtomicU64::new(0);
+
+/// Read the RTC and start the wall clock from it. Needs the monotonic clock to be running.
+pub fn init() {
+    let date_time = read();
+    let now_ns = super::now_ns().expect("Wall clock needs a monotonic clock");
+    BOOT_WALL_CLOCK_NS.store(
+        date_time.unix_seconds() * NS_PER_SECOND - now_ns,
+        Ordering::SeqCst,
+    );
+    crate::info!("RTC time is {:?}", date_time);
+}
+
+/// Nanoseconds since 1970-01-01 00:00:00, or None if the wall clock isn't running yet
+pub fn wall_clock_ns() -> Option<u64> {
+    match BOOT_WALL_CLOCK_NS.load(Ordering::SeqCst) {
+        0 => None,
+        boot_ns => Some(boot_ns + super::now_ns()?),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn date_time(year: u16, month: u8, day: u8, hour: u8, minute: u8, second: u8) -> DateTime {
+        DateTime {
+            year,
+            month,
+            day,
+            hour,
+            minute,
+            second,
+        }
+    }
+
+    #[test_case]
+    fn unix_seconds_count_from_1970() {
+        assert_eq!(date_time(1970, 1, 1, 0, 0, 0).unix_seconds(), 0);
+        assert_eq!(date_time(2000, 3, 1, 0, 0, 0).unix_seconds(), 951_868_800);
+        assert_eq!(
+            date_time(2020, 2, 29, 12, 34, 56).unix_seconds(),
+            1_582_979_696
+        );
+        assert_eq!(date_time(2038, 1, 19, 3, 14, 8).unix_seconds(), 1 << 31);
+    }
+
+    #[test_case]
+    fn raw_registers_are_decoded() {
+        let raw = RawTime {
+            second: 0x59,
+            minute: 0x30,
+            hour: 0x12 | HOUR_PM,
+            day: 0x31,
+            month: 0x12,
+            year: 0x20,
+        };
+        assert_eq!(raw.decode(0), date_time(2020, 12, 31, 12, 30, 59));
+        assert_eq!(
+            RawTime { hour: 0x12, ..raw }.decode(0),
+            date_time(2020, 12, 31, 0, 30, 59)
+        );
+        assert_eq!(
+            RawTime { hour: 0x23, ..raw }.decode(HOURS_24),
+            date_time(2020, 12, 31, 23, 30, 59)
+        );
+
+        let binary = RawTime {
+            second: 59,
+            minute: 30,
+            hour: 7 | HOUR_PM,
+            day: 31,
+            month: 12,
+            year: 20,
+        };
+        assert_eq!(binary.decode(BINARY), date_time(2020, 12, 31, 19, 30, 59));
+    }
+
+    #[test_case]
+    fn rtc_time_is_sane() {
+        let first = read();
+        let second = read();
+
+        for date_time in &[first, second] {
+            assert!(date_time.year >= 2020 && date_time.year < 2100);
+            assert!(date_time.month >= 1 && date_time.month <= 12);
+            assert!(date_time.day >= 1 && date_time.day <= 31);
+            assert!(date_time.hour < 24);
+            assert!(date_time.minute < 60);
+            assert!(date_time.second < 60);
+        }
+        assert!(first <= second, "RTC went from {:?} to {:?}", first, second);
+    }
+
+    #[test_case]
+    fn wall_clock_advances_between_rtc_reads() {
+        let start = wall_clock_ns().expect("Wall clock is not running");
+        crate::scheduler::sleep(core::time::Duration::from_millis(2));
+        let end = wall_clock_ns().unwrap();
+        assert!(end >= start + 2_000_000);
+
+        // It stays close to the RTC, which only has whole seconds
+        let rtc_seconds = read().unix_seconds();
+        let wall_seconds = end / NS_PER_SECOND;
+        assert!(rtc_seconds + 2 >= wall_seconds && wall_seconds + 2 >= rtc_seconds);
+    }
+}
@@ -0,0 +1,184 @@
+// Clocksources. Everything that can tell the time registers here, and the kernel reads the time
+// from the best of them: stable sources beat unstable ones, and then the finest resolution wins.
+// They all count from the same moment, so they agree with each other. A source can have nothing to
+// say on some CPUs, like the TSC on an AP that hasn't lined itself up yet, and the next best one
+// answers instead.
+
+use crate::sync::IrqMutex;
+
+pub trait Clocksource: Sync {
+    fn name(&self) -> &'static str;
+
+    /// Nanoseconds on the kernel's clock, or None if this source can't read it right now
+    fn now_ns(&self) -> Option<u64>;
+
+    /// The smallest step the clock moves in
+    fn resolution_ns(&self) -> u64;
+
+    /// Whether the clock keeps time whatever the CPU is doing
+    fn stable(&self) -> bool;
+}
+
+const MAX_CLOCKSOURCES: usize = 4;
+
+pub struct Clocksources {
+    // Best first, with the unused slots at the end
+    sources: [Option<&'static dyn Clocksource>; MAX_CLOCKSOURCES],
+}
+
+fn better(a: &dyn Clocksource, b: &dyn Clocksource) -> bool {
+    match (a.stable(), b.stable()) {
+        (true, false) => true,
+        (false, true) => false,
+        _ => a.resolution_ns() < b.resolution_ns(),
+    }
+}
+
+impl Clocksources {
+    pub const fn new() -> Self {
+        Self {
+            sources: [None; MAX_CLOCKSOURCES],
+        }
+    }
+
+    pub fn register(&mut self, source: &'static dyn Clocksource) {
+        assert!(
+            self.sources[MAX_CLOCKSOURCES - 1].is_none(),
+            "Too many clocksources"
+        );
+        let index = self
+            .sources
+            .iter()
+            .position(|slot| slot.map_or(true, |other| better(source, other)))
+            .unwrap();
+
+        self.sources[index..].rotate_right(1);
+        self.sources[index] = Some(source);
+    }
+
+    pub fn selected(&self) -> Option<&'static dyn Clocksource> {
+        self.sources[0]
+    }
+
+    pub fn now_ns(&self) -> Option<u64> {
+        self.sources
+            .iter()
+            .flatten()
+            .find_map(|source| source.now_ns())
+    }
+}
+
+static CLOCKSOURCES: IrqMutex<Clocksources> = IrqMutex::new(Clocksources::new());
+
+pub fn register_clocksource(source: &'static dyn Clocksource) {
+    let mut clocksources = CLOCKSOURCES.lock();
+    clocksources.register(source);
+    crate::info!(
+        "Registered the {} clocksource, using {}",
+        source.name(),
+        clocksources.selected().unwrap().name()
+    );
+}
+
+/// The source the time is read from, when it can be
+pub fn selected_clocksource() -> Option<&'static dyn Clocksource> {
+    CLOCKSOURCES.lock().selected()
+}
+
+/// Nanoseconds since boot from the best clocksource, or None if there isn't one yet
+pub fn now_ns() -> Option<u64> {
+    // The sources take locks of their own, so don't hold this one while reading them
+    let clocksources = Clocksources {
+        sources: CLOCKSOURCES.lock().sources,
+    };
+    clocksources.now_ns()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    struct FakeClocksource {
+        resolution_ns: u64,
+        stable: bool,
+        now_ns: Option<u64>,
+    }
+
+    impl Clocksource for FakeClocksource {
+        fn name(&self) -> &'static str {
+            "fake"
+        }
+
+        fn now_ns(&self) -> Option<u64> {
+            self.now_ns
+        }
+
+        fn resolution_ns(&self) -> u64 {
+            self.resolution_ns
+        }
+
+        fn stable(&self) -> bool {
+            self.stable
+        }
+    }
+
+    static COARSE: FakeClocksource = FakeClocksource {
+        resolution_ns: 1000,
+        stable: true,
+        now_ns: Some(1),
+    };
+    static FINE: FakeClocksource = FakeClocksource {
+        resolution_ns: 10,
+        stable: true,
+        now_ns: Some(2),
+    };
+    static UNSTABLE: FakeClocksource = FakeClocksource {
+        resolution_ns: 1,
+        stable: false,
+        now_ns: Some(3),
+    };
+    static SILENT: FakeClocksource = FakeClocksource {
+        resolution_ns: 1,
+        stable: true,
+        now_ns: None,
+    };
+
+    fn is(source: Option<&'static dyn Clocksource>, fake: &'static FakeClocksource) -> bool {
+        source.map_or(false, |source| {
+            source as *const dyn Clocksource as *const u8
+                == fake as *const FakeClocksource as *const u8
+        })
+    }
+
+    #[test_case]
+    fn finest_stable_clocksource_is_used() {
+        let mut clocksources = Clocksources::new();
+        assert_eq!(clocksources.now_ns(), None);
+
+        clocksources.register(&COARSE);
+        assert_eq!(clocksources.now_ns(), Some(1));
+
+        clocksources.register(&UNSTABLE);
+        clocksources.register(&FINE);
+        assert!(is(clocksources.selected(), &FINE));
+        assert_eq!(clocksources.now_ns(), Some(2));
+
+        // The best source has nothing to say, so the next one answers
+        clocksources.register(&SILENT);
+        assert!(is(clocksources.selected(), &SILENT));
+        assert_eq!(clocksources.now_ns(), Some(2));
+    }
+
+    #[test_case]
+    fn kernel_time_comes_from_the_selected_clocksource() {
+        let selected = selected_clocksource().expect("No clocksource registered");
+        let before = selected.now_ns().unwrap();
+        let now = super::super::now_ns().unwrap();
+        let after = selected.now_ns().unwrap();
+
+        assert!(before <= now && now <= after);
+        if crate::devices::tsc::invariant_tsc() {
+            assert_eq!(selected.name(), "tsc");
+        }
+    }
+}
@@ -0,0 +1,422 @@
+// Software timers on top of the monotonic clock. Timers go into the slot of a wheel for the tick
+// they expire in, and each periodic timer interrupt only looks at the slots for the ticks
+// that have passed since the last one. A timer more than a turn of the wheel away stays in its
+// slot until the wheel comes round to it at the right time. Sleeping tasks get a slot list of their
+// own, which is chained through the tasks, so that sleeping never allocates.
+//
+// The slots are intrusive lists, so the interrupt can take the expired timers out without
+// allocating. The timers that have fired are kept on a list of their own until a task adds or
+// cancels a timer, so that the interrupt doesn't free them either.
+
+use super::now_ns;
+use crate::interrupts;
+use crate::scheduler::{Task, TaskReference};
+use crate::sync::IrqMutex;
+use alloc::boxed::Box;
+use intrusive_collections::intrusive_adapter;
+use intrusive_collections::{LinkedList, LinkedListLink};
+
+/// How much time each slot of the wheel covers
+pub const SLOT_NS: u64 = 1_000_000;
+const WHEEL_SLOTS: usize = 256;
+
+// Called through a mutable reference, so that firing a timer doesn't free it. Only the first call
+// does anything.
+type TimerCallback = Box<dyn FnMut() + Send>;
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct TimerId(u64);
+
+struct Timer {
+    link: LinkedListLink,
+    id: TimerId,
+    deadline_ns: u64,
+    callback: TimerCallback,
+}
+
+intrusive_adapter!(TimerAdapter = Box<Timer>: Timer { link: LinkedListLink });
+
+/// Whether the clock has reached deadline_ns. The clock is allowed to wrap, so this holds for
+/// deadlines up to half its range behind it.
+pub fn deadline_passed(now_ns: u64, deadline_ns: u64) -> bool {
+    now_ns.wrapping_sub(deadline_ns) as i64 >= 0
+}
+
+fn slot_tick(ns: u64) -> u64 {
+    ns / SLOT_NS
+}
+
+fn slot_index(tick: u64) -> usize {
+    tick as usize % WHEEL_SLOTS
+}
+
+/// The timer that wakes a task from sleep. Each task has one, which is only touched with the wheel
+/// locked.
+#[derive(Debug)]
+pub struct SleepTimer {
+    deadline_ns: Option<u64>,
+    // The next sleeper in the same slot of the wheel
+    next: Option<TaskReference>,
+}
+
+impl SleepTimer {
+    pub const fn new() -> Self {
+        Self {
+            deadline_ns: None,
+            next: None,
+        }
+    }
+
+    pub fn is_armed(&self) -> bool {
+        self.deadline_ns.is_some()
+    }
+}
+
+struct TimerWheel {
+    slots: [LinkedList<TimerAdapter>; WHEEL_SLOTS],
+    sleepers: [Option<TaskReference>; WHEEL_SLOTS],
+    // Timers that have fired, waiting to be freed outside the interrupt
+    fired: LinkedList<TimerAdapter>,
+    // The tick of the last expire, whose slot has already been looked at
+    last_tick: u64,
+    next_id: u64,
+}
+
+const EMPTY_SLOT: LinkedList<TimerAdapter> = LinkedList::new(TimerAdapter::NEW);
+const NO_SLEEPERS: Option<TaskReference> = None;
+
+impl TimerWheel {
+    const fn new() -> Self {
+        Self {
+            slots: [EMPTY_SLOT; WHEEL_SLOTS],
+            sleepers: [NO_SLEEPERS; WHEEL_SLOTS],
+            fired: LinkedList::new(TimerAdapter::NEW),
+            last_tick: 0,
+            next_id: 0,
+        }
+    }
+
+    fn add(&mut self, deadline_ns: u64, callback: TimerCallback) -> TimerId {
+        let id = TimerId(self.next_id);
+        self.next_id += 1;
+
+        let slot = slot_index(slot_tick(deadline_ns));
+        self.slots[slot].push_back(box Timer {
+            link: LinkedListLink::new(),
+            id,
+            deadline_ns,
+            callback,
+        });
+        id
+    }
+
+    fn cancel(&mut self, id: TimerId) -> Option<Box<Timer>> {
+        for slot in self.slots.iter_mut() {
+            let mut cursor = slot.front_mut();
+            while let Some(timer) = cursor.get() {
+                if timer.id == id {
+                    return cursor.remove();
+                }
+                cursor.move_next();
+            }
+        }
+
+        None
+    }
+
+    fn add_sleeper(&mut self, task: TaskReference, deadline_ns: u64) {
+        let slot = slot_index(slot_tick(deadline_ns));
+        {
+            let mut timer = task.sleep_timer().lock();
+            assert!(!timer.is_armed(), "Task is already sleeping");
+            timer.deadline_ns = Some(deadline_ns);
+            timer.next = self.sleepers[slot].take();
+        }
+        self.sleepers[slot] = Some(task);
+    }
+
+    fn cancel_sleeper(&mut self, task: &Task) -> bool {
+        let deadline_ns = match task.sleep_timer().lock().deadline_ns {
+            Some(deadline_ns) => deadline_ns,
+            None => return false,
+        };
+
+        self.retain_sleepers(slot_index(slot_tick(deadline_ns)), |sleeper, _| {
+            sleeper.pid() != task.pid()
+        });
+        true
+    }
+
+    // Go through the sleepers in a slot, and disarm and unlink the ones that keep returns false for
+    fn retain_sleepers(&mut self, slot: usize, mut keep: impl FnMut(&TaskReference, u64) -> bool) {
+        let mut remaining = self.sleepers[slot].take();
+        while let Some(task) = remaining {
+            let mut timer = task.sleep_timer().lock();
+            remaining = timer.next.take();
+
+            let deadline_ns = timer.deadline_ns.expect("Sleeper has no deadline");
+            if keep(&task, deadline_ns) {
+                timer.next = self.sleepers[slot].take();
+                core::mem::drop(timer);
+                self.sleepers[slot] = Some(task);
+            } else {
+                timer.deadline_ns = None;
+            }
+        }
+    }
+
+    // Take out every timer that has expired by now_ns, and wake the sleepers whose deadlines have
+    // passed
+    fn expire(&mut self, now_ns: u64) -> LinkedList<TimerAdapter> {
+        let now_tick = slot_tick(now_ns);
+
+        // The slot of the last tick is looked at again, because a timer can be added to it after it
+        // was. If the clock went backwards because it wrapped, or a whole turn went by, every slot
+        // might have something in it.
+        let ticks = match now_tick.checked_sub(self.last_tick) {
+            Some(ticks) if ticks < WHEEL_SLOTS as u64 => ticks + 1,
+            _ => WHEEL_SLOTS as u64,
+        };
+        self.last_tick = now_tick;
+
+        let mut expired = LinkedList::new(TimerAdapter::NEW);
+        for tick in 0..ticks {
+            let slot_number = slot_index(now_tick.wrapping_sub(tick));
+            self.retain_sleepers(slot_number, |task, deadline_ns| {
+                if deadline_passed(now_ns, deadline_ns) {
+                    task.wake();
+                    false
+                } else {
+                    true
+                }
+            });
+
+            let mut cursor = self.slots[slot_number].front_mut();
+            while let Some(timer) = cursor.get() {
+                if deadline_passed(now_ns, timer.deadline_ns) {
+                    expired.push_back(cursor.remove().unwrap());
+                } else {
+                    cursor.move_next();
+                }
+            }
+        }
+
+        expired
+    }
+
+    // Take the timers that have fired, for the caller to free
+    fn take_fired(&mut self) -> LinkedList<TimerAdapter> {
+        core::mem::replace(&mut self.fired, LinkedList::new(TimerAdapter::NEW))
+    }
+}
+
+static TIMER_WHEEL: IrqMutex<TimerWheel> = IrqMutex::new(TimerWheel::new());
+
+/// Call callback from the timer interrupt once the monotonic clock reaches deadline_ns. It runs
+/// with interrupts disabled, so it should do no more than wake something up.
+pub fn add_timer(deadline_ns: u64, callback: impl FnOnce() + Send + 'static) -> TimerId {
+    free_fired_timers();
+
+    let mut callback = Some(callback);
+    TIMER_WHEEL.lock().add(deadline_ns, box move || {
+        if let Some(callback) = callback.take() {
+            callback();
+        }
+    })
+}
+
+/// Stop a timer from firing. Returns false if it has already fired or been cancelled.
+pub fn cancel_timer(id: TimerId) -> bool {
+    free_fired_timers();
+
+    let cancelled = TIMER_WHEEL.lock().cancel(id);
+    cancelled.is_some()
+}
+
+// Free the timers that have fired, unless this is the interrupt, or a callback that it runs
+fn free_fired_timers() {
+    if interrupts::are_enabled() {
+        let fired = TIMER_WHEEL.lock().take_fired();
+        core::mem::drop(fired);
+    }
+}
+
+/// Wake task from the timer interrupt once the monotonic clock reaches deadline_ns. The timer is
+/// kept in the task, so this never allocates, and the task can only sleep on one at a time.
+pub fn add_sleep_timer(task: TaskReference, deadline_ns: u64) {
+    TIMER_WHEEL.lock().add_sleeper(task, deadline_ns);
+}
+
+/// Stop a task's sleep timer from waking it. Returns false if it wasn't armed.
+pub fn cancel_sleep_timer(task: &Task) -> bool {
+    TIMER_WHEEL.lock().cancel_sleeper(task)
+}
+
+/// Fire the timers that have expired. The periodic timer interrupt calls this on every tick.
+pub fn tick() {
+    let now_ns = match now_ns() {
+        Some(now_ns) => now_ns,
+        None => return,
+    };
+
+    // The callbacks are free to add timers of their own
+    let mut expired = TIMER_WHEEL.lock().expire(now_ns);
+    while let Some(mut timer) = expired.pop_front() {
+        (timer.callback)();
+        TIMER_WHEEL.lock().fired.push_back(timer);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::scheduler::{current_task, exit, sleep, spawn};
+    use alloc::sync::Arc;
+    use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use core::time::Duration;
+
+    const SLEEP_MS: u64 = 20;
+
+    fn counting_timer(wheel: &mut TimerWheel, deadline_ns: u64, count: &Arc<AtomicUsize>) {
+        let count = count.clone();
+        wheel.add(deadline_ns, box move || {
+            count.fetch_add(1, Ordering::SeqCst);
+        });
+    }
+
+    fn run(mut expired: LinkedList<TimerAdapter>) -> usize {
+        let mut fired = 0;
+        while let Some(mut timer) = expired.pop_front() {
+            (timer.callback)();
+            fired += 1;
+        }
+        fired
+    }
+
+    #[test_case]
+    fn deadlines_survive_the_clock_wrapping() {
+        assert!(deadline_passed(10, 10));
+        assert!(deadline_passed(11, 10));
+        assert!(!deadline_passed(9, 10));
+        assert!(deadline_passed(5, u64::MAX - 5));
+        assert!(!deadline_passed(u64::MAX - 5, 5));
+    }
+
+    #[test_case]
+    fn timers_fire_once_their_deadline_passes() {
+        let mut wheel = TimerWheel::new();
+        let count = Arc::new(AtomicUsize::new(0));
+
+        counting_timer(&mut wheel, 3 * SLOT_NS, &count);
+        counting_timer(&mut wheel, 3 * SLOT_NS + 1, &count);
+        // Lands in the same slot as the first two, but a turn of the wheel later
+        counting_timer(&mut wheel, (3 + WHEEL_SLOTS as u64) * SLOT_NS, &count);
+
+        assert_eq!(run(wheel.expire(2 * SLOT_NS)), 0);
+        assert_eq!(run(wheel.expire(3 * SLOT_NS)), 1);
+        assert_eq!(run(wheel.expire(4 * SLOT_NS)), 1);
+        assert_eq!(count.load(Ordering::SeqCst), 2);
+
+        assert_eq!(run(wheel.expire((3 + WHEEL_SLOTS as u64) * SLOT_NS)), 1);
+        assert_eq!(count.load(Ordering::SeqCst), 3);
+    }
+
+    #[test_case]
+    fn cancelled_timers_do_not_fire() {
+        let mut wheel = TimerWheel::new();
+        let id = wheel.add(SLOT_NS, box || panic!("Cancelled timer fired"));
+
+        assert!(wheel.cancel(id).is_some());
+        assert!(wheel.cancel(id).is_none());
+        assert_eq!(run(wheel.expire(2 * SLOT_NS)), 0);
+    }
+
+    #[test_case]
+    fn fired_timers_wait_to_be_freed() {
+        let now_ns = match now_ns() {
+            Some(now_ns) => now_ns,
+            None => {
+                crate::skip_test("no clock");
+                return;
+            }
+        };
+
+        let count = Arc::new(AtomicUsize::new(0));
+        let counted = count.clone();
+        let id = add_timer(now_ns, move || {
+            counted.fetch_add(1, Ordering::SeqCst);
+        });
+
+        // Fire it as the interrupt would, with interrupts off
+        let were_enabled = interrupts::are_enabled();
+        unsafe { interrupts::disable() };
+        tick();
+        assert_eq!(count.load(Ordering::SeqCst), 1);
+        assert!(TIMER_WHEEL.lock().fired.iter().any(|timer| timer.id == id));
+        free_fired_timers();
+        assert!(TIMER_WHEEL.lock().fired.iter().any(|timer| timer.id == id));
+        if were_enabled {
+            unsafe { interrupts::enable() };
+        }
+
+        // The next timer a task adds or cancels frees it
+        assert!(!cancel_timer(id));
+        assert!(!TIMER_WHEEL.lock().fired.iter().any(|timer| timer.id == id));
+    }
+
+    #[test_case]
+    fn timers_fire_across_the_clock_wrapping() {
+        let mut wheel = TimerWheel::new();
+        let count = Arc::new(AtomicUsize::new(0));
+
+        let before_wrap = u64::MAX - SLOT_NS;
+        assert_eq!(run(wheel.expire(before_wrap)), 0);
+        counting_timer(&mut wheel, before_wrap.wrapping_add(2 * SLOT_NS), &count);
+
+        assert_eq!(run(wheel.expire(before_wrap + SLOT_NS / 2)), 0);
+        assert_eq!(run(wheel.expire(before_wrap.wrapping_add(3 * SLOT_NS))), 1);
+        assert_eq!(count.load(Ordering::SeqCst), 1);
+    }
+
+    #[test_case]
+    fn cancelled_sleep_timers_are_unlinked() {
+        let task = current_task();
+        let deadline_ns = now_ns().unwrap_or(0).wrapping_add(1000 * SLOT_NS);
+
+        add_sleep_timer(task.clone(), deadline_ns);
+        assert!(task.sleep_timer().lock().is_armed());
+        assert!(cancel_sleep_timer(&task));
+        assert!(!task.sleep_timer().lock().is_armed());
+        assert!(!cancel_sleep_timer(&task));
+    }
+
+    // Exits with whether its sleep timer was still armed once the sleep was over
+    fn sleeper_task(slept: Arc<AtomicBool>) -> ! {
+        sleep(Duration::from_millis(SLEEP_MS));
+        let armed = current_task().sleep_timer().lock().is_armed();
+        slept.store(true, Ordering::SeqCst);
+
+        core::mem::drop(slept);
+        exit(armed as isize)
+    }
+
+    #[test_case]
+    fn woken_sleepers_leave_no_timer_behind() {
+        let start_ns = now_ns();
+        let slept = Arc::new(AtomicBool::new(false));
+        let slept_clone = slept.clone();
+        let task = unsafe { spawn(None, move || sleeper_task(slept_clone)) }
+            .expect("Failed to spawn sleeper");
+
+        // Every early wake sends the task back to sleep with its timer armed again
+        while !slept.load(Ordering::SeqCst) {
+            task.wake();
+            sleep(Duration::from_millis(1));
+        }
+
+        assert_eq!(task.join(), 0);
+        if let (Some(start_ns), Some(end_ns)) = (start_ns, now_ns()) {
+            assert!(end_ns.wrapping_sub(start_ns) >= SLEEP_MS * 1_000_000);
+        }
+    }
+}
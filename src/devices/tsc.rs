@@ -0,0 +1,166 @@
+// The TSC as a clock. Reading it is far cheaper than reading the HPET or PIT, but it is only a
+// clock if it is invariant, ticking at the same rate whatever the CPU is doing, and that rate has
+// to be measured against the kernel's clock. Every CPU has its own TSC, so each one records where
+// its TSC was at a moment on that clock and measures from there, which makes all of them agree.
+
+use super::clock::{self, monotonic_ns, TimerSource};
+use super::pit;
+use super::time::{register_clocksource, Clocksource};
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86::cpuid::CpuId;
+use x86::time::rdtsc;
+
+// How long to measure the TSC against the clock for
+const CALIBRATION_NS: u64 = 10_000_000;
+
+// Nanoseconds per TSC tick as a 32.32 fixed point number, or zero if the TSC isn't used
+static NS_PER_TICK: AtomicU64 = AtomicU64::new(0);
+
+// This CPU's TSC and the clock at the same moment
+#[thread_local]
+static mut CLOCK_BASE: Option<(u64, u64)> = None;
+
+pub fn invariant_tsc() -> bool {
+    CpuId::new()
+        .get_extended_function_info()
+        .map_or(false, |info| info.has_invariant_tsc())
+}
+
+// Read the TSC either side of the clock, and take the middle
+fn sample() -> Option<(u64, u64)> {
+    let before = unsafe { rdtsc() };
+    let ns = monotonic_ns()?;
+    let after = unsafe { rdtsc() };
+
+    Some((before + (after - before) / 2, ns))
+}
+
+fn ticks_to_ns(ticks: u64, ns_per_tick: u64) -> u64 {
+    ((u128::from(ticks) * u128::from(ns_per_tick)) >> 32) as u64
+}
+
+/// Measure the TSC against the clock, which must already be initialized
+pub unsafe fn init_bsp() {
+    if !invariant_tsc() {
+        crate::info!("No invariant TSC, so it isn't used as a clock");
+        return;
+    }
+
+    let (start_tsc, end_tsc, elapsed_ns) = match clock::timer_source() {
+        // The PIT's clock only moves on when its interrupts are taken, which they aren't this
+        // early, so the PIT times the wait itself
+        Some(TimerSource::Pit) => pit::measure_wait_ns(CALIBRATION_NS, || rdtsc()),
+
+        Some(TimerSource::Hpet) => {
+            let (start_tsc, start_ns) = sample().unwrap();
+            clock::spin_wait_ns(CALIBRATION_NS);
+            let (end_tsc, end_ns) = sample().unwrap();
+            (start_tsc, end_tsc, end_ns - start_ns)
+        }
+
+        None => return,
+    };
+
+    let ns_per_tick = (u128::from(elapsed_ns) << 32) / u128::from(end_tsc - start_tsc);
+    CLOCK_BASE = sample();
+    NS_PER_TICK.store(ns_per_tick as u64, Ordering::SeqCst);
+
+    register_clocksource(&TSC_CLOCKSOURCE);
+    crate::info!(
+        "TSC runs at {} kHz",
+        (u128::from(end_tsc - start_tsc) * 1_000_000) / u128::from(elapsed_ns)
+    );
+}
+
+/// How many TSC ticks there are in ns nanoseconds, or None if the TSC isn't used as a clock
+pub fn ns_to_ticks(ns: u64) -> Option<u64> {
+    match NS_PER_TICK.load(Ordering::Relaxed) {
+        0 => None,
+        ns_per_tick => Some(((u128::from(ns) << 32) / u128::from(ns_per_tick)) as u64),
+    }
+}
+
+/// Line this AP's TSC up with the clock, so that it agrees with the other CPUs
+pub unsafe fn init_ap() {
+    if NS_PER_TICK.load(Ordering::SeqCst) != 0 {
+        CLOCK_BASE = sample();
+    }
+}
+
+/// Nanoseconds on the same clock as clock::monotonic_ns, read from the TSC. Returns None if the
+/// TSC isn't used as a clock, or this CPU hasn't lined its TSC up yet.
+pub fn now_ns() -> Option<u64> {
+    let ns_per_tick = NS_PER_TICK.load(Ordering::Relaxed);
+    match unsafe { CLOCK_BASE } {
+        Some((base_tsc, base_ns)) if ns_per_tick != 0 => {
+            let ticks = unsafe { rdtsc() }.wrapping_sub(base_tsc);
+            Some(base_ns.wrapping_add(ticks_to_ns(ticks, ns_per_tick)))
+        }
+
+        _ => None,
+    }
+}
+
+struct TscClocksource;
+
+impl Clocksource for TscClocksource {
+    fn name(&self) -> &'static str {
+        "tsc"
+    }
+
+    fn now_ns(&self) -> Option<u64> {
+        now_ns()
+    }
+
+    fn resolution_ns(&self) -> u64 {
+        ticks_to_ns(1, NS_PER_TICK.load(Ordering::Relaxed)).max(1)
+    }
+
+    // Only an invariant TSC is ever registered
+    fn stable(&self) -> bool {
+        true
+    }
+}
+
+static TSC_CLOCKSOURCE: TscClocksource = TscClocksource;
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn tsc_agrees_with_the_clock() {
+        // Reading the two clocks one after the other puts a little time between them, and emulated
+        // TSCs are not very steady
+        const TOLERANCE_NS: u64 = 500_000;
+
+        if !invariant_tsc() {
+            crate::skip_test("no invariant tsc");
+            return;
+        }
+
+        for _ in 0..4 {
+            let (tsc_ns, clock_ns) = match (now_ns(), monotonic_ns()) {
+                (Some(tsc_ns), Some(clock_ns)) => (tsc_ns, clock_ns),
+                _ => panic!("The invariant TSC wasn't registered as a clock"),
+            };
+
+            let difference = (tsc_ns as i64).wrapping_sub(clock_ns as i64).abs() as u64;
+            assert!(
+                difference < TOLERANCE_NS,
+                "Clocks differ by {}ns",
+                difference
+            );
+
+            clock::spin_wait_ns(CALIBRATION_NS / 2);
+        }
+    }
+
+    #[test_case]
+    fn ticks_scale_to_nanoseconds() {
+        // A 2.5GHz TSC
+        let ns_per_tick = (2 << 32) / 5;
+        assert_eq!(ticks_to_ns(5_000_000_000, ns_per_tick), 1_999_999_999);
+        assert_eq!(ticks_to_ns(0, ns_per_tick), 0);
+    }
+}
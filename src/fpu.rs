@@ -0,0 +1,121 @@
+// The kernel itself is built without SSE, so only tasks that use floating point or SIMD ever touch
+// the FPU. Its state is switched lazily: every context switch sets CR0.TS, so the first FPU
+// instruction a task runs raises the device not available exception, and the handler loads that
+// task's state. A task that used the FPU has its state saved when it is switched out, which means
+// it can be picked up by any CPU.
+
+use x86::controlregs::{self, Cr0, Cr4};
+
+const FXSAVE_AREA_SIZE: usize = 512;
+
+// Offsets of the control words in the FXSAVE area, and their values after FNINIT
+const FCW_OFFSET: usize = 0;
+const MXCSR_OFFSET: usize = 24;
+const DEFAULT_FCW: u16 = 0x037f;
+const DEFAULT_MXCSR: u32 = 0x1f80;
+
+/// The saved x87 and SSE state of a task
+#[repr(C, align(16))]
+pub struct FpuState([u8; FXSAVE_AREA_SIZE]);
+
+impl FpuState {
+    /// The state that a task starts with, which is the same as after FNINIT with all exceptions
+    /// masked
+    pub fn new() -> Self {
+        let mut area = [0; FXSAVE_AREA_SIZE];
+        area[FCW_OFFSET..FCW_OFFSET + 2].copy_from_slice(&DEFAULT_FCW.to_le_bytes());
+        area[MXCSR_OFFSET..MXCSR_OFFSET + 4].copy_from_slice(&DEFAULT_MXCSR.to_le_bytes());
+        Self(area)
+    }
+
+    unsafe fn save(&mut self) {
+        asm!("fxsave64 [{}]", in(reg) self.0.as_mut_ptr(), options(nostack));
+    }
+
+    unsafe fn restore(&self) {
+        asm!("fxrstor64 [{}]", in(reg) self.0.as_ptr(), options(nostack));
+    }
+}
+
+impl core::fmt::Debug for FpuState {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        f.debug_struct("FpuState").finish()
+    }
+}
+
+/// Enable the FPU and SSE on this CPU
+pub unsafe fn init() {
+    let cr0 = (controlregs::cr0() - Cr0::CR0_EMULATE_COPROCESSOR - Cr0::CR0_TASK_SWITCHED)
+        | Cr0::CR0_MONITOR_COPROCESSOR
+        | Cr0::CR0_NUMERIC_ERROR;
+    controlregs::cr0_write(cr0);
+    controlregs::cr4_write(controlregs::cr4() | Cr4::CR4_ENABLE_SSE | Cr4::CR4_UNMASKED_SSE);
+
+    asm!("fninit", options(nomem, nostack));
+}
+
+/// Called when switching away from a task. If the task used the FPU since it was switched to, its
+/// state is saved. Either way, the next task's first FPU instruction will trap.
+pub unsafe fn switch_out(state: Option<&mut FpuState>) {
+    let cr0 = controlregs::cr0();
+    if !cr0.contains(Cr0::CR0_TASK_SWITCHED) {
+        if let Some(state) = state {
+            state.save();
+        }
+    }
+
+    controlregs::cr0_write(cr0 | Cr0::CR0_TASK_SWITCHED);
+}
+
+/// Called from the device not available exception to give the FPU to the current task
+pub unsafe fn switch_in(state: &FpuState) {
+    asm!("clts", options(nomem, nostack));
+    state.restore();
+}
+
+#[cfg(test)]
+mod test {
+    use crate::scheduler::{exit, reschedule, spawn, TaskReference};
+    use alloc::vec::Vec;
+
+    const ITERATIONS: u64 = 8;
+
+    // Add step to a running total in xmm0, giving up the CPU after every addition, so that the
+    // other task gets to use the same registers in between. The task exits with the total.
+    fn sse_task(start: u64, step: u64) -> ! {
+        let total: u64;
+        unsafe {
+            asm!(
+                "movq xmm0, {}",
+                "movq xmm1, {}",
+                in(reg) start,
+                in(reg) step,
+                options(nomem, nostack)
+            );
+            for _ in 0..ITERATIONS {
+                asm!("paddq xmm0, xmm1", options(nomem, nostack));
+                reschedule();
+            }
+            asm!("movq {}, xmm0", out(reg) total, options(nomem, nostack));
+        }
+
+        exit(total as isize)
+    }
+
+    #[test_case]
+    fn tasks_keep_their_own_sse_registers() {
+        const TASKS: [(u64, u64); 2] = [(0x1000, 1), (0x2000_0000, 0x100)];
+
+        let tasks: Vec<TaskReference> = TASKS
+            .iter()
+            .cloned()
+            .map(|(start, step)| {
+                unsafe { spawn(None, move || sse_task(start, step)) }.expect("Failed to spawn task")
+            })
+            .collect();
+
+        for (task, (start, step)) in tasks.iter().zip(TASKS.iter()) {
+            assert_eq!(task.join() as u64, start + step * ITERATIONS);
+        }
+    }
+}
@@ -1,5 +1,6 @@
-use crate::paging::KernelStack;
+use crate::paging::{self, page_align_down, KernelStack, PresentPageFlags, PAGE_SIZE};
 use core::mem;
+use core::sync::atomic::{AtomicUsize, Ordering};
 use x86::bits64::task::TaskStateSegment;
 use x86::dtables::{self, DescriptorTablePointer};
 use x86::segmentation::load_cs;
@@ -71,6 +72,10 @@ pub const GDT_USER_TLS_UNUSED: usize = 6;
 pub const GDT_TSS: usize = 7;
 pub const GDT_TSS_HIGH: usize = 8;
 
+// What ring 3 runs with
+pub const USER_CODE_SELECTOR: usize = GDT_USER_CODE << 3 | 3;
+pub const USER_DATA_SELECTOR: usize = GDT_USER_DATA << 3 | 3;
+
 pub const GDT_A_PRESENT: u8 = 1 << 7;
 pub const GDT_A_RING_0: u8 = 0 << 5;
 pub const GDT_A_RING_1: u8 = 1 << 5;
@@ -178,6 +183,8 @@ pub static mut GDT: [GdtEntry; 9] = [
     GdtEntry::new(0, 0, 0, 0),
 ];
 
+// The syscall entry reads the ring 0 stack out of this, so it needs a name it can find
+#[no_mangle]
 #[thread_local]
 pub static mut TSS: TaskStateSegment = TaskStateSegment {
     reserved: 0,
@@ -189,14 +196,140 @@ pub static mut TSS: TaskStateSegment = TaskStateSegment {
     iomap_base: 0xFFFF,
 };
 
+// The interrupt stack table slots used by the IDT. The double fault has its own stack so that it can
+// report a kernel stack overflow, and NMIs and machine checks can arrive at any point, including
+// in the middle of another exception handler.
+pub const IST_DOUBLE_FAULT: u8 = 0;
+pub const IST_NMI: u8 = 1;
+pub const IST_MACHINE_CHECK: u8 = 2;
+
+const IST_STACK_COUNT: usize = 3;
+
+// The stacks that the TSS points at. They are never freed, because the CPU can switch to them at
+// any time.
+#[thread_local]
+static mut IST_STACKS: [Option<KernelStack>; IST_STACK_COUNT] = [None, None, None];
+
 pub unsafe fn set_tss_stack(stack: &KernelStack) {
-    TSS.rsp[0] = stack.stack_top() as u64;
+    set_kernel_stack_top(stack.stack_top());
+}
+
+/// Set the stack that this CPU switches to when ring 3 code enters the kernel. The scheduler
+/// points it at the top of each task's kernel stack as the task is switched to.
+pub unsafe fn set_kernel_stack_top(stack_top: usize) {
+    TSS.rsp[0] = stack_top as u64;
+}
+
+unsafe fn set_ist_stack(ist: u8, stack: KernelStack) {
+    TSS.ist[ist as usize] = stack.stack_top() as u64;
+    IST_STACKS[ist as usize] = Some(stack);
+}
+
+/// The bottom and top of the stack in an IST slot on this CPU, if one has been set
+pub fn ist_stack_bounds(ist: u8) -> Option<(usize, usize)> {
+    unsafe { IST_STACKS.get(ist as usize)?.as_ref() }
+        .map(|stack| (stack.stack_bottom(), stack.stack_top()))
+}
+
+/// Something that would stop the CPU from delivering a fault. Finding one of these when the fault
+/// happens is too late, because the fault on the fault becomes a triple fault and the machine
+/// resets without saying why.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum FaultPathError {
+    IstStackMissing(u8),
+    IstStackMisaligned(u8, u64),
+    IstStackNotMapped(u8, usize),
+    TssBaseWrong(u64),
+    TssLimitWrong(u32),
+    TssTypeWrong(u8),
+}
+
+/// Check that the stack in an IST slot on this CPU can be switched to. The CPU aligns the stack
+/// pointer itself, but a misaligned one means the slot has been overwritten.
+pub fn check_ist_stack(ist: u8) -> Result<(), FaultPathError> {
+    let top = unsafe { TSS.ist[ist as usize] };
+    if top == 0 {
+        return Err(FaultPathError::IstStackMissing(ist));
+    } else if top % 16 != 0 {
+        return Err(FaultPathError::IstStackMisaligned(ist, top));
+    }
+
+    // If the slot still points at the stack that was put there, all of it has to be mapped, but
+    // otherwise the most that can be checked is the page the CPU pushes the fault onto
+    let top = top as usize;
+    let bottom = match ist_stack_bounds(ist) {
+        Some((bottom, stack_top)) if stack_top == top => bottom,
+        _ => page_align_down(top - 1),
+    };
+
+    let page_table = unsafe { paging::lock_page_table() };
+    for page in (bottom..top).step_by(PAGE_SIZE) {
+        let writable = page_table
+            .get_pte_for_address(page)
+            .and_then(|pte| pte.present().ok())
+            .map_or(false, |pte| {
+                pte.flags().contains(PresentPageFlags::WRITABLE)
+            });
+        if !writable {
+            return Err(FaultPathError::IstStackNotMapped(ist, page));
+        }
+    }
+
+    Ok(())
+}
+
+/// Check that a pair of GDT entries describes tss
+pub fn check_tss_descriptor(
+    low: &GdtEntry,
+    high: &GdtEntry,
+    tss: &TaskStateSegment,
+) -> Result<(), FaultPathError> {
+    let base = u64::from(low.offsetl)
+        | u64::from(low.offsetm) << 16
+        | u64::from(low.offseth) << 24
+        | u64::from(high.limitl) << 32
+        | u64::from(high.offsetl) << 48;
+    let limit = u32::from(low.limitl) | u32::from(low.flags_limith & 0x0F) << 16;
+    let access = low.access;
+
+    if base != tss as *const _ as u64 {
+        Err(FaultPathError::TssBaseWrong(base))
+    } else if limit < mem::size_of::<TaskStateSegment>() as u32 - 1 {
+        Err(FaultPathError::TssLimitWrong(limit))
+    } else if access & GDT_A_PRESENT == 0
+        || access & GDT_A_SYSTEM != 0
+        || (access & 0xF != GDT_A_TSS_AVAIL && access & 0xF != GDT_A_TSS_BUSY)
+    {
+        Err(FaultPathError::TssTypeWrong(access))
+    } else {
+        Ok(())
+    }
+}
+
+fn check_fault_paths() -> Result<(), FaultPathError> {
+    unsafe { check_tss_descriptor(&GDT[GDT_TSS], &GDT[GDT_TSS_HIGH], &TSS)? };
+    for ist in [IST_DOUBLE_FAULT, IST_NMI, IST_MACHINE_CHECK].iter() {
+        check_ist_stack(*ist)?;
+    }
+
+    Ok(())
+}
+
+/// Where the GDT that this CPU has loaded is
+pub fn loaded_gdt() -> *const GdtEntry {
+    let mut gdtr = DescriptorTablePointer::<SegmentDescriptor> {
+        limit: 0,
+        base: core::ptr::null(),
+    };
+    unsafe { dtables::sgdt(&mut gdtr) };
+    gdtr.base as *const GdtEntry
 }
 
 // Initialize GDT
 pub unsafe fn init() {
-    // Setup the initial GDT with TLS, so we can setup the TLS GDT (a little confusing)
-    // This means that each CPU will have its own GDT, but we only need to define it once as a thread local
+    // Every CPU starts on the shared initial GDT, which has the kernel TLS entry but no TSS. It only
+    // has to last until thread locals work, when init_post_paging moves the CPU onto the GDT in its
+    // own thread local block, along with its own TSS.
     INIT_GDTR.limit = (INIT_GDT.len() * mem::size_of::<GdtEntry>() - 1) as u16;
     INIT_GDTR.base = INIT_GDT.as_ptr() as *const SegmentDescriptor;
 
@@ -212,14 +345,31 @@ pub unsafe fn init() {
     segmentation::load_ss(SegmentSelector::new(GDT_KERNEL_DATA as u16, Ring::Ring0));
 }
 
+// How many CPUs are running without thread locals yet. The BSP starts out without them, and each
+// AP does until init_ap. Only the FS base tells a CPU whether it is one of them, and reading that
+// is slow, so it is only read while this is non-zero. An AP that fails to start may leave it set.
+static CPUS_WITHOUT_TLS: AtomicUsize = AtomicUsize::new(1);
+
+/// Whether thread locals work on this CPU
+pub fn tls_ready() -> bool {
+    use x86::msr::{rdmsr, IA32_FS_BASE};
+
+    CPUS_WITHOUT_TLS.load(Ordering::SeqCst) == 0 || unsafe { rdmsr(IA32_FS_BASE) != 0 }
+}
+
+/// Called by the BSP before it starts an AP, which runs without thread locals until init_ap
+pub fn expect_cpu_without_tls() {
+    CPUS_WITHOUT_TLS.fetch_add(1, Ordering::SeqCst);
+}
+
 pub unsafe fn init_post_paging(
     tcb_offset: usize,
     init_stack: &KernelStack,
-    fault_stack: &KernelStack,
+    double_fault_stack: KernelStack,
 ) {
     // Set the FS base to point to the tcb data so that we can access the thread local GDT. From
     // this point thread locals work.
-    use x86::msr::{wrmsr, IA32_FS_BASE};
+    use x86::msr::{wrmsr, IA32_FS_BASE, IA32_KERNEL_GSBASE};
     wrmsr(IA32_FS_BASE, tcb_offset as u64);
 
     // Now set up our local GDT
@@ -232,7 +382,22 @@ pub unsafe fn init_post_paging(
     GDT[GDT_TSS_HIGH] = tss_high;
 
     set_tss_stack(init_stack);
-    TSS.ist[0] = fault_stack.stack_top() as u64;
+    set_ist_stack(IST_DOUBLE_FAULT, double_fault_stack);
+    set_ist_stack(
+        IST_NMI,
+        paging::allocate_kernel_stack(paging::DEFAULT_KERNEL_STACK_PAGES)
+            .expect("Failed to allocate NMI stack"),
+    );
+    set_ist_stack(
+        IST_MACHINE_CHECK,
+        paging::allocate_kernel_stack(paging::DEFAULT_KERNEL_STACK_PAGES)
+            .expect("Failed to allocate machine check stack"),
+    );
+
+    // The console still works, so this is the time to find out
+    if let Err(err) = check_fault_paths() {
+        panic!("Faults on this CPU would triple fault: {:?}", err);
+    }
 
     dtables::lgdt(&GDTR);
 
@@ -247,12 +412,169 @@ pub unsafe fn init_post_paging(
     // We reloaded FS so we need to reload the fs base register
     wrmsr(IA32_FS_BASE, tcb_offset as u64);
 
+    // The kernel gs base always holds the thread local block as well. Syscalls swapgs to reach it,
+    // and interrupts and syscalls from ring 3 load the fs base from it.
+    wrmsr(IA32_KERNEL_GSBASE, tcb_offset as u64);
+
     // Set the TSS
     task::load_tr(SegmentSelector::new(GDT_TSS as u16, Ring::Ring0));
+
+    // Only now, since reloading FS cleared the FS base for a moment
+    CPUS_WITHOUT_TLS.fetch_sub(1, Ordering::SeqCst);
 }
 
-pub unsafe fn init_ap(tcb_offset: usize, init_stack: &KernelStack, fault_stack: &KernelStack) {
+pub unsafe fn init_ap(
+    tcb_offset: usize,
+    init_stack: &KernelStack,
+    double_fault_stack: KernelStack,
+) {
     // Only one AP is initialized at a time, so we can do this
     init();
-    init_post_paging(tcb_offset, init_stack, fault_stack);
+    init_post_paging(tcb_offset, init_stack, double_fault_stack);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::idt;
+    use crate::interrupts::exceptions;
+    use crate::scheduler::{exit, spawn_on, TaskReference, MAX_CPUS};
+    use alloc::vec::Vec;
+
+    #[test_case]
+    fn fault_vectors_use_their_own_stacks() {
+        assert_eq!(idt::ist(2), Some(IST_NMI));
+        assert_eq!(idt::ist(8), Some(IST_DOUBLE_FAULT));
+        assert_eq!(idt::ist(14), None);
+        assert_eq!(idt::ist(18), Some(IST_MACHINE_CHECK));
+
+        for ist in [IST_DOUBLE_FAULT, IST_NMI, IST_MACHINE_CHECK].iter() {
+            let (_, top) = ist_stack_bounds(*ist).expect("IST stack has not been allocated");
+            assert_eq!(unsafe { TSS.ist[*ist as usize] }, top as u64);
+        }
+    }
+
+    // No IDT entry uses this slot, so nothing can switch to whatever the tests put in it
+    const UNUSED_IST: u8 = 6;
+
+    fn check_with_ist_top(top: u64) -> Result<(), FaultPathError> {
+        unsafe {
+            let old_top = TSS.ist[UNUSED_IST as usize];
+            TSS.ist[UNUSED_IST as usize] = top;
+            let result = check_ist_stack(UNUSED_IST);
+            TSS.ist[UNUSED_IST as usize] = old_top;
+            result
+        }
+    }
+
+    #[test_case]
+    fn fault_paths_are_checked_before_they_are_needed() {
+        assert_eq!(check_fault_paths(), Ok(()));
+    }
+
+    #[test_case]
+    fn bad_ist_stacks_are_caught() {
+        let stack = paging::allocate_kernel_stack(2).expect("Failed to allocate stack");
+        assert_eq!(check_with_ist_top(stack.stack_top() as u64), Ok(()));
+
+        assert_eq!(
+            check_with_ist_top(0),
+            Err(FaultPathError::IstStackMissing(UNUSED_IST))
+        );
+        assert_eq!(
+            check_with_ist_top(stack.stack_top() as u64 - 8),
+            Err(FaultPathError::IstStackMisaligned(
+                UNUSED_IST,
+                stack.stack_top() as u64 - 8
+            ))
+        );
+
+        // Pointing at the bottom of the stack means the fault would be pushed onto the guard page
+        assert_eq!(
+            check_with_ist_top(stack.stack_bottom() as u64),
+            Err(FaultPathError::IstStackNotMapped(
+                UNUSED_IST,
+                stack.stack_bottom() - PAGE_SIZE
+            ))
+        );
+    }
+
+    #[test_case]
+    fn bad_tss_descriptors_are_caught() {
+        let (low, high) = unsafe { tss_entries(&TSS) };
+        assert_eq!(unsafe { check_tss_descriptor(&low, &high, &TSS) }, Ok(()));
+
+        let mut wrong_base = high;
+        wrong_base.offsetl ^= 1;
+        assert!(matches!(
+            unsafe { check_tss_descriptor(&low, &wrong_base, &TSS) },
+            Err(FaultPathError::TssBaseWrong(_))
+        ));
+
+        let mut short = low;
+        short.set_limit(8);
+        assert_eq!(
+            unsafe { check_tss_descriptor(&short, &high, &TSS) },
+            Err(FaultPathError::TssLimitWrong(8))
+        );
+
+        let mut not_present = low;
+        not_present.access &= !GDT_A_PRESENT;
+        assert!(matches!(
+            unsafe { check_tss_descriptor(&not_present, &high, &TSS) },
+            Err(FaultPathError::TssTypeWrong(_))
+        ));
+    }
+
+    // Check the GDT that is actually loaded, rather than the one that init_post_paging filled in,
+    // and exit with its address
+    fn record_gdt() -> ! {
+        let gdt = loaded_gdt();
+        let task_register: u16;
+        let result = unsafe {
+            asm!("str {0:x}", out(reg) task_register, options(nomem, nostack));
+            assert_eq!(usize::from(task_register >> 3), GDT_TSS);
+            check_tss_descriptor(&*gdt.add(GDT_TSS), &*gdt.add(GDT_TSS_HIGH), &TSS)
+        };
+        assert_eq!(result, Ok(()), "CPU {} TSS descriptor", crate::cpu_id());
+
+        exit(gdt as isize)
+    }
+
+    #[test_case]
+    fn every_cpu_has_its_own_gdt() {
+        let cpus: Vec<usize> = (0..MAX_CPUS)
+            .filter(|id| crate::init::cpu_online(*id))
+            .collect();
+        let tasks: Vec<TaskReference> = cpus
+            .iter()
+            .map(|cpu_id| {
+                unsafe { spawn_on(*cpu_id, || record_gdt()) }.expect("Failed to spawn task")
+            })
+            .collect();
+        let gdts: Vec<usize> = tasks.iter().map(|task| task.join() as usize).collect();
+
+        assert_eq!(loaded_gdt(), unsafe { GDT.as_ptr() });
+        for (index, (cpu_id, gdt)) in cpus.iter().zip(gdts.iter()).enumerate() {
+            assert!(
+                !gdts[..index].contains(gdt),
+                "CPU {} shares its GDT at {:#x}",
+                cpu_id,
+                gdt
+            );
+        }
+    }
+
+    #[test_case]
+    fn double_fault_runs_on_its_own_stack() {
+        let (bottom, top) = ist_stack_bounds(IST_DOUBLE_FAULT).unwrap();
+        let stack = unsafe { exceptions::force_double_fault() }.expect("No double fault happened");
+        assert!(
+            stack >= bottom && stack < top,
+            "Double fault stack {:#x} is outside {:#x}-{:#x}",
+            stack,
+            bottom,
+            top
+        );
+    }
 }
@@ -1,3 +1,4 @@
+use crate::gdt;
 use crate::interrupts::{exceptions, ipi, irq};
 use bitflags::bitflags;
 use x86::dtables::{self, DescriptorTablePointer};
@@ -59,7 +60,7 @@ impl IdtEntry {
     }
 
     pub fn set_ist(&mut self, ist: u8) {
-        assert!(ist < 8, "Invalid IST");
+        assert!(ist < 7, "Invalid IST");
         self.ist = ist + 1;
     }
 }
@@ -84,10 +85,50 @@ static mut INIT_IDTR: DescriptorTablePointer<X86IdtEntry> = DescriptorTablePoint
     base: 0 as *const X86IdtEntry,
 };
 
+#[thread_local]
+static mut IDT: Idt = Idt::new();
+
 pub unsafe fn early_init() {
     dtables::lidt(&INIT_IDTR);
 }
 
+/// Mark a vector in this CPU's IDT as present or not, and return whether it was present before
+#[cfg(test)]
+pub(crate) unsafe fn set_present(vector: u8, present: bool) -> bool {
+    let entry = &mut IDT.entries[vector as usize];
+    let was_present = entry.attribute & IdtFlags::PRESENT.bits != 0;
+    if present {
+        entry.attribute |= IdtFlags::PRESENT.bits;
+    } else {
+        entry.attribute &= !IdtFlags::PRESENT.bits;
+    }
+    was_present
+}
+
+// Point an entry at the catch-all for its vector
+fn set_unhandled(entry: &mut IdtEntry, vector: u8) {
+    entry.set_flags(IdtFlags::PRESENT | IdtFlags::RING_0 | IdtFlags::INTERRUPT);
+    entry.set_offset(8, irq::unhandled_vector_stub(vector));
+}
+
+/// Point a vector in this CPU's IDT at func, or back at the catch-all
+#[cfg(test)]
+pub(crate) unsafe fn set_handler(vector: u8, func: Option<unsafe extern "C" fn()>) {
+    let entry = &mut IDT.entries[vector as usize];
+    match func {
+        Some(func) => entry.set_func(func),
+        None => set_unhandled(entry, vector),
+    }
+}
+
+#[cfg(test)]
+pub(crate) fn ist(vector: u8) -> Option<u8> {
+    match unsafe { IDT.entries[vector as usize].ist } {
+        0 => None,
+        ist => Some(ist - 1),
+    }
+}
+
 pub fn init(is_bsp: bool) {
     let (idt, idtr) = unsafe {
         use core::sync::atomic::{AtomicBool, Ordering};
@@ -100,9 +141,6 @@ pub fn init(is_bsp: bool) {
             "IDT for this CPU is already initialized"
         );
 
-        #[thread_local]
-        static mut IDT: Idt = Idt::new();
-
         #[thread_local]
         static mut IDTR: DescriptorTablePointer<X86IdtEntry> = DescriptorTablePointer {
             limit: 0,
@@ -118,7 +156,7 @@ pub fn init(is_bsp: bool) {
     idt.entries[0].set_func(exceptions::divide_by_zero);
     idt.entries[1].set_func(exceptions::debug);
     idt.entries[2].set_func(exceptions::non_maskable);
-    idt.entries[2].set_ist(0);
+    idt.entries[2].set_ist(gdt::IST_NMI);
     idt.entries[3].set_func(exceptions::breakpoint);
     idt.entries[3].set_flags(IdtFlags::PRESENT | IdtFlags::RING_3 | IdtFlags::INTERRUPT);
     idt.entries[4].set_func(exceptions::overflow);
@@ -126,18 +164,20 @@ pub fn init(is_bsp: bool) {
     idt.entries[6].set_func(exceptions::invalid_opcode);
     idt.entries[7].set_func(exceptions::device_not_available);
     idt.entries[8].set_func(exceptions::double_fault);
-    idt.entries[8].set_ist(0);
+    idt.entries[8].set_ist(gdt::IST_DOUBLE_FAULT);
     // 9 no longer available
     idt.entries[10].set_func(exceptions::invalid_tss);
     idt.entries[11].set_func(exceptions::segment_not_present);
     idt.entries[12].set_func(exceptions::stack_segment);
     idt.entries[13].set_func(exceptions::protection);
+    // Page faults run on the current stack. A page fault on a stack overflow can't be delivered
+    // there, and becomes a double fault instead.
     idt.entries[14].set_func(exceptions::page);
-    idt.entries[14].set_ist(0);
     // 15 reserved
     idt.entries[16].set_func(exceptions::fpu_fault);
     idt.entries[17].set_func(exceptions::alignment_check);
     idt.entries[18].set_func(exceptions::machine_check);
+    idt.entries[18].set_ist(gdt::IST_MACHINE_CHECK);
     idt.entries[19].set_func(exceptions::simd);
     idt.entries[20].set_func(exceptions::virtualization);
     // 21 through 29 reserved
@@ -146,13 +186,35 @@ pub fn init(is_bsp: bool) {
 
     if is_bsp {
         idt.entries[32].set_func(irq::timer);
+        for (index, func) in irq::LEGACY_IRQS.iter().enumerate() {
+            idt.entries[33 + index].set_func(*func);
+        }
     }
 
     idt.entries[0xf0].set_func(ipi::tlb);
+    idt.entries[0xfb].set_func(ipi::reschedule);
+    idt.entries[0xfc].set_func(irq::local_timer);
     idt.entries[0xfd].set_func(ipi::ipi_timer);
     idt.entries[0xfe].set_func(ipi::halt);
     idt.entries[0xff].set_func(irq::spurious);
 
+    // Vectors that nothing else has get the catch-all, which reports them rather than letting them
+    // become a fault. The reserved exceptions below 31 are left alone, as some push an error code.
+    for (vector, entry) in idt.entries.iter_mut().enumerate().skip(31) {
+        if entry.attribute & IdtFlags::PRESENT.bits == 0 {
+            set_unhandled(entry, vector as u8);
+        }
+    }
+
+    // A fault that can't switch to its stack turns into a triple fault, so find out now
+    for (vector, entry) in idt.entries.iter().enumerate() {
+        if entry.ist != 0 {
+            if let Err(err) = gdt::check_ist_stack(entry.ist - 1) {
+                panic!("Vector {} would triple fault: {:?}", vector, err);
+            }
+        }
+    }
+
     unsafe {
         dtables::lidt(idtr);
     }
@@ -1,18 +1,30 @@
 use crate::acpi;
 use crate::allocator;
 use crate::devices;
+use crate::fpu;
 use crate::gdt;
 use crate::idt;
 use crate::paging;
 use crate::physmem;
 use crate::println;
 use crate::scheduler;
+use crate::syscall;
+use crate::{debug, info};
 use alloc::vec::Vec;
 use bootloader::{bootinfo::MemoryRegion, BootInfo};
+use core::fmt;
 use core::panic::PanicInfo;
 use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use x86::cpuid::CpuId;
+
+// Indexed by local APIC id, which is what the APs use as their CPU id
+static AP_READY: [AtomicBool; scheduler::MAX_CPUS] = [AtomicBool::new(false); scheduler::MAX_CPUS];
+static AP_FAILED: [AtomicBool; scheduler::MAX_CPUS] = [AtomicBool::new(false); scheduler::MAX_CPUS];
+
+// The AP that the BSP is waiting for, if there is one
+const NO_STARTING_AP: usize = usize::MAX;
+static STARTING_AP: AtomicUsize = AtomicUsize::new(NO_STARTING_AP);
 
-pub static AP_READY: AtomicBool = AtomicBool::new(false);
 static BSP_READY: AtomicBool = AtomicBool::new(false);
 
 #[thread_local]
@@ -22,7 +34,122 @@ pub fn cpu_id() -> usize {
     CPU_ID.load(Ordering::SeqCst)
 }
 
+struct RequiredFeature {
+    name: &'static str,
+    present: fn(&CpuId) -> bool,
+}
+
+// Everything here is assumed by the paging and device code, so there is no point in going further
+// without it
+const REQUIRED_FEATURES: [RequiredFeature; 7] = [
+    RequiredFeature {
+        name: "long mode",
+        present: |cpuid| {
+            cpuid
+                .get_extended_function_info()
+                .map_or(false, |info| info.has_64bit_mode())
+        },
+    },
+    RequiredFeature {
+        name: "no-execute pages",
+        present: |cpuid| {
+            cpuid
+                .get_extended_function_info()
+                .map_or(false, |info| info.has_execute_disable())
+        },
+    },
+    RequiredFeature {
+        name: "2MiB pages",
+        present: |cpuid| {
+            cpuid
+                .get_feature_info()
+                .map_or(false, |info| info.has_pse() && info.has_pae())
+        },
+    },
+    RequiredFeature {
+        name: "local APIC",
+        present: |cpuid| {
+            cpuid
+                .get_feature_info()
+                .map_or(false, |info| info.has_apic())
+        },
+    },
+    // Every long mode CPU has the FS base MSR, so having MSRs at all is all that needs checking
+    RequiredFeature {
+        name: "rdmsr/wrmsr and the FS base MSR",
+        present: |cpuid| {
+            cpuid
+                .get_feature_info()
+                .map_or(false, |info| info.has_msr())
+        },
+    },
+    RequiredFeature {
+        name: "SSE with fxsave and fxrstor",
+        present: |cpuid| {
+            cpuid
+                .get_feature_info()
+                .map_or(false, |info| info.has_sse() && info.has_fxsave_fxstor())
+        },
+    },
+    RequiredFeature {
+        name: "cpuid extended functions",
+        present: |cpuid| cpuid.get_extended_function_info().is_some(),
+    },
+];
+
+fn missing_features() -> impl Iterator<Item = &'static str> + Clone {
+    REQUIRED_FEATURES
+        .iter()
+        .filter(|feature| !(feature.present)(&CpuId::new()))
+        .map(|feature| feature.name)
+}
+
+struct FeatureList<I>(I);
+
+impl<I: Iterator<Item = &'static str> + Clone> fmt::Display for FeatureList<I> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (index, name) in self.0.clone().enumerate() {
+            if index > 0 {
+                f.write_str(", ")?;
+            }
+            f.write_str(name)?;
+        }
+        Ok(())
+    }
+}
+
+/// Make sure that the CPU has everything the kernel depends on, and turn on no-execute support.
+/// This runs before the heap is up, so it can't allocate.
+pub unsafe fn check_required_features() {
+    use x86::msr::{rdmsr, wrmsr, IA32_EFER};
+
+    let missing = missing_features();
+    if missing.clone().next().is_some() {
+        panic!("CPU is missing required features: {}", FeatureList(missing));
+    }
+
+    const EFER_NXE: u64 = 1 << 11;
+    wrmsr(IA32_EFER, rdmsr(IA32_EFER) | EFER_NXE);
+}
+
+/// Choices about how the kernel starts, for kstart_with_options
+#[derive(Debug, Default, Clone, Copy)]
+pub struct BootOptions {
+    /// Use the PIT as the clock even if there is an HPET, so that the PIT can be tested on
+    /// machines that have both
+    pub ignore_hpet: bool,
+}
+
 pub unsafe fn kstart(boot_info: &'static BootInfo, func: impl FnOnce() -> ! + 'static) -> ! {
+    kstart_with_options(boot_info, BootOptions::default(), func)
+}
+
+pub unsafe fn kstart_with_options(
+    boot_info: &'static BootInfo,
+    options: BootOptions,
+    func: impl FnOnce() -> ! + 'static,
+) -> ! {
+    check_required_features();
     paging::pre_init(boot_info);
 
     println!("Starting kernel...");
@@ -30,7 +157,14 @@ pub unsafe fn kstart(boot_info: &'static BootInfo, func: impl FnOnce() -> ! + 's
     gdt::init();
     idt::early_init();
 
-    physmem::early_init(boot_info.memory_map.iter());
+    // Check a copy of the boot memory map before the frame database is built from it. There are
+    // no thread locals yet, so the only way to report a problem is the serial port.
+    let mut boot_memory_map = physmem::MemoryMapCopy::new(&boot_info.memory_map);
+    if let Err(err) = boot_memory_map.validate() {
+        crate::serial_println!("Boot memory map is inconsistent: {:?}", err);
+    }
+
+    physmem::early_init(boot_memory_map.iter());
 
     // Initialize the allocator before paging. The allocator uses a small internal buffer which
     // gives us enough working heap to allocate during paging initialization
@@ -38,9 +172,10 @@ pub unsafe fn kstart(boot_info: &'static BootInfo, func: impl FnOnce() -> ! + 's
 
     // Now that we have a functioning heap, we can make a copy of the boot memory map.
     // Eventually we will pass this to the paging manager instead of the one from the bootloader
-    let memory_map: Vec<_> = boot_info.memory_map.iter().cloned().collect();
+    let memory_map: Vec<_> = boot_memory_map.iter().cloned().collect();
 
     let tcb_offset = paging::init(0);
+    paging::verify_wx();
 
     physmem::init_post_paging(memory_map.iter());
 
@@ -50,8 +185,9 @@ pub unsafe fn kstart(boot_info: &'static BootInfo, func: impl FnOnce() -> ! + 's
         .expect("Failed to allocate first kernel stack");
     let fault_stack = paging::allocate_kernel_stack(paging::DEFAULT_KERNEL_STACK_PAGES)
         .expect("Failed to allocate fault stack");
-    idle_thread_stack.switch_to_permanent(move |stack| {
-        init_post_paging(stack, fault_stack, tcb_offset, memory_map, func);
+    // Nothing owns the boot stack, which is left behind for good
+    idle_thread_stack.switch_to_permanent(None, move |stack| {
+        init_post_paging(stack, fault_stack, tcb_offset, memory_map, options, func);
     });
 }
 
@@ -60,6 +196,7 @@ unsafe fn init_post_paging(
     fault_stack: paging::KernelStack,
     tcb_offset: usize,
     memory_map: Vec<MemoryRegion>,
+    options: BootOptions,
     func: impl FnOnce() -> ! + 'static,
 ) -> ! {
     println!(
@@ -67,27 +204,39 @@ unsafe fn init_post_paging(
         &idle_thread_stack as *const paging::KernelStack, tcb_offset,
     );
 
-    gdt::init_post_paging(tcb_offset, &idle_thread_stack, &fault_stack);
+    // The GDT keeps the fault stack as the double fault stack. We keep the idle thread stack
+    // because we need it for the idle task
+    gdt::init_post_paging(tcb_offset, &idle_thread_stack, fault_stack);
     idt::init(true);
+    fpu::init();
+    syscall::init();
 
     CPU_ID.store(0, Ordering::SeqCst);
 
-    // Once the GDT has got the fault stack, we don't need it any more. We keep the idle
-    // thread stack because we need it for the idle task
-    let _ = core::mem::ManuallyDrop::new(fault_stack);
-
-    physmem::init_reclaim(memory_map.iter());
+    // Nothing reads the boot info past the copy of the memory map, paging::init switched away from
+    // the bootloader's page table, and this runs on the idle stack, so once the boot stack is
+    // unmapped the bootloader's memory is finished with
+    paging::unmap_boot_stack();
+    debug!(
+        "Reclaimed {} frames of boot memory",
+        physmem::init_reclaim(memory_map.iter())
+    );
+    debug!(
+        "Reclaimed {} frames from the initial heap",
+        allocator::reclaim_initial_region()
+    );
 
     acpi::init_bsp();
 
     // At this point, memory is fully working and in our control. The next thing to do is to bring up
     // the basic hardware
-    devices::init_bsp();
+    devices::init_bsp(&options);
+    acpi::enable_sci();
 
     // Before starting the APs, create our idle task and initialize the schedule
     let idle_task =
         scheduler::init(0, true, idle_thread_stack).expect("Failed to create idle task for CPU 0");
-    println!("idle task pid {}", idle_task.pid());
+    debug!("idle task pid {}", idle_task.pid());
 
     // Once the devices are broadly set up, start the other proessors
     devices::start_aps();
@@ -98,11 +247,27 @@ unsafe fn init_post_paging(
     // Spawn the init task
     {
         let init_task =
-            scheduler::spawn(move || userland_init(func)).expect("Failed to spawn init task");
-        println!("Spawned init task {}", init_task.pid());
+            scheduler::spawn(None, move || userland_init(func)).expect("Failed to spawn init task");
+        info!("Spawned init task {}", init_task.pid());
     }
 
-    crate::println!("CPU {} going idle", 0);
+    {
+        let shell_task = scheduler::spawn(None, crate::shell::run).expect("Failed to spawn shell");
+        info!("Spawned shell task {}", shell_task.pid());
+    }
+
+    {
+        let event_task = acpi::spawn_event_task().expect("Failed to spawn ACPI event task");
+        info!("Spawned ACPI event task {}", event_task.pid());
+    }
+
+    {
+        let release_task =
+            allocator::spawn_release_task().expect("Failed to spawn heap release task");
+        info!("Spawned heap release task {}", release_task.pid());
+    }
+
+    debug!("CPU {} going idle", 0);
 
     idle_loop();
 }
@@ -110,46 +275,130 @@ unsafe fn init_post_paging(
 pub unsafe fn kstart_ap(cpu_id: usize, idle_thread_stack: paging::KernelStack) -> ! {
     println!("Starting AP {}", cpu_id);
 
+    #[cfg(test)]
+    if cpu_id == test::FAILING_AP.load(Ordering::SeqCst) {
+        panic!("AP {} failing to start on purpose", cpu_id);
+    }
+
     let tcb_offset = paging::init_ap(cpu_id);
 
     let fault_stack = paging::allocate_kernel_stack(paging::DEFAULT_KERNEL_STACK_PAGES)
         .expect("Failed to allocate AP fault stack");
-    gdt::init_ap(tcb_offset, &idle_thread_stack, &fault_stack);
+    gdt::init_ap(tcb_offset, &idle_thread_stack, fault_stack);
     idt::init(false);
+    fpu::init();
+    syscall::init();
 
     CPU_ID.store(cpu_id, Ordering::SeqCst);
 
-    // Once the GDT has got the fault stack, we don't need it any more. We keep the idle
-    // thread stack because we need it for the idle task
-    let _ = core::mem::ManuallyDrop::new(fault_stack);
-
     devices::init_ap(cpu_id);
 
     // Create our idle task
     scheduler::init(cpu_id, false, idle_thread_stack).expect("Failed to create idle task for AP");
 
     // Finally, signal that we're done starting up
-    AP_READY.store(true, Ordering::SeqCst);
+    AP_READY[cpu_id].store(true, Ordering::SeqCst);
 
     while !BSP_READY.load(Ordering::SeqCst) {
         crate::interrupts::pause();
     }
 
-    crate::println!("CPU {} going idle", cpu_id);
+    debug!("CPU {} going idle", cpu_id);
 
     idle_loop()
 }
 
+/// Called by the BSP before it starts an AP
+pub fn begin_ap_startup(cpu_id: usize) {
+    assert!(
+        cpu_id < scheduler::MAX_CPUS,
+        "AP {} is out of range",
+        cpu_id
+    );
+    AP_FAILED[cpu_id].store(false, Ordering::SeqCst);
+    gdt::expect_cpu_without_tls();
+    STARTING_AP.store(cpu_id, Ordering::SeqCst);
+}
+
+/// Called by the BSP once the AP it started is ready, or has failed
+pub fn end_ap_startup() {
+    STARTING_AP.store(NO_STARTING_AP, Ordering::SeqCst);
+}
+
+pub fn ap_ready(cpu_id: usize) -> bool {
+    AP_READY[cpu_id].load(Ordering::SeqCst)
+}
+
+/// Whether a CPU is up and scheduling tasks. CPU 0 is the BSP.
+pub fn cpu_online(cpu_id: usize) -> bool {
+    cpu_id == 0 || (cpu_id < scheduler::MAX_CPUS && ap_ready(cpu_id))
+}
+
+/// How many CPUs are up. CPU ids are local APIC ids, so they aren't necessarily below this.
+pub fn cpu_count() -> usize {
+    (0..scheduler::MAX_CPUS)
+        .filter(|cpu_id| cpu_online(*cpu_id))
+        .count()
+}
+
+/// Whether the AP panicked before it was ready
+pub fn ap_failed(cpu_id: usize) -> bool {
+    AP_FAILED[cpu_id].load(Ordering::SeqCst)
+}
+
+// The panic handler can run before this CPU has thread local storage, so it can't use cpu_id
+fn is_starting_ap() -> bool {
+    let apic_id = CpuId::new()
+        .get_feature_info()
+        .map(|info| usize::from(info.initial_local_apic_id()));
+    apic_id == Some(STARTING_AP.load(Ordering::SeqCst))
+}
+
+// Tell the BSP that this AP isn't going to start, and stop. If the AP panicked holding a lock that
+// the BSP needs, the BSP will still get stuck, but at least it isn't waiting for a flag that will
+// never be set.
+fn fail_ap_startup() -> ! {
+    AP_FAILED[STARTING_AP.load(Ordering::SeqCst)].store(true, Ordering::SeqCst);
+    crate::interrupts::disable_and_halt()
+}
+
 fn userland_init(func: impl FnOnce() -> ! + 'static) -> ! {
-    println!("Running in userland_init");
+    info!("Running in userland_init");
     func()
 }
 
+// Ask MWAIT for C1, the shallowest sleep state. It wakes as quickly as HLT does.
+const MWAIT_HINT_C1: u32 = 0;
+
 pub fn idle_loop() -> ! {
+    use crate::interrupts;
+
+    let use_mwait = scheduler::mwait_supported();
+
     loop {
+        // The task in the idle loop can move to another CPU when it reschedules
+        let idle_state = scheduler::idle_state(cpu_id());
+        idle_state.enter_idle(use_mwait);
+
+        if idle_state.needs_resched().swap(false, Ordering::SeqCst) {
+            idle_state.leave_idle();
+            scheduler::reschedule();
+            continue;
+        }
+
         unsafe {
-            crate::interrupts::enable_and_halt();
+            if use_mwait {
+                interrupts::disable();
+                interrupts::monitor_mwait(idle_state.needs_resched(), MWAIT_HINT_C1);
+            } else {
+                interrupts::enable_and_halt();
+            }
+
+            // Whatever woke us has been handled by now, and tasks switch with interrupts disabled
+            interrupts::disable();
         }
+
+        idle_state.count_wakeup();
     }
 }
 
@@ -157,7 +406,18 @@ pub fn idle_loop() -> ! {
 #[cfg(not(test))]
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
+    // Nothing should interrupt the panic message, or run on a CPU that is in a bad way
+    unsafe { crate::interrupts::disable() };
+
+    // An AP that fails to start only stops itself, and leaves the devices to the rest of the system
+    if is_starting_ap() {
+        println!("{}", info);
+        fail_ap_startup();
+    }
+
+    crate::devices::quiesce_interrupts();
     println!("{}", info);
+    crate::log::dump();
     use crate::ipi::{ipi, IpiKind, IpiTarget};
     ipi(IpiKind::Halt, IpiTarget::Other);
     crate::interrupts::disable_and_halt()
@@ -166,5 +426,68 @@ fn panic(info: &PanicInfo) -> ! {
 #[cfg(test)]
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
+    // An AP that fails to start isn't a failed test, so leave it to the test that checks for it
+    if is_starting_ap() {
+        crate::serial_println!("{}", info);
+        fail_ap_startup();
+    }
+
     crate::test_panic_handler(info)
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloc::string::ToString;
+
+    #[test_case]
+    fn required_features_are_present() {
+        assert_eq!(missing_features().count(), 0);
+        assert_eq!(FeatureList(["a", "b"].iter().copied()).to_string(), "a, b");
+
+        let efer = unsafe { x86::msr::rdmsr(x86::msr::IA32_EFER) };
+        assert_ne!(efer & (1 << 11), 0, "No-execute is not enabled");
+    }
+
+    // kstart_ap panics on this AP, so that the BSP has to carry on without it
+    pub(super) static FAILING_AP: AtomicUsize = AtomicUsize::new(NO_STARTING_AP);
+
+    // The AP that start_aps keeps back in tests
+    fn spare_ap() -> Option<usize> {
+        let acpi_lock = crate::acpi::ACPI.lock();
+        acpi_lock
+            .as_ref()
+            .unwrap()
+            .acpi_context
+            .application_processors
+            .last()
+            .map(|ap| usize::from(ap.local_apic_id))
+            .filter(|ap| !cpu_online(*ap))
+    }
+
+    #[test_case]
+    fn failed_ap_does_not_stop_the_others() {
+        let ap = match spare_ap() {
+            Some(ap) => ap,
+            None => {
+                crate::skip_test("no spare AP");
+                return;
+            }
+        };
+        let online_before = cpu_count();
+
+        FAILING_AP.store(ap, Ordering::SeqCst);
+        let started = unsafe { crate::devices::start_ap(ap) };
+        FAILING_AP.store(NO_STARTING_AP, Ordering::SeqCst);
+
+        assert!(!started, "AP {} started", ap);
+        assert!(ap_failed(ap));
+        assert!(!cpu_online(ap));
+        assert_eq!(cpu_count(), online_before);
+
+        // Nothing makes it fail now, so it can be started again, and stays up for the other tests
+        assert!(unsafe { crate::devices::start_ap(ap) }, "AP {} failed", ap);
+        assert!(!ap_failed(ap));
+        assert_eq!(cpu_count(), online_before + 1);
+    }
+}
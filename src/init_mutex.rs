@@ -1,4 +1,7 @@
+use core::cell::UnsafeCell;
+use core::mem::MaybeUninit;
 use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{spin_loop_hint, AtomicU8, Ordering};
 use spin::{Mutex, MutexGuard};
 
 pub struct InitMutex<T> {
@@ -52,3 +55,120 @@ impl<'a, T> DerefMut for InitMutexGuard<'a, T> {
             .expect("InitMutexGuard has not been initialized")
     }
 }
+
+const ONCE_EMPTY: u8 = 0;
+const ONCE_INITIALIZING: u8 = 1;
+const ONCE_READY: u8 = 2;
+
+/// A value that is written once, usually during init, and then only read. Unlike InitMutex there
+/// is no lock to take once the value is set, so readers get a plain &T.
+pub struct Once<T> {
+    state: AtomicU8,
+    value: UnsafeCell<MaybeUninit<T>>,
+}
+
+// The value is only written while the state is INITIALIZING, which only one CPU can move it to,
+// and it is only read once the state is READY
+unsafe impl<T: Send + Sync> Sync for Once<T> {}
+unsafe impl<T: Send> Send for Once<T> {}
+
+impl<T> Once<T> {
+    pub const fn new() -> Self {
+        Self {
+            state: AtomicU8::new(ONCE_EMPTY),
+            value: UnsafeCell::new(MaybeUninit::uninit()),
+        }
+    }
+
+    /// Set the value. Panics if it has already been set.
+    pub fn set(&self, value: T) -> &T {
+        match self.try_set(value) {
+            Ok(value) => value,
+            Err(_) => panic!("Once value has already been set"),
+        }
+    }
+
+    /// Set the value, or give it back if the value has already been set
+    pub fn try_set(&self, value: T) -> Result<&T, T> {
+        if self
+            .state
+            .compare_exchange(
+                ONCE_EMPTY,
+                ONCE_INITIALIZING,
+                Ordering::Acquire,
+                Ordering::Acquire,
+            )
+            .is_err()
+        {
+            return Err(value);
+        }
+
+        Ok(unsafe { self.complete(value) })
+    }
+
+    /// Get the value, or None if it hasn't been set yet or is still being set
+    pub fn get(&self) -> Option<&T> {
+        if self.state.load(Ordering::Acquire) == ONCE_READY {
+            Some(unsafe { &*(*self.value.get()).as_ptr() })
+        } else {
+            None
+        }
+    }
+
+    /// Get the value, setting it with init if nobody has yet. If another CPU is setting the value
+    /// this waits for it to finish.
+    pub fn get_or_init(&self, init: impl FnOnce() -> T) -> &T {
+        loop {
+            match self.state.compare_exchange(
+                ONCE_EMPTY,
+                ONCE_INITIALIZING,
+                Ordering::Acquire,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => return unsafe { self.complete(init()) },
+                Err(ONCE_READY) => return self.get().unwrap(),
+                Err(_) => spin_loop_hint(),
+            }
+        }
+    }
+
+    // Must only be called by whoever moved the state to INITIALIZING
+    unsafe fn complete(&self, value: T) -> &T {
+        let slot = &mut *self.value.get();
+        *slot = MaybeUninit::new(value);
+        self.state.store(ONCE_READY, Ordering::Release);
+        &*slot.as_ptr()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn once_can_only_be_set_once() {
+        static ONCE: Once<usize> = Once::new();
+
+        assert_eq!(ONCE.get(), None);
+        assert_eq!(*ONCE.set(1), 1);
+        assert_eq!(ONCE.try_set(2), Err(2));
+        assert_eq!(ONCE.get(), Some(&1));
+        assert_eq!(*ONCE.get_or_init(|| 3), 1);
+    }
+
+    #[test_case]
+    fn once_get_during_init_returns_none() {
+        static ONCE: Once<usize> = Once::new();
+
+        // The init function runs with the Once in the initializing state, which is what another
+        // CPU would see if it raced with us
+        let value = ONCE.get_or_init(|| {
+            assert_eq!(ONCE.get(), None);
+            assert!(ONCE.try_set(5).is_err());
+            4
+        });
+
+        assert_eq!(*value, 4);
+        assert_eq!(ONCE.get(), Some(&4));
+    }
+}
@@ -0,0 +1,233 @@
+// Hardware breakpoints, using the debug registers. DR0-DR3 hold the addresses, DR7 says what kind
+// of access each one catches, and DR6 says which one fired. The debug registers belong to the CPU,
+// so a breakpoint only fires on the CPU that set it.
+
+use super::InterruptStack;
+
+const BREAKPOINT_COUNT: usize = 4;
+
+// DR6 reads as this when nothing has fired
+const DR6_CLEAR: usize = 0xffff_0ff0;
+
+// Resume flag, which stops an execute breakpoint firing again when the instruction is restarted
+const RFLAGS_RF: usize = 1 << 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(usize)]
+pub enum BreakpointKind {
+    Execute = 0b00,
+    Write = 0b01,
+    ReadWrite = 0b11,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BreakpointError {
+    NoFreeSlot,
+    InvalidLength,
+    Unaligned,
+}
+
+/// What the callback is told when a breakpoint fires
+#[derive(Debug, Clone, Copy)]
+pub struct BreakpointHit {
+    pub slot: usize,
+    pub address: usize,
+    pub kind: BreakpointKind,
+    pub rip: usize,
+}
+
+pub type BreakpointCallback = fn(&BreakpointHit);
+
+#[derive(Clone, Copy)]
+struct Breakpoint {
+    address: usize,
+    kind: BreakpointKind,
+    callback: BreakpointCallback,
+}
+
+#[thread_local]
+static mut BREAKPOINTS: [Option<Breakpoint>; BREAKPOINT_COUNT] = [None; BREAKPOINT_COUNT];
+
+unsafe fn read_dr(index: usize) -> usize {
+    let value: usize;
+    match index {
+        0 => asm!("mov {}, dr0", out(reg) value, options(nomem, nostack)),
+        1 => asm!("mov {}, dr1", out(reg) value, options(nomem, nostack)),
+        2 => asm!("mov {}, dr2", out(reg) value, options(nomem, nostack)),
+        3 => asm!("mov {}, dr3", out(reg) value, options(nomem, nostack)),
+        6 => asm!("mov {}, dr6", out(reg) value, options(nomem, nostack)),
+        7 => asm!("mov {}, dr7", out(reg) value, options(nomem, nostack)),
+        _ => panic!("Invalid debug register {}", index),
+    }
+    value
+}
+
+unsafe fn write_dr(index: usize, value: usize) {
+    match index {
+        0 => asm!("mov dr0, {}", in(reg) value, options(nomem, nostack)),
+        1 => asm!("mov dr1, {}", in(reg) value, options(nomem, nostack)),
+        2 => asm!("mov dr2, {}", in(reg) value, options(nomem, nostack)),
+        3 => asm!("mov dr3, {}", in(reg) value, options(nomem, nostack)),
+        6 => asm!("mov dr6, {}", in(reg) value, options(nomem, nostack)),
+        7 => asm!("mov dr7, {}", in(reg) value, options(nomem, nostack)),
+        _ => panic!("Invalid debug register {}", index),
+    }
+}
+
+// The LEN field encodings for each size. Execute breakpoints must use a length of 1.
+fn length_bits(len: usize) -> Option<usize> {
+    match len {
+        1 => Some(0b00),
+        2 => Some(0b01),
+        4 => Some(0b11),
+        8 => Some(0b10),
+        _ => None,
+    }
+}
+
+/// Set a breakpoint on this CPU, and return the slot it went in. The callback runs in the debug
+/// exception handler, so it must not take any locks that the code being watched might hold.
+pub fn set_hardware_breakpoint(
+    addr: usize,
+    len: usize,
+    kind: BreakpointKind,
+    callback: BreakpointCallback,
+) -> Result<usize, BreakpointError> {
+    let len_bits = match kind {
+        BreakpointKind::Execute if len != 1 => return Err(BreakpointError::InvalidLength),
+        _ => length_bits(len).ok_or(BreakpointError::InvalidLength)?,
+    };
+    if addr % len != 0 {
+        return Err(BreakpointError::Unaligned);
+    }
+
+    unsafe {
+        let slot = BREAKPOINTS
+            .iter()
+            .position(|breakpoint| breakpoint.is_none())
+            .ok_or(BreakpointError::NoFreeSlot)?;
+
+        BREAKPOINTS[slot] = Some(Breakpoint {
+            address: addr,
+            kind,
+            callback,
+        });
+
+        let control_shift = 16 + slot * 4;
+        let dr7 = read_dr(7) & !(0b1111 << control_shift);
+        write_dr(slot, addr);
+        write_dr(
+            7,
+            dr7 | (kind as usize | len_bits << 2) << control_shift | 1 << (slot * 2),
+        );
+
+        Ok(slot)
+    }
+}
+
+pub fn clear_hardware_breakpoint(slot: usize) {
+    assert!(slot < BREAKPOINT_COUNT, "Invalid breakpoint slot {}", slot);
+
+    unsafe {
+        write_dr(7, read_dr(7) & !(1 << (slot * 2)));
+        write_dr(slot, 0);
+        BREAKPOINTS[slot] = None;
+    }
+}
+
+/// Called from the debug exception. Returns false if none of our breakpoints fired, which leaves
+/// DR6 alone for whoever is looking at it.
+pub(super) unsafe fn handle_debug_exception(stack: &mut InterruptStack) -> bool {
+    let dr6 = read_dr(6);
+    let mut handled = false;
+
+    for slot in 0..BREAKPOINT_COUNT {
+        if dr6 & 1 << slot == 0 {
+            continue;
+        }
+
+        if let Some(breakpoint) = BREAKPOINTS[slot] {
+            (breakpoint.callback)(&BreakpointHit {
+                slot,
+                address: breakpoint.address,
+                kind: breakpoint.kind,
+                rip: stack.rip(),
+            });
+
+            // An execute breakpoint is a fault, so the instruction hasn't run yet
+            if breakpoint.kind == BreakpointKind::Execute {
+                stack.set_rflags(stack.rflags() | RFLAGS_RF);
+            }
+
+            handled = true;
+        }
+    }
+
+    if handled {
+        write_dr(6, DR6_CLEAR);
+    }
+    handled
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    static mut WATCHED: u64 = 0;
+
+    static HIT_COUNT: AtomicUsize = AtomicUsize::new(0);
+    static HIT_ADDRESS: AtomicUsize = AtomicUsize::new(0);
+
+    fn record_hit(hit: &BreakpointHit) {
+        assert_eq!(hit.kind, BreakpointKind::Write);
+        HIT_ADDRESS.store(hit.address, Ordering::SeqCst);
+        HIT_COUNT.fetch_add(1, Ordering::SeqCst);
+    }
+
+    #[test_case]
+    fn write_watchpoint_calls_back() {
+        let address = unsafe { &WATCHED as *const u64 as usize };
+
+        // The breakpoint is only set on this CPU, so don't move
+        crate::interrupts::without_interrupts(|| {
+            let slot = set_hardware_breakpoint(address, 8, BreakpointKind::Write, record_hit)
+                .expect("Failed to set breakpoint");
+
+            unsafe {
+                // Reads don't trigger a write watchpoint
+                assert_eq!(core::ptr::read_volatile(&WATCHED), 0);
+                assert_eq!(HIT_COUNT.load(Ordering::SeqCst), 0);
+
+                core::ptr::write_volatile(&mut WATCHED, 0x1234);
+            }
+
+            clear_hardware_breakpoint(slot);
+        });
+
+        assert_eq!(HIT_COUNT.load(Ordering::SeqCst), 1);
+        assert_eq!(HIT_ADDRESS.load(Ordering::SeqCst), address);
+
+        // Nothing fires once the breakpoint is cleared
+        unsafe { core::ptr::write_volatile(&mut WATCHED, 0x5678) };
+        assert_eq!(HIT_COUNT.load(Ordering::SeqCst), 1);
+    }
+
+    #[test_case]
+    fn invalid_breakpoints_are_rejected() {
+        fn ignore_hit(_: &BreakpointHit) {}
+
+        assert_eq!(
+            set_hardware_breakpoint(0x1000, 2, BreakpointKind::Execute, ignore_hit),
+            Err(BreakpointError::InvalidLength)
+        );
+        assert_eq!(
+            set_hardware_breakpoint(0x1000, 3, BreakpointKind::Write, ignore_hit),
+            Err(BreakpointError::InvalidLength)
+        );
+        assert_eq!(
+            set_hardware_breakpoint(0x1004, 8, BreakpointKind::ReadWrite, ignore_hit),
+            Err(BreakpointError::Unaligned)
+        );
+    }
+}
@@ -0,0 +1,67 @@
+// Instructions that are allowed to fault. Each entry pairs the address of an instruction with the
+// address to carry on from if it page faults, and the linker gathers them all between
+// __ex_table_start and __ex_table_end. Code that touches memory it doesn't trust adds an entry with
+// exception_table_entry! next to the instruction.
+
+#[repr(C)]
+struct ExceptionTableEntry {
+    fault: usize,
+    fixup: usize,
+}
+
+extern "C" {
+    static __ex_table_start: ExceptionTableEntry;
+    static __ex_table_end: ExceptionTableEntry;
+}
+
+/// Emit an exception table entry from assembly, for use inside intel_asm!. fault and fixup are the
+/// names of labels.
+#[macro_export]
+macro_rules! exception_table_entry {
+    ($fault:expr, $fixup:expr) => {
+        concat!(
+            ".pushsection .ex_table, \"a\"\n",
+            ".balign 8\n",
+            ".quad ",
+            $fault,
+            ", ",
+            $fixup,
+            "\n",
+            ".popsection\n",
+        )
+    };
+}
+
+fn entries() -> &'static [ExceptionTableEntry] {
+    unsafe {
+        let start = &__ex_table_start as *const ExceptionTableEntry;
+        let end = &__ex_table_end as *const ExceptionTableEntry;
+        core::slice::from_raw_parts(start, end.offset_from(start) as usize)
+    }
+}
+
+/// Where to resume if the instruction at rip faults, or None if it isn't allowed to
+pub fn find_fixup(rip: usize) -> Option<usize> {
+    entries()
+        .iter()
+        .find(|entry| entry.fault == rip)
+        .map(|entry| entry.fixup)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn only_listed_instructions_have_fixups() {
+        assert!(!entries().is_empty());
+        for entry in entries() {
+            assert_eq!(find_fixup(entry.fault), Some(entry.fixup));
+        }
+
+        assert_eq!(
+            find_fixup(only_listed_instructions_have_fixups as usize),
+            None
+        );
+    }
+}
@@ -1,15 +1,101 @@
-use crate::{interrupt_error, interrupt_stack};
+use super::exception_table;
+use crate::{exception_table_entry, intel_asm, interrupt_error, interrupt_stack};
+#[cfg(test)]
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+// Write a byte, returning 1 in al if the write page faulted or 0 if it worked. The exception table
+// sends a fault on the write to probe_write_fault.
+intel_asm!(
+    ".global probe_write\n",
+    ".type probe_write, @function\n",
+    ".section .text.probe_write, \"ax\", @progbits\n",
+    "probe_write:\n",
+    "xor eax, eax\n",
+    "probe_write_access:\n",
+    "mov [rdi], sil\n",
+    "ret\n",
+    "probe_write_fault:\n",
+    "mov eax, 1\n",
+    "ret\n",
+    exception_table_entry!("probe_write_access", "probe_write_fault"),
+    ".size probe_write, . - probe_write\n",
+    ".text\n",
+);
+
+extern "C" {
+    fn probe_write(addr: *mut u8, value: u8) -> bool;
+}
+
+/// Write a byte to an address that might not be writable. Returns false, rather than panicking,
+/// if the write page faults.
+pub unsafe fn try_write_byte(addr: *mut u8, value: u8) -> bool {
+    !probe_write(addr, value)
+}
+
+// Read from a non-canonical address while the general protection vector is marked not present. The
+// protection fault can't be delivered, so the CPU raises a double fault instead, and the double
+// fault handler resumes after the read with 1 in al.
+#[cfg(test)]
+intel_asm!(
+    ".global probe_double_fault\n",
+    ".type probe_double_fault, @function\n",
+    ".section .text.probe_double_fault, \"ax\", @progbits\n",
+    "probe_double_fault:\n",
+    "xor eax, eax\n",
+    "mov rdi, 0x8000000000000000\n",
+    ".global probe_double_fault_access\n",
+    "probe_double_fault_access:\n",
+    "mov dil, [rdi]\n",
+    ".global probe_double_fault_resume\n",
+    "probe_double_fault_resume:\n",
+    "ret\n",
+    ".size probe_double_fault, . - probe_double_fault\n",
+    ".text\n",
+);
+
+#[cfg(test)]
+extern "C" {
+    fn probe_double_fault() -> bool;
+    static probe_double_fault_access: u8;
+    static probe_double_fault_resume: u8;
+}
+
+// Where the double fault handler's stack was the last time it recovered from probe_double_fault
+#[cfg(test)]
+static DOUBLE_FAULT_STACK: AtomicUsize = AtomicUsize::new(0);
+
+/// Cause a double fault on this CPU and recover from it. Returns the address of the double fault
+/// handler's stack frame, or None if there was no double fault.
+#[cfg(test)]
+pub(crate) unsafe fn force_double_fault() -> Option<usize> {
+    // The IDT is per CPU, so we can't be moved to another one while the vector is missing
+    crate::interrupts::without_interrupts(|| {
+        let was_present = crate::idt::set_present(13, false);
+        let faulted = probe_double_fault();
+        crate::idt::set_present(13, was_present);
+
+        if faulted {
+            Some(DOUBLE_FAULT_STACK.load(Ordering::SeqCst))
+        } else {
+            None
+        }
+    })
+}
 
 interrupt_stack!(divide_by_zero, |stack| {
     panic!("Divide by zero: {:x?}", stack);
 });
 
 interrupt_stack!(debug, |stack| {
+    if super::breakpoint::handle_debug_exception(stack) {
+        return;
+    }
+
     panic!("Debug exception: {:x?}", stack);
 });
 
-interrupt_stack!(non_maskable, |stack| {
-    panic!("Non maskable exception: {:x?}", stack);
+interrupt_stack!(paranoid non_maskable, |stack| {
+    super::nmi::handle(stack);
 });
 
 interrupt_stack!(breakpoint, |stack| {
@@ -29,10 +115,25 @@ interrupt_stack!(invalid_opcode, |stack| {
 });
 
 interrupt_stack!(device_not_available, |stack| {
+    // The FPU is switched lazily, so this is how a task gets its FPU state back
+    if crate::scheduler::restore_fpu_state() {
+        return;
+    }
+
     panic!("Device not available exception: {:x?}", stack);
 });
 
 interrupt_error!(double_fault, |stack| {
+    #[cfg(test)]
+    {
+        if stack.inner.rip() == &probe_double_fault_access as *const u8 as usize {
+            DOUBLE_FAULT_STACK.store(stack as *const _ as usize, Ordering::SeqCst);
+            stack.inner.set_rip(&probe_double_fault_resume as *const u8 as usize);
+            stack.inner.set_rax(1);
+            return;
+        }
+    }
+
     panic!("Double fault exception: {:x?}", stack);
 });
 
@@ -56,6 +157,12 @@ interrupt_error!(page, |stack| {
     let cr2: usize;
     asm!("mov {}, cr2", out(reg) cr2);
 
+    // Code that expects to fault has somewhere to go instead
+    if let Some(fixup) = exception_table::find_fixup(stack.inner.rip()) {
+        stack.inner.set_rip(fixup);
+        return;
+    }
+
     panic!("Page fault: cr2: {:#x} {:x?}", cr2, stack);
 });
 
@@ -67,7 +174,7 @@ interrupt_error!(alignment_check, |stack| {
     panic!("Alignment check exception: {:x?}", stack);
 });
 
-interrupt_stack!(machine_check, |stack| {
+interrupt_stack!(paranoid machine_check, |stack| {
     panic!("Machine check exception: {:x?}", stack);
 });
 
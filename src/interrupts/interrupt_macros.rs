@@ -41,12 +41,49 @@ pub struct IretRegisters {
 #[derive(Default, Debug, Copy, Clone)]
 #[repr(packed)]
 pub struct InterruptStack {
+    // The interrupted fs base, not the selector
     pub fs: usize,
     pub preserved: PreservedRegisters,
     pub scratch: ScratchRegisters,
     pub iret: IretRegisters,
 }
 
+// The stack is packed and made of nothing but usize, so each register is at a fixed word index.
+// Taking a reference to a packed field isn't allowed, so the accessors go through raw pointers.
+macro_rules! stack_registers {
+    ($($index:expr => $get:ident, $set:ident;)+) => {
+        impl InterruptStack {
+            $(
+                pub fn $get(&self) -> usize {
+                    let words = self as *const Self as *const usize;
+                    unsafe { core::ptr::read_unaligned(words.add($index)) }
+                }
+
+                pub fn $set(&mut self, value: usize) {
+                    let words = self as *mut Self as *mut usize;
+                    unsafe { core::ptr::write_unaligned(words.add($index), value) }
+                }
+            )+
+        }
+    };
+}
+
+stack_registers! {
+    7 => r11, set_r11;
+    8 => r10, set_r10;
+    9 => r9, set_r9;
+    10 => r8, set_r8;
+    11 => rsi, set_rsi;
+    12 => rdi, set_rdi;
+    13 => rdx, set_rdx;
+    14 => rcx, set_rcx;
+    15 => rax, set_rax;
+    16 => rip, set_rip;
+    17 => cs, set_cs;
+    18 => rflags, set_rflags;
+    19 => rsp, set_rsp;
+}
+
 #[derive(Default, Debug, Copy, Clone)]
 #[repr(packed)]
 pub struct InterruptErrorStack {
@@ -151,17 +188,56 @@ macro_rules! pop_preserved {
 macro_rules! push_fs {
     () => {
         "
-        // Push fs
-        push fs
-
-        // Load kernel tls
+        // Save the interrupted fs base, which is the user's if the interrupt came from ring 3, and
+        // load this CPU's thread local block, which the kernel gs base MSR holds.
         //
-        // NOTE: We can't load the value directly into `fs`. So we need to use a
-        // scratch register (as preserved registers aren't backed up by the
-        // interrupt! macro) to store it. We also can't use `rax` as the temporary
-        // value, as during errors that's already used for the error code.
-        mov rcx, 0x18
-        mov fs, cx
+        // NOTE: During errors rax already holds the error code, so it is kept on the stack while
+        // rdmsr and wrmsr use it. rcx and rdx have already been saved.
+        push rax
+        mov ecx, 0xc0000100
+        rdmsr
+        shl rdx, 32
+        or rax, rdx
+        push rax
+        mov ecx, 0xc0000102
+        rdmsr
+        mov ecx, 0xc0000100
+        wrmsr
+        pop rax
+        xchg [rsp], rax
+    "
+    };
+}
+#[macro_export]
+macro_rules! push_fs_paranoid {
+    () => {
+        "
+        // push_fs for NMIs and machine checks, which can arrive inside syscall_entry between its
+        // two swapgs. In that window the gs base holds this CPU's thread local block and the kernel
+        // gs base holds the user's gs base, so the thread local block is read from whichever MSR
+        // holds it at the interrupted rip. That sits above the two values pushed here, the
+        // registers interrupt_stack has saved and rax.
+        push rax
+        mov ecx, 0xc0000100
+        rdmsr
+        shl rdx, 32
+        or rax, rdx
+        push rax
+        mov ecx, 0xc0000102
+        mov rax, [rsp + 17 * 8]
+        lea rdx, [rip + syscall_entry]
+        cmp rax, rdx
+        jbe 2f
+        lea rdx, [rip + syscall_gs_restored]
+        cmp rax, rdx
+        jae 2f
+        mov ecx, 0xc0000101
+    2:
+        rdmsr
+        mov ecx, 0xc0000100
+        wrmsr
+        pop rax
+        xchg [rsp], rax
     "
     };
 }
@@ -169,8 +245,12 @@ macro_rules! push_fs {
 macro_rules! pop_fs {
     () => {
         "
-        // Pop fs
-        pop fs
+        // Put the interrupted fs base back. The scratch registers are restored after this.
+        mov ecx, 0xc0000100
+        mov eax, [rsp]
+        mov edx, [rsp + 4]
+        wrmsr
+        add rsp, 8
     "
     };
 }
@@ -178,6 +258,13 @@ macro_rules! pop_fs {
 #[macro_export]
 macro_rules! interrupt_stack {
     ($name:ident, |$stack:ident| $code:block) => {
+        $crate::interrupt_stack!(@with push_fs, $name, |$stack| $code);
+    };
+    // For NMIs and machine checks, which can arrive while the gs base is swapped
+    (paranoid $name:ident, |$stack:ident| $code:block) => {
+        $crate::interrupt_stack!(@with push_fs_paranoid, $name, |$stack| $code);
+    };
+    (@with $push_fs:ident, $name:ident, |$stack:ident| $code:block) => {
         paste::item! {
             #[no_mangle]
             unsafe extern "C" fn [<__interrupt_ $name>](stack: *mut $crate::interrupts::InterruptStack) {
@@ -195,7 +282,7 @@ macro_rules! interrupt_stack {
                 "push rax\n",
                 $crate::push_scratch!(),
                 $crate::push_preserved!(),
-                $crate::push_fs!(),
+                $crate::$push_fs!(),
 
                 // TODO: Map PTI
                 // $crate::arch::x86_64::pti::map();
@@ -303,3 +390,29 @@ macro_rules! interrupt_error {
         }
     };
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn accessors_read_and_write_the_right_registers() {
+        assert_eq!(core::mem::size_of::<InterruptStack>(), 21 * 8);
+
+        let mut stack = InterruptStack::default();
+        stack.iret.rip = 0x1000;
+        stack.scratch.rdi = 0x2000;
+        stack.iret.rsp = 0x3000;
+        assert_eq!(stack.rip(), 0x1000);
+        assert_eq!(stack.rdi(), 0x2000);
+        assert_eq!(stack.rsp(), 0x3000);
+
+        stack.set_rip(0xffff_8000_1234_5678);
+        stack.set_rax(42);
+        let (rip, rax, cs) = (stack.iret.rip, stack.scratch.rax, stack.iret.cs);
+        assert_eq!(rip, 0xffff_8000_1234_5678);
+        assert_eq!(rax, 42);
+        assert_eq!(cs, 0);
+        assert_eq!(stack.rip(), 0xffff_8000_1234_5678);
+    }
+}
@@ -2,7 +2,7 @@ use crate::interrupt;
 
 interrupt!(tlb, || {
     crate::devices::local_apic::local_apic_access().eoi();
-    x86::tlb::flush_all();
+    crate::paging::flush_local_tlb();
 });
 
 interrupt!(halt, || {
@@ -10,6 +10,12 @@ interrupt!(halt, || {
     crate::interrupts::disable_and_halt()
 });
 
+// Another CPU has work for this one, which it picks up when it returns to its idle loop
+interrupt!(reschedule, || {
+    crate::devices::local_apic::local_apic_access().eoi();
+    crate::scheduler::wake_cpu(crate::cpu_id());
+});
+
 interrupt!(ipi_timer, || {
     crate::devices::local_apic::local_apic_access().eoi();
     //crate::println!("AP timer");
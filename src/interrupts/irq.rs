@@ -1,13 +1,162 @@
 use crate::ipi::{ipi, IpiKind, IpiTarget};
-use crate::{interrupt, interrupt_stack};
+use crate::sync::IrqMutex;
+use crate::{intel_asm, interrupt, interrupt_error, interrupt_stack};
 
 interrupt_stack!(timer, |_stack| {
+    crate::devices::clock::tick_interrupt();
     crate::devices::local_apic::local_apic_access().eoi();
 
     //crate::println!("TIMER INTERRUPT");
     ipi(IpiKind::Timer, IpiTarget::Other);
+
+    crate::devices::timer::tick();
+    crate::scheduler::balance_if_due();
+});
+
+// The local APIC timer marks the end of a time slice. A task in ring 3 is preempted by it. Kernel
+// tasks run with interrupts disabled, so otherwise it only comes in while the CPU waits in its
+// idle loop, and all it does is get it to look for work.
+interrupt_stack!(local_timer, |stack| {
+    crate::devices::local_apic::local_apic_access().eoi();
+    if stack.cs() & 3 == 3 {
+        crate::scheduler::preempt_user_task();
+    } else {
+        crate::scheduler::wake_cpu(crate::cpu_id());
+    }
 });
 
 interrupt!(spurious, || {
     panic!("Spurious interrupt");
 });
+
+// The IO APIC routes legacy IRQs 1 to 15 to the BSP on vectors 33 to 47 (IRQ 0 is the timer, which
+// has its own handler). Drivers that own one of these IRQs register a handler for it at runtime.
+static LEGACY_HANDLERS: IrqMutex<[Option<fn()>; 16]> = IrqMutex::new([None; 16]);
+
+pub fn register_legacy_handler(irq: u8, handler: fn()) {
+    assert!(irq > 0 && irq < 16, "Invalid legacy IRQ {}", irq);
+
+    let mut handlers = LEGACY_HANDLERS.lock();
+    assert!(
+        handlers[irq as usize].is_none(),
+        "Legacy IRQ {} already has a handler",
+        irq
+    );
+    handlers[irq as usize] = Some(handler);
+}
+
+/// Swap in a different handler for a legacy IRQ, or none, and return the one it had
+pub fn replace_legacy_handler(irq: u8, handler: Option<fn()>) -> Option<fn()> {
+    assert!(irq > 0 && irq < 16, "Invalid legacy IRQ {}", irq);
+
+    core::mem::replace(&mut LEGACY_HANDLERS.lock()[irq as usize], handler)
+}
+
+fn legacy_irq(irq: u8) {
+    let handler = LEGACY_HANDLERS.lock()[irq as usize];
+
+    // The handler runs before the EOI so that it can quiet a level triggered source first
+    match handler {
+        Some(handler) => handler(),
+        None => crate::warn!("Unhandled legacy IRQ {}", irq),
+    }
+
+    crate::devices::local_apic::local_apic_access().eoi();
+}
+
+interrupt!(legacy_irq1, || { legacy_irq(1) });
+interrupt!(legacy_irq2, || { legacy_irq(2) });
+interrupt!(legacy_irq3, || { legacy_irq(3) });
+interrupt!(legacy_irq4, || { legacy_irq(4) });
+interrupt!(legacy_irq5, || { legacy_irq(5) });
+interrupt!(legacy_irq6, || { legacy_irq(6) });
+interrupt!(legacy_irq7, || { legacy_irq(7) });
+interrupt!(legacy_irq8, || { legacy_irq(8) });
+interrupt!(legacy_irq9, || { legacy_irq(9) });
+interrupt!(legacy_irq10, || { legacy_irq(10) });
+interrupt!(legacy_irq11, || { legacy_irq(11) });
+interrupt!(legacy_irq12, || { legacy_irq(12) });
+interrupt!(legacy_irq13, || { legacy_irq(13) });
+interrupt!(legacy_irq14, || { legacy_irq(14) });
+interrupt!(legacy_irq15, || { legacy_irq(15) });
+
+pub static LEGACY_IRQS: [unsafe extern "C" fn(); 15] = [
+    legacy_irq1,
+    legacy_irq2,
+    legacy_irq3,
+    legacy_irq4,
+    legacy_irq5,
+    legacy_irq6,
+    legacy_irq7,
+    legacy_irq8,
+    legacy_irq9,
+    legacy_irq10,
+    legacy_irq11,
+    legacy_irq12,
+    legacy_irq13,
+    legacy_irq14,
+    legacy_irq15,
+];
+
+// Every vector has a stub that pushes its number where an error code would go and jumps to the
+// handler below, so that anything the IDT has nothing else for can still say which vector it was.
+// The stubs are all the same size, so a vector's stub is found by its number.
+const UNHANDLED_STUB_SIZE: usize = 16;
+
+intel_asm!(
+    ".global unhandled_vector_stubs\n",
+    ".section .text.unhandled_vector_stubs, \"ax\", @progbits\n",
+    ".balign 16\n",
+    "unhandled_vector_stubs:\n",
+    ".set unhandled_vector_number, 0\n",
+    ".rept 256\n",
+    // push imm32, spelled out so the assembler can't pick a shorter form for small vectors
+    ".byte 0x68\n",
+    ".long unhandled_vector_number\n",
+    "jmp unhandled\n",
+    ".balign 16\n",
+    ".set unhandled_vector_number, unhandled_vector_number + 1\n",
+    ".endr\n",
+    ".text\n",
+);
+
+extern "C" {
+    static unhandled_vector_stubs: u8;
+}
+
+/// The address of the catch-all handler for vector
+pub fn unhandled_vector_stub(vector: u8) -> usize {
+    let stubs = unsafe { &unhandled_vector_stubs as *const u8 as usize };
+    stubs + usize::from(vector) * UNHANDLED_STUB_SIZE
+}
+
+interrupt_error!(unhandled, |stack| {
+    let vector = stack.code as u8;
+    crate::warn!(
+        "Unhandled interrupt vector {:#x} at {:#x}",
+        vector,
+        stack.inner.rip()
+    );
+
+    // Only a delivered interrupt needs an EOI, and not an int instruction that used the vector
+    if let Some(local_apic) = crate::devices::local_apic::local_apic_access_safe() {
+        if local_apic.in_service(vector) {
+            local_apic.eoi();
+        }
+    }
+});
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn unassigned_vector_is_logged() {
+        let log = crate::log::capture(|| unsafe { asm!("int 0x90", options(nomem, nostack)) });
+        assert!(
+            log.contains("Unhandled interrupt vector 0x90 at "),
+            "{}",
+            log
+        );
+    }
+}
@@ -1,10 +1,19 @@
+mod breakpoint;
+pub mod exception_table;
 pub mod exceptions;
 mod interrupt_macros;
 pub mod ipi;
 pub mod irq;
+pub mod nmi;
 
+pub use breakpoint::{
+    clear_hardware_breakpoint, set_hardware_breakpoint, BreakpointCallback, BreakpointError,
+    BreakpointHit, BreakpointKind,
+};
 pub use interrupt_macros::{InterruptErrorStack, InterruptStack};
 
+use core::sync::atomic::{AtomicBool, Ordering};
+
 /// Clear interrupts
 #[inline(always)]
 pub unsafe fn disable() {
@@ -17,6 +26,24 @@ pub unsafe fn enable() {
     asm!("sti", options(nomem, nostack));
 }
 
+const RFLAGS_IF: u64 = 1 << 9;
+
+/// Read RFLAGS
+#[inline(always)]
+pub fn raw_rflags() -> u64 {
+    let rflags: u64;
+    unsafe {
+        asm!("pushfq; pop {}", out(reg) rflags, options(nomem, preserves_flags));
+    }
+    rflags
+}
+
+/// Whether interrupts are enabled on this CPU
+#[inline(always)]
+pub fn are_enabled() -> bool {
+    raw_rflags() & RFLAGS_IF != 0
+}
+
 /// Set interrupts and halt
 /// This will atomically wait for the next interrupt
 /// Performing enable followed by halt is not guaranteed to be atomic, use this instead!
@@ -25,6 +52,34 @@ pub unsafe fn enable_and_halt() {
     asm!("sti; hlt", options(nomem, nostack));
 }
 
+/// Start watching the cache line that contains addr, so that a write to it ends the next mwait
+#[inline(always)]
+pub unsafe fn monitor(addr: *const u8) {
+    asm!("monitor", in("rax") addr, in("ecx") 0, in("edx") 0, options(nostack));
+}
+
+/// Set interrupts and mwait
+/// Like enable_and_halt, the interrupt shadow of sti covers the mwait, so an interrupt can't
+/// slip in between them and be missed
+#[inline(always)]
+pub unsafe fn enable_and_mwait(hints: u32) {
+    asm!("sti; mwait", in("eax") hints, in("ecx") 0, options(nomem, nostack));
+}
+
+/// Wait until flag is set or an interrupt arrives. Call with interrupts disabled, and they will be
+/// enabled on return.
+#[inline(always)]
+pub unsafe fn monitor_mwait(flag: &AtomicBool, hints: u32) {
+    monitor(flag as *const AtomicBool as *const u8);
+
+    // The flag may have been set before the monitor was armed, and then nothing would wake us
+    if flag.load(Ordering::SeqCst) {
+        enable();
+    } else {
+        enable_and_mwait(hints);
+    }
+}
+
 /// Set interrupts and nop
 /// This will enable interrupts and allow the IF flag to be processed
 /// Simply enabling interrupts does not gurantee that they will trigger, use this instead!
@@ -54,3 +109,87 @@ pub fn disable_and_halt() -> ! {
         asm!("cli; hlt", options(noreturn));
     }
 }
+
+// How many without_interrupts regions this CPU is inside, and whether interrupts were enabled
+// when it entered the outermost one
+#[thread_local]
+static mut DISABLE_DEPTH: usize = 0;
+#[thread_local]
+static mut ENABLE_ON_EXIT: bool = false;
+
+/// Run func with interrupts disabled on this CPU. Regions can nest, and interrupts only go back on
+/// when the outermost one returns, and only if they were on when it was entered. func must not
+/// reschedule, because the depth belongs to the CPU rather than the task.
+pub fn without_interrupts<R>(func: impl FnOnce() -> R) -> R {
+    let enabled = are_enabled();
+    unsafe {
+        disable();
+        if DISABLE_DEPTH == 0 {
+            ENABLE_ON_EXIT = enabled;
+        }
+        DISABLE_DEPTH += 1;
+    }
+
+    let result = func();
+
+    unsafe {
+        DISABLE_DEPTH -= 1;
+        if DISABLE_DEPTH == 0 && ENABLE_ON_EXIT {
+            enable();
+        }
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn interrupt_flag() -> bool {
+        raw_rflags() & RFLAGS_IF != 0
+    }
+
+    #[test_case]
+    fn are_enabled_tracks_the_interrupt_flag() {
+        let were_enabled = are_enabled();
+
+        unsafe { enable() };
+        assert!(are_enabled());
+        assert!(interrupt_flag());
+
+        unsafe { disable() };
+        assert!(!are_enabled());
+        assert!(!interrupt_flag());
+
+        // Bit 1 of RFLAGS is always set
+        assert_ne!(raw_rflags() & 2, 0);
+
+        if were_enabled {
+            unsafe { enable() };
+        }
+    }
+
+    #[test_case]
+    fn nested_regions_enable_interrupts_at_the_outermost_exit() {
+        let were_enabled = interrupt_flag();
+        unsafe { enable() };
+
+        let inner_exit = without_interrupts(|| {
+            assert!(!interrupt_flag());
+            without_interrupts(|| assert!(!interrupt_flag()));
+            interrupt_flag()
+        });
+        assert!(!inner_exit);
+        assert!(interrupt_flag());
+
+        // Regions entered with interrupts disabled leave them that way
+        unsafe { disable() };
+        without_interrupts(|| without_interrupts(|| ()));
+        assert!(!interrupt_flag());
+
+        if were_enabled {
+            unsafe { enable() };
+        }
+    }
+}
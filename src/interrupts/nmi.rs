@@ -0,0 +1,142 @@
+// Non maskable interrupts. The chipset raises one for a system error or an IO channel check, and
+// says which in system control port B. Anything else was sent by the kernel with send_nmi, which
+// leaves a note for the CPU it sends it to first. An NMI can arrive in the middle of anything,
+// including code holding the log's locks, so the handler takes no locks and only logs if it can.
+
+use super::InterruptStack;
+use crate::io_port::{Io, Port};
+use crate::log::{try_log, Level};
+use crate::scheduler::MAX_CPUS;
+use core::sync::atomic::{AtomicBool, AtomicU8, AtomicUsize, Ordering};
+
+const SYSTEM_ERROR: u8 = 0x80;
+const IO_CHECK: u8 = 0x40;
+
+// Setting these turns the sources off, and turning them back on clears them
+const SYSTEM_ERROR_DISABLE: u8 = 0x04;
+const IO_CHECK_DISABLE: u8 = 0x08;
+
+// Only the low four bits of the port can be written
+const WRITABLE_BITS: u8 = 0x0f;
+
+const DELIVERY_MODE_NMI: u32 = 0b100 << 8;
+const LEVEL_ASSERT: u32 = 1 << 14;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum NmiReason {
+    None = 0,
+    SystemError = 1,
+    IoCheck = 2,
+    Software = 3,
+    Unknown = 4,
+}
+
+impl NmiReason {
+    fn from_u8(reason: u8) -> Self {
+        match reason {
+            0 => Self::None,
+            1 => Self::SystemError,
+            2 => Self::IoCheck,
+            3 => Self::Software,
+            _ => Self::Unknown,
+        }
+    }
+}
+
+const NOT_PENDING: AtomicBool = AtomicBool::new(false);
+static SOFTWARE_NMI_PENDING: [AtomicBool; MAX_CPUS] = [NOT_PENDING; MAX_CPUS];
+
+const NO_REASON: AtomicU8 = AtomicU8::new(NmiReason::None as u8);
+static LAST_REASON: [AtomicU8; MAX_CPUS] = [NO_REASON; MAX_CPUS];
+
+const NO_NMIS: AtomicUsize = AtomicUsize::new(0);
+static NMI_COUNT: [AtomicUsize; MAX_CPUS] = [NO_NMIS; MAX_CPUS];
+
+/// Send an NMI to a CPU, for when it has to be reached whatever it is doing, as a watchdog would
+pub fn send_nmi(cpu_id: usize) {
+    use crate::devices::local_apic::local_apic_access;
+
+    SOFTWARE_NMI_PENDING[cpu_id].store(true, Ordering::SeqCst);
+    local_apic_access().set_icr(cpu_id as u32, DELIVERY_MODE_NMI | LEVEL_ASSERT);
+}
+
+/// Why the last NMI on a CPU came, and how many it has had
+pub fn last_nmi(cpu_id: usize) -> (NmiReason, usize) {
+    (
+        NmiReason::from_u8(LAST_REASON[cpu_id].load(Ordering::SeqCst)),
+        NMI_COUNT[cpu_id].load(Ordering::SeqCst),
+    )
+}
+
+pub fn handle(stack: &InterruptStack) {
+    let cpu_id = crate::cpu_id();
+    let mut status_port = Port::<u8, 0x61>::new();
+    let status = status_port.read();
+
+    let reason = if status & SYSTEM_ERROR != 0 {
+        NmiReason::SystemError
+    } else if status & IO_CHECK != 0 {
+        NmiReason::IoCheck
+    } else if SOFTWARE_NMI_PENDING[cpu_id].swap(false, Ordering::SeqCst) {
+        NmiReason::Software
+    } else {
+        NmiReason::Unknown
+    };
+    LAST_REASON[cpu_id].store(reason as u8, Ordering::SeqCst);
+    NMI_COUNT[cpu_id].fetch_add(1, Ordering::SeqCst);
+
+    match reason {
+        NmiReason::SystemError | NmiReason::IoCheck => {
+            let enabled = status & WRITABLE_BITS & !(SYSTEM_ERROR_DISABLE | IO_CHECK_DISABLE);
+            status_port.write(enabled | SYSTEM_ERROR_DISABLE | IO_CHECK_DISABLE);
+            status_port.write(enabled);
+            try_log(
+                Level::Error,
+                format_args!("NMI from hardware: {:?}", reason),
+            );
+        }
+
+        // The task that was running is the one a watchdog wants to know about
+        NmiReason::Software => match crate::scheduler::current_pid() {
+            Some(pid) => try_log(
+                Level::Warn,
+                format_args!("NMI from software in task {:#x}: {:x?}", pid, stack),
+            ),
+            None => try_log(
+                Level::Warn,
+                format_args!("NMI from software before any task: {:x?}", stack),
+            ),
+        },
+
+        _ => try_log(
+            Level::Warn,
+            format_args!("NMI for no known reason at {:#x}", stack.rip()),
+        ),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::devices::local_apic::local_apic_access;
+
+    #[test_case]
+    fn software_nmi_is_recognized() {
+        // The NMI has to come back to this CPU, and APIC IDs are CPU IDs
+        let cpu_id = crate::cpu_id();
+        assert_eq!(local_apic_access().id() as usize, cpu_id);
+
+        let (_, count) = last_nmi(cpu_id);
+        send_nmi(cpu_id);
+        for _ in 0..1_000_000 {
+            if last_nmi(cpu_id).1 != count {
+                break;
+            }
+            crate::interrupts::pause();
+        }
+
+        assert_eq!(last_nmi(cpu_id), (NmiReason::Software, count + 1));
+        assert!(!SOFTWARE_NMI_PENDING[cpu_id].load(Ordering::SeqCst));
+    }
+}
@@ -7,6 +7,93 @@ pub trait Io {
     fn read(&self) -> Self::Value;
 }
 
+/// A value that can be transferred with a single in or out instruction
+pub trait PortValue: Copy + private::Sealed {
+    unsafe fn read_port(port: u16) -> Self;
+    unsafe fn write_port(port: u16, value: Self);
+}
+
+mod private {
+    pub trait Sealed {}
+
+    impl Sealed for u8 {}
+    impl Sealed for u16 {}
+    impl Sealed for u32 {}
+}
+
+impl PortValue for u8 {
+    #[inline(always)]
+    unsafe fn read_port(port: u16) -> Self {
+        let value: u8;
+        asm!(
+            "in al, dx",
+            out("al") value,
+            in("dx") port,
+            options(nomem)
+        );
+        value
+    }
+
+    #[inline(always)]
+    unsafe fn write_port(port: u16, value: Self) {
+        asm!(
+            "out dx, al",
+            in("al") value,
+            in("dx") port,
+            options(nomem)
+        );
+    }
+}
+
+impl PortValue for u16 {
+    #[inline(always)]
+    unsafe fn read_port(port: u16) -> Self {
+        let value: u16;
+        asm!(
+            "in ax, dx",
+            out("ax") value,
+            in("dx") port,
+            options(nomem)
+        );
+        value
+    }
+
+    #[inline(always)]
+    unsafe fn write_port(port: u16, value: Self) {
+        asm!(
+            "out dx, ax",
+            in("ax") value,
+            in("dx") port,
+            options(nomem)
+        );
+    }
+}
+
+impl PortValue for u32 {
+    #[inline(always)]
+    unsafe fn read_port(port: u16) -> Self {
+        let value: u32;
+        asm!(
+            "in eax, dx",
+            out("eax") value,
+            in("dx") port,
+            options(nomem)
+        );
+        value
+    }
+
+    #[inline(always)]
+    unsafe fn write_port(port: u16, value: Self) {
+        asm!(
+            "out dx, eax",
+            in("eax") value,
+            in("dx") port,
+            options(nomem)
+        );
+    }
+}
+
+/// A port whose address is only known at runtime
 pub struct IoPort<T> {
     port: u16,
     _marker: PhantomData<T>,
@@ -21,92 +108,125 @@ impl<T> IoPort<T> {
     }
 }
 
-impl Io for IoPort<u8> {
-    type Value = u8;
+impl<T: PortValue> Io for IoPort<T> {
+    type Value = T;
 
     #[inline(always)]
     fn read(&self) -> Self::Value {
-        let value: u8;
-        unsafe {
-            asm!(
-                "in al, dx",
-                out("al") value,
-                in("dx") self.port,
-                options(nomem)
-            );
-        }
-        value
+        unsafe { T::read_port(self.port) }
     }
 
     #[inline(always)]
     fn write(&mut self, value: Self::Value) {
-        unsafe {
-            asm!(
-                "out dx, al",
-                in("al") value,
-                in("dx") self.port,
-                options(nomem)
-            );
-        }
+        unsafe { T::write_port(self.port, value) }
     }
 }
 
-impl Io for IoPort<u16> {
-    type Value = u16;
+/// A port with a fixed address and width, for well known legacy devices. It takes no space, and
+/// the address and width can't be mixed up at the call site.
+pub struct Port<T, const ADDR: u16>(PhantomData<T>);
 
-    #[inline(always)]
-    fn read(&self) -> Self::Value {
-        let value: u16;
-        unsafe {
-            asm!(
-                "in ax, dx",
-                out("ax") value,
-                in("dx") self.port,
-                options(nomem)
-            );
-        }
-        value
+impl<T, const ADDR: u16> Port<T, ADDR> {
+    pub const fn new() -> Self {
+        Self(PhantomData)
     }
 
-    #[inline(always)]
-    fn write(&mut self, value: Self::Value) {
-        unsafe {
-            asm!(
-                "out ax, dx",
-                in("ax") value,
-                in("dx") self.port,
-                options(nomem)
-            );
-        }
+    pub const fn address(&self) -> u16 {
+        ADDR
     }
 }
 
-impl Io for IoPort<u32> {
-    type Value = u32;
+impl<T: PortValue, const ADDR: u16> Io for Port<T, ADDR> {
+    type Value = T;
 
     #[inline(always)]
     fn read(&self) -> Self::Value {
-        let value: u32;
-        unsafe {
-            asm!(
-                "in eax, dx",
-                out("eax") value,
-                in("dx") self.port,
-                options(nomem)
-            );
-        }
-        value
+        unsafe { T::read_port(ADDR) }
     }
 
     #[inline(always)]
     fn write(&mut self, value: Self::Value) {
-        unsafe {
-            asm!(
-                "out eax, dx",
-                in("eax") value,
-                in("dx") self.port,
-                options(nomem)
-            );
+        unsafe { T::write_port(ADDR, value) }
+    }
+}
+
+/// A block of contiguous ports belonging to one device. Registers are addressed by their byte
+/// offset from the start of the block, and an access that doesn't fit inside the block panics.
+#[derive(Debug, Clone, Copy)]
+pub struct PortRange {
+    base: u16,
+    length: u16,
+}
+
+impl PortRange {
+    pub const fn new(base: u16, length: u16) -> Self {
+        Self { base, length }
+    }
+
+    pub const fn base(&self) -> u16 {
+        self.base
+    }
+
+    pub const fn len(&self) -> u16 {
+        self.length
+    }
+
+    /// Get the port at offset, or None if a T at that offset would run past the end of the block
+    pub fn try_port<T: PortValue>(&self, offset: u16) -> Option<IoPort<T>> {
+        let end = usize::from(offset) + core::mem::size_of::<T>();
+        if end <= usize::from(self.length) {
+            Some(IoPort::new(self.base + offset))
+        } else {
+            None
         }
     }
+
+    pub fn port<T: PortValue>(&self, offset: u16) -> IoPort<T> {
+        self.try_port(offset).unwrap_or_else(|| {
+            panic!(
+                "Port offset {:#x} is outside the range {:#x}+{:#x}",
+                offset, self.base, self.length
+            )
+        })
+    }
+
+    pub fn read<T: PortValue>(&self, offset: u16) -> T {
+        self.port(offset).read()
+    }
+
+    pub fn write<T: PortValue>(&self, offset: u16, value: T) {
+        self.port(offset).write(value)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // The UART has a scratch register that does nothing but hold the last value written to it
+    const COM1: PortRange = PortRange::new(0x3f8, 8);
+    const COM1_SCRATCH: u16 = 7;
+
+    #[test_case]
+    fn port_range_rejects_out_of_range_offsets() {
+        assert_eq!(COM1.try_port::<u8>(7).map(|port| port.port), Some(0x3ff));
+        assert_eq!(COM1.try_port::<u16>(6).map(|port| port.port), Some(0x3fe));
+        assert!(COM1.try_port::<u8>(8).is_none());
+        assert!(COM1.try_port::<u16>(7).is_none());
+        assert!(COM1.try_port::<u32>(6).is_none());
+    }
+
+    #[test_case]
+    fn ports_access_the_expected_address() {
+        let mut scratch = Port::<u8, 0x3ff>::new();
+        assert_eq!(core::mem::size_of_val(&scratch), 0);
+        assert_eq!(scratch.address(), 0x3ff);
+
+        scratch.write(0x5a);
+        assert_eq!(IoPort::<u8>::new(0x3ff).read(), 0x5a);
+        assert_eq!(COM1.read::<u8>(COM1_SCRATCH), 0x5a);
+
+        COM1.write::<u8>(COM1_SCRATCH, 0xa5);
+        assert_eq!(scratch.read(), 0xa5);
+    }
 }
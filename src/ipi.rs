@@ -2,6 +2,7 @@
 #[repr(u8)]
 pub enum IpiKind {
     Tlb = 0xf0,
+    Reschedule = 0xfb,
     Timer = 0xfd,
     Halt = 0xfe,
 }
@@ -18,7 +19,16 @@ pub fn ipi(kind: IpiKind, target: IpiTarget) {
     use crate::devices::local_apic::local_apic_access_safe;
 
     if let Some(local_apic) = local_apic_access_safe() {
-        let icr = (target as u64) << 18 | 1 << 14 | (kind as u64);
-        local_apic.set_icr(icr);
+        let command = (target as u32) << 18 | 1 << 14 | (kind as u32);
+        local_apic.set_icr(0, command);
+    }
+}
+
+/// Send an IPI to a single CPU. CPU ids are local APIC ids.
+pub fn ipi_cpu(kind: IpiKind, cpu_id: usize) {
+    use crate::devices::local_apic::local_apic_access_safe;
+
+    if let Some(local_apic) = local_apic_access_safe() {
+        local_apic.set_icr(cpu_id as u32, 1 << 14 | (kind as u32));
     }
 }
@@ -12,6 +12,7 @@
 #![feature(custom_test_frameworks)]
 #![feature(global_asm)]
 #![feature(maybe_uninit_extra)]
+#![feature(min_const_generics)]
 #![feature(naked_functions)]
 #![feature(never_type)]
 #![feature(slice_fill)]
@@ -31,6 +32,7 @@ extern crate alloc;
 pub mod acpi;
 pub mod allocator;
 pub mod devices;
+pub mod fpu;
 pub mod gdt;
 pub mod idt;
 pub mod init;
@@ -38,14 +40,22 @@ pub mod init_mutex;
 pub mod interrupts;
 pub mod io_port;
 pub mod ipi;
+pub mod loader;
+pub mod lock_order;
+pub mod log;
 pub mod mm;
 pub mod paging;
+pub mod panic_recovery;
 pub mod physmem;
 pub mod scheduler;
 pub mod serial;
+pub mod shell;
+pub mod sync;
+pub mod syscall;
+pub mod util;
 pub mod vga_buffer;
 
-pub use init::cpu_id;
+pub use init::{cpu_count, cpu_id};
 
 #[cfg(test)]
 use bootloader::BootInfo;
@@ -59,33 +69,147 @@ fn alloc_error_handler(layout: alloc::alloc::Layout) -> ! {
     panic!("allocation error: {:?}", layout);
 }
 
+/// Run func, and return how long it took in microseconds. Returns None if there is no clock yet.
+pub fn time_us(func: impl FnOnce()) -> Option<u64> {
+    use devices::now_ns;
+
+    let start = now_ns();
+    func();
+    let end = now_ns();
+
+    start.zip(end).map(|(start, end)| (end - start) / 1000)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TestOutcome {
+    /// The test passed, and took this many microseconds if that could be measured
+    Passed(Option<u64>),
+    Failed,
+    /// The test couldn't check anything on this machine, for this reason
+    Skipped(&'static str),
+}
+
+// Set by skip_test while a test runs
+static SKIP_REASON: spin::Mutex<Option<&'static str>> = spin::Mutex::new(None);
+
+/// Mark the running test as skipped, for tests that need hardware the machine doesn't have, so
+/// that it isn't reported as passing. The test should return straight after calling this.
+pub fn skip_test(reason: &'static str) {
+    *SKIP_REASON.lock() = Some(reason);
+}
+
 pub trait Testable {
-    fn run(&self) -> ();
+    fn name(&self) -> &'static str;
+
+    fn run(&self) -> TestOutcome;
 }
 
 impl<T> Testable for T
 where
     T: Fn(),
 {
-    fn run(&self) {
-        serial_print!("{}...\t", core::any::type_name::<T>());
-        self();
-        serial_println!("[ok]");
+    fn name(&self) -> &'static str {
+        core::any::type_name::<T>()
+    }
+
+    fn run(&self) -> TestOutcome {
+        serial_print!("{}...\t", self.name());
+
+        // The panic handler reports the failure
+        let mut elapsed_us = None;
+        *SKIP_REASON.lock() = None;
+        if !panic_recovery::catch_panic(|| elapsed_us = time_us(|| self())) {
+            return TestOutcome::Failed;
+        }
+
+        if let Some(reason) = SKIP_REASON.lock().take() {
+            serial_println!("[skipped] {}", reason);
+            return TestOutcome::Skipped(reason);
+        }
+
+        match elapsed_us {
+            Some(elapsed_us) => serial_println!("[ok] {}us", elapsed_us),
+            None => serial_println!("[ok]"),
+        }
+        TestOutcome::Passed(elapsed_us)
     }
 }
 
-pub fn test_runner(tests: &[&dyn Testable]) {
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TestSummary {
+    pub passed: usize,
+    pub failed: usize,
+    pub skipped: usize,
+}
+
+const SLOWEST_TESTS_REPORTED: usize = 5;
+
+/// Run every test, carrying on past any that panic
+pub fn run_test_suite(tests: &[&dyn Testable]) -> TestSummary {
     serial_println!("Running {} tests", tests.len());
+
+    let mut summary = TestSummary {
+        passed: 0,
+        failed: 0,
+        skipped: 0,
+    };
+
+    // Slowest first
+    let mut slowest: [Option<(u64, &'static str)>; SLOWEST_TESTS_REPORTED] =
+        [None; SLOWEST_TESTS_REPORTED];
+
     for test in tests {
-        test.run();
+        match test.run() {
+            TestOutcome::Passed(elapsed_us) => {
+                summary.passed += 1;
+
+                if let Some(elapsed_us) = elapsed_us {
+                    let entry = Some((elapsed_us, test.name()));
+                    if let Some(position) = slowest.iter().position(|slow| *slow < entry) {
+                        slowest[position..].rotate_right(1);
+                        slowest[position] = entry;
+                    }
+                }
+            }
+            TestOutcome::Failed => summary.failed += 1,
+            TestOutcome::Skipped(_) => summary.skipped += 1,
+        }
+    }
+
+    if slowest[0].is_some() {
+        serial_println!("Slowest tests:");
+        for (elapsed_us, name) in slowest.iter().flatten() {
+            serial_println!("  {}us\t{}", elapsed_us, name);
+        }
+    }
+
+    serial_println!(
+        "{} passed, {} failed, {} skipped",
+        summary.passed,
+        summary.failed,
+        summary.skipped
+    );
+    summary
+}
+
+pub fn test_runner(tests: &[&dyn Testable]) {
+    let failed = run_test_suite(tests).failed;
+    if failed == 0 {
+        exit_qemu(QemuExitCode::Success);
+    } else {
+        exit_qemu(QemuExitCode::Failed(failed));
     }
-    exit_qemu(QemuExitCode::Success);
 }
 
 pub fn test_panic_handler(info: &PanicInfo) -> ! {
+    unsafe { panic_recovery::recover_expected(info) };
+
     serial_println!("[failed]\n");
     serial_println!("Error: {}\n", info);
-    exit_qemu(QemuExitCode::Failed);
+    unsafe { panic_recovery::recover() };
+
+    // Other tests may have failed before this one, but there's no way to know how many from here
+    exit_qemu(QemuExitCode::Failed(1));
     loop {}
 }
 
@@ -111,11 +235,32 @@ pub unsafe extern "C" fn _start(boot_info: &'static BootInfo) -> ! {
     init::kstart(boot_info, run_tests)
 }
 
+/// What the tests tell QEMU when they finish. QEMU exits with (code << 1) | 1, so a harness sees:
+///
+///   Success                     0x10, which QEMU turns into 33
+///   Failed(n), 1 <= n <= 111    0x10 + n, which QEMU turns into 33 + 2n
+///   Failed(n), n > 111          0x7f, the same as 111 failures, because that is the largest code
+///                               that still fits in an exit status
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
-#[repr(u32)]
 pub enum QemuExitCode {
-    Success = 0x10,
-    Failed = 0x11,
+    Success,
+    /// This many tests failed. Zero is treated as one, because something still failed.
+    Failed(usize),
+}
+
+const SUCCESS_EXIT_CODE: u32 = 0x10;
+pub const MAX_REPORTED_FAILURES: usize = 0x6f;
+
+impl QemuExitCode {
+    /// The value written to the isa-debug-exit port
+    pub fn code(self) -> u32 {
+        match self {
+            Self::Success => SUCCESS_EXIT_CODE,
+            Self::Failed(failed) => {
+                SUCCESS_EXIT_CODE + failed.max(1).min(MAX_REPORTED_FAILURES) as u32
+            }
+        }
+    }
 }
 
 pub fn exit_qemu(exit_code: QemuExitCode) {
@@ -123,6 +268,55 @@ pub fn exit_qemu(exit_code: QemuExitCode) {
 
     unsafe {
         let mut port = Port::new(0xf4);
-        port.write(exit_code as u32);
+        port.write(exit_code.code());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use core::sync::atomic::{AtomicBool, Ordering};
+
+    static RAN_AFTER_FAILURE: AtomicBool = AtomicBool::new(false);
+
+    fn passes() {}
+
+    fn fails() {
+        panic!("This test fails on purpose");
+    }
+
+    fn passes_after_failure() {
+        RAN_AFTER_FAILURE.store(true, Ordering::SeqCst);
+    }
+
+    fn skips() {
+        skip_test("This test skips on purpose");
+    }
+
+    // This prints a failure in the middle of the log, but it is the inner suite's failure and not
+    // this test's
+    #[test_case]
+    fn failing_test_does_not_stop_the_suite() {
+        let tests: [&dyn Testable; 4] = [&passes, &fails, &skips, &passes_after_failure];
+
+        assert_eq!(
+            run_test_suite(&tests),
+            TestSummary {
+                passed: 2,
+                failed: 1,
+                skipped: 1
+            }
+        );
+        assert!(RAN_AFTER_FAILURE.load(Ordering::SeqCst));
+    }
+
+    #[test_case]
+    fn exit_code_counts_failures() {
+        assert_eq!(QemuExitCode::Success.code(), 0x10);
+        assert_eq!(QemuExitCode::Failed(0).code(), 0x11);
+        assert_eq!(QemuExitCode::Failed(1).code(), 0x11);
+        assert_eq!(QemuExitCode::Failed(3).code(), 0x13);
+        assert_eq!(QemuExitCode::Failed(MAX_REPORTED_FAILURES).code(), 0x7f);
+        assert_eq!(QemuExitCode::Failed(1000).code(), 0x7f);
     }
 }
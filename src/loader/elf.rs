@@ -0,0 +1,92 @@
+// Just enough of the ELF64 format to find the loadable segments of a static executable
+
+use super::{LoaderError, Result};
+use core::mem;
+
+const ELF_MAGIC: [u8; 4] = *b"\x7fELF";
+const ELF_CLASS_64: u8 = 2;
+const ELF_DATA_LITTLE_ENDIAN: u8 = 1;
+const ELF_TYPE_EXECUTABLE: u16 = 2;
+const ELF_MACHINE_X86_64: u16 = 0x3e;
+
+pub const PT_LOAD: u32 = 1;
+pub const PT_TLS: u32 = 7;
+
+pub const PF_X: u32 = 1;
+pub const PF_W: u32 = 2;
+
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct FileHeader {
+    pub ident: [u8; 16],
+    pub elf_type: u16,
+    pub machine: u16,
+    pub version: u32,
+    pub entry: u64,
+    pub phoff: u64,
+    pub shoff: u64,
+    pub flags: u32,
+    pub ehsize: u16,
+    pub phentsize: u16,
+    pub phnum: u16,
+    pub shentsize: u16,
+    pub shnum: u16,
+    pub shstrndx: u16,
+}
+
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct ProgramHeader {
+    pub segment_type: u32,
+    pub flags: u32,
+    pub offset: u64,
+    pub vaddr: u64,
+    pub paddr: u64,
+    pub filesz: u64,
+    pub memsz: u64,
+    pub align: u64,
+}
+
+// Read a T from anywhere in bytes, which doesn't need to be aligned for it
+fn read<T: Copy>(bytes: &[u8], offset: usize) -> Result<T> {
+    let end = offset
+        .checked_add(mem::size_of::<T>())
+        .ok_or(LoaderError::InvalidElf)?;
+    let bytes = bytes.get(offset..end).ok_or(LoaderError::InvalidElf)?;
+    Ok(unsafe { (bytes.as_ptr() as *const T).read_unaligned() })
+}
+
+pub fn file_header(bytes: &[u8]) -> Result<FileHeader> {
+    let header: FileHeader = read(bytes, 0)?;
+    if header.ident[..4] != ELF_MAGIC {
+        return Err(LoaderError::InvalidElf);
+    }
+
+    if header.ident[4] != ELF_CLASS_64
+        || header.ident[5] != ELF_DATA_LITTLE_ENDIAN
+        || header.elf_type != ELF_TYPE_EXECUTABLE
+        || header.machine != ELF_MACHINE_X86_64
+    {
+        return Err(LoaderError::UnsupportedElf);
+    }
+
+    if usize::from(header.phentsize) != mem::size_of::<ProgramHeader>() {
+        return Err(LoaderError::InvalidElf);
+    }
+
+    Ok(header)
+}
+
+pub fn program_headers<'a>(
+    bytes: &'a [u8],
+    header: &FileHeader,
+) -> impl Iterator<Item = Result<ProgramHeader>> + 'a {
+    let phoff = header.phoff as usize;
+    (0..usize::from(header.phnum)).map(move |index| {
+        let offset = index
+            .checked_mul(mem::size_of::<ProgramHeader>())
+            .and_then(|offset| offset.checked_add(phoff))
+            .ok_or(LoaderError::InvalidElf)?;
+        read(bytes, offset)
+    })
+}
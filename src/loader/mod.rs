@@ -0,0 +1,402 @@
+// Loading userland executables. Only static ELF64 executables are supported. PT_LOAD segments
+// that share a page share its frame, and the page gets the permissions of both. The segments and
+// the stack are written through the frames, because the address space being loaded isn't the
+// active one.
+
+mod elf;
+mod stack;
+mod tls;
+
+use crate::paging::{
+    hyperspace, page_align_down, page_align_up, AddressSpace, Frame, Mapper, MemoryError,
+    PresentPageFlags, PAGE_SIZE,
+};
+use crate::physmem;
+use crate::syscall::USER_ADDRESS_LIMIT;
+use elf::{ProgramHeader, PF_W, PF_X, PT_LOAD};
+
+pub use stack::{setup_user_stack, USER_STACK_PAGES, USER_STACK_TOP};
+pub use tls::{setup_user_tls, tls_template, TlsTemplate, USER_TLS_BASE};
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum LoaderError {
+    MemoryError(MemoryError),
+    InvalidElf,
+    UnsupportedElf,
+    InvalidSegment,
+    WritableAndExecutable,
+    ArgumentsTooLarge,
+}
+
+impl From<MemoryError> for LoaderError {
+    fn from(memory_error: MemoryError) -> Self {
+        Self::MemoryError(memory_error)
+    }
+}
+
+pub type Result<T> = core::result::Result<T, LoaderError>;
+
+fn segment_flags(segment: &ProgramHeader) -> Result<PresentPageFlags> {
+    let writable = segment.flags & PF_W != 0;
+    let executable = segment.flags & PF_X != 0;
+
+    let flags = match (writable, executable) {
+        (true, true) => return Err(LoaderError::WritableAndExecutable),
+        (true, false) => PresentPageFlags::WRITABLE | PresentPageFlags::NO_EXECUTE,
+        (false, true) => PresentPageFlags::empty(),
+        (false, false) => PresentPageFlags::NO_EXECUTE,
+    };
+
+    Ok(flags | PresentPageFlags::USER_ACCESSIBLE)
+}
+
+// Copy whatever part of data overlaps page into frame, with data starting at data_address
+fn copy_into_frame(frame: Frame, page: usize, data: &[u8], data_address: usize) -> Result<()> {
+    let copy_start = page.max(data_address);
+    let copy_end = (page + PAGE_SIZE).min(data_address + data.len());
+    hyperspace::with_frame(frame, |ptr| unsafe {
+        if copy_start < copy_end {
+            ptr.add(copy_start - page).copy_from_nonoverlapping(
+                data[copy_start - data_address..].as_ptr(),
+                copy_end - copy_start,
+            );
+        }
+    })?;
+    Ok(())
+}
+
+// The flags for a page that two segments share. It is writable or executable if either segment
+// is, which mustn't make it both.
+fn merge_flags(old: PresentPageFlags, new: PresentPageFlags) -> Result<PresentPageFlags> {
+    let mut flags = (old | new) - PresentPageFlags::NO_EXECUTE;
+    if old.contains(PresentPageFlags::NO_EXECUTE) && new.contains(PresentPageFlags::NO_EXECUTE) {
+        flags |= PresentPageFlags::NO_EXECUTE;
+    }
+
+    if flags.contains(PresentPageFlags::WRITABLE) && !flags.contains(PresentPageFlags::NO_EXECUTE) {
+        return Err(LoaderError::WritableAndExecutable);
+    }
+    Ok(flags)
+}
+
+// Map a new frame at page. It holds whatever part of data overlaps the page, with data starting
+// at data_address, and is zero everywhere else.
+fn map_new_page(
+    mapper: &mut Mapper,
+    page: usize,
+    flags: PresentPageFlags,
+    data: &[u8],
+    data_address: usize,
+) -> Result<()> {
+    if mapper
+        .get_pte_for_address(page)
+        .map_or(false, |pte| !pte.is_unused())
+    {
+        return Err(LoaderError::InvalidSegment);
+    }
+
+    let frame = physmem::allocate_user_frame().ok_or(MemoryError::OutOfMemory)?;
+    let filled = hyperspace::with_frame(frame, |ptr| unsafe { ptr.write_bytes(0, PAGE_SIZE) })
+        .map_err(LoaderError::from)
+        .and_then(|()| copy_into_frame(frame, page, data, data_address));
+
+    match filled.and_then(|()| mapper.map_to(page, frame, flags).map_err(LoaderError::from)) {
+        // The address space isn't active, so there is nothing to flush
+        Ok(flush) => {
+            unsafe { flush.ignore() };
+            Ok(())
+        }
+        Err(error) => {
+            physmem::deallocate_frame(frame);
+            Err(error)
+        }
+    }
+}
+
+// Map a page of a segment. If an earlier segment already mapped the page, the data goes into its
+// frame and the page gets the permissions of both.
+fn map_segment_page(
+    mapper: &mut Mapper,
+    page: usize,
+    flags: PresentPageFlags,
+    data: &[u8],
+    data_address: usize,
+) -> Result<()> {
+    let shared = mapper
+        .get_pte_for_address(page)
+        .and_then(|pte| pte.present().ok());
+    match shared {
+        Some(pte) => {
+            let flags = merge_flags(pte.flags(), flags)?;
+            copy_into_frame(pte.frame(), page, data, data_address)?;
+            unsafe { mapper.remap(page, flags)?.ignore() };
+            Ok(())
+        }
+        None => map_new_page(mapper, page, flags, data, data_address),
+    }
+}
+
+fn load_segment(bytes: &[u8], segment: &ProgramHeader, mapper: &mut Mapper) -> Result<()> {
+    let flags = segment_flags(segment)?;
+    let vaddr = segment.vaddr as usize;
+    let filesz = segment.filesz as usize;
+    let memsz = segment.memsz as usize;
+    let offset = segment.offset as usize;
+
+    let end = vaddr
+        .checked_add(memsz)
+        .filter(|end| *end <= USER_ADDRESS_LIMIT && filesz <= memsz)
+        .ok_or(LoaderError::InvalidSegment)?;
+    let file_data = offset
+        .checked_add(filesz)
+        .and_then(|file_end| bytes.get(offset..file_end))
+        .ok_or(LoaderError::InvalidElf)?;
+
+    for page in (page_align_down(vaddr)..page_align_up(end)).step_by(PAGE_SIZE) {
+        map_segment_page(mapper, page, flags, file_data, vaddr)?;
+    }
+
+    Ok(())
+}
+
+/// Map the loadable segments of an ELF executable into an address space, returning its entry
+/// point. The address space must not be active. If loading fails, whatever was already mapped is
+/// left for the address space to free.
+pub fn load_elf(bytes: &[u8], address_space: &mut AddressSpace) -> Result<usize> {
+    let header = elf::file_header(bytes)?;
+    let entry = header.entry as usize;
+    let mut mapper = unsafe { address_space.mapper() };
+    let mut entry_is_executable = false;
+
+    for segment in elf::program_headers(bytes, &header) {
+        let segment = segment?;
+        if segment.segment_type != PT_LOAD {
+            continue;
+        }
+
+        load_segment(bytes, &segment, &mut mapper)?;
+
+        let vaddr = segment.vaddr as usize;
+        if segment.flags & PF_X != 0 && (vaddr..vaddr + segment.memsz as usize).contains(&entry) {
+            entry_is_executable = true;
+        }
+    }
+
+    if !entry_is_executable {
+        return Err(LoaderError::InvalidElf);
+    }
+
+    Ok(entry)
+}
+
+#[cfg(test)]
+mod test {
+    use super::elf::FileHeader;
+    use super::*;
+    use crate::paging::new_address_space;
+    use crate::scheduler::spawn;
+    use crate::syscall::enter_user_mode;
+    use alloc::vec::Vec;
+    use core::{mem, slice};
+
+    const PF_R: u32 = 4;
+
+    const TEXT_ADDRESS: usize = 0x40_0000;
+    const DATA_ADDRESS: usize = 0x40_1008;
+    const DATA_SIZE: usize = 0x1800;
+    const ENTRY: usize = TEXT_ADDRESS + 4;
+
+    const TEXT_OFFSET: usize = 0x100;
+    const DATA_OFFSET: usize = 0x200;
+    // Some padding before the entry point, and then exit(EXIT_CODE)
+    const TEXT: [u8; 18] = [
+        0x90, 0x90, 0x90, 0x90, 0xb8, 0x02, 0x00, 0x00, 0x00, 0xbf, EXIT_CODE, 0x00, 0x00, 0x00,
+        0x0f, 0x05, 0x0f, 0x0b,
+    ];
+    const EXIT_CODE: u8 = 42;
+    const DATA: u64 = 0x1122_3344_5566_7788;
+
+    fn as_bytes<T>(value: &T) -> &[u8] {
+        unsafe { slice::from_raw_parts(value as *const T as *const u8, mem::size_of::<T>()) }
+    }
+
+    fn segment(
+        flags: u32,
+        offset: usize,
+        vaddr: usize,
+        filesz: usize,
+        memsz: usize,
+    ) -> ProgramHeader {
+        ProgramHeader {
+            segment_type: PT_LOAD,
+            flags,
+            offset: offset as u64,
+            vaddr: vaddr as u64,
+            paddr: vaddr as u64,
+            filesz: filesz as u64,
+            memsz: memsz as u64,
+            align: PAGE_SIZE as u64,
+        }
+    }
+
+    // A text segment and a data segment with some BSS after it
+    fn build_elf(data_flags: u32) -> Vec<u8> {
+        build_elf_with_data_at(data_flags, DATA_ADDRESS)
+    }
+
+    fn build_elf_with_data_at(data_flags: u32, data_address: usize) -> Vec<u8> {
+        let mut ident = [0; 16];
+        ident[..4].copy_from_slice(b"\x7fELF");
+        ident[4] = 2;
+        ident[5] = 1;
+        ident[6] = 1;
+
+        let header = FileHeader {
+            ident,
+            elf_type: 2,
+            machine: 0x3e,
+            version: 1,
+            entry: ENTRY as u64,
+            phoff: mem::size_of::<FileHeader>() as u64,
+            shoff: 0,
+            flags: 0,
+            ehsize: mem::size_of::<FileHeader>() as u16,
+            phentsize: mem::size_of::<ProgramHeader>() as u16,
+            phnum: 2,
+            shentsize: 0,
+            shnum: 0,
+            shstrndx: 0,
+        };
+        let text = segment(
+            PF_R | PF_X,
+            TEXT_OFFSET,
+            TEXT_ADDRESS,
+            TEXT.len(),
+            TEXT.len(),
+        );
+        let data = segment(data_flags, DATA_OFFSET, data_address, 8, DATA_SIZE);
+
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(as_bytes(&header));
+        bytes.extend_from_slice(as_bytes(&text));
+        bytes.extend_from_slice(as_bytes(&data));
+        bytes.resize(TEXT_OFFSET, 0);
+        bytes.extend_from_slice(&TEXT);
+        bytes.resize(DATA_OFFSET, 0);
+        bytes.extend_from_slice(&DATA.to_le_bytes());
+        bytes
+    }
+
+    // The flags and the contents of a page in an address space
+    fn read_page(address_space: &mut AddressSpace, page: usize) -> (PresentPageFlags, [u8; 32]) {
+        let mapper = unsafe { address_space.mapper() };
+        let pte = mapper
+            .get_pte_for_address(page)
+            .and_then(|pte| pte.present().ok())
+            .expect("Page is not mapped");
+
+        let mut contents = [0; 32];
+        hyperspace::with_frame(pte.frame(), |ptr| unsafe {
+            ptr.copy_to_nonoverlapping(contents.as_mut_ptr(), contents.len())
+        })
+        .expect("Failed to read page");
+
+        (pte.flags(), contents)
+    }
+
+    #[test_case]
+    fn elf_segments_are_mapped_with_their_flags() {
+        let mut address_space = new_address_space().expect("Failed to create address space");
+        let entry = load_elf(&build_elf(PF_R | PF_W), &mut address_space).expect("Failed to load");
+        assert_eq!(entry, ENTRY);
+
+        let (flags, contents) = read_page(&mut address_space, TEXT_ADDRESS);
+        assert!(flags.contains(PresentPageFlags::USER_ACCESSIBLE));
+        assert!(!flags.contains(PresentPageFlags::WRITABLE));
+        assert!(!flags.contains(PresentPageFlags::NO_EXECUTE));
+        assert_eq!(contents[..TEXT.len()], TEXT);
+
+        let data_page = page_align_down(DATA_ADDRESS);
+        let (flags, contents) = read_page(&mut address_space, data_page);
+        assert!(flags.contains(
+            PresentPageFlags::USER_ACCESSIBLE
+                | PresentPageFlags::WRITABLE
+                | PresentPageFlags::NO_EXECUTE
+        ));
+        assert_eq!(contents[..8], [0; 8]);
+        assert_eq!(contents[8..16], DATA.to_le_bytes());
+        assert_eq!(contents[16..], [0; 16]);
+
+        // The BSS runs on into the next page
+        let (flags, contents) = read_page(&mut address_space, data_page + PAGE_SIZE);
+        assert!(flags.contains(PresentPageFlags::WRITABLE | PresentPageFlags::NO_EXECUTE));
+        assert_eq!(contents, [0; 32]);
+    }
+
+    #[test_case]
+    fn writable_and_executable_segments_are_refused() {
+        let mut address_space = new_address_space().expect("Failed to create address space");
+        assert_eq!(
+            load_elf(&build_elf(PF_R | PF_W | PF_X), &mut address_space),
+            Err(LoaderError::WritableAndExecutable)
+        );
+    }
+
+    #[test_case]
+    fn segments_sharing_a_page_are_merged() {
+        // Read only data straight after the text, on the same page
+        const SHARED_ADDRESS: usize = TEXT_ADDRESS + 0x18;
+
+        let mut address_space = new_address_space().expect("Failed to create address space");
+        load_elf(
+            &build_elf_with_data_at(PF_R, SHARED_ADDRESS),
+            &mut address_space,
+        )
+        .expect("Failed to load");
+
+        let (flags, contents) = read_page(&mut address_space, TEXT_ADDRESS);
+        assert!(flags.contains(PresentPageFlags::USER_ACCESSIBLE));
+        assert!(!flags.contains(PresentPageFlags::WRITABLE));
+        assert!(!flags.contains(PresentPageFlags::NO_EXECUTE));
+        assert_eq!(contents[..TEXT.len()], TEXT);
+        assert_eq!(contents[0x18..0x20], DATA.to_le_bytes());
+
+        // Writable data can't share a page with the text
+        let mut address_space = new_address_space().expect("Failed to create address space");
+        assert_eq!(
+            load_elf(
+                &build_elf_with_data_at(PF_R | PF_W, SHARED_ADDRESS),
+                &mut address_space
+            ),
+            Err(LoaderError::WritableAndExecutable)
+        );
+    }
+
+    #[test_case]
+    fn bad_elf_headers_are_refused() {
+        let mut address_space = new_address_space().expect("Failed to create address space");
+
+        let mut bytes = build_elf(PF_R | PF_W);
+        bytes[0] = 0;
+        assert_eq!(
+            load_elf(&bytes, &mut address_space),
+            Err(LoaderError::InvalidElf)
+        );
+        assert_eq!(
+            load_elf(&bytes[..32], &mut address_space),
+            Err(LoaderError::InvalidElf)
+        );
+    }
+
+    #[test_case]
+    fn loaded_programs_run_until_they_exit() {
+        let mut address_space = new_address_space().expect("Failed to create address space");
+        let entry = load_elf(&build_elf(PF_R | PF_W), &mut address_space).expect("Failed to load");
+        let rsp =
+            setup_user_stack(&mut address_space, &["test"], &[]).expect("Failed to set up stack");
+
+        let task = unsafe { spawn(Some(address_space), move || enter_user_mode(entry, rsp, 0)) }
+            .expect("Failed to spawn user task");
+        assert_eq!(task.join(), EXIT_CODE as isize);
+    }
+}
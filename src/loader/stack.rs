@@ -0,0 +1,229 @@
+// The stack that a user program starts with. As the System V AMD64 ABI lays it out, rsp points at
+// argc, followed by the argv pointers, a null, the envp pointers, another null and then the
+// auxiliary vector, which is empty apart from its AT_NULL terminator. The strings that the pointers
+// point to come after all of that.
+
+use super::{map_new_page, LoaderError, Result};
+use crate::paging::{AddressSpace, PresentPageFlags, PAGE_SIZE};
+use alloc::vec;
+use alloc::vec::Vec;
+use core::mem;
+
+/// Where the user stack ends. Every program gets the same stack, so it sits at the top of the
+/// user half, with a guard page above it.
+pub const USER_STACK_TOP: usize = 0x0000_7fff_ffff_f000;
+pub const USER_STACK_PAGES: usize = 16;
+
+// The arguments can't take up more than this much of the stack, to leave the rest for the program
+const MAX_ARGUMENTS_SIZE: usize = USER_STACK_PAGES * PAGE_SIZE / 4;
+
+const STACK_ALIGNMENT: usize = 16;
+
+const AT_NULL: usize = 0;
+
+// What goes at the top of the stack, which starts at the returned rsp
+fn build_stack_image(args: &[&str], env: &[&str]) -> Result<(usize, Vec<u8>)> {
+    let word = mem::size_of::<usize>();
+    let strings_size: usize = args.iter().chain(env).map(|string| string.len() + 1).sum();
+    // argc, the two nulls and the AT_NULL entry of the auxiliary vector
+    let words = args.len() + env.len() + 5;
+
+    let size = (words * word + strings_size + STACK_ALIGNMENT - 1) & !(STACK_ALIGNMENT - 1);
+    if size > MAX_ARGUMENTS_SIZE {
+        return Err(LoaderError::ArgumentsTooLarge);
+    }
+
+    let rsp = USER_STACK_TOP - size;
+    let mut image = vec![0; size];
+    let mut word_offset = 0;
+    let mut string_offset = words * word;
+
+    let mut push_word = |image: &mut Vec<u8>, value: usize| {
+        image[word_offset..word_offset + word].copy_from_slice(&value.to_le_bytes());
+        word_offset += word;
+    };
+
+    push_word(&mut image, args.len());
+    for strings in [args, env].iter() {
+        for string in strings.iter() {
+            push_word(&mut image, rsp + string_offset);
+
+            // The image starts zeroed, which terminates the string
+            image[string_offset..string_offset + string.len()].copy_from_slice(string.as_bytes());
+            string_offset += string.len() + 1;
+        }
+        push_word(&mut image, 0);
+    }
+    push_word(&mut image, AT_NULL);
+    push_word(&mut image, 0);
+
+    Ok((rsp, image))
+}
+
+/// Map a stack into an address space that isn't active, with the arguments and environment on it,
+/// and return the stack pointer that the program should start with
+pub fn setup_user_stack(
+    address_space: &mut AddressSpace,
+    args: &[&str],
+    env: &[&str],
+) -> Result<usize> {
+    let (rsp, image) = build_stack_image(args, env)?;
+    let mut mapper = unsafe { address_space.mapper() };
+    let flags = PresentPageFlags::USER_ACCESSIBLE
+        | PresentPageFlags::WRITABLE
+        | PresentPageFlags::NO_EXECUTE;
+
+    let stack_bottom = USER_STACK_TOP - USER_STACK_PAGES * PAGE_SIZE;
+    for page in (stack_bottom..USER_STACK_TOP).step_by(PAGE_SIZE) {
+        map_new_page(&mut mapper, page, flags, &image, rsp)?;
+    }
+
+    Ok(rsp)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::intel_asm;
+    use crate::paging::{hyperspace, new_address_space};
+    use crate::physmem;
+    use crate::scheduler::spawn;
+    use crate::syscall::enter_user_mode;
+    use core::slice;
+
+    // Record what the program sees on its stack in the page that rdi points at, and then exit with
+    // argc. Each string is only recorded as its first eight bytes.
+    intel_asm!(
+        ".global stack_test_stub\n",
+        ".global stack_test_stub_end\n",
+        ".section .text.stack_test_stub, \"ax\", @progbits\n",
+        "stack_test_stub:\n",
+        "mov [rdi], rsp\n",
+        "mov rax, [rsp]\n",
+        "mov [rdi + 8], rax\n",
+        "mov rax, [rsp + 8]\n",
+        "mov [rdi + 16], rax\n",
+        "mov rcx, [rax]\n",
+        "mov [rdi + 24], rcx\n",
+        "mov rax, [rsp + 16]\n",
+        "mov [rdi + 32], rax\n",
+        "mov rcx, [rax]\n",
+        "mov [rdi + 40], rcx\n",
+        "mov rax, [rsp + 24]\n",
+        "mov [rdi + 48], rax\n",
+        "mov rax, [rsp + 32]\n",
+        "mov [rdi + 56], rax\n",
+        "mov rcx, [rax]\n",
+        "mov [rdi + 64], rcx\n",
+        "mov rax, [rsp + 40]\n",
+        "mov [rdi + 72], rax\n",
+        "mov rax, [rsp + 48]\n",
+        "mov [rdi + 80], rax\n",
+        "mov eax, 2\n",
+        "mov rdi, [rsp]\n",
+        "syscall\n",
+        "ud2\n",
+        "stack_test_stub_end:\n",
+        ".text\n",
+    );
+
+    extern "C" {
+        static stack_test_stub: u8;
+        static stack_test_stub_end: u8;
+    }
+
+    const CODE_PAGE: usize = 0x40_0000;
+    const DATA_PAGE: usize = 0x40_1000;
+
+    const ARGS: [&str; 2] = ["init", "--test"];
+    const ENV: [&str; 1] = ["TERM=vt"];
+
+    // What the stub recorded: rsp, argc, argv[0] and its string, argv[1] and its string, argv[2],
+    // envp[0] and its string, envp[1] and the type of the first auxiliary vector entry
+    const RECORDED_SLOTS: usize = 11;
+
+    fn string_prefix(recorded: usize, string: &str) -> bool {
+        let len = string.len().min(mem::size_of::<usize>() - 1);
+        recorded.to_le_bytes()[..len] == string.as_bytes()[..len]
+            && (string.len() >= mem::size_of::<usize>() || recorded.to_le_bytes()[len] == 0)
+    }
+
+    #[test_case]
+    fn program_sees_its_arguments() {
+        let code = unsafe {
+            let start = &stack_test_stub as *const u8;
+            let end = &stack_test_stub_end as *const u8;
+            slice::from_raw_parts(start, end as usize - start as usize)
+        };
+
+        let mut address_space = new_address_space().expect("Failed to create address space");
+        let rsp =
+            setup_user_stack(&mut address_space, &ARGS, &ENV).expect("Failed to set up stack");
+        assert_eq!(rsp % STACK_ALIGNMENT, 0);
+
+        // We keep hold of the data frame, so that we can see what was recorded after the address
+        // space has gone to the task
+        let data_frame = physmem::allocate_user_frame().expect("Failed to allocate data frame");
+        hyperspace::zero_frame(data_frame).expect("Failed to zero data frame");
+        {
+            let mut mapper = unsafe { address_space.mapper() };
+            map_new_page(
+                &mut mapper,
+                CODE_PAGE,
+                PresentPageFlags::USER_ACCESSIBLE,
+                code,
+                CODE_PAGE,
+            )
+            .expect("Failed to map code page");
+            let flush = mapper
+                .map_to(
+                    DATA_PAGE,
+                    data_frame,
+                    PresentPageFlags::USER_ACCESSIBLE
+                        | PresentPageFlags::WRITABLE
+                        | PresentPageFlags::NO_EXECUTE,
+                )
+                .expect("Failed to map data page");
+            unsafe { flush.ignore() };
+        }
+
+        let task = unsafe {
+            spawn(Some(address_space), move || {
+                enter_user_mode(CODE_PAGE, rsp, DATA_PAGE)
+            })
+        }
+        .expect("Failed to spawn user task");
+
+        assert_eq!(task.join(), ARGS.len() as isize);
+
+        let mut recorded = [0usize; RECORDED_SLOTS];
+        hyperspace::with_frame(data_frame, |ptr| unsafe {
+            (ptr as *const usize).copy_to_nonoverlapping(recorded.as_mut_ptr(), RECORDED_SLOTS)
+        })
+        .expect("Failed to read data page");
+
+        let (seen_rsp, argc, argv, envp) =
+            (recorded[0], recorded[1], &recorded[2..7], &recorded[7..]);
+        assert_eq!(seen_rsp, rsp);
+        assert_eq!(argc, ARGS.len());
+        assert!(argv[0] > rsp && argv[0] < USER_STACK_TOP);
+        assert!(string_prefix(argv[1], ARGS[0]));
+        assert!(argv[2] > argv[0] && argv[2] < USER_STACK_TOP);
+        assert!(string_prefix(argv[3], ARGS[1]));
+        assert_eq!(argv[4], 0);
+        assert!(envp[0] > argv[2] && envp[0] < USER_STACK_TOP);
+        assert!(string_prefix(envp[1], ENV[0]));
+        assert_eq!(envp[2], 0);
+        assert_eq!(envp[3], AT_NULL);
+    }
+
+    #[test_case]
+    fn oversized_arguments_are_refused() {
+        let long_argument = "x".repeat(MAX_ARGUMENTS_SIZE);
+        let mut address_space = new_address_space().expect("Failed to create address space");
+        assert_eq!(
+            setup_user_stack(&mut address_space, &[long_argument.as_str()], &[]),
+            Err(LoaderError::ArgumentsTooLarge)
+        );
+    }
+}
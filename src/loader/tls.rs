@@ -0,0 +1,231 @@
+// Thread local storage for user programs. Each task gets its own copy of the program's PT_TLS
+// template, laid out as x86_64 expects: the block ends at the thread pointer, which the fs base
+// points at, and the first word there points back at itself. Kernel thread locals are per CPU
+// rather than per task, because the scheduler keeps its own state in them.
+
+use super::elf::{self, PT_TLS};
+use super::{map_new_page, LoaderError, Result};
+use crate::paging::{page_align_up, AddressSpace, PresentPageFlags, PAGE_SIZE};
+use alloc::vec;
+use core::mem;
+
+/// Where each task's thread local block goes, below the stack
+pub const USER_TLS_BASE: usize = 0x0000_7fff_f000_0000;
+
+/// What a task's thread locals start out as. The data is copied to the start of the block, and the
+/// rest of it is zeroed.
+#[derive(Debug, Clone, Copy)]
+pub struct TlsTemplate<'a> {
+    pub data: &'a [u8],
+    pub size: usize,
+    pub align: usize,
+}
+
+/// The thread local template of an ELF executable, if it has one
+pub fn tls_template(bytes: &[u8]) -> Result<Option<TlsTemplate>> {
+    let header = elf::file_header(bytes)?;
+
+    for segment in elf::program_headers(bytes, &header) {
+        let segment = segment?;
+        if segment.segment_type != PT_TLS {
+            continue;
+        }
+
+        let offset = segment.offset as usize;
+        let data = offset
+            .checked_add(segment.filesz as usize)
+            .and_then(|end| bytes.get(offset..end))
+            .ok_or(LoaderError::InvalidElf)?;
+
+        return Ok(Some(TlsTemplate {
+            data,
+            size: segment.memsz as usize,
+            align: (segment.align as usize).max(1),
+        }));
+    }
+
+    Ok(None)
+}
+
+/// Map a copy of the template into an address space that isn't active, and return the fs base that
+/// the task should run with
+pub fn setup_user_tls(address_space: &mut AddressSpace, template: &TlsTemplate) -> Result<usize> {
+    let word = mem::size_of::<usize>();
+    if !template.align.is_power_of_two()
+        || template.align > PAGE_SIZE
+        || template.data.len() > template.size
+    {
+        return Err(LoaderError::InvalidSegment);
+    }
+
+    // The block starts at a page boundary, so the thread pointer is aligned as long as the block
+    // size is
+    let block_size = (template.size + template.align - 1) & !(template.align - 1);
+    let fs_base = USER_TLS_BASE + block_size;
+
+    let mut image = vec![0; block_size + word];
+    image[..template.data.len()].copy_from_slice(template.data);
+    image[block_size..].copy_from_slice(&fs_base.to_le_bytes());
+
+    let mut mapper = unsafe { address_space.mapper() };
+    let flags = PresentPageFlags::USER_ACCESSIBLE
+        | PresentPageFlags::WRITABLE
+        | PresentPageFlags::NO_EXECUTE;
+    let end = page_align_up(USER_TLS_BASE + image.len());
+    for page in (USER_TLS_BASE..end).step_by(PAGE_SIZE) {
+        map_new_page(&mut mapper, page, flags, &image, USER_TLS_BASE)?;
+    }
+
+    Ok(fs_base)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::intel_asm;
+    use crate::loader::setup_user_stack;
+    use crate::paging::{hyperspace, new_address_space};
+    use crate::physmem::{self, Frame};
+    use crate::scheduler::{spawn_user, TaskReference};
+    use crate::syscall::enter_user_mode;
+    use core::slice;
+
+    // Add this task's increment to a thread local that starts out as TLS_INITIAL_VALUE, give the
+    // other task a chance to run, and then record what the thread locals hold
+    intel_asm!(
+        ".global tls_test_stub\n",
+        ".global tls_test_stub_end\n",
+        ".section .text.tls_test_stub, \"ax\", @progbits\n",
+        "tls_test_stub:\n",
+        "mov rbx, rdi\n",
+        "mov rax, qword ptr fs:[0]\n",
+        "mov [rbx], rax\n",
+        "mov rax, [rbx + 8]\n",
+        "add qword ptr fs:[-16], rax\n",
+        "mov eax, 4\n",
+        "syscall\n",
+        "mov eax, 4\n",
+        "syscall\n",
+        "mov rax, qword ptr fs:[-16]\n",
+        "mov [rbx + 16], rax\n",
+        "mov rax, qword ptr fs:[-8]\n",
+        "mov [rbx + 24], rax\n",
+        "mov eax, 2\n",
+        "xor edi, edi\n",
+        "syscall\n",
+        "ud2\n",
+        "tls_test_stub_end:\n",
+        ".text\n",
+    );
+
+    extern "C" {
+        static tls_test_stub: u8;
+        static tls_test_stub_end: u8;
+    }
+
+    const CODE_PAGE: usize = 0x40_0000;
+    const DATA_PAGE: usize = 0x40_1000;
+
+    // One initialized thread local, followed by one in .tbss
+    const TLS_INITIAL_VALUE: u64 = 7;
+    const TLS_SIZE: usize = 16;
+
+    // Start a task running the stub in an address space of its own. The data frame stays ours, so
+    // that we can see what was recorded.
+    fn spawn_tls_task(increment: u64, data_frame: Frame) -> TaskReference {
+        let code = unsafe {
+            let start = &tls_test_stub as *const u8;
+            let end = &tls_test_stub_end as *const u8;
+            slice::from_raw_parts(start, end as usize - start as usize)
+        };
+        let template_data = TLS_INITIAL_VALUE.to_le_bytes();
+        let template = TlsTemplate {
+            data: &template_data,
+            size: TLS_SIZE,
+            align: 8,
+        };
+
+        hyperspace::with_frame(data_frame, |ptr| unsafe {
+            ptr.write_bytes(0, PAGE_SIZE);
+            (ptr as *mut u64).add(1).write(increment);
+        })
+        .expect("Failed to fill data frame");
+
+        let mut address_space = new_address_space().expect("Failed to create address space");
+        let rsp = setup_user_stack(&mut address_space, &[], &[]).expect("Failed to set up stack");
+        {
+            let mut mapper = unsafe { address_space.mapper() };
+            map_new_page(
+                &mut mapper,
+                CODE_PAGE,
+                PresentPageFlags::USER_ACCESSIBLE,
+                code,
+                CODE_PAGE,
+            )
+            .expect("Failed to map code page");
+            let flush = mapper
+                .map_to(
+                    DATA_PAGE,
+                    data_frame,
+                    PresentPageFlags::USER_ACCESSIBLE
+                        | PresentPageFlags::WRITABLE
+                        | PresentPageFlags::NO_EXECUTE,
+                )
+                .expect("Failed to map data page");
+            unsafe { flush.ignore() };
+        }
+
+        unsafe {
+            spawn_user(address_space, Some(&template), move || {
+                enter_user_mode(CODE_PAGE, rsp, DATA_PAGE)
+            })
+        }
+        .expect("Failed to spawn user task")
+    }
+
+    #[test_case]
+    fn tasks_have_their_own_thread_locals() {
+        const INCREMENTS: [u64; 2] = [1, 2];
+
+        let frames = [
+            physmem::allocate_user_frame().expect("Failed to allocate data frame"),
+            physmem::allocate_user_frame().expect("Failed to allocate data frame"),
+        ];
+        let tasks = [
+            spawn_tls_task(INCREMENTS[0], frames[0]),
+            spawn_tls_task(INCREMENTS[1], frames[1]),
+        ];
+
+        for task in tasks.iter() {
+            assert_eq!(task.join(), 0);
+        }
+
+        for (frame, increment) in frames.iter().zip(INCREMENTS.iter()) {
+            let mut recorded = [0u64; 4];
+            hyperspace::with_frame(*frame, |ptr| unsafe {
+                (ptr as *const u64).copy_to_nonoverlapping(recorded.as_mut_ptr(), recorded.len())
+            })
+            .expect("Failed to read data frame");
+
+            let fs_base = (USER_TLS_BASE + TLS_SIZE) as u64;
+            assert_eq!(
+                recorded,
+                [fs_base, *increment, TLS_INITIAL_VALUE + increment, 0]
+            );
+        }
+    }
+
+    #[test_case]
+    fn elf_without_tls_has_no_template() {
+        // Just the file header, with no program headers
+        let mut bytes = [0; 64];
+        bytes[..4].copy_from_slice(b"\x7fELF");
+        bytes[4] = 2;
+        bytes[5] = 1;
+        bytes[16] = 2;
+        bytes[18] = 0x3e;
+        bytes[54] = 56;
+
+        assert!(matches!(tls_template(&bytes), Ok(None)));
+    }
+}
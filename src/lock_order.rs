@@ -0,0 +1,221 @@
+// The memory manager has two global locks, and whenever both are needed they must be taken in this
+// order:
+//
+//   1. The region manager lock, which protects the kernel address space allocator
+//   2. The page table lock (PAGE_LOCK), which protects the page tables themselves
+//
+// Allocating or freeing a region holds the region manager lock and then maps or unmaps the pages,
+// so the opposite order would deadlock as soon as two CPUs did it at once. That includes anything
+// that can grow the heap while the page table is locked, because growing the heap allocates a
+// region.
+//
+// In debug builds every CPU keeps a stack of the ordered locks that it holds, and taking a lock
+// that is not strictly after the last one held panics, rather than deadlocking some time later.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LockLevel {
+    RegionManager = 1,
+    PageTable = 2,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LockOrderViolation {
+    pub held: LockLevel,
+    pub acquiring: LockLevel,
+}
+
+/// Records that an ordered lock is held for as long as it lives. Create it before taking the lock,
+/// so that a violation panics instead of deadlocking.
+#[must_use = "The lock is only tracked while the token is alive"]
+pub struct LockOrderToken(#[allow(dead_code)] LockLevel);
+
+impl LockOrderToken {
+    pub fn acquire(level: LockLevel) -> Self {
+        #[cfg(debug_assertions)]
+        tracking::push(level);
+
+        Self(level)
+    }
+}
+
+impl Drop for LockOrderToken {
+    fn drop(&mut self) {
+        #[cfg(debug_assertions)]
+        tracking::pop(self.0);
+    }
+}
+
+/// Check whether taking a lock at this level now would break the lock order. This is always Ok in
+/// release builds, where nothing is tracked.
+pub fn check_acquire(level: LockLevel) -> Result<(), LockOrderViolation> {
+    #[cfg(debug_assertions)]
+    {
+        tracking::check(level)
+    }
+
+    #[cfg(not(debug_assertions))]
+    {
+        let _ = level;
+        Ok(())
+    }
+}
+
+/// How many ordered locks this CPU holds. This is always 0 in release builds.
+pub fn held_count() -> usize {
+    #[cfg(debug_assertions)]
+    {
+        tracking::held_count()
+    }
+
+    #[cfg(not(debug_assertions))]
+    {
+        0
+    }
+}
+
+/// Stop tracking all but the first count ordered locks that this CPU holds, for when the code that
+/// held the rest has been abandoned without dropping their tokens, as after a panic is caught
+pub fn forget_held_after(count: usize) {
+    #[cfg(debug_assertions)]
+    tracking::truncate(count);
+
+    #[cfg(not(debug_assertions))]
+    let _ = count;
+}
+
+#[cfg(debug_assertions)]
+mod tracking {
+    use super::{LockLevel, LockOrderViolation};
+
+    const MAX_HELD_LOCKS: usize = 8;
+
+    struct HeldLocks {
+        levels: [Option<LockLevel>; MAX_HELD_LOCKS],
+        count: usize,
+    }
+
+    #[thread_local]
+    static mut HELD_LOCKS: HeldLocks = HeldLocks {
+        levels: [None; MAX_HELD_LOCKS],
+        count: 0,
+    };
+
+    // The locks are taken while paging is being set up, before this CPU has any thread local
+    // storage, and there is nothing to track against until it does
+    fn held_locks() -> Option<&'static mut HeldLocks> {
+        if crate::gdt::tls_ready() {
+            Some(unsafe { &mut HELD_LOCKS })
+        } else {
+            None
+        }
+    }
+
+    pub fn check(level: LockLevel) -> Result<(), LockOrderViolation> {
+        let held = held_locks()
+            .filter(|held_locks| held_locks.count > 0)
+            .and_then(|held_locks| held_locks.levels[held_locks.count - 1]);
+
+        match held {
+            Some(held) if held >= level => Err(LockOrderViolation {
+                held,
+                acquiring: level,
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn push(level: LockLevel) {
+        if let Err(violation) = check(level) {
+            panic!("Lock order violation: {:?}", violation);
+        }
+
+        if let Some(held_locks) = held_locks() {
+            assert!(
+                held_locks.count < MAX_HELD_LOCKS,
+                "Too many ordered locks held"
+            );
+            held_locks.levels[held_locks.count] = Some(level);
+            held_locks.count += 1;
+        }
+    }
+
+    pub fn held_count() -> usize {
+        held_locks().map_or(0, |held_locks| held_locks.count)
+    }
+
+    pub fn truncate(count: usize) {
+        if let Some(held_locks) = held_locks() {
+            while held_locks.count > count {
+                held_locks.count -= 1;
+                held_locks.levels[held_locks.count] = None;
+            }
+        }
+    }
+
+    pub fn pop(level: LockLevel) {
+        // A lock taken before thread local storage was set up can be dropped after, so there may
+        // be nothing to pop
+        if let Some(held_locks) = held_locks() {
+            if let Some(index) = held_locks.levels[..held_locks.count]
+                .iter()
+                .rposition(|held| *held == Some(level))
+            {
+                held_locks
+                    .levels
+                    .copy_within(index + 1..held_locks.count, index);
+                held_locks.count -= 1;
+                held_locks.levels[held_locks.count] = None;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::paging::lock_page_table;
+
+    #[test_case]
+    fn region_manager_then_page_table_is_allowed() {
+        let _region_manager = LockOrderToken::acquire(LockLevel::RegionManager);
+        assert_eq!(check_acquire(LockLevel::PageTable), Ok(()));
+        let _page_table = unsafe { lock_page_table() };
+    }
+
+    // LockOrderToken::acquire panics when check_acquire fails, but a panic ends the test run, so
+    // check for the violation directly
+    #[cfg(debug_assertions)]
+    #[test_case]
+    fn page_table_then_region_manager_is_a_violation() {
+        {
+            let _page_table = unsafe { lock_page_table() };
+            assert_eq!(
+                check_acquire(LockLevel::RegionManager),
+                Err(LockOrderViolation {
+                    held: LockLevel::PageTable,
+                    acquiring: LockLevel::RegionManager,
+                })
+            );
+            assert!(check_acquire(LockLevel::PageTable).is_err());
+        }
+
+        assert_eq!(check_acquire(LockLevel::RegionManager), Ok(()));
+    }
+
+    // A test that panics with an ordered lock held never drops its token, and the tests after it
+    // mustn't see the lock as still held
+    #[cfg(debug_assertions)]
+    #[test_case]
+    fn caught_panics_forget_their_locks() {
+        let held = held_count();
+        // Only the token, as the real page table lock would stay locked for good
+        let message = crate::panic_recovery::expect_panic(|| {
+            let _page_table = LockOrderToken::acquire(LockLevel::PageTable);
+            panic!("Panicking with a lock held");
+        });
+
+        assert!(message.unwrap().contains("Panicking with a lock held"));
+        assert_eq!(held_count(), held);
+        assert_eq!(check_acquire(LockLevel::RegionManager), Ok(()));
+    }
+}
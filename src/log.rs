@@ -0,0 +1,328 @@
+use crate::sync::IrqMutex;
+use core::fmt;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(usize)]
+pub enum Level {
+    Error = 1,
+    Warn = 2,
+    Info = 3,
+    Debug = 4,
+    Trace = 5,
+}
+
+impl Level {
+    fn from_usize(level: usize) -> Self {
+        match level {
+            1 => Self::Error,
+            2 => Self::Warn,
+            3 => Self::Info,
+            4 => Self::Debug,
+            _ => Self::Trace,
+        }
+    }
+}
+
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Self::Error => "ERROR",
+            Self::Warn => "WARN ",
+            Self::Info => "INFO ",
+            Self::Debug => "DEBUG",
+            Self::Trace => "TRACE",
+        })
+    }
+}
+
+static MAX_LEVEL: AtomicUsize = AtomicUsize::new(Level::Info as usize);
+
+/// Records above this level are dropped
+pub fn set_level(level: Level) {
+    MAX_LEVEL.store(level as usize, Ordering::SeqCst);
+}
+
+pub fn level() -> Level {
+    Level::from_usize(MAX_LEVEL.load(Ordering::SeqCst))
+}
+
+pub fn enabled(level: Level) -> bool {
+    level as usize <= MAX_LEVEL.load(Ordering::Relaxed)
+}
+
+struct Record<'a> {
+    cpu_id: usize,
+    level: Level,
+    args: fmt::Arguments<'a>,
+}
+
+impl<'a> fmt::Display for Record<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "[CPU {} {}] {}", self.cpu_id, self.level, self.args)
+    }
+}
+
+// Every record is also kept in memory, so that we can replay recent history if the serial output
+// wasn't being captured. This is a plain static array so that it works before the heap is up.
+const LOG_BUFFER_SIZE: usize = 16 * 1024;
+
+struct LogBuffer {
+    data: [u8; LOG_BUFFER_SIZE],
+    // The total number of bytes ever written. The next byte goes at head % LOG_BUFFER_SIZE.
+    head: usize,
+}
+
+impl LogBuffer {
+    const fn new() -> Self {
+        Self {
+            data: [0; LOG_BUFFER_SIZE],
+            head: 0,
+        }
+    }
+
+    fn dump_to(&self, writer: &mut impl fmt::Write) -> fmt::Result {
+        let start = self.head.saturating_sub(LOG_BUFFER_SIZE);
+        let mut bytes = (start..self.head).map(|index| self.data[index % LOG_BUFFER_SIZE]);
+
+        // Once the buffer has wrapped the oldest record has probably been partly overwritten, so
+        // skip forward to the start of the next one
+        if start > 0 {
+            bytes.by_ref().find(|byte| *byte == b'\n');
+        }
+
+        for byte in bytes {
+            writer.write_char(byte as char)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl fmt::Write for LogBuffer {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            self.data[self.head % LOG_BUFFER_SIZE] = byte;
+            self.head += 1;
+        }
+        Ok(())
+    }
+}
+
+// Records can come from interrupt handlers, so the lock keeps them out while it is held
+static LOG_BUFFER: IrqMutex<LogBuffer> = IrqMutex::new(LogBuffer::new());
+
+/// Replay the in memory log over the serial port. This is meant for use from the panic handler, so
+/// if the buffer is locked it gives up rather than waiting.
+pub fn dump() {
+    match LOG_BUFFER.try_lock() {
+        Some(buffer) => {
+            crate::serial_println!("---- kernel log ----");
+            if let Some(serial) = crate::serial::SERIAL1.lock().as_mut() {
+                let _ = buffer.dump_to(serial);
+            }
+            crate::serial_println!("---- end of kernel log ----");
+        }
+
+        None => crate::serial_println!("Kernel log is locked, unable to dump it"),
+    }
+}
+
+// What a test captures goes into a fixed buffer, since records can come from an NMI, which mustn't
+// allocate. Anything past the end is dropped.
+#[cfg(test)]
+const CAPTURE_SIZE: usize = 16 * 1024;
+
+#[cfg(test)]
+struct Capture {
+    data: [u8; CAPTURE_SIZE],
+    len: usize,
+    active: bool,
+}
+
+#[cfg(test)]
+impl Capture {
+    const fn new() -> Self {
+        Self {
+            data: [0; CAPTURE_SIZE],
+            len: 0,
+            active: false,
+        }
+    }
+
+    fn start(&mut self) {
+        self.len = 0;
+        self.active = true;
+    }
+
+    fn stop(&mut self) -> alloc::string::String {
+        self.active = false;
+        alloc::string::String::from_utf8_lossy(&self.data[..self.len]).into_owned()
+    }
+}
+
+#[cfg(test)]
+impl fmt::Write for Capture {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        if self.active {
+            let len = s.len().min(CAPTURE_SIZE - self.len);
+            self.data[self.len..self.len + len].copy_from_slice(&s.as_bytes()[..len]);
+            self.len += len;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+static CAPTURE: spin::Mutex<Capture> = spin::Mutex::new(Capture::new());
+
+/// Run func, and return everything logged on any CPU while it ran
+#[cfg(test)]
+pub(crate) fn capture(func: impl FnOnce()) -> alloc::string::String {
+    CAPTURE.lock().start();
+    func();
+    CAPTURE.lock().stop()
+}
+
+// Records include the CPU ID, which lives in thread local storage, so the klog macros can't be used
+// until the per CPU data has been set up. Before that, use println!.
+#[doc(hidden)]
+pub fn _log(level: Level, args: fmt::Arguments) {
+    if !enabled(level) {
+        return;
+    }
+
+    let record = Record {
+        cpu_id: crate::cpu_id(),
+        level,
+        args,
+    };
+
+    crate::serial::_print(format_args!("{}", record));
+    crate::vga_buffer::_print(format_args!("{}", record));
+
+    {
+        use core::fmt::Write;
+        let _ = write!(LOG_BUFFER.lock(), "{}", record);
+    }
+
+    #[cfg(test)]
+    {
+        use core::fmt::Write;
+        let _ = write!(CAPTURE.lock(), "{}", record);
+    }
+}
+
+/// Log a record without waiting for any locks, for the places that could have interrupted a CPU
+/// that holds them. It goes to the serial port and the in memory log if they are free, and is
+/// dropped if neither is.
+pub fn try_log(level: Level, args: fmt::Arguments) {
+    use core::fmt::Write;
+
+    if !enabled(level) {
+        return;
+    }
+
+    let record = Record {
+        cpu_id: crate::cpu_id(),
+        level,
+        args,
+    };
+
+    if let Some(mut serial) = crate::serial::SERIAL1.try_lock() {
+        if let Some(serial) = serial.as_mut() {
+            let _ = write!(serial, "{}", record);
+        }
+    }
+
+    if let Some(mut buffer) = LOG_BUFFER.try_lock() {
+        let _ = write!(buffer, "{}", record);
+    }
+
+    #[cfg(test)]
+    {
+        if let Some(mut capture) = CAPTURE.try_lock() {
+            let _ = write!(capture, "{}", record);
+        }
+    }
+}
+
+#[macro_export]
+macro_rules! klog {
+    ($level:expr, $($arg:tt)*) => ($crate::log::_log($level, format_args!($($arg)*)));
+}
+
+#[macro_export]
+macro_rules! error {
+    ($($arg:tt)*) => ($crate::klog!($crate::log::Level::Error, $($arg)*));
+}
+
+#[macro_export]
+macro_rules! warn {
+    ($($arg:tt)*) => ($crate::klog!($crate::log::Level::Warn, $($arg)*));
+}
+
+#[macro_export]
+macro_rules! info {
+    ($($arg:tt)*) => ($crate::klog!($crate::log::Level::Info, $($arg)*));
+}
+
+#[macro_export]
+macro_rules! debug {
+    ($($arg:tt)*) => ($crate::klog!($crate::log::Level::Debug, $($arg)*));
+}
+
+#[macro_export]
+macro_rules! trace {
+    ($($arg:tt)*) => ($crate::klog!($crate::log::Level::Trace, $($arg)*));
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloc::string::String;
+
+    #[test_case]
+    fn records_above_level_are_dropped() {
+        let old_level = level();
+        let captured = capture(|| {
+            set_level(Level::Warn);
+            crate::info!("klog test info record");
+            crate::error!("klog test error record");
+            set_level(old_level);
+        });
+
+        assert!(!captured.contains("klog test info record"));
+        assert!(captured.contains("ERROR] klog test error record"));
+    }
+
+    #[test_case]
+    fn dump_shows_most_recent_records_in_order() {
+        use core::fmt::Write;
+
+        let mut buffer = box LogBuffer::new();
+        let record_size = "record 00000\n".len();
+        let record_count = (2 * LOG_BUFFER_SIZE) / record_size;
+        for i in 0..record_count {
+            writeln!(buffer, "record {:05}", i).unwrap();
+        }
+
+        let mut dumped = String::new();
+        buffer.dump_to(&mut dumped).unwrap();
+
+        let records: alloc::vec::Vec<usize> = dumped
+            .lines()
+            .map(|line| {
+                assert!(line.starts_with("record "), "Partial record {:?}", line);
+                line["record ".len()..].parse().unwrap()
+            })
+            .collect();
+
+        assert!(dumped.len() <= LOG_BUFFER_SIZE);
+        assert!(records.len() >= (LOG_BUFFER_SIZE / record_size) - 1);
+        assert_eq!(*records.last().unwrap(), record_count - 1);
+        for pair in records.windows(2) {
+            assert_eq!(pair[0] + 1, pair[1]);
+        }
+    }
+}
@@ -0,0 +1,212 @@
+use super::page_entry::RawPte;
+use super::{
+    kernel_cr3, lock_page_table, phys_to_virt, phys_to_virt_mut, Mapper, MemoryError, PageTable,
+    PageTableIndex, PageTableLevel, PresentPageFlags, Result, FIRST_KERNEL_PML4, L1, L2, L3, L4,
+};
+use crate::physmem::{self, Frame};
+use core::convert::TryFrom;
+use x86::controlregs;
+
+/// A set of page tables that tasks can run in. The user half of the address space belongs to it,
+/// and the kernel half is shared with every other address space.
+#[derive(Debug)]
+pub struct AddressSpace {
+    p4_frame: Frame,
+}
+
+impl AddressSpace {
+    /// The value to load into CR3 to switch to this address space
+    pub fn cr3(&self) -> usize {
+        self.p4_frame.physical_address()
+    }
+
+    /// A mapper for this address space's page tables, for changing the user half. Nothing flushes
+    /// the TLB for it, so the address space must not be active on any CPU while it is changed.
+    pub unsafe fn mapper(&mut self) -> Mapper {
+        Mapper::new(self.p4_frame)
+    }
+}
+
+// Every kernel PML4 entry is created while paging is initialized, and only the tables below them
+// change after that, so copying the entries shares the whole kernel half.
+pub fn new_address_space() -> Result<AddressSpace> {
+    let p4_frame = physmem::allocate_kernel_frame().ok_or(MemoryError::OutOfMemory)?;
+    let p4: &mut PageTable<L4> = unsafe { &mut *phys_to_virt_mut(p4_frame.physical_address()) };
+    p4.zero();
+
+    let page_table = unsafe { lock_page_table() };
+    let first_kernel_entry = usize::from(FIRST_KERNEL_PML4);
+    for (entry, kernel_entry) in p4
+        .iter_mut()
+        .zip(page_table.p4().iter())
+        .skip(first_kernel_entry)
+    {
+        *entry = *kernel_entry;
+    }
+
+    Ok(AddressSpace { p4_frame })
+}
+
+// The CPU sets the accessed bit in whichever copy of an entry it walks through, so the copies of a
+// shared entry can differ in that and still be the same
+fn shared_bits(pte: RawPte) -> u64 {
+    match pte.present() {
+        Ok(_) => u64::from(pte) & !PresentPageFlags::ACCESSED.bits(),
+        Err(_) => u64::from(pte),
+    }
+}
+
+// Find a kernel PML4 entry in p4 that isn't the one in the kernel page table
+fn find_unshared_kernel_entry(p4: &PageTable<L4>) -> Option<PageTableIndex> {
+    let kernel_p4: &PageTable<L4> = unsafe { &*phys_to_virt(kernel_cr3()) };
+    (usize::from(FIRST_KERNEL_PML4)..512)
+        .map(index)
+        .find(|index| shared_bits(p4[*index]) != shared_bits(kernel_p4[*index]))
+}
+
+/// Panic if an address space doesn't share the kernel half with the kernel page table. The kernel
+/// would only crash once it touched the part that differs, and only in that address space, so this
+/// is much easier to debug.
+pub fn verify_kernel_shared(address_space: &AddressSpace) {
+    verify_cr3_kernel_shared(address_space.cr3());
+}
+
+/// The same check, for the address space that cr3 would switch to
+pub fn verify_cr3_kernel_shared(cr3: usize) {
+    // The kernel page table shares with itself, and until it exists there is nothing to check
+    if cr3 == kernel_cr3() || kernel_cr3() == 0 {
+        return;
+    }
+
+    let p4: &PageTable<L4> = unsafe { &*phys_to_virt(cr3) };
+    if let Some(index) = find_unshared_kernel_entry(p4) {
+        panic!(
+            "Address space {:#x} has its own kernel PML4 entry {:?}: {:?}",
+            cr3, index, p4[index]
+        );
+    }
+}
+
+fn index(index: usize) -> PageTableIndex {
+    PageTableIndex::try_from(index).unwrap()
+}
+
+// A page table that mapper may change underneath us, so it is only ever read an entry at a time
+fn table<L: PageTableLevel>(frame: Frame) -> *const PageTable<L> {
+    phys_to_virt(frame.physical_address())
+}
+
+// Clear the entry for a table that has just been freed. Unmapping a frame that is mapped more than
+// once walks the address space, and mustn't find its way into the freed table.
+unsafe fn clear_entry<L: PageTableLevel>(table: *const PageTable<L>, entry: usize) {
+    (*(table as *mut PageTable<L>))[index(entry)].store(RawPte::unused());
+}
+
+// Unmap and free everything in the user half, and then the page tables that mapped it. Only the
+// tables that are present are walked, so an address space with a few pages is quick to free.
+unsafe fn free_user_half(p4_frame: Frame) {
+    let mut mapper = Mapper::new(p4_frame);
+    let user_entries = usize::from(FIRST_KERNEL_PML4);
+
+    for p4_index in 0..user_entries {
+        let p3_frame = match mapper.p4().next_table_frame(index(p4_index)) {
+            Some(p3_frame) => p3_frame,
+            None => continue,
+        };
+        let p3 = table::<L3>(p3_frame);
+
+        for p3_index in 0..512 {
+            let p2_frame = match (*p3).next_table_frame(index(p3_index)) {
+                Some(p2_frame) => p2_frame,
+                None => continue,
+            };
+            let p2 = table::<L2>(p2_frame);
+
+            for p2_index in 0..512 {
+                let p1_frame = match (*p2).next_table_frame(index(p2_index)) {
+                    Some(p1_frame) => p1_frame,
+                    None => continue,
+                };
+                let p1 = table::<L1>(p1_frame);
+
+                for p1_index in 0..512 {
+                    if !(*p1)[index(p1_index)].is_present() {
+                        continue;
+                    }
+
+                    // The mapper unmaps the page, so that frames that are mapped more than once
+                    // are only freed once. Nothing is running in this address space, so there is
+                    // no TLB to flush.
+                    let page = p4_index << 39 | p3_index << 30 | p2_index << 21 | p1_index << 12;
+                    mapper
+                        .unmap(page, true)
+                        .expect("User page is not canonical")
+                        .ignore();
+                }
+
+                clear_entry(p2, p2_index);
+                physmem::deallocate_frame(p1_frame);
+            }
+
+            clear_entry(p3, p3_index);
+            physmem::deallocate_frame(p2_frame);
+        }
+
+        mapper.p4_mut()[index(p4_index)].store(RawPte::unused());
+        physmem::deallocate_frame(p3_frame);
+    }
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        assert_ne!(
+            unsafe { controlregs::cr3() } as usize,
+            self.cr3(),
+            "Cannot free the active address space"
+        );
+
+        unsafe { free_user_half(self.p4_frame) };
+        physmem::deallocate_frame(self.p4_frame);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::paging::page_entry::RawPresentPte;
+
+    #[test_case]
+    fn unshared_kernel_entry_is_found() {
+        let address_space = new_address_space().expect("Failed to create address space");
+        verify_kernel_shared(&address_space);
+
+        let p4: &mut PageTable<L4> = unsafe { &mut *phys_to_virt_mut(address_space.cr3()) };
+        let last = index(511);
+        let shared_entry = p4[last];
+        let present_entry = shared_entry
+            .present()
+            .expect("Last PML4 entry is not present");
+
+        // Only the accessed bit changing doesn't count
+        p4[last].store(RawPresentPte::from_frame_flags_and_counter(
+            present_entry.frame(),
+            present_entry.flags() ^ PresentPageFlags::ACCESSED,
+            present_entry.counter(),
+        ));
+        let accessed_only = find_unshared_kernel_entry(p4);
+
+        p4[last].store(RawPresentPte::from_frame_flags_and_counter(
+            present_entry.frame(),
+            present_entry.flags() ^ PresentPageFlags::WRITABLE,
+            present_entry.counter(),
+        ));
+        let corrupted = find_unshared_kernel_entry(p4);
+
+        p4[last].store(shared_entry);
+        let restored = find_unshared_kernel_entry(p4);
+
+        assert_eq!(accessed_only, None);
+        assert_eq!(corrupted, Some(last));
+        assert_eq!(restored, None);
+    }
+}
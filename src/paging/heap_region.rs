@@ -3,9 +3,12 @@ use super::{
     lock_page_table, page_entry, ActivePageTable, Frame, MapperFlushAll, MemoryError, Result,
     PAGE_SIZE,
 };
-use crate::init_mutex::InitMutex;
+use crate::init_mutex::{InitMutex, InitMutexGuard};
+use crate::lock_order::{LockLevel, LockOrderToken};
 use crate::physmem;
 use bitflags::bitflags;
+use core::fmt;
+use core::ops::{Deref, DerefMut};
 
 bitflags! {
     pub struct PhysicalMappingFlags: u64 {
@@ -130,12 +133,105 @@ impl RegionInfo {
     }
 }
 
-struct RegionManager {
+/// What the region manager needs from the rest of the memory manager. Keeping track of the regions
+/// doesn't depend on how they are mapped or where the pages of the region map come from, so tests
+/// can drive the bookkeeping without touching the page tables.
+trait RegionBacking {
+    /// A frame for another page of the region map. It must already be mapped in the identity map.
+    fn allocate_table_frame(&mut self) -> Option<Frame>;
+    fn free_table_frame(&mut self, frame: Frame);
+
+    /// Map a free region as region_type
+    fn map(&mut self, region_entry: &RegionMapEntry, region_type: RegionType) -> Result<()>;
+    fn unmap(&mut self, region_entry: &RegionMapEntry);
+    /// Unmap the part of a region from new_limit on, as it shrinks
+    fn unmap_tail(&mut self, region_entry: &RegionMapEntry, new_limit: usize);
+}
+
+// Pages of the region map have to be kernel frames, because we depend on them already being
+// mapped. If memory is exhausted they come from the reserve, so the region manager can keep working.
+// Freeing a region only ever merges entries, so it never needs a frame at all.
+fn allocate_region_map_frame() -> Option<Frame> {
+    physmem::allocate_kernel_frame().or_else(physmem::allocate_reserved_frame)
+}
+
+/// Maps regions into the kernel's page table
+struct KernelBacking;
+
+impl RegionBacking for KernelBacking {
+    fn allocate_table_frame(&mut self) -> Option<Frame> {
+        allocate_region_map_frame()
+    }
+
+    fn free_table_frame(&mut self, frame: Frame) {
+        physmem::deallocate_frame(frame);
+    }
+
+    fn map(&mut self, region_entry: &RegionMapEntry, region_type: RegionType) -> Result<()> {
+        Self::map_region(region_entry, region_type)
+    }
+
+    fn unmap(&mut self, region_entry: &RegionMapEntry) {
+        Self::unmap_region(region_entry);
+    }
+
+    fn unmap_tail(&mut self, region_entry: &RegionMapEntry, new_limit: usize) {
+        // The header is the first page, so the tail can be unmapped like a region of its own
+        Self::unmap_region(&RegionMapEntry {
+            base: new_limit,
+            ..*region_entry
+        });
+    }
+}
+
+/// How full the region map is. Every region, and every free gap between them, takes an entry, and a
+/// new page is added to the map when the entries run out.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RegionMapStats {
+    pub pages: usize,
+    pub entries: usize,
+    /// Entries on the pages already in the map that aren't in use yet
+    pub unused_entries: usize,
+    pub free_regions: usize,
+    pub heap_regions: usize,
+    pub kernel_stacks: usize,
+    pub physical_mappings: usize,
+}
+
+// Why a region can't be freed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BadFree {
+    DoubleFree(usize),
+    NotARegion(usize),
+}
+
+impl fmt::Display for BadFree {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::DoubleFree(base) => write!(f, "Double free of region at {:#x}", base),
+            Self::NotARegion(base) => write!(f, "No region to free at {:#x}", base),
+        }
+    }
+}
+
+struct RegionManager<B: RegionBacking = KernelBacking> {
     head_page: RegionMapPage,
+    backing: B,
 }
 
 impl RegionManager {
     pub fn new(base: usize, limit: usize) -> Self {
+        Self::with_backing(base, limit, KernelBacking)
+    }
+
+    pub fn allocate_region(&mut self, pages: usize, region_type: RegionType) -> Result<Region> {
+        self.allocate(pages, region_type)
+            .map(|region_info| Region::new(region_info))
+    }
+}
+
+impl<B: RegionBacking> RegionManager<B> {
+    fn with_backing(base: usize, limit: usize, backing: B) -> Self {
         let mut entries = [RegionMapEntry {
             base: 0,
             limit: 0,
@@ -156,34 +252,39 @@ impl RegionManager {
                 },
                 entries,
             },
+            backing,
         }
     }
 
-    pub fn allocate_region(&mut self, pages: usize, region_type: RegionType) -> Result<Region> {
+    fn allocate(&mut self, pages: usize, region_type: RegionType) -> Result<RegionInfo> {
         let required_size = pages * PAGE_SIZE as usize;
-        let ret = Self::allocate_first_fit(&mut self.head_page, required_size, |entry| {
-            debug_assert_eq!(
-                entry.size(),
-                required_size,
-                "allocate_first_fit returned wrong size region"
-            );
-            debug_assert_eq!(
-                entry.region_type.unwrap(),
-                RegionType::Free,
-                "allocate_first_fit returned incorrect region type"
-            );
+        Self::allocate_first_fit(
+            &mut self.head_page,
+            &mut self.backing,
+            required_size,
+            |backing, entry| {
+                debug_assert_eq!(
+                    entry.size(),
+                    required_size,
+                    "allocate_first_fit returned wrong size region"
+                );
+                debug_assert_eq!(
+                    entry.region_type.unwrap(),
+                    RegionType::Free,
+                    "allocate_first_fit returned incorrect region type"
+                );
 
-            Self::map_region(entry, region_type)?;
-            Ok(region_type)
-        })
-        .map(|region_info| Region::new(region_info));
-        ret
+                backing.map(entry, region_type)?;
+                Ok(region_type)
+            },
+        )
     }
 
     fn allocate_first_fit(
         mut this_page: &mut RegionMapPage,
+        backing: &mut B,
         required_size: usize,
-        mapper: impl FnOnce(&RegionMapEntry) -> Result<RegionType>,
+        mapper: impl FnOnce(&mut B, &RegionMapEntry) -> Result<RegionType>,
     ) -> Result<RegionInfo> {
         loop {
             for i in 0..REGION_MAP_ENTRIES_IN_PAGE {
@@ -198,10 +299,10 @@ impl RegionManager {
 
                     Some(RegionType::Free) if this_page.entries[i].size() > required_size => {
                         // We might need a frame to extend the table. We allocate one now so that we know that
-                        // we don't have to worry about that failure mode later. This has to be a kernel frame because we
-                        // depend on it already being mapped
-                        let table_frame =
-                            physmem::allocate_kernel_frame().ok_or(MemoryError::OutOfMemory)?;
+                        // we don't have to worry about that failure mode later.
+                        let table_frame = backing
+                            .allocate_table_frame()
+                            .ok_or(MemoryError::OutOfMemory)?;
 
                         let last_entry = RegionMapEntry {
                             base: this_page.entries[i].base + required_size,
@@ -212,14 +313,14 @@ impl RegionManager {
 
                         // Do the mapping before shuffling the page entries. This is safe as long as the mapper does
                         // not recurse, which it can't
-                        let region_type = match mapper(&this_page.entries[i]) {
+                        let region_type = match mapper(backing, &this_page.entries[i]) {
                             Ok(region_type) => region_type,
 
                             Err(e) => {
                                 // We need to put the region back as it was. This is why we did the mapping before the
                                 // shuffle because the only thing we need to reverse is the size change
                                 this_page.entries[i].limit = last_entry.limit;
-                                physmem::deallocate_frame(table_frame);
+                                backing.free_table_frame(table_frame);
                                 return Err(e);
                             }
                         };
@@ -232,7 +333,7 @@ impl RegionManager {
 
                         // If we didn't use the frame, we can free it
                         if let Some(unused_frame) = table_frame {
-                            physmem::deallocate_frame(unused_frame);
+                            backing.free_table_frame(unused_frame);
                         }
 
                         return Ok(this_page.entries[i].region_info());
@@ -240,7 +341,7 @@ impl RegionManager {
 
                     Some(RegionType::Free) if this_page.entries[i].size() == required_size => {
                         // We've found a region that is exactly the right size, so all we need to do is map it
-                        let region_type = mapper(&this_page.entries[i])?;
+                        let region_type = mapper(backing, &this_page.entries[i])?;
                         this_page.entries[i].region_type = Some(region_type);
 
                         return Ok(this_page.entries[i].region_info());
@@ -292,165 +393,43 @@ impl RegionManager {
         }
     }
 
-    fn map_region(region_entry: &RegionMapEntry, region_type: RegionType) -> Result<()> {
-        debug_assert_eq!(
-            region_entry.region_type.unwrap(),
-            RegionType::Free,
-            "map_region can only be used on free regions"
-        );
-
-        match region_type {
-            RegionType::Heap => Self::map_nonpaged(region_entry.base, region_entry.limit)?,
-            RegionType::KernelStack => {
-                Self::map_kernel_stack(region_entry.base, region_entry.limit)?
-            }
-            RegionType::PhysicalMapping(physical_mapping) => {
-                Self::map_physical_memory(&physical_mapping, region_entry.base, region_entry.limit)?
-            }
-
-            RegionType::Free => panic!("Cannot map free region"),
+    pub fn deallocate_region(&mut self, region_info: &RegionInfo) {
+        // Freeing merges entries as it goes, so a bad free has to be caught before it starts or it
+        // would corrupt the map
+        if let Err(bad_free) = self.check_deallocate(region_info) {
+            panic!("{}", bad_free);
         }
 
-        Ok(())
+        Self::deallocate_recurse_thing(&mut self.head_page, &mut self.backing, region_info);
     }
 
-    fn map_nonpaged_impl(
-        page_table: &mut ActivePageTable,
-        flusher: &mut MapperFlushAll,
-        base: usize,
-        limit: usize,
-        unmap_base: usize,
-        unmap_limit: usize,
-    ) -> Result<()> {
-        let allocate_result: Result<()> = try {
-            let pages = (limit - base) / PAGE_SIZE as usize;
-            for page in 0..pages {
-                let page_addr = base + (page * PAGE_SIZE as usize);
-                // We can use user frames here since we're mapping them
-                let frame = physmem::allocate_user_frame().ok_or(MemoryError::OutOfMemory)?;
-
-                flusher.consume(page_table.map_to(
-                    page_addr,
-                    frame,
-                    PresentPageFlags::WRITABLE
-                        | PresentPageFlags::GLOBAL
-                        | PresentPageFlags::NO_EXECUTE,
-                )?);
+    // Check that region_info is exactly one entry in the map, and that it is in use
+    fn check_deallocate(&self, region_info: &RegionInfo) -> core::result::Result<(), BadFree> {
+        let start = region_info.start_va;
+        let mut page = Some(&self.head_page);
+        while let Some(this_page) = page {
+            let entry = this_page.entries.iter().find(|entry| {
+                entry.region_type.is_some() && entry.base <= start && start < entry.limit
+            });
+
+            match entry {
+                Some(entry) if entry.region_type == Some(RegionType::Free) => {
+                    return Err(BadFree::DoubleFree(start))
+                }
+                Some(entry) if entry.base == start && entry.limit == region_info.limit_va => {
+                    return Ok(())
+                }
+                Some(_) => return Err(BadFree::NotARegion(start)),
+                None => page = this_page.header.next_entry.as_deref(),
             }
-        };
-
-        if allocate_result.is_err() {
-            Self::unmap_nonpaged(unmap_base, unmap_limit, true);
         }
 
-        allocate_result
-    }
-
-    fn map_nonpaged(base: usize, limit: usize) -> Result<()> {
-        debug_assert!(limit > base, "Invalid range");
-        debug_assert_eq!(
-            base,
-            align_up(base, PAGE_SIZE as usize),
-            "base address is not page aligned"
-        );
-        debug_assert_eq!(
-            limit,
-            align_down(limit, PAGE_SIZE as usize),
-            "limit address is not page aligned"
-        );
-
-        let mut page_table = unsafe { lock_page_table() };
-        let mut flusher = MapperFlushAll::new();
-
-        let result =
-            Self::map_nonpaged_impl(&mut page_table, &mut flusher, base, limit, base, limit);
-
-        flusher.flush(&mut page_table);
-
-        result
-    }
-
-    fn map_kernel_stack(base: usize, limit: usize) -> Result<()> {
-        debug_assert!(limit > base + PAGE_SIZE, "Invalid range");
-        debug_assert_eq!(
-            base,
-            align_up(base, PAGE_SIZE as usize),
-            "base address is not page aligned"
-        );
-        debug_assert_eq!(
-            limit,
-            align_down(limit, PAGE_SIZE as usize),
-            "limit address is not page aligned"
-        );
-
-        let mut page_table = unsafe { lock_page_table() };
-        let mut flusher = MapperFlushAll::new();
-
-        let result = try {
-            flusher.consume(
-                page_table.set_not_present(base, page_entry::KernelStackGuardPagePte::new())?,
-            );
-            Self::map_nonpaged_impl(
-                &mut page_table,
-                &mut flusher,
-                base + PAGE_SIZE,
-                limit,
-                base,
-                limit,
-            )?;
-        };
-
-        flusher.flush(&mut page_table);
-        result
-    }
-
-    fn map_physical_memory(
-        physical_mapping: &PhysicalMapping,
-        base: usize,
-        limit: usize,
-    ) -> Result<()> {
-        debug_assert!(limit > base, "Invalid range");
-        debug_assert_eq!(
-            base,
-            align_up(base, PAGE_SIZE as usize),
-            "base address is not page aligned"
-        );
-        debug_assert_eq!(
-            limit,
-            align_down(limit, PAGE_SIZE as usize),
-            "limit address is not page aligned"
-        );
-
-        let mut page_table = unsafe { lock_page_table() };
-        let mut flusher = MapperFlushAll::new();
-
-        let result = try {
-            let pages = (limit - base) / PAGE_SIZE as usize;
-            for page in 0..pages {
-                let page_addr = base + (page * PAGE_SIZE as usize);
-                // We can use user frames here since we're mapping them
-                let frame = Frame::containing_address(
-                    physical_mapping.physical_address + (page * PAGE_SIZE),
-                );
-
-                flusher.consume(page_table.map_to(
-                    page_addr,
-                    frame,
-                    physical_mapping.flags.into(),
-                )?);
-            }
-        };
-
-        flusher.flush(&mut page_table);
-        result
-    }
-
-    pub fn deallocate_region(&mut self, region_info: &RegionInfo) {
-        Self::deallocate_recurse_thing(&mut self.head_page, region_info);
+        Err(BadFree::NotARegion(start))
     }
 
     fn deallocate_recurse_thing<'a>(
         mut this_page: &'a mut RegionMapPage,
+        backing: &mut B,
         region_info: &RegionInfo,
     ) {
         loop {
@@ -473,13 +452,13 @@ impl RegionManager {
                         // We could check here whether the next region is good, but there is no need - it
                         // will be checked later before we free it.
                         this_page.entries[j] = this_page.entries[j + 1];
-                        Self::shuffle_entries_down(this_page, j + 1);
+                        Self::shuffle_entries_down(this_page, backing, j + 1);
                     } else {
                         let next_page = this_page.header.next_entry.as_mut().unwrap();
                         // We could check here whether the next region is good, but there is no need - it
                         // will be checked later before we free it.
                         this_page.entries[j] = next_page.entries[0];
-                        Self::shuffle_entries_down(next_page, 0);
+                        Self::shuffle_entries_down(next_page, backing, 0);
                     }
 
                     Some((j, lead_bytes))
@@ -500,14 +479,14 @@ impl RegionManager {
                         "Attempting to free invalid region"
                     );
 
-                    Self::unmap_region(&this_page.entries[drop_region_index]);
+                    backing.unmap(&this_page.entries[drop_region_index]);
 
                     let tail_bytes = if drop_region_index + 1 < REGION_MAP_ENTRIES_IN_PAGE {
                         if this_page.entries[drop_region_index + 1].region_type
                             == Some(RegionType::Free)
                         {
                             let tail_bytes = this_page.entries[drop_region_index + 1].size();
-                            Self::shuffle_entries_down(this_page, drop_region_index + 1);
+                            Self::shuffle_entries_down(this_page, backing, drop_region_index + 1);
                             tail_bytes
                         } else {
                             // The next entry is not free so leave it alone
@@ -518,7 +497,7 @@ impl RegionManager {
                         let next_page = this_page.header.next_entry.as_mut().unwrap();
                         if next_page.entries[0].region_type == Some(RegionType::Free) {
                             let tail_bytes = next_page.entries[0].size();
-                            Self::shuffle_entries_down(next_page, 0);
+                            Self::shuffle_entries_down(next_page, backing, 0);
                             tail_bytes
                         } else {
                             // The next entry is not free so leave it alone
@@ -545,7 +524,124 @@ impl RegionManager {
         }
     }
 
-    fn shuffle_entries_down(mut this_page: &mut RegionMapPage, region_index: usize) {
+    pub fn shrink_region(&mut self, region_info: &RegionInfo, new_limit: usize) -> Result<()> {
+        assert!(
+            new_limit > region_info.start_va && new_limit < region_info.limit_va,
+            "Invalid limit for shrinking region"
+        );
+
+        // The pages given back might need an entry of their own, so get the frame for that first,
+        // before anything has changed
+        let mut table_frame = Some(
+            self.backing
+                .allocate_table_frame()
+                .ok_or(MemoryError::OutOfMemory)?,
+        );
+        Self::shrink_entry(
+            &mut self.head_page,
+            &mut self.backing,
+            region_info,
+            new_limit,
+            &mut table_frame,
+        );
+
+        if let Some(unused_frame) = table_frame {
+            self.backing.free_table_frame(unused_frame);
+        }
+        Ok(())
+    }
+
+    fn shrink_entry(
+        mut this_page: &mut RegionMapPage,
+        backing: &mut B,
+        region_info: &RegionInfo,
+        new_limit: usize,
+        table_frame: &mut Option<Frame>,
+    ) {
+        loop {
+            let found = this_page.entries.iter().position(|entry| {
+                entry.region_type.is_some() && entry.base == region_info.start_va
+            });
+
+            if let Some(i) = found {
+                let entry = this_page.entries[i];
+                assert_ne!(
+                    entry.region_type.unwrap(),
+                    RegionType::Free,
+                    "Attempting to shrink invalid region"
+                );
+                assert_eq!(
+                    entry.limit, region_info.limit_va,
+                    "Attempting to shrink invalid region"
+                );
+
+                backing.unmap_tail(&entry, new_limit);
+                this_page.entries[i].limit = new_limit;
+
+                // Merge the pages given back into the free entry after this one, if there is one
+                let next_entry = if i + 1 < REGION_MAP_ENTRIES_IN_PAGE {
+                    Some(&mut this_page.entries[i + 1])
+                } else {
+                    this_page
+                        .header
+                        .next_entry
+                        .as_mut()
+                        .map(|next_page| &mut next_page.entries[0])
+                };
+
+                match next_entry {
+                    Some(next_entry) if next_entry.region_type == Some(RegionType::Free) => {
+                        next_entry.base = new_limit;
+                    }
+
+                    _ => {
+                        let free_entry = RegionMapEntry {
+                            base: new_limit,
+                            limit: entry.limit,
+                            region_type: Some(RegionType::Free),
+                        };
+                        Self::shuffle_entries_up(this_page, i + 1, free_entry, table_frame);
+                    }
+                }
+
+                return;
+            }
+
+            assert!(
+                this_page.header.next_entry.is_some(),
+                "Attempting to shrink an invalid region"
+            );
+            this_page = this_page.header.next_entry.as_mut().unwrap();
+        }
+    }
+
+    pub fn stats(&self) -> RegionMapStats {
+        let mut stats = RegionMapStats::default();
+        let mut page = Some(&self.head_page);
+        while let Some(this_page) = page {
+            stats.pages += 1;
+            for entry in this_page.entries.iter() {
+                match entry.region_type {
+                    None => stats.unused_entries += 1,
+                    Some(RegionType::Free) => stats.free_regions += 1,
+                    Some(RegionType::Heap) => stats.heap_regions += 1,
+                    Some(RegionType::KernelStack) => stats.kernel_stacks += 1,
+                    Some(RegionType::PhysicalMapping(_)) => stats.physical_mappings += 1,
+                }
+            }
+
+            page = this_page.header.next_entry.as_deref();
+        }
+
+        stats.entries = stats.pages * REGION_MAP_ENTRIES_IN_PAGE - stats.unused_entries;
+        stats
+    }
+
+    fn shuffle_entries_down(
+        mut this_page: &mut RegionMapPage,
+        backing: &mut B,
+        region_index: usize,
+    ) {
         let mut pos = region_index;
         loop {
             if pos == REGION_MAP_ENTRIES_IN_PAGE - 1 {
@@ -562,7 +658,7 @@ impl RegionManager {
                     let next_page = this_page.header.next_entry.take();
                     let next_page_ref = next_page.as_ref().unwrap();
                     if let Some(frame) = next_page_ref.header.frame {
-                        physmem::deallocate_frame(frame);
+                        backing.free_table_frame(frame);
                     }
 
                     return;
@@ -581,35 +677,194 @@ impl RegionManager {
             }
         }
     }
+}
 
-    fn unmap_region(region_entry: &RegionMapEntry) {
-        debug_assert_ne!(
+impl KernelBacking {
+    fn map_region(region_entry: &RegionMapEntry, region_type: RegionType) -> Result<()> {
+        debug_assert_eq!(
             region_entry.region_type.unwrap(),
             RegionType::Free,
-            "unmap_region cannot be used on free regions"
+            "map_region can only be used on free regions"
         );
 
-        match region_entry.region_type.unwrap() {
-            RegionType::Heap | RegionType::KernelStack => {
-                Self::unmap_nonpaged(region_entry.base, region_entry.limit, true)
+        match region_type {
+            RegionType::Heap => Self::map_nonpaged(region_entry.base, region_entry.limit)?,
+            RegionType::KernelStack => {
+                Self::map_kernel_stack(region_entry.base, region_entry.limit)?
             }
-            RegionType::PhysicalMapping(_) => {
-                Self::unmap_nonpaged(region_entry.base, region_entry.limit, false)
+            RegionType::PhysicalMapping(physical_mapping) => {
+                Self::map_physical_memory(&physical_mapping, region_entry.base, region_entry.limit)?
             }
 
-            RegionType::Free => panic!("Cannot unmap free region"),
+            RegionType::Free => panic!("Cannot map free region"),
         }
+
+        Ok(())
     }
 
-    fn unmap_nonpaged(base: usize, limit: usize, free_pages: bool) {
-        debug_assert!(limit > base, "Invalid range");
-        debug_assert_eq!(
-            base,
-            align_up(base, PAGE_SIZE as usize),
-            "base address is not page aligned"
-        );
-        debug_assert_eq!(
-            limit,
+    fn map_nonpaged_impl(
+        page_table: &mut ActivePageTable,
+        flusher: &mut MapperFlushAll,
+        base: usize,
+        limit: usize,
+        unmap_base: usize,
+        unmap_limit: usize,
+    ) -> Result<()> {
+        let allocate_result: Result<()> = try {
+            let pages = (limit - base) / PAGE_SIZE as usize;
+            for page in 0..pages {
+                let page_addr = base + (page * PAGE_SIZE as usize);
+                // We can use user frames here since we're mapping them
+                let frame = physmem::allocate_user_frame().ok_or(MemoryError::OutOfMemory)?;
+
+                let mut flags = PresentPageFlags::WRITABLE
+                    | PresentPageFlags::GLOBAL
+                    | PresentPageFlags::NO_EXECUTE;
+                // unmap_base is the start of the whole region. Kernel stacks start with a guard page
+                // which is the header instead.
+                if page_addr == unmap_base {
+                    flags |= PresentPageFlags::REGION_HEADER;
+                }
+
+                flusher.consume(page_table.map_to(page_addr, frame, flags)?);
+            }
+        };
+
+        if allocate_result.is_err() {
+            Self::unmap_nonpaged(unmap_base, unmap_limit, true);
+        }
+
+        allocate_result
+    }
+
+    fn map_nonpaged(base: usize, limit: usize) -> Result<()> {
+        debug_assert!(limit > base, "Invalid range");
+        debug_assert_eq!(
+            base,
+            align_up(base, PAGE_SIZE as usize),
+            "base address is not page aligned"
+        );
+        debug_assert_eq!(
+            limit,
+            align_down(limit, PAGE_SIZE as usize),
+            "limit address is not page aligned"
+        );
+
+        let mut page_table = unsafe { lock_page_table() };
+        let mut flusher = MapperFlushAll::new();
+
+        let result =
+            Self::map_nonpaged_impl(&mut page_table, &mut flusher, base, limit, base, limit);
+
+        flusher.flush(&mut page_table);
+
+        result
+    }
+
+    fn map_kernel_stack(base: usize, limit: usize) -> Result<()> {
+        debug_assert!(limit > base + PAGE_SIZE, "Invalid range");
+        debug_assert_eq!(
+            base,
+            align_up(base, PAGE_SIZE as usize),
+            "base address is not page aligned"
+        );
+        debug_assert_eq!(
+            limit,
+            align_down(limit, PAGE_SIZE as usize),
+            "limit address is not page aligned"
+        );
+
+        let mut page_table = unsafe { lock_page_table() };
+        let mut flusher = MapperFlushAll::new();
+
+        let result = try {
+            flusher.consume(
+                page_table.set_not_present(base, page_entry::KernelStackGuardPagePte::new())?,
+            );
+            Self::map_nonpaged_impl(
+                &mut page_table,
+                &mut flusher,
+                base + PAGE_SIZE,
+                limit,
+                base,
+                limit,
+            )?;
+        };
+
+        flusher.flush(&mut page_table);
+        result
+    }
+
+    fn map_physical_memory(
+        physical_mapping: &PhysicalMapping,
+        base: usize,
+        limit: usize,
+    ) -> Result<()> {
+        debug_assert!(limit > base, "Invalid range");
+        debug_assert_eq!(
+            base,
+            align_up(base, PAGE_SIZE as usize),
+            "base address is not page aligned"
+        );
+        debug_assert_eq!(
+            limit,
+            align_down(limit, PAGE_SIZE as usize),
+            "limit address is not page aligned"
+        );
+
+        let mut page_table = unsafe { lock_page_table() };
+        let mut flusher = MapperFlushAll::new();
+
+        let result = try {
+            let pages = (limit - base) / PAGE_SIZE as usize;
+            for page in 0..pages {
+                let page_addr = base + (page * PAGE_SIZE as usize);
+                // We can use user frames here since we're mapping them
+                let frame = Frame::containing_address(
+                    physical_mapping.physical_address + (page * PAGE_SIZE),
+                );
+
+                let mut flags: PresentPageFlags = physical_mapping.flags.into();
+                if page == 0 {
+                    flags |= PresentPageFlags::REGION_HEADER;
+                }
+
+                flusher.consume(page_table.map_to(page_addr, frame, flags)?);
+            }
+        };
+
+        flusher.flush(&mut page_table);
+        result
+    }
+
+    fn unmap_region(region_entry: &RegionMapEntry) {
+        debug_assert_ne!(
+            region_entry.region_type.unwrap(),
+            RegionType::Free,
+            "unmap_region cannot be used on free regions"
+        );
+
+        match region_entry.region_type.unwrap() {
+            RegionType::Heap | RegionType::KernelStack => {
+                Self::unmap_nonpaged(region_entry.base, region_entry.limit, true)
+            }
+            RegionType::PhysicalMapping(_) => {
+                Self::unmap_nonpaged(region_entry.base, region_entry.limit, false)
+            }
+
+            RegionType::Free => panic!("Cannot unmap free region"),
+        }
+    }
+
+    fn unmap_nonpaged(base: usize, limit: usize, free_pages: bool) {
+        debug_assert!(limit > base, "Invalid range");
+        debug_assert_eq!(
+            base,
+            align_up(base, PAGE_SIZE as usize),
+            "base address is not page aligned"
+        );
+        debug_assert_eq!(
+            limit,
             align_down(limit, PAGE_SIZE as usize),
             "limit address is not page aligned"
         );
@@ -621,7 +876,11 @@ impl RegionManager {
         for page in 0..pages {
             let page_addr = base + (page * PAGE_SIZE as usize);
 
-            flusher.consume(page_table.unmap(page_addr, free_pages));
+            flusher.consume(
+                page_table
+                    .unmap(page_addr, free_pages)
+                    .expect("Region page is not canonical"),
+            );
         }
 
         flusher.flush(&mut page_table);
@@ -630,11 +889,50 @@ impl RegionManager {
 
 static REGION_MANAGER: InitMutex<RegionManager> = InitMutex::new();
 
+// The region manager maps and unmaps pages with its lock held, so it comes before the page table
+// lock in the lock order
+struct RegionManagerGuard {
+    guard: InitMutexGuard<'static, RegionManager>,
+    _order: LockOrderToken,
+}
+
+impl Deref for RegionManagerGuard {
+    type Target = RegionManager;
+    fn deref(&self) -> &Self::Target {
+        &self.guard
+    }
+}
+
+impl DerefMut for RegionManagerGuard {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.guard
+    }
+}
+
+fn lock_region_manager() -> RegionManagerGuard {
+    let _order = LockOrderToken::acquire(LockLevel::RegionManager);
+    RegionManagerGuard {
+        guard: REGION_MANAGER.lock(),
+        _order,
+    }
+}
+
+/// A value that Region::read_volatile and write_volatile can move. Any bit pattern has to be a
+/// valid value, because that is what reading device memory can give.
+pub unsafe trait MmioValue: Copy {}
+
+unsafe impl MmioValue for u8 {}
+unsafe impl MmioValue for u16 {}
+unsafe impl MmioValue for u32 {}
+unsafe impl MmioValue for u64 {}
+
 #[derive(Debug)]
 pub struct Region {
     region_info: RegionInfo,
     sub_region_offset: usize,
     sub_region_length: usize,
+    // Whether dropping this gives the region back. Views of a mapping that is kept for good don't.
+    owned: bool,
 }
 
 impl Region {
@@ -643,6 +941,7 @@ impl Region {
             region_info,
             sub_region_offset: 0,
             sub_region_length: region_info.size(),
+            owned: true,
         }
     }
 
@@ -654,14 +953,46 @@ impl Region {
             region_info: md.region_info,
             sub_region_offset: md.sub_region_offset + offset,
             sub_region_length: length,
+            owned: md.owned,
+        }
+    }
+
+    /// A region covering length bytes of this one from offset, which leaves the mapping alone when
+    /// it is dropped. This region must never be dropped while the view is in use, so this is only
+    /// for mappings that are kept for good.
+    pub unsafe fn view(&self, offset: usize, length: usize) -> Self {
+        assert!(
+            offset
+                .checked_add(length)
+                .map_or(false, |end| end <= self.size()),
+            "View at {:#x} is outside the region",
+            offset
+        );
+        Self {
+            region_info: self.region_info,
+            sub_region_offset: self.sub_region_offset + offset,
+            sub_region_length: length,
+            owned: false,
         }
     }
 
+    // Whether a T at offset is inside the region
+    fn fits<T>(&self, offset: usize) -> bool {
+        offset
+            .checked_add(core::mem::size_of::<T>())
+            .map_or(false, |end| end <= self.size())
+    }
+
     pub fn as_ptr<T>(&self) -> *const T {
         self.as_ptr_offset(0)
     }
 
     pub fn as_ptr_offset<T>(&self, offset: usize) -> *const T {
+        debug_assert!(
+            self.fits::<T>(offset),
+            "Offset {:#x} is outside the region",
+            offset
+        );
         (self.start() + offset) as *const T
     }
     pub fn as_mut_ptr<T>(&mut self) -> *mut T {
@@ -669,9 +1000,35 @@ impl Region {
     }
 
     pub fn as_mut_ptr_offset<T>(&mut self, offset: usize) -> *mut T {
+        debug_assert!(
+            self.fits::<T>(offset),
+            "Offset {:#x} is outside the region",
+            offset
+        );
         (self.start() + offset) as *mut T
     }
 
+    /// Read a register or other value from the region in a single access
+    pub fn read_volatile<T: MmioValue>(&self, offset: usize) -> T {
+        assert!(
+            self.fits::<T>(offset),
+            "Offset {:#x} is outside the region",
+            offset
+        );
+        unsafe { core::ptr::read_volatile((self.start() + offset) as *const T) }
+    }
+
+    /// Write a register or other value in the region in a single access. Device registers are
+    /// written through a shared reference, so this doesn't need a mutable one.
+    pub fn write_volatile<T: MmioValue>(&self, offset: usize, value: T) {
+        assert!(
+            self.fits::<T>(offset),
+            "Offset {:#x} is outside the region",
+            offset
+        );
+        unsafe { core::ptr::write_volatile((self.start() + offset) as *mut T, value) }
+    }
+
     pub fn start(&self) -> usize {
         self.region_info.start_va + self.sub_region_offset
     }
@@ -683,11 +1040,30 @@ impl Region {
     pub fn size(&self) -> usize {
         self.sub_region_length
     }
+
+    /// Cut the region down to new_size bytes, and give the pages after them back to the region
+    /// manager. The start stays where it is, and at least one page is always kept.
+    pub fn shrink(&mut self, new_size: usize) -> Result<()> {
+        assert!(new_size <= self.size(), "Shrinking a region can't grow it");
+        assert!(self.owned, "Only the owner of a region can shrink it");
+
+        let new_limit =
+            align_up(self.start() + new_size, PAGE_SIZE).max(self.region_info.start_va + PAGE_SIZE);
+        if new_limit < self.region_info.limit_va {
+            lock_region_manager().shrink_region(&self.region_info, new_limit)?;
+            self.region_info.limit_va = new_limit;
+        }
+
+        self.sub_region_length = new_size;
+        Ok(())
+    }
 }
 
 impl Drop for Region {
     fn drop(&mut self) {
-        REGION_MANAGER.lock().deallocate_region(&self.region_info);
+        if self.owned {
+            lock_region_manager().deallocate_region(&self.region_info);
+        }
     }
 }
 
@@ -697,15 +1073,26 @@ pub unsafe fn init(base: usize, limit: usize) {
     REGION_MANAGER.init(RegionManager::new(base, limit));
 }
 
+/// How full the kernel's region map is, for when it runs out of room
+pub fn region_map_stats() -> RegionMapStats {
+    lock_region_manager().stats()
+}
+
 pub fn allocate_region(pages: usize) -> Result<Region> {
-    REGION_MANAGER
-        .lock()
-        .allocate_region(pages, RegionType::Heap)
+    lock_region_manager().allocate_region(pages, RegionType::Heap)
 }
 
+// Kernel stacks are counted in pages including the guard page, so there has to be at least one
+// more page for the stack itself
+const MIN_KERNEL_STACK_PAGES: usize = 2;
+const MAX_KERNEL_STACK_PAGES: usize = 63;
+
 pub fn allocate_kernel_stack(pages: usize) -> Result<KernelStack> {
-    REGION_MANAGER
-        .lock()
+    if pages < MIN_KERNEL_STACK_PAGES || pages > MAX_KERNEL_STACK_PAGES {
+        return Err(MemoryError::InvalidStack);
+    }
+
+    lock_region_manager()
         .allocate_region(pages, RegionType::KernelStack)
         .map(|region| KernelStack::new(region))
 }
@@ -720,8 +1107,7 @@ pub unsafe fn map_physical_memory(
     let pages = (aligned_limit - aligned_start) / PAGE_SIZE;
     let offset = physical_address - aligned_start;
 
-    REGION_MANAGER
-        .lock()
+    lock_region_manager()
         .allocate_region(
             pages,
             RegionType::PhysicalMapping(PhysicalMapping {
@@ -731,3 +1117,533 @@ pub unsafe fn map_physical_memory(
         )
         .map(|region| region.apply_offset(offset, size))
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::panic_recovery::expect_panic;
+    use alloc::vec::Vec;
+
+    // The pages of the region map are reached through the identity map, so they are real kernel
+    // frames, but nothing else is mapped
+    #[derive(Default)]
+    struct TestBacking {
+        table_frames: usize,
+        mapped_regions: usize,
+        unmapped_tail_pages: usize,
+        fail_maps: bool,
+    }
+
+    impl RegionBacking for TestBacking {
+        fn allocate_table_frame(&mut self) -> Option<Frame> {
+            let frame = allocate_region_map_frame()?;
+            self.table_frames += 1;
+            Some(frame)
+        }
+
+        fn free_table_frame(&mut self, frame: Frame) {
+            self.table_frames -= 1;
+            physmem::deallocate_frame(frame);
+        }
+
+        fn map(&mut self, _region_entry: &RegionMapEntry, _region_type: RegionType) -> Result<()> {
+            if self.fail_maps {
+                return Err(MemoryError::OutOfMemory);
+            }
+
+            self.mapped_regions += 1;
+            Ok(())
+        }
+
+        fn unmap(&mut self, _region_entry: &RegionMapEntry) {
+            self.mapped_regions -= 1;
+        }
+
+        fn unmap_tail(&mut self, region_entry: &RegionMapEntry, new_limit: usize) {
+            self.unmapped_tail_pages += (region_entry.limit - new_limit) / PAGE_SIZE;
+        }
+    }
+
+    // Nothing is ever mapped here, so it doesn't matter that it isn't part of the kernel heap
+    const TEST_BASE: usize = 0xffff_a000_0000_0000;
+    const TEST_PAGES: usize = 4096;
+    const TEST_LIMIT: usize = TEST_BASE + TEST_PAGES * PAGE_SIZE;
+
+    fn test_manager() -> RegionManager<TestBacking> {
+        RegionManager::with_backing(TEST_BASE, TEST_LIMIT, TestBacking::default())
+    }
+
+    // Check that the entries cover the whole range in order, that no two free entries are next to
+    // each other, and that every page after the first is in use. Returns how many entries and
+    // pages there are.
+    fn check_region_map(manager: &RegionManager<TestBacking>) -> (usize, usize) {
+        let mut expected_base = TEST_BASE;
+        let mut previous_free = false;
+        let mut ended = false;
+        let mut entries = 0;
+        let mut pages = 0;
+
+        let mut page = Some(&manager.head_page);
+        while let Some(this_page) = page {
+            pages += 1;
+            assert!(
+                pages == 1 || this_page.entries[0].region_type.is_some(),
+                "Region map page {} is empty",
+                pages
+            );
+
+            for entry in this_page.entries.iter() {
+                let region_type = match entry.region_type {
+                    Some(region_type) => region_type,
+                    None => {
+                        ended = true;
+                        continue;
+                    }
+                };
+
+                assert!(!ended, "Entry at {:#x} is after the end", entry.base);
+                assert_eq!(entry.base, expected_base, "Region map has a gap or overlap");
+                assert!(entry.limit > entry.base, "Empty entry at {:#x}", entry.base);
+
+                let free = region_type == RegionType::Free;
+                assert!(
+                    !(free && previous_free),
+                    "Free entry at {:#x} was not merged",
+                    entry.base
+                );
+
+                previous_free = free;
+                expected_base = entry.limit;
+                entries += 1;
+            }
+
+            page = this_page.header.next_entry.as_deref();
+        }
+
+        assert_eq!(expected_base, TEST_LIMIT);
+        assert_eq!(manager.backing.table_frames, pages - 1);
+        (entries, pages)
+    }
+
+    fn xorshift(state: &mut u64) -> usize {
+        *state ^= *state << 13;
+        *state ^= *state >> 7;
+        *state ^= *state << 17;
+        *state as usize
+    }
+
+    #[test_case]
+    fn random_allocations_keep_the_region_map_consistent() {
+        let mut manager = test_manager();
+        let mut live = Vec::new();
+        let mut state = 0x2545_f491_4f6c_dd1d;
+        let mut most_pages = 0;
+
+        for _ in 0..4000 {
+            let random = xorshift(&mut state);
+            if live.is_empty() || random % 3 != 0 {
+                let pages = (random >> 8) % 8 + 1;
+                if let Ok(region_info) = manager.allocate(pages, RegionType::Heap) {
+                    assert_eq!(region_info.size(), pages * PAGE_SIZE);
+                    live.push(region_info);
+                }
+            } else {
+                let region_info = live.swap_remove((random >> 8) % live.len());
+                manager.deallocate_region(&region_info);
+            }
+
+            let (_, pages) = check_region_map(&manager);
+            most_pages = most_pages.max(pages);
+            assert_eq!(manager.backing.mapped_regions, live.len());
+        }
+
+        assert!(most_pages > 1, "The region map never needed a second page");
+
+        for region_info in live.drain(..) {
+            manager.deallocate_region(&region_info);
+            check_region_map(&manager);
+        }
+        assert_eq!(check_region_map(&manager), (1, 1));
+        assert_eq!(manager.backing.mapped_regions, 0);
+    }
+
+    #[test_case]
+    fn emptied_region_map_pages_are_freed() {
+        let mut manager = test_manager();
+        let regions: Vec<_> = (0..REGION_MAP_ENTRIES_IN_PAGE * 3)
+            .map(|_| manager.allocate(1, RegionType::Heap).unwrap())
+            .collect();
+        let (_, pages) = check_region_map(&manager);
+        assert!(pages > 3);
+
+        // Freeing every other region doesn't merge anything, so the map stays the same size
+        for region_info in regions.iter().step_by(2) {
+            manager.deallocate_region(region_info);
+        }
+        assert_eq!(check_region_map(&manager).1, pages);
+
+        for region_info in regions.iter().skip(1).step_by(2) {
+            manager.deallocate_region(region_info);
+        }
+        assert_eq!(check_region_map(&manager), (1, 1));
+    }
+
+    #[test_case]
+    fn region_map_grows_from_the_reserve_when_out_of_memory() {
+        // Fill the first page of the region map, so the next allocation has to add another
+        let mut manager = test_manager();
+        let regions: Vec<_> = (0..REGION_MAP_ENTRIES_IN_PAGE - 1)
+            .map(|_| manager.allocate(1, RegionType::Heap).unwrap())
+            .collect();
+        assert_eq!(check_region_map(&manager), (REGION_MAP_ENTRIES_IN_PAGE, 1));
+
+        let exhausted = physmem::ExhaustedKernelFrames::new();
+        let reserved_frames = physmem::reserved_frames();
+        assert!(reserved_frames > 0);
+
+        let grown = manager
+            .allocate(1, RegionType::Heap)
+            .expect("Region map failed to grow while out of memory");
+        let grown_map = check_region_map(&manager);
+        let reserve_after_growth = physmem::reserved_frames();
+
+        // Nothing is allocated while freeing, and the emptied page goes back to the reserve
+        for region_info in regions.iter().chain(core::iter::once(&grown)) {
+            manager.deallocate_region(region_info);
+        }
+        let reserve_after_free = physmem::reserved_frames();
+        drop(exhausted);
+
+        assert_eq!(grown_map, (REGION_MAP_ENTRIES_IN_PAGE + 1, 2));
+        assert_eq!(reserve_after_growth, reserved_frames - 1);
+        assert_eq!(check_region_map(&manager), (1, 1));
+        assert_eq!(reserve_after_free, reserved_frames);
+    }
+
+    #[test_case]
+    fn shrunk_regions_give_their_tail_back() {
+        let mut manager = test_manager();
+        let first = manager.allocate(8, RegionType::Heap).unwrap();
+        let second = manager.allocate(8, RegionType::Heap).unwrap();
+        let shrunk = |region_info: &RegionInfo, pages| RegionInfo {
+            start_va: region_info.start_va,
+            limit_va: region_info.start_va + pages * PAGE_SIZE,
+        };
+
+        // There is a region on both sides, so the tail needs an entry of its own
+        manager
+            .shrink_region(&first, shrunk(&first, 3).limit_va)
+            .unwrap();
+        assert_eq!(check_region_map(&manager), (4, 1));
+        assert_eq!(manager.backing.unmapped_tail_pages, 5);
+
+        // This tail joins the free space after it
+        manager
+            .shrink_region(&second, shrunk(&second, 2).limit_va)
+            .unwrap();
+        assert_eq!(check_region_map(&manager), (4, 1));
+        assert_eq!(manager.backing.unmapped_tail_pages, 11);
+
+        let refill = manager.allocate(5, RegionType::Heap).unwrap();
+        assert_eq!(refill.start_va, shrunk(&first, 3).limit_va);
+
+        manager.deallocate_region(&shrunk(&first, 3));
+        manager.deallocate_region(&shrunk(&second, 2));
+        manager.deallocate_region(&refill);
+        assert_eq!(check_region_map(&manager), (1, 1));
+        assert_eq!(manager.backing.mapped_regions, 0);
+    }
+
+    #[test_case]
+    fn region_shrinks_in_place() {
+        let mut region = allocate_region(8).expect("Failed to allocate region");
+        let start = region.start();
+        for page in 0..8 {
+            region.write_volatile::<u64>(page * PAGE_SIZE, page as u64);
+        }
+
+        let free_frames = physmem::free_frames();
+        region
+            .shrink(3 * PAGE_SIZE - 8)
+            .expect("Failed to shrink region");
+        let freed_frames = physmem::free_frames() - free_frames;
+
+        assert_eq!(freed_frames, 5);
+        assert_eq!(region.start(), start);
+        assert_eq!(region.size(), 3 * PAGE_SIZE - 8);
+        for page in 0..3 {
+            assert_eq!(region.read_volatile::<u64>(page * PAGE_SIZE), page as u64);
+        }
+
+        let page_table = unsafe { lock_page_table() };
+        for page in 3..8 {
+            assert!(page_table
+                .get_pte_for_address(start + page * PAGE_SIZE)
+                .map_or(true, |pte| pte.is_unused()));
+        }
+    }
+
+    #[test_case]
+    fn stats_count_entries_by_type() {
+        let mut manager = test_manager();
+        let physical_mapping = RegionType::PhysicalMapping(PhysicalMapping {
+            physical_address: 0,
+            flags: PhysicalMappingFlags::READ_ONLY,
+        });
+        let regions: Vec<_> = [
+            RegionType::Heap,
+            RegionType::KernelStack,
+            RegionType::Heap,
+            physical_mapping,
+            RegionType::KernelStack,
+            RegionType::Heap,
+        ]
+        .iter()
+        .map(|region_type| manager.allocate(2, *region_type).unwrap())
+        .collect();
+
+        // Freeing one in the middle leaves a free gap as well as the free space at the end
+        manager.deallocate_region(&regions[1]);
+
+        assert_eq!(
+            manager.stats(),
+            RegionMapStats {
+                pages: 1,
+                entries: 7,
+                unused_entries: REGION_MAP_ENTRIES_IN_PAGE - 7,
+                free_regions: 2,
+                heap_regions: 3,
+                kernel_stacks: 1,
+                physical_mappings: 1,
+            }
+        );
+
+        for region_info in regions
+            .iter()
+            .filter(|region_info| region_info.start_va != regions[1].start_va)
+        {
+            manager.deallocate_region(region_info);
+        }
+        assert_eq!(manager.stats().entries, 1);
+
+        // The kernel's region map has at least the region just allocated
+        let region = allocate_region(1).expect("Failed to allocate region");
+        let stats = region_map_stats();
+        assert!(stats.heap_regions >= 1 && stats.pages >= 1);
+        drop(region);
+    }
+
+    #[test_case]
+    fn double_free_is_caught() {
+        let mut manager = test_manager();
+        let regions: Vec<_> = (0..4)
+            .map(|_| manager.allocate(2, RegionType::Heap).unwrap())
+            .collect();
+        for region_info in regions.iter() {
+            assert_eq!(manager.check_deallocate(region_info), Ok(()));
+        }
+
+        // These merge into one free entry, so only the first is still the start of an entry
+        manager.deallocate_region(&regions[0]);
+        manager.deallocate_region(&regions[1]);
+        manager.deallocate_region(&regions[2]);
+        let before = check_region_map(&manager);
+
+        for region_info in regions[..3].iter() {
+            let bad_free = manager.check_deallocate(region_info);
+            assert_eq!(bad_free, Err(BadFree::DoubleFree(region_info.start_va)));
+            assert_eq!(
+                alloc::format!("{}", bad_free.unwrap_err()),
+                alloc::format!("Double free of region at {:#x}", region_info.start_va)
+            );
+
+            // Freeing it for real panics before it touches the map
+            let message = expect_panic(|| manager.deallocate_region(region_info))
+                .expect("Double free did not panic");
+            assert!(
+                message.contains(&alloc::format!(
+                    "Double free of region at {:#x}",
+                    region_info.start_va
+                )),
+                "Unexpected panic: {}",
+                message
+            );
+            assert_eq!(check_region_map(&manager), before);
+        }
+
+        // Part of a live region isn't a region either
+        let part = RegionInfo {
+            start_va: regions[3].start_va + PAGE_SIZE,
+            limit_va: regions[3].limit_va,
+        };
+        assert_eq!(
+            manager.check_deallocate(&part),
+            Err(BadFree::NotARegion(part.start_va))
+        );
+        assert_eq!(check_region_map(&manager), before);
+
+        manager.deallocate_region(&regions[3]);
+        assert_eq!(check_region_map(&manager), (1, 1));
+    }
+
+    #[test_case]
+    fn failed_maps_leave_the_region_map_alone() {
+        let mut manager = test_manager();
+        let kept = manager.allocate(2, RegionType::Heap).unwrap();
+        let before = check_region_map(&manager);
+
+        manager.backing.fail_maps = true;
+        assert_eq!(
+            manager
+                .allocate(4, RegionType::Heap)
+                .map(|region_info| region_info.start_va),
+            Err(MemoryError::OutOfMemory)
+        );
+        manager.backing.fail_maps = false;
+        assert_eq!(check_region_map(&manager), before);
+
+        manager.deallocate_region(&kept);
+        assert_eq!(check_region_map(&manager), (1, 1));
+    }
+
+    #[test_case]
+    fn region_header_found_from_interior_address() {
+        let region = allocate_region(4).expect("Failed to allocate region");
+        let page_table = unsafe { lock_page_table() };
+
+        for offset in [0, PAGE_SIZE + 0x10, (3 * PAGE_SIZE) + PAGE_SIZE - 1].iter() {
+            assert_eq!(
+                page_table.find_region_header(region.start() + offset),
+                Some(region.start())
+            );
+        }
+
+        // The identity map isn't managed by the region manager, so there is no header to find
+        assert_eq!(
+            page_table.find_region_header(crate::paging::phys_to_virt_addr(0x1000, 1)),
+            None
+        );
+    }
+
+    #[test_case]
+    fn kernel_stack_guard_page_is_region_header() {
+        let stack = allocate_kernel_stack(4).expect("Failed to allocate kernel stack");
+        let page_table = unsafe { lock_page_table() };
+
+        let header = page_table
+            .find_region_header(stack.stack_top() - 8)
+            .expect("Kernel stack has no region header");
+        assert!(page_table
+            .get_pte_for_address(header)
+            .unwrap()
+            .not_present()
+            .map(|pte| pte.page_type() == page_entry::NotPresentPageType::GuardPage)
+            .unwrap_or(false));
+    }
+
+    #[test_case]
+    fn kernel_stacks_of_bad_sizes_are_refused() {
+        for pages in [0, 1, MAX_KERNEL_STACK_PAGES + 1, 100].iter() {
+            assert_eq!(
+                allocate_kernel_stack(*pages).map(|stack| stack.size()),
+                Err(MemoryError::InvalidStack)
+            );
+        }
+
+        for pages in [MIN_KERNEL_STACK_PAGES, MAX_KERNEL_STACK_PAGES].iter() {
+            let stack = allocate_kernel_stack(*pages).expect("Failed to allocate kernel stack");
+            assert_eq!(stack.size(), (pages - 1) * PAGE_SIZE);
+        }
+    }
+
+    #[test_case]
+    fn volatile_accesses_stay_inside_the_region() {
+        let region = allocate_region(1).expect("Failed to allocate region");
+        region.write_volatile::<u64>(0, 0x0123_4567_89ab_cdef);
+        region.write_volatile::<u32>(PAGE_SIZE - 4, 0xfeed_f00d);
+
+        assert_eq!(region.read_volatile::<u64>(0), 0x0123_4567_89ab_cdef);
+        assert_eq!(region.read_volatile::<u16>(2), 0x89ab);
+        assert_eq!(region.read_volatile::<u32>(PAGE_SIZE - 4), 0xfeed_f00d);
+
+        // A sub region only reaches its own bytes
+        let sub_region = region.apply_offset(8, 8);
+        assert!(sub_region.fits::<u64>(0));
+        assert!(!sub_region.fits::<u64>(1));
+        assert!(!sub_region.fits::<u8>(8));
+        assert!(!sub_region.fits::<u8>(usize::MAX));
+
+        // Accesses that don't fit panic rather than reaching past it
+        let message = expect_panic(|| {
+            sub_region.read_volatile::<u64>(1);
+        })
+        .expect("Out of bounds read did not panic");
+        assert!(message.contains("Offset 0x1 is outside the region"));
+
+        let beyond = region.read_volatile::<u32>(16);
+        let message = expect_panic(|| sub_region.write_volatile::<u32>(8, !beyond))
+            .expect("Out of bounds write did not panic");
+        assert!(message.contains("Offset 0x8 is outside the region"));
+        assert_eq!(region.read_volatile::<u32>(16), beyond);
+
+        assert!(expect_panic(|| {
+            sub_region.read_volatile::<u8>(usize::MAX);
+        })
+        .is_some());
+    }
+
+    #[test_case]
+    fn region_manager_uses_reserve_when_out_of_memory() {
+        // Map and free a physical mapping first, so that the page tables it needs already exist
+        // and the only frame the next allocation needs is the one for the region table
+        let mapping_size = PAGE_SIZE;
+        drop(unsafe { map_physical_memory(0, mapping_size, PhysicalMappingFlags::READ_ONLY) });
+
+        let exhausted = physmem::ExhaustedKernelFrames::new();
+        assert!(physmem::allocate_kernel_frame().is_none());
+        let reserved_frames = physmem::reserved_frames();
+        assert!(reserved_frames > 0);
+
+        let region =
+            unsafe { map_physical_memory(0, mapping_size, PhysicalMappingFlags::READ_ONLY) }
+                .expect("Region manager failed while out of memory");
+
+        // The table frame wasn't needed, so freeing it put it straight back in the reserve
+        assert_eq!(physmem::reserved_frames(), reserved_frames);
+
+        drop(region);
+        drop(exhausted);
+    }
+
+    #[test_case]
+    fn failed_region_mapping_is_rolled_back() {
+        const PAGES: usize = 8;
+
+        // Allocate and free the region once so that the page tables for it already exist, and
+        // the only frames the mapping takes are the ones for the pages themselves
+        let start = allocate_region(PAGES)
+            .expect("Failed to allocate region")
+            .start();
+        let free_frames = physmem::free_frames();
+
+        // The first allocation is the region table frame, so this fails part way through mapping
+        physmem::fail_allocations_after(4, 1);
+        let result = allocate_region(PAGES).map(|region| region.start());
+        physmem::fail_next_n_allocations(0);
+
+        assert_eq!(result, Err(MemoryError::OutOfMemory));
+        assert_eq!(physmem::free_frames(), free_frames);
+
+        let page_table = unsafe { lock_page_table() };
+        for page in 0..PAGES {
+            assert!(page_table
+                .get_pte_for_address(start + page * PAGE_SIZE)
+                .map_or(true, |pte| pte.is_unused()));
+        }
+        drop(page_table);
+
+        // The address space went back to the region manager too
+        let region = allocate_region(PAGES).expect("Failed to allocate region after rollback");
+        assert_eq!(region.start(), start);
+    }
+}
@@ -0,0 +1,169 @@
+use super::page_entry::PresentPageFlags;
+use super::{
+    lock_page_table, p2_index, p3_index, p4_index, phys_to_virt_mut, MapperFlushAll, MemoryError,
+    PageTable, Result, HYPERSPACE_BASE, HYPERSPACE_LIMIT, IDENTITY_MAP_SIZE, L4, PAGE_SIZE,
+};
+use crate::physmem::Frame;
+use spin::Mutex;
+
+// Hyperspace is a single L1 table worth of kernel address space that we use to temporarily map
+// frames which are not covered by the identity map (i.e. anything above IDENTITY_MAP_SIZE).
+// The page tables for it are created at boot, so mapping a page in here never needs to allocate.
+const HYPERSPACE_PAGES: usize = (HYPERSPACE_LIMIT - HYPERSPACE_BASE) / PAGE_SIZE;
+const HYPERSPACE_SLOT_WORDS: usize = (HYPERSPACE_PAGES + 63) / 64;
+
+static HYPERSPACE_SLOTS: Mutex<[u64; HYPERSPACE_SLOT_WORDS]> =
+    Mutex::new([0; HYPERSPACE_SLOT_WORDS]);
+
+fn allocate_slot() -> Result<usize> {
+    let mut slots = HYPERSPACE_SLOTS.lock();
+    for (word_index, word) in slots.iter_mut().enumerate() {
+        if *word != !0 {
+            let bit = (!*word).trailing_zeros() as usize;
+            let slot = (word_index * 64) + bit;
+            if slot >= HYPERSPACE_PAGES {
+                break;
+            }
+
+            *word |= 1 << bit;
+            return Ok(slot);
+        }
+    }
+
+    Err(MemoryError::OutOfHyperspacePages)
+}
+
+fn free_slot(slot: usize) {
+    let mut slots = HYPERSPACE_SLOTS.lock();
+    let (word_index, bit) = (slot / 64, slot % 64);
+
+    assert!(
+        slots[word_index] & (1 << bit) != 0,
+        "Freeing hyperspace slot {} which is not in use",
+        slot
+    );
+    slots[word_index] &= !(1 << bit);
+}
+
+pub(super) unsafe fn prepare(init_p4_table: &mut PageTable<L4>) -> Result<()> {
+    // Make sure that the whole of the table hierarchy down to the L1 table exists. Hyperspace lives
+    // in the kernel half of the address space, so every address space will share these tables.
+    init_p4_table
+        .create_next_table(p4_index(HYPERSPACE_BASE), false)?
+        .create_next_table(p3_index(HYPERSPACE_BASE), false)?
+        .create_next_table(p2_index(HYPERSPACE_BASE), false)?;
+
+    Ok(())
+}
+
+/// Map a frame into hyperspace, returning the virtual address that it is mapped at. The mapping
+/// must be released with `unmap_page`.
+pub fn map_page(frame: Frame) -> Result<usize> {
+    let slot = allocate_slot()?;
+    let addr = HYPERSPACE_BASE + (slot * PAGE_SIZE);
+
+    let mut page_table = unsafe { lock_page_table() };
+
+    // We deliberately do not make these mappings global. That way even a plain CR3 reload, as a
+    // task switch does, gets rid of them when they are unmapped.
+    match page_table.map_to(
+        addr,
+        frame,
+        PresentPageFlags::WRITABLE | PresentPageFlags::NO_EXECUTE,
+    ) {
+        Ok(flush) => {
+            flush.flush(&page_table);
+            Ok(addr)
+        }
+
+        Err(e) => {
+            core::mem::drop(page_table);
+            free_slot(slot);
+            Err(e)
+        }
+    }
+}
+
+/// Release a mapping previously returned from `map_page`. The frame itself is not freed.
+pub unsafe fn unmap_page(addr: usize) {
+    assert!(
+        addr >= HYPERSPACE_BASE && addr < HYPERSPACE_LIMIT,
+        "Address {:#x} is not in hyperspace",
+        addr
+    );
+    assert_eq!(addr % PAGE_SIZE, 0, "Hyperspace address is not page aligned");
+
+    {
+        let mut page_table = lock_page_table();
+        let mut flusher = MapperFlushAll::new();
+        flusher.consume(
+            page_table
+                .unmap(addr, false)
+                .expect("Hyperspace page is not canonical"),
+        );
+        flusher.flush(&page_table);
+    }
+
+    free_slot((addr - HYPERSPACE_BASE) / PAGE_SIZE);
+}
+
+/// Run a function with a frame mapped somewhere in kernel address space. Frames covered by the
+/// identity map are accessed directly, anything else is mapped through hyperspace.
+///
+/// This must not be called with the page table lock held.
+pub fn with_frame<R>(frame: Frame, func: impl FnOnce(*mut u8) -> R) -> Result<R> {
+    if frame.physical_address() + PAGE_SIZE <= IDENTITY_MAP_SIZE {
+        Ok(func(phys_to_virt_mut(frame.physical_address())))
+    } else {
+        let addr = map_page(frame)?;
+        let ret = func(addr as *mut u8);
+        unsafe { unmap_page(addr) };
+        Ok(ret)
+    }
+}
+
+/// Fill a frame with zeroes, wherever it lives in physical memory
+pub fn zero_frame(frame: Frame) -> Result<()> {
+    with_frame(frame, |ptr| unsafe { core::ptr::write_bytes(ptr, 0, PAGE_SIZE) })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::physmem;
+
+    #[test_case]
+    fn map_write_and_reuse_slot() {
+        let frame = physmem::allocate_user_frame().expect("Failed to allocate test frame");
+
+        let addr = map_page(frame).expect("Failed to map frame into hyperspace");
+        assert!(addr >= HYPERSPACE_BASE && addr < HYPERSPACE_LIMIT);
+
+        unsafe {
+            let ptr = addr as *mut u64;
+            for i in 0..(PAGE_SIZE / 8) {
+                ptr.add(i).write_volatile(0xdead_beef_0000_0000 | i as u64);
+            }
+            for i in 0..(PAGE_SIZE / 8) {
+                assert_eq!(ptr.add(i).read_volatile(), 0xdead_beef_0000_0000 | i as u64);
+            }
+
+            unmap_page(addr);
+        }
+
+        {
+            let page_table = unsafe { lock_page_table() };
+            assert!(!page_table.get_pte_for_address(addr).unwrap().is_present());
+        }
+
+        // The slot we just released is the lowest free one, so we should get it straight back
+        let second_addr = map_page(frame).expect("Failed to remap frame into hyperspace");
+        assert_eq!(second_addr, addr);
+        unsafe {
+            assert_eq!((second_addr as *const u64).read_volatile(), 0xdead_beef_0000_0000);
+            unmap_page(second_addr);
+        }
+
+        physmem::deallocate_frame(frame);
+    }
+}
@@ -0,0 +1,107 @@
+use super::{p4_index, KERNEL_PML4, PAGE_SIZE};
+use core::ops::Range;
+
+// The linker script puts these at the start and end of each section, and pads every section out
+// to a page boundary
+extern "C" {
+    static __kernel_start: u8;
+    static __text_start: u8;
+    static __text_end: u8;
+    static __rodata_start: u8;
+    static __rodata_end: u8;
+    static __data_start: u8;
+    static __data_end: u8;
+    static __tdata_start: u8;
+    static __tbss_start: u8;
+    static __tbss_end: u8;
+    static __bss_start: u8;
+    static __bss_end: u8;
+    static __kernel_end: u8;
+}
+
+fn section(start: &'static u8, end: &'static u8) -> Range<usize> {
+    let start = start as *const u8 as usize;
+    let end = end as *const u8 as usize;
+
+    assert_eq!(start % PAGE_SIZE, 0, "Kernel section is not page aligned");
+    assert_eq!(end % PAGE_SIZE, 0, "Kernel section is not page aligned");
+    assert!(start <= end, "Kernel section ends before it starts");
+    assert_eq!(
+        p4_index(start),
+        KERNEL_PML4,
+        "Kernel is not in kernel PML4 region"
+    );
+    assert_eq!(
+        p4_index(end),
+        KERNEL_PML4,
+        "Kernel is not in kernel PML4 region"
+    );
+
+    start..end
+}
+
+/// The whole kernel image
+pub fn kernel_range() -> Range<usize> {
+    unsafe { section(&__kernel_start, &__kernel_end) }
+}
+
+pub fn text_range() -> Range<usize> {
+    unsafe { section(&__text_start, &__text_end) }
+}
+
+pub fn rodata_range() -> Range<usize> {
+    unsafe { section(&__rodata_start, &__rodata_end) }
+}
+
+pub fn data_range() -> Range<usize> {
+    unsafe { section(&__data_start, &__data_end) }
+}
+
+pub fn bss_range() -> Range<usize> {
+    unsafe { section(&__bss_start, &__bss_end) }
+}
+
+/// What every CPU's thread local storage is copied from. The initialized .tdata comes first, and
+/// the .tbss after it is zeroed instead.
+pub fn tls_template_range() -> Range<usize> {
+    unsafe { section(&__tdata_start, &__tbss_end) }
+}
+
+/// How much of the thread local storage template is .tdata
+pub fn tls_data_size() -> usize {
+    unsafe { &__tbss_start as *const u8 as usize - &__tdata_start as *const u8 as usize }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn sections_are_ordered_and_page_aligned() {
+        let sections = [
+            text_range(),
+            rodata_range(),
+            data_range(),
+            tls_template_range(),
+            bss_range(),
+        ];
+        let kernel = kernel_range();
+
+        for section in sections.iter() {
+            assert_eq!(section.start % PAGE_SIZE, 0);
+            assert_eq!(section.end % PAGE_SIZE, 0);
+            assert!(section.start >= kernel.start && section.end <= kernel.end);
+        }
+
+        for pair in sections.windows(2) {
+            assert!(
+                pair[0].end <= pair[1].start,
+                "{:?} overlaps {:?}",
+                pair[0],
+                pair[1]
+            );
+        }
+
+        assert!(tls_data_size() <= tls_template_range().len());
+    }
+}
@@ -1,6 +1,9 @@
-use super::Region;
+use super::{Region, PAGE_SIZE};
 use alloc::boxed::Box;
 
+// New stacks are filled with this, so that we can tell how much of the stack has been used
+const STACK_SENTINEL: u8 = 0xab;
+
 #[derive(Debug)]
 pub struct KernelStack {
     region: Region,
@@ -35,9 +38,20 @@ extern "C" fn stack_switch_entry(trampoline: *mut Box<dyn TrampolineCallable>) {
     trampoline.call_on_stack();
 }
 
+// The ABI needs the stack to be 16 byte aligned at a call, and the trampoline calls
+// stack_switch_entry from the very top of the new stack
+fn stack_top_aligned(stack_top: usize) -> bool {
+    stack_top % 16 == 0
+}
+
 fn switch_to_trampoline(trampoline: Box<dyn TrampolineCallable>) -> ! {
     // Get the new stack pointer
     let stack_pointer = trampoline.get_stack_top();
+    debug_assert!(
+        stack_top_aligned(stack_pointer),
+        "Switching to misaligned stack top {:#x}",
+        stack_pointer
+    );
 
     // Take a raw pointer to the trampoline
     let trampoline = box trampoline;
@@ -47,7 +61,8 @@ fn switch_to_trampoline(trampoline: Box<dyn TrampolineCallable>) -> ! {
         asm!(
             "mov rsp, {0}",
             "mov rdi, {1}",
-            "jmp stack_switch_entry",
+            "call stack_switch_entry",
+            "ud2",
             in(reg) stack_pointer,
             in(reg) trampoline as usize,
             options(noreturn),
@@ -57,14 +72,67 @@ fn switch_to_trampoline(trampoline: Box<dyn TrampolineCallable>) -> ! {
 
 impl KernelStack {
     pub(super) fn new(region: Region) -> Self {
-        Self { region }
+        let stack = Self { region };
+        unsafe {
+            core::ptr::write_bytes(stack.stack_bottom() as *mut u8, STACK_SENTINEL, stack.size());
+        }
+        stack
+    }
+
+    // The first page of the region is the guard page
+    pub fn stack_bottom(&self) -> usize {
+        self.region.start() + PAGE_SIZE
     }
 
     pub fn stack_top(&self) -> usize {
         self.region.limit()
     }
 
-    pub fn switch_to_permanent(self, function: impl FnOnce(KernelStack) -> ! + 'static) -> ! {
+    pub fn size(&self) -> usize {
+        self.stack_top() - self.stack_bottom()
+    }
+
+    /// The most of this stack that has ever been used. This is an estimate, because anything that
+    /// happened to write the sentinel value at the deepest point won't be counted.
+    pub fn peak_usage(&self) -> usize {
+        let stack = unsafe {
+            core::slice::from_raw_parts(self.stack_bottom() as *const u8, self.size())
+        };
+        stack
+            .iter()
+            .position(|byte| *byte != STACK_SENTINEL)
+            .map_or(0, |untouched| self.size() - untouched)
+    }
+
+    // Whether rsp is somewhere in this stack, including its guard page
+    fn contains(&self, rsp: usize) -> bool {
+        rsp >= self.region.start() && rsp <= self.stack_top()
+    }
+
+    /// Move onto this stack for good and call function on it. Whatever stack this is called on is
+    /// abandoned, and nothing on it may be referenced after the switch. That is why function has
+    /// to be 'static, and it is moved to the heap to carry it across. A KernelStack that the caller
+    /// is running on has to be given up as old_stack, which leaks it, so that it can never be freed
+    /// while anything could return to it. Only a stack that something else owns, like the boot
+    /// stack, is left out, and that has to outlive anything on it.
+    pub fn switch_to_permanent(
+        self,
+        old_stack: Option<KernelStack>,
+        function: impl FnOnce(KernelStack) -> ! + 'static,
+    ) -> ! {
+        let rsp = x86::bits64::registers::rsp() as usize;
+        debug_assert!(
+            !self.contains(rsp),
+            "Switching to the stack that is already in use"
+        );
+        if let Some(old_stack) = old_stack {
+            debug_assert!(
+                old_stack.contains(rsp),
+                "Giving up a stack that isn't the one in use"
+            );
+            core::mem::forget(old_stack);
+        }
+
         let trampoline = box Trampoline {
             stack: self,
             function,
@@ -72,3 +140,164 @@ impl KernelStack {
         switch_to_trampoline(trampoline);
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::paging::page_entry::NotPresentPageType;
+    use crate::paging::{allocate_kernel_stack, lock_page_table};
+    use crate::panic_recovery::expect_panic;
+    use crate::scheduler::{exit, spawn};
+
+    const FRAME_SIZE: usize = 256;
+    const CALL_DEPTH: usize = 16;
+
+    extern "C" fn recurse(depth: usize) {
+        let mut frame = [0u8; FRAME_SIZE];
+        unsafe { core::ptr::write_volatile(&mut frame, [depth as u8; FRAME_SIZE]) };
+
+        if depth > 1 {
+            recurse(depth - 1);
+        }
+
+        // Keep the frame alive until the deeper calls have returned
+        unsafe { core::ptr::read_volatile(&frame) };
+    }
+
+    unsafe fn call_on_stack(stack: &KernelStack, func: extern "C" fn(usize), arg: usize) {
+        asm!(
+            "mov r12, rsp",
+            "mov rsp, {stack_top}",
+            "call {func}",
+            "mov rsp, r12",
+            stack_top = in(reg) stack.stack_top(),
+            func = in(reg) func,
+            inout("rdi") arg => _,
+            out("r12") _,
+            lateout("rax") _,
+            lateout("rcx") _,
+            lateout("rdx") _,
+            lateout("rsi") _,
+            lateout("r8") _,
+            lateout("r9") _,
+            lateout("r10") _,
+            lateout("r11") _,
+        );
+    }
+
+    #[test_case]
+    fn peak_usage_tracks_deepest_call() {
+        let stack = allocate_kernel_stack(8).expect("Failed to allocate kernel stack");
+        assert_eq!(stack.size(), 7 * PAGE_SIZE);
+        assert_eq!(stack.peak_usage(), 0);
+
+        unsafe { call_on_stack(&stack, recurse, CALL_DEPTH) };
+
+        let peak_usage = stack.peak_usage();
+        assert!(
+            peak_usage >= CALL_DEPTH * FRAME_SIZE,
+            "Peak usage {:#x} is less than the call depth",
+            peak_usage
+        );
+        assert!(peak_usage < stack.size());
+    }
+
+    #[test_case]
+    fn stacks_sit_above_a_guard_page() {
+        let stack = allocate_kernel_stack(4).expect("Failed to allocate kernel stack");
+        let page_table = unsafe { lock_page_table() };
+
+        let guard = page_table
+            .get_pte_for_address(stack.stack_bottom() - PAGE_SIZE)
+            .and_then(|pte| pte.not_present().ok())
+            .expect("Kernel stack has no guard page");
+        assert_eq!(guard.page_type(), NotPresentPageType::GuardPage);
+
+        for page in (stack.stack_bottom()..stack.stack_top()).step_by(PAGE_SIZE) {
+            assert!(page_table
+                .get_pte_for_address(page)
+                .map_or(false, |pte| pte.is_present()));
+        }
+    }
+
+    #[test_case]
+    fn dropped_stacks_are_unmapped() {
+        let stack = allocate_kernel_stack(4).expect("Failed to allocate kernel stack");
+        let (guard, top) = (stack.stack_bottom() - PAGE_SIZE, stack.stack_top());
+        drop(stack);
+
+        let page_table = unsafe { lock_page_table() };
+        for page in (guard..top).step_by(PAGE_SIZE) {
+            assert!(page_table
+                .get_pte_for_address(page)
+                .map_or(true, |pte| pte.is_unused()));
+        }
+    }
+
+    #[test_case]
+    fn stack_switches_check_the_new_stack() {
+        let stack = allocate_kernel_stack(4).expect("Failed to allocate kernel stack");
+        assert!(stack_top_aligned(stack.stack_top()));
+        assert!(!stack_top_aligned(stack.stack_top() - 8));
+        assert!(!stack_top_aligned(stack.stack_top() - 1));
+
+        assert!(stack.contains(stack.stack_top()));
+        assert!(stack.contains(stack.stack_bottom() - PAGE_SIZE));
+        assert!(!stack.contains(x86::bits64::registers::rsp() as usize));
+    }
+
+    #[test_case]
+    fn misaligned_stack_switches_panic() {
+        let stack = allocate_kernel_stack(4).expect("Failed to allocate kernel stack");
+        // The same stack, with its top 8 bytes short
+        let misaligned = KernelStack {
+            region: unsafe { stack.region.view(0, stack.region.size() - 8) },
+        };
+
+        let message = expect_panic(move || {
+            misaligned.switch_to_permanent(None, |_| unreachable!("Switched to misaligned stack"))
+        })
+        .expect("Switching to a misaligned stack did not panic");
+        assert!(
+            message.contains("Switching to misaligned stack top"),
+            "Unexpected panic: {}",
+            message
+        );
+    }
+
+    // The compiler counts on the ABI's stack alignment to place this, rather than aligning it
+    #[repr(align(16))]
+    struct Aligned(u8);
+
+    #[test_case]
+    fn switch_to_permanent_runs_on_the_new_stack() {
+        let first = allocate_kernel_stack(4).expect("Failed to allocate kernel stack");
+        let second = allocate_kernel_stack(4).expect("Failed to allocate kernel stack");
+        let (bottom, top) = (second.stack_bottom(), second.stack_top());
+
+        // Switching stacks never comes back, so it happens in a task of its own. It moves onto the
+        // first stack, gives that up for the second, and exits with the rsp that it sees there.
+        let task = unsafe {
+            spawn(None, move || {
+                first.switch_to_permanent(None, move |first| {
+                    second.switch_to_permanent(Some(first), move |second| {
+                        // The function was given the stack it is running on
+                        assert_eq!(second.stack_top(), top);
+
+                        let aligned = Aligned(0);
+                        assert_eq!(&aligned as *const Aligned as usize % 16, 0);
+                        exit(x86::bits64::registers::rsp() as isize)
+                    })
+                })
+            })
+        }
+        .expect("Failed to spawn task");
+
+        let rsp = task.join() as usize;
+        assert!(
+            rsp >= bottom && rsp < top,
+            "rsp {:#x} is not on the new stack",
+            rsp
+        );
+    }
+}
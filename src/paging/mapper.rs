@@ -1,10 +1,12 @@
 use super::page_entry::{PresentPageFlags, RawNotPresentPte, RawPresentPte, RawPte};
 use super::{
-    p1_index, p2_index, p3_index, p4_index, phys_to_virt_mut, ActivePageTable, PageTable, Result,
-    L4,
+    is_canonical, p1_index, p2_index, p3_index, p4_index, page_align_down, phys_to_virt_mut,
+    ActivePageTable, MemoryError, PageTable, PageTableIndex, PageTableLevel, Result,
+    HUGE_PAGE_SIZE, L1, L2, L3, L4, PAGE_SIZE,
 };
 use crate::physmem::{self, Frame};
 use core::mem::ManuallyDrop;
+use core::ops::Range;
 
 #[must_use = "Must use a mapper flush"]
 pub struct MapperFlush(usize);
@@ -30,23 +32,41 @@ impl Drop for MapperFlush {
     }
 }
 
+// Flushing a handful of pages one at a time is much cheaper than throwing away the whole TLB, but
+// past this many it is quicker to flush everything
+const FLUSH_BATCH_SIZE: usize = 16;
+
 #[must_use = "Must use a mapper flush"]
-pub struct MapperFlushAll(bool);
+pub struct MapperFlushAll {
+    pages: [usize; FLUSH_BATCH_SIZE],
+    count: usize,
+}
 
 impl MapperFlushAll {
     pub fn new() -> Self {
-        Self(false)
+        Self {
+            pages: [0; FLUSH_BATCH_SIZE],
+            count: 0,
+        }
     }
 
     pub fn consume(&mut self, flush: MapperFlush) {
-        let _ = ManuallyDrop::new(flush);
-        self.0 = true;
+        let flush = ManuallyDrop::new(flush);
+        if self.count < FLUSH_BATCH_SIZE {
+            self.pages[self.count] = flush.0;
+        }
+        self.count += 1;
     }
 
     pub fn flush(self, active: &ActivePageTable) {
-        if self.0 {
-            let _ = ManuallyDrop::new(self);
+        let mdself = ManuallyDrop::new(self);
+        if mdself.count > FLUSH_BATCH_SIZE {
             active.flush_all();
+        } else if mdself.count > 0 {
+            for page in mdself.pages[..mdself.count].iter() {
+                active.flush(*page);
+            }
+            active.flush_other_cpus();
         }
     }
 
@@ -57,7 +77,66 @@ impl MapperFlushAll {
 
 impl Drop for MapperFlushAll {
     fn drop(&mut self) {
-        assert!(!self.0, "Ignored a mapper flush all");
+        assert_eq!(self.count, 0, "Ignored a mapper flush all");
+    }
+}
+
+// The counter field of a present PTE holds the number of other mappings of the same frame in this
+// address space, and every mapping of the frame carries the same value, so a PTE with a counter of
+// zero can be unmapped without looking for the others. If there are too many mappings to fit, the
+// counter is set to this value and the frame's global mapping count in physmem stands in for it.
+const COUNTER_OVERFLOW: u16 = RawPresentPte::MAX_COUNTER_VALUE - 1;
+
+// Get the table that an entry points to, if it is present and not a huge page
+unsafe fn table_for_entry<L: PageTableLevel>(entry: &RawPte) -> Option<&'static mut PageTable<L>> {
+    entry
+        .present()
+        .ok()
+        .filter(|present_pte| !present_pte.is_huge())
+        .map(|present_pte| &mut *phys_to_virt_mut(present_pte.frame().physical_address()))
+}
+
+/// A present page as iter_mappings reports it: the virtual addresses it covers, the frame it maps,
+/// its flags, and whether it is a huge page
+pub type Mapping = (Range<usize>, Frame, PresentPageFlags, bool);
+
+// A huge page in a P3 table covers everything a P2 table could map
+const P3_HUGE_PAGE_SIZE: usize = HUGE_PAGE_SIZE * 512;
+
+// The present entries in a table, with their indices
+fn present_entries<L: PageTableLevel>(
+    table: &PageTable<L>,
+) -> impl Iterator<Item = (u16, RawPresentPte)> + '_ {
+    (0..)
+        .zip(table.iter())
+        .filter_map(|(index, entry)| Some((index, entry.present().ok()?)))
+}
+
+// The address that a walk through these table indices leads to, sign extended to make it canonical
+fn address_from_indices(p4: u16, p3: u16, p2: u16, p1: u16) -> usize {
+    let addr = usize::from(p4) << 39
+        | usize::from(p3) << 30
+        | usize::from(p2) << 21
+        | usize::from(p1) << 12;
+    match addr & (1 << 47) {
+        0 => addr,
+        _ => addr | 0xffff_0000_0000_0000,
+    }
+}
+
+// The mapping for an entry that maps memory directly, if this one does
+fn huge_mapping(start: usize, size: usize, pte: RawPresentPte) -> Option<Mapping> {
+    match pte.is_huge() {
+        true => Some((start..start + size, pte.frame(), pte.flags(), true)),
+        false => None,
+    }
+}
+
+// Everything that changes a mapping checks this first, as the wrong slot would be changed otherwise
+fn check_canonical(page: usize) -> Result<()> {
+    match is_canonical(page) {
+        true => Ok(()),
+        false => Err(MemoryError::InvalidRegion),
     }
 }
 
@@ -96,44 +175,214 @@ impl Mapper {
             .map(|p1| &mut p1[p1_index(addr)])
     }
 
-    pub fn create_pte_mut_for_address<'a>(&'a mut self, addr: usize) -> Result<&'a mut RawPte> {
+    /// Get the PTE for addr, creating the tables above it if they are missing. They are only made
+    /// user accessible if user_accessible is set, for a user page.
+    pub fn create_pte_mut_for_address<'a>(
+        &'a mut self,
+        addr: usize,
+        user_accessible: bool,
+    ) -> Result<&'a mut RawPte> {
         let p1 = self
             .p4_mut()
-            .create_next_table(p4_index(addr))?
-            .create_next_table(p3_index(addr))?
-            .create_next_table(p2_index(addr))?;
+            .create_next_table(p4_index(addr), user_accessible)?
+            .create_next_table(p3_index(addr), user_accessible)?
+            .create_next_table(p2_index(addr), user_accessible)?;
 
         Ok(&mut p1[p1_index(addr)])
     }
 
+    /// Get the physical address that addr is mapped to, or None if its page isn't present
+    pub fn translate(&self, addr: usize) -> Option<usize> {
+        let present_pte = self.get_pte_for_address(addr)?.present().ok()?;
+        Some(present_pte.frame().physical_address() + addr % PAGE_SIZE)
+    }
+
+    /// Every present page in this address space, in address order. This walks every table, so it is
+    /// meant for debugging, such as dumping the kernel's mappings.
+    pub fn iter_mappings<'a>(&'a self) -> impl Iterator<Item = Mapping> + 'a {
+        let index = PageTableIndex::new_truncate;
+        let p4 = self.p4();
+
+        present_entries(p4).flat_map(move |(i4, _)| {
+            p4.next_table(index(i4)).into_iter().flat_map(move |p3| {
+                present_entries(p3).flat_map(move |(i3, p3_entry)| {
+                    let start = address_from_indices(i4, i3, 0, 0);
+                    let p2_mappings = p3.next_table(index(i3)).into_iter().flat_map(move |p2| {
+                        present_entries(p2).flat_map(move |(i2, p2_entry)| {
+                            let start = address_from_indices(i4, i3, i2, 0);
+                            let p1_mappings =
+                                p2.next_table(index(i2)).into_iter().flat_map(move |p1| {
+                                    present_entries(p1).map(move |(i1, pte)| {
+                                        let start = address_from_indices(i4, i3, i2, i1);
+                                        (start..start + PAGE_SIZE, pte.frame(), pte.flags(), false)
+                                    })
+                                });
+
+                            huge_mapping(start, HUGE_PAGE_SIZE, p2_entry)
+                                .into_iter()
+                                .chain(p1_mappings)
+                        })
+                    });
+
+                    huge_mapping(start, P3_HUGE_PAGE_SIZE, p3_entry)
+                        .into_iter()
+                        .chain(p2_mappings)
+                })
+            })
+        })
+    }
+
+    /// Find the start of the region containing addr by walking back to the page marked as the region
+    /// header. Returns None if addr is not inside a region.
+    pub fn find_region_header(&self, addr: usize) -> Option<usize> {
+        let mut page = page_align_down(addr);
+        loop {
+            let pte = self.get_pte_for_address(page)?;
+            if pte.is_region_header() {
+                return Some(page);
+            } else if pte.is_unused() {
+                return None;
+            }
+
+            page = page.checked_sub(PAGE_SIZE)?;
+        }
+    }
+
+    // Call func on every 4KiB PTE in this address space that maps the given frame. There is no
+    // reverse mapping, so this has to walk all of the page tables, and is only done for frames that
+    // are mapped more than once.
+    fn walk_mappings(&mut self, frame: Frame, mut func: impl FnMut(&mut RawPte)) {
+        unsafe {
+            for p4_entry in self.p4.iter() {
+                let p3 = match table_for_entry::<L3>(p4_entry) {
+                    Some(p3) => p3,
+                    None => continue,
+                };
+
+                for p3_entry in p3.iter() {
+                    let p2 = match table_for_entry::<L2>(p3_entry) {
+                        Some(p2) => p2,
+                        None => continue,
+                    };
+
+                    for p2_entry in p2.iter() {
+                        let p1 = match table_for_entry::<L1>(p2_entry) {
+                            Some(p1) => p1,
+                            None => continue,
+                        };
+
+                        for pte in p1.iter_mut() {
+                            let maps_frame = pte
+                                .present()
+                                .map_or(false, |present_pte| present_pte.frame() == frame);
+                            if maps_frame {
+                                func(pte);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    // Count the mappings of a frame in this address space, and store the count in all of them
+    fn update_counters(&mut self, frame: Frame) {
+        let mut count = 0;
+        self.walk_mappings(frame, |_| count += 1);
+
+        let counter = match count {
+            0 => return,
+            count => (count - 1).min(usize::from(COUNTER_OVERFLOW)) as u16,
+        };
+
+        // The counter bits are ignored by the CPU, so changing them doesn't need a TLB flush
+        self.walk_mappings(frame, |pte| {
+            let present_pte = pte.present().unwrap();
+            pte.store(RawPresentPte::from_frame_flags_and_counter(
+                present_pte.frame(),
+                present_pte.flags(),
+                counter,
+            ));
+        });
+    }
+
+    /// How many times the frame mapped at page is mapped in this address space, or None if page
+    /// isn't present. Past what the PTE counter can hold, this is the frame's count across every
+    /// address space instead.
+    pub fn mapping_count(&self, page: usize) -> Option<usize> {
+        let present_pte = self.get_pte_for_address(page)?.present().ok()?;
+        match present_pte.counter() {
+            COUNTER_OVERFLOW => Some(physmem::mapping_count(present_pte.frame())),
+            counter => Some(usize::from(counter) + 1),
+        }
+    }
+
     pub fn map_to(
         &mut self,
         page: usize,
         frame: Frame,
         flags: PresentPageFlags,
     ) -> Result<MapperFlush> {
-        let pte = self.create_pte_mut_for_address(page)?;
+        check_canonical(page)?;
+        let user_accessible = flags.contains(PresentPageFlags::USER_ACCESSIBLE);
+        let pte = self.create_pte_mut_for_address(page, user_accessible)?;
 
         assert_eq!(*pte, RawPte::unused());
         assert!(pte.is_unused());
-        *pte = RawPresentPte::from_frame_and_flags(frame, flags).into();
+        pte.store(RawPresentPte::from_frame_and_flags(frame, flags));
+
+        // Only a frame that is already mapped somewhere can have other mappings in this address
+        // space, so most maps don't need to look for them
+        if physmem::add_mapping(frame) > 1 {
+            self.update_counters(frame);
+        }
+
         Ok(MapperFlush::new(page))
     }
 
-    pub fn unmap(&mut self, page: usize, free: bool) -> MapperFlush {
-        let pte = self.get_pte_mut_for_address(page);
+    /// Unmap a page. If free is set, the frame is released once nothing else maps it.
+    pub fn unmap(&mut self, page: usize, free: bool) -> Result<MapperFlush> {
+        check_canonical(page)?;
+        let old_pte = self
+            .get_pte_mut_for_address(page)
+            .map(|pte| pte.swap(RawNotPresentPte::unused()));
 
-        if let Some(pte) = pte {
-            if free {
-                if let Ok(present_pte) = pte.present() {
-                    physmem::deallocate_frame(present_pte.frame());
-                }
+        if let Some(present_pte) = old_pte.and_then(|pte| pte.present().ok()) {
+            let frame = present_pte.frame();
+            if present_pte.counter() != 0 {
+                self.update_counters(frame);
             }
 
-            *pte = RawNotPresentPte::unused().into();
+            if physmem::remove_mapping(frame) == 0 && free {
+                physmem::deallocate_frame(frame);
+            }
         }
 
-        MapperFlush::new(page)
+        Ok(MapperFlush::new(page))
+    }
+
+    /// Change the flags of a page that is already mapped. The frame stays the same, and so does the
+    /// mapping count.
+    pub fn remap(&mut self, page: usize, flags: PresentPageFlags) -> Result<MapperFlush> {
+        check_canonical(page)?;
+        let present_pte = self
+            .get_pte_for_address(page)
+            .ok_or(MemoryError::NotMapped)?
+            .present()
+            .map_err(|_| MemoryError::NotMapped)?;
+
+        // The tables are all there already, but a page becoming user accessible needs them to be
+        let user_accessible = flags.contains(PresentPageFlags::USER_ACCESSIBLE);
+        let pte = self.create_pte_mut_for_address(page, user_accessible)?;
+
+        let region_header = present_pte.flags() & PresentPageFlags::REGION_HEADER;
+        pte.store(RawPresentPte::from_frame_flags_and_counter(
+            present_pte.frame(),
+            flags | region_header,
+            present_pte.counter(),
+        ));
+
+        Ok(MapperFlush::new(page))
     }
 
     pub fn set_present(
@@ -153,11 +402,451 @@ impl Mapper {
     }
 
     fn do_set_pte(&mut self, page: usize, new_pte: impl Into<RawPte>) -> Result<MapperFlush> {
-        let pte = self.create_pte_mut_for_address(page)?;
+        check_canonical(page)?;
+        let new_pte = new_pte.into();
+        let user_accessible = new_pte.present().map_or(false, |present_pte| {
+            present_pte
+                .flags()
+                .contains(PresentPageFlags::USER_ACCESSIBLE)
+        });
+        let pte = self.create_pte_mut_for_address(page, user_accessible)?;
 
         // We should only be doing this for not present pages
         assert!(!pte.is_present());
-        *pte = new_pte.into();
+        pte.store(new_pte);
         Ok(MapperFlush::new(page))
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::paging::{
+        hyperspace, lock_page_table, FLUSH_ALL_COUNT, FLUSH_COUNT, HUGE_PAGE_SIZE, KERNEL_HEAP_BASE,
+    };
+    use crate::scheduler::{exit, sleep, spawn_on, MAX_CPUS};
+    use alloc::sync::Arc;
+    use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+    use core::time::Duration;
+
+    fn counter_at(addr: usize) -> u16 {
+        let page_table = unsafe { lock_page_table() };
+        page_table
+            .get_pte_for_address(addr)
+            .and_then(|pte| pte.present().ok())
+            .expect("Page is not mapped")
+            .counter()
+    }
+
+    #[test_case]
+    fn shared_frame_counter_tracks_unmap() {
+        let frame = physmem::allocate_user_frame().expect("Failed to allocate test frame");
+
+        let first = hyperspace::map_page(frame).expect("Failed to map frame");
+        assert_eq!(counter_at(first), 0);
+        assert_eq!(physmem::mapping_count(frame), 1);
+
+        let second = hyperspace::map_page(frame).expect("Failed to map frame again");
+        assert_eq!(counter_at(first), 1);
+        assert_eq!(counter_at(second), 1);
+        assert_eq!(unsafe { lock_page_table() }.mapping_count(first), Some(2));
+        assert_eq!(physmem::mapping_count(frame), 2);
+
+        unsafe { hyperspace::unmap_page(second) };
+
+        assert_eq!(counter_at(first), 0);
+        assert_eq!(unsafe { lock_page_table() }.mapping_count(first), Some(1));
+        assert_eq!(physmem::mapping_count(frame), 1);
+        unsafe {
+            (first as *mut u64).write_volatile(0x1234_5678);
+            assert_eq!((first as *const u64).read_volatile(), 0x1234_5678);
+            hyperspace::unmap_page(first);
+        }
+
+        assert_eq!(unsafe { lock_page_table() }.mapping_count(first), None);
+        assert_eq!(physmem::mapping_count(frame), 0);
+        physmem::deallocate_frame(frame);
+    }
+
+    #[test_case]
+    fn translate_finds_the_frame() {
+        let frame = physmem::allocate_user_frame().expect("Failed to allocate test frame");
+        let page = hyperspace::map_page(frame).expect("Failed to map frame");
+
+        let (mapped, unmapped) = {
+            let page_table = unsafe { lock_page_table() };
+            (
+                page_table.translate(page + 0x123),
+                page_table.translate(0x0000_6400_0000_0000),
+            )
+        };
+        unsafe { hyperspace::unmap_page(page) };
+        physmem::deallocate_frame(frame);
+
+        assert_eq!(mapped, Some(frame.physical_address() + 0x123));
+        assert_eq!(unmapped, None);
+    }
+
+    // Count the TLB flushes done when flushing a batch of the given number of pages
+    fn flushes_for_batch(pages: usize) -> (usize, usize) {
+        let page_table = unsafe { lock_page_table() };
+        let flush_count = FLUSH_COUNT.load(Ordering::SeqCst);
+        let flush_all_count = FLUSH_ALL_COUNT.load(Ordering::SeqCst);
+
+        let mut flusher = MapperFlushAll::new();
+        for page in 0..pages {
+            flusher.consume(MapperFlush::new(KERNEL_HEAP_BASE + page * PAGE_SIZE));
+        }
+        flusher.flush(&page_table);
+
+        (
+            FLUSH_COUNT.load(Ordering::SeqCst) - flush_count,
+            FLUSH_ALL_COUNT.load(Ordering::SeqCst) - flush_all_count,
+        )
+    }
+
+    #[test_case]
+    fn small_batches_flush_individual_pages() {
+        assert_eq!(flushes_for_batch(0), (0, 0));
+        assert_eq!(flushes_for_batch(3), (3, 0));
+        assert_eq!(flushes_for_batch(FLUSH_BATCH_SIZE), (FLUSH_BATCH_SIZE, 0));
+    }
+
+    #[test_case]
+    fn large_batches_flush_everything() {
+        assert_eq!(flushes_for_batch(FLUSH_BATCH_SIZE + 1), (0, 1));
+        assert_eq!(flushes_for_batch(1000), (0, 1));
+    }
+
+    #[test_case]
+    fn mapping_without_memory_for_page_tables_fails_cleanly() {
+        // Nothing is mapped in this part of the lower half, so the mapping needs new page tables
+        let page = 0x0000_6400_0000_0000;
+        let frame = physmem::allocate_user_frame().expect("Failed to allocate test frame");
+
+        let result = {
+            let _exhausted = physmem::ExhaustedKernelFrames::new();
+            let mut page_table = unsafe { lock_page_table() };
+            assert!(page_table.p4().next_table(p4_index(page)).is_none());
+
+            page_table
+                .map_to(page, frame, PresentPageFlags::WRITABLE)
+                .map(|flush| flush.flush(&page_table))
+        };
+
+        assert_eq!(result, Err(MemoryError::OutOfMemory));
+        assert!(unsafe { lock_page_table() }
+            .get_pte_for_address(page)
+            .is_none());
+        physmem::deallocate_frame(frame);
+    }
+
+    // The flags on the P4, P3 and P2 entries above addr
+    fn table_flags(addr: usize) -> [PresentPageFlags; 3] {
+        let page_table = unsafe { lock_page_table() };
+        let p4 = page_table.p4();
+        let p3 = p4.next_table(p4_index(addr)).expect("No P3 table");
+        let p2 = p3.next_table(p3_index(addr)).expect("No P2 table");
+        [
+            p4[p4_index(addr)].present().unwrap().flags(),
+            p3[p3_index(addr)].present().unwrap().flags(),
+            p2[p2_index(addr)].present().unwrap().flags(),
+        ]
+    }
+
+    #[test_case]
+    fn only_tables_above_user_pages_are_user_accessible() {
+        // Nothing else is mapped in this part of the lower half, so the kernel page gets new tables.
+        // The user page shares its P3 and P2 tables, but not its P1 table.
+        let kernel_page = 0x0000_6500_0000_0000;
+        let user_page = kernel_page + HUGE_PAGE_SIZE;
+        let kernel_frame = physmem::allocate_kernel_frame().expect("Failed to allocate frame");
+        let user_frame = physmem::allocate_user_frame().expect("Failed to allocate frame");
+        let user = PresentPageFlags::USER_ACCESSIBLE;
+
+        let map = |page, frame, flags| {
+            let mut page_table = unsafe { lock_page_table() };
+            page_table
+                .map_to(page, frame, flags)
+                .expect("Failed to map test page")
+                .flush(&page_table);
+        };
+
+        map(kernel_page, kernel_frame, PresentPageFlags::WRITABLE);
+        let kernel_tables = table_flags(kernel_page);
+        map(user_page, user_frame, user | PresentPageFlags::NO_EXECUTE);
+        let user_tables = table_flags(user_page);
+        let kernel_tables_after = table_flags(kernel_page);
+
+        {
+            let mut page_table = unsafe { lock_page_table() };
+            for page in &[kernel_page, user_page] {
+                page_table
+                    .unmap(*page, true)
+                    .expect("Failed to unmap test page")
+                    .flush(&page_table);
+            }
+        }
+
+        assert!(kernel_tables.iter().all(|flags| !flags.contains(user)));
+        assert!(user_tables.iter().all(|flags| flags.contains(user)));
+        assert!(kernel_tables_after[..2]
+            .iter()
+            .all(|flags| flags.contains(user)));
+        assert!(!kernel_tables_after[2].contains(user));
+    }
+
+    #[test_case]
+    fn remap_changes_flags_and_keeps_the_frame() {
+        let frame = physmem::allocate_kernel_frame().expect("Failed to allocate test frame");
+        let page = hyperspace::map_page(frame).expect("Failed to map frame");
+
+        let mut page_table = unsafe { lock_page_table() };
+        page_table
+            .remap(page, PresentPageFlags::NO_EXECUTE)
+            .expect("Failed to remap page")
+            .flush(&page_table);
+
+        let pte = page_table
+            .get_pte_for_address(page)
+            .and_then(|pte| pte.present().ok())
+            .expect("Page is not mapped");
+        assert_eq!(pte.frame(), frame);
+        assert_eq!(pte.flags(), PresentPageFlags::NO_EXECUTE);
+        drop(page_table);
+
+        unsafe { hyperspace::unmap_page(page) };
+        physmem::deallocate_frame(frame);
+    }
+
+    #[test_case]
+    fn non_canonical_addresses_are_rejected() {
+        // Without the check, this would go in the same slot as the first page of the kernel half
+        let page = 0x0000_8000_0000_0000;
+        let frame = physmem::allocate_user_frame().expect("Failed to allocate test frame");
+
+        let (mapped, remapped, unmapped) = {
+            let mut page_table = unsafe { lock_page_table() };
+            (
+                page_table
+                    .map_to(page, frame, PresentPageFlags::WRITABLE)
+                    .map(|flush| flush.flush(&page_table)),
+                page_table
+                    .remap(page, PresentPageFlags::empty())
+                    .map(|flush| flush.flush(&page_table)),
+                page_table
+                    .unmap(0xfff0_0000_0000_0000, false)
+                    .map(|flush| flush.flush(&page_table)),
+            )
+        };
+        physmem::deallocate_frame(frame);
+
+        assert_eq!(mapped, Err(MemoryError::InvalidRegion));
+        assert_eq!(remapped, Err(MemoryError::InvalidRegion));
+        assert_eq!(unmapped, Err(MemoryError::InvalidRegion));
+        assert_eq!(physmem::mapping_count(frame), 0);
+    }
+
+    #[test_case]
+    fn canonical_addresses() {
+        assert!(is_canonical(0));
+        assert!(is_canonical(0x0000_7fff_ffff_f000));
+        assert!(is_canonical(0xffff_8000_0000_0000));
+        assert!(is_canonical(usize::MAX));
+        assert!(!is_canonical(0x0000_8000_0000_0000));
+        assert!(!is_canonical(0xffff_7fff_ffff_f000));
+        assert!(!is_canonical(0x0001_0000_0000_0000));
+    }
+
+    #[test_case]
+    fn remap_of_unmapped_page_fails() {
+        let page = 0x0000_6400_0000_0000;
+        let mut page_table = unsafe { lock_page_table() };
+        assert_eq!(
+            page_table
+                .remap(page, PresentPageFlags::empty())
+                .map(|flush| flush.flush(&page_table)),
+            Err(MemoryError::NotMapped)
+        );
+    }
+
+    const SHARED_PAGE: usize = 0x0000_6500_4000_0000;
+    const FIRST_VALUE: u64 = 0x1111_2222_3333_4444;
+    const SECOND_VALUE: u64 = 0x5555_6666_7777_8888;
+    const NOT_READ: u64 = 0;
+
+    struct SharedPageReader {
+        // The test moves this on to tell the reader what to do next
+        step: AtomicUsize,
+        first_read: AtomicU64,
+        second_read: AtomicU64,
+    }
+
+    impl SharedPageReader {
+        fn wait_for_step(&self, step: usize) {
+            while self.step.load(Ordering::SeqCst) < step {
+                sleep(Duration::from_millis(1));
+            }
+        }
+    }
+
+    fn read_shared_page() -> u64 {
+        unsafe { (SHARED_PAGE as *const u64).read_volatile() }
+    }
+
+    // Exits with how many reads saw neither value
+    fn reader_task(reader: Arc<SharedPageReader>) -> ! {
+        // A page that wasn't present can't be in this CPU's TLB, so the new mapping is used at once
+        reader.wait_for_step(1);
+        let first_read = read_shared_page();
+        reader.first_read.store(first_read, Ordering::SeqCst);
+
+        // The old frame can still be seen until the flush reaches this CPU, which it can only do
+        // while the task sleeps, but nothing else can
+        reader.wait_for_step(2);
+        let mut unexpected_reads = 0;
+        for _ in 0..1000 {
+            let value = read_shared_page();
+            if value == SECOND_VALUE {
+                reader.second_read.store(value, Ordering::SeqCst);
+                break;
+            } else if value != FIRST_VALUE {
+                unexpected_reads += 1;
+            }
+            sleep(Duration::from_millis(1));
+        }
+
+        core::mem::drop(reader);
+        exit(unexpected_reads)
+    }
+
+    #[test_case]
+    fn mapping_changes_are_seen_by_other_cpus() {
+        let this_cpu = crate::cpu_id();
+        let other_cpu =
+            match (0..MAX_CPUS).find(|id| *id != this_cpu && crate::init::cpu_online(*id)) {
+                Some(other_cpu) => other_cpu,
+                None => return,
+            };
+
+        let mut frames = [Frame::containing_address(0); 2];
+        for (frame, value) in frames.iter_mut().zip([FIRST_VALUE, SECOND_VALUE].iter()) {
+            *frame = physmem::allocate_kernel_frame().expect("Failed to allocate frame");
+            unsafe { *phys_to_virt_mut::<u64>(frame.physical_address()) = *value };
+        }
+        let flags = PresentPageFlags::WRITABLE | PresentPageFlags::NO_EXECUTE;
+
+        let reader = Arc::new(SharedPageReader {
+            step: AtomicUsize::new(0),
+            first_read: AtomicU64::new(NOT_READ),
+            second_read: AtomicU64::new(NOT_READ),
+        });
+        let reader_clone = reader.clone();
+        let task = unsafe { spawn_on(other_cpu, move || reader_task(reader_clone)) }
+            .expect("Failed to spawn reader");
+        {
+            let mut page_table = unsafe { lock_page_table() };
+            page_table
+                .map_to(SHARED_PAGE, frames[0], flags)
+                .expect("Failed to map test page")
+                .flush(&page_table);
+        }
+        reader.step.store(1, Ordering::SeqCst);
+        while reader.first_read.load(Ordering::SeqCst) == NOT_READ {
+            sleep(Duration::from_millis(1));
+        }
+
+        let change_mapping = |frame: Option<Frame>| {
+            let mut page_table = unsafe { lock_page_table() };
+            let mut flush_all = MapperFlushAll::new();
+            flush_all.consume(
+                page_table
+                    .unmap(SHARED_PAGE, false)
+                    .expect("Failed to unmap test page"),
+            );
+            if let Some(frame) = frame {
+                flush_all.consume(
+                    page_table
+                        .map_to(SHARED_PAGE, frame, flags)
+                        .expect("Failed to map test page"),
+                );
+            }
+            flush_all.flush(&page_table);
+        };
+
+        change_mapping(Some(frames[1]));
+        reader.step.store(2, Ordering::SeqCst);
+        let unexpected_reads = task.join();
+        change_mapping(None);
+        for frame in frames.iter() {
+            physmem::deallocate_frame(*frame);
+        }
+
+        assert_eq!(reader.first_read.load(Ordering::SeqCst), FIRST_VALUE);
+        assert_eq!(reader.second_read.load(Ordering::SeqCst), SECOND_VALUE);
+        assert_eq!(unexpected_reads, 0);
+    }
+
+    #[test_case]
+    fn iter_mappings_finds_every_page() {
+        const BASE: usize = 0x0000_6500_8000_0000;
+        let read_only = PresentPageFlags::NO_EXECUTE;
+        let writable = PresentPageFlags::WRITABLE;
+        let pages = [
+            (BASE, writable),
+            (BASE + PAGE_SIZE, read_only),
+            (BASE + HUGE_PAGE_SIZE + 5 * PAGE_SIZE, writable | read_only),
+        ];
+
+        let mut frames = [Frame::containing_address(0); 3];
+        {
+            let mut page_table = unsafe { lock_page_table() };
+            for (frame, (page, flags)) in frames.iter_mut().zip(pages.iter()) {
+                *frame = physmem::allocate_kernel_frame().expect("Failed to allocate frame");
+                page_table
+                    .map_to(*page, *frame, *flags)
+                    .expect("Failed to map test page")
+                    .flush(&page_table);
+            }
+        }
+
+        // Nothing can be allocated with the page table locked, so the results go in an array
+        let mut found: [Option<Mapping>; 4] = Default::default();
+        let mut found_count = 0;
+        let identity_map;
+        {
+            let mut page_table = unsafe { lock_page_table() };
+            let in_test_area =
+                |mapping: &Mapping| (BASE..BASE + P3_HUGE_PAGE_SIZE).contains(&mapping.0.start);
+            for mapping in page_table.iter_mappings().filter(in_test_area) {
+                if found_count < found.len() {
+                    found[found_count] = Some(mapping);
+                }
+                found_count += 1;
+            }
+            identity_map = page_table
+                .iter_mappings()
+                .find(|mapping| mapping.0.start == crate::paging::phys_offset());
+
+            for page in pages.iter() {
+                page_table
+                    .unmap(page.0, true)
+                    .expect("Failed to unmap test page")
+                    .flush(&page_table);
+            }
+        }
+
+        assert_eq!(found_count, pages.len());
+        for ((mapping, (page, flags)), frame) in found.iter().zip(pages.iter()).zip(frames.iter()) {
+            assert_eq!(
+                mapping,
+                &Some((*page..*page + PAGE_SIZE, *frame, *flags, false))
+            );
+        }
+
+        let (range, frame, flags, huge) = identity_map.expect("Identity map not found");
+        assert_eq!(range.end - range.start, HUGE_PAGE_SIZE);
+        assert_eq!(frame, Frame::containing_address(0));
+        assert!(huge && flags.contains(PresentPageFlags::HUGE_PAGE));
+    }
+}
@@ -1,22 +1,30 @@
+use crate::lock_order::{LockLevel, LockOrderToken};
 use crate::physmem;
 use bootloader::BootInfo;
-use core::ops::{Deref, DerefMut};
+use core::ops::{Deref, DerefMut, Range};
+use core::sync::atomic::{AtomicUsize, Ordering};
 use spin::{Mutex, MutexGuard};
 use x86::{controlregs, tlb};
 
 pub use crate::physmem::{page_align_down, page_align_up, Frame, PAGE_SIZE};
 
-use table::{p1_index, p2_index, p3_index, p4_index};
+use table::{is_canonical, p1_index, p2_index, p3_index, p4_index};
 pub use table::{HierarchyLevel, PageTable, PageTableIndex, PageTableLevel, L1, L2, L3, L4};
 
+pub use address_space::{
+    new_address_space, verify_cr3_kernel_shared, verify_kernel_shared, AddressSpace,
+};
 pub use heap_region::{
-    allocate_kernel_stack, allocate_region, map_physical_memory, KernelStack, PhysicalMappingFlags,
-    Region,
+    allocate_kernel_stack, allocate_region, map_physical_memory, region_map_stats, KernelStack,
+    MmioValue, PhysicalMappingFlags, Region, RegionMapStats,
 };
-pub use mapper::{Mapper, MapperFlush, MapperFlushAll};
+pub use mapper::{Mapper, MapperFlush, MapperFlushAll, Mapping};
 pub use page_entry::PresentPageFlags;
 
+mod address_space;
 mod heap_region;
+pub mod hyperspace;
+pub mod kernel_layout;
 mod kernel_stack;
 mod mapper;
 mod page_entry;
@@ -29,41 +37,87 @@ pub enum MemoryError {
     OutOfMemory,
     InvalidStack,
     InvalidRegion,
+    OutOfHyperspacePages,
 }
 
 pub type Result<T> = core::result::Result<T, MemoryError>;
 
 pub const FIRST_KERNEL_PML4: PageTableIndex = p4_index(0xffff_8000_0000_0000);
 pub const KERNEL_PML4: PageTableIndex = p4_index(0xffff_8000_0000_0000);
-pub const IDENTITY_MAP_PML4: PageTableIndex = p4_index(IDENTITY_MAP_REGION);
 pub const KERNEL_DATA_PML4: PageTableIndex = p4_index(KERNEL_HEAP_BASE);
 
-// We're going to use a whole PML4 entry to identity map memory. For now we will only map the first 4GB
+// We're going to use a whole PML4 entry to identity map memory. For now we will only map the first 4GB.
+// This is where Cargo.toml asks the bootloader to put it, but the kernel goes wherever the
+// bootloader says it actually did.
 pub const IDENTITY_MAP_REGION: usize = 0xffff_8080_0000_0000;
+static PHYS_OFFSET: AtomicUsize = AtomicUsize::new(IDENTITY_MAP_REGION);
 
 // Allow 3GB of kernel address space for kernel heap
 pub const KERNEL_HEAP_BASE: usize = 0xffff_ff80_0000_0000;
 pub const KERNEL_HEAP_LIMIT: usize = 0xffff_ff80_c000_0000;
 
+// Hyperspace sits directly after the kernel heap and is a single L1 table (2MiB) of address space
+// used for temporarily mapping frames that are outside of the identity map
+pub const HYPERSPACE_BASE: usize = KERNEL_HEAP_LIMIT;
+pub const HYPERSPACE_LIMIT: usize = HYPERSPACE_BASE + HUGE_PAGE_SIZE;
+
 pub const DEFAULT_KERNEL_STACK_PAGES: usize = 32;
 
+// The page table that paging init builds, which kernel tasks run in
+static KERNEL_CR3: AtomicUsize = AtomicUsize::new(0);
+
+pub fn kernel_cr3() -> usize {
+    KERNEL_CR3.load(Ordering::Relaxed)
+}
+
+/// Throw away every entry in this CPU's TLB. Reloading CR3 leaves the GLOBAL kernel mappings
+/// behind, but turning global pages off and back on gets rid of those too.
+pub unsafe fn flush_local_tlb() {
+    use controlregs::Cr4;
+
+    let cr4 = controlregs::cr4();
+    if cr4.contains(Cr4::CR4_ENABLE_GLOBAL_PAGES) {
+        controlregs::cr4_write(cr4 - Cr4::CR4_ENABLE_GLOBAL_PAGES);
+        controlregs::cr4_write(cr4);
+    } else {
+        tlb::flush_all();
+    }
+}
+
 pub struct ActivePageTable<'a> {
     #[allow(dead_code)]
     guard: MutexGuard<'a, ()>,
+    #[allow(dead_code)]
+    order: LockOrderToken,
     mapper: Mapper,
 }
 
+// Count TLB flushes, so tests can check how mappings get flushed
+#[cfg(test)]
+pub(crate) static FLUSH_COUNT: AtomicUsize = AtomicUsize::new(0);
+#[cfg(test)]
+pub(crate) static FLUSH_ALL_COUNT: AtomicUsize = AtomicUsize::new(0);
+
 impl<'a> ActivePageTable<'a> {
     pub fn flush(&self, addr: usize) {
+        #[cfg(test)]
+        FLUSH_COUNT.fetch_add(1, Ordering::SeqCst);
+
         unsafe { tlb::flush(addr) };
     }
 
     pub fn flush_all(&self) {
-        unsafe {
-            use crate::ipi::{ipi, IpiKind, IpiTarget};
-            tlb::flush_all();
-            ipi(IpiKind::Tlb, IpiTarget::Other);
-        }
+        #[cfg(test)]
+        FLUSH_ALL_COUNT.fetch_add(1, Ordering::SeqCst);
+
+        unsafe { flush_local_tlb() };
+        self.flush_other_cpus();
+    }
+
+    /// Other CPUs can't be asked to flush individual pages, so they flush their whole TLB
+    pub fn flush_other_cpus(&self) {
+        use crate::ipi::{ipi, IpiKind, IpiTarget};
+        ipi(IpiKind::Tlb, IpiTarget::Other);
     }
 }
 
@@ -83,10 +137,13 @@ impl<'a> DerefMut for ActivePageTable<'a> {
 pub unsafe fn lock_page_table() -> ActivePageTable<'static> {
     static PAGE_LOCK: Mutex<()> = Mutex::new(());
 
+    // The region manager lock must be taken first, see lock_order
+    let order = LockOrderToken::acquire(LockLevel::PageTable);
     let guard = PAGE_LOCK.lock();
 
     ActivePageTable {
         guard,
+        order,
         mapper: Mapper::new(Frame::containing_address(controlregs::cr3() as usize)),
     }
 }
@@ -101,16 +158,14 @@ pub unsafe fn lock_page_table() -> ActivePageTable<'static> {
 unsafe fn copy_boot_mapping(
     boot_p4_table: &PageTable<L4>,
     init_p4_table: &mut PageTable<L4>,
-    start: usize,
-    end: usize,
+    range: Range<usize>,
     flags: page_entry::PresentPageFlags,
 ) -> Result<()> {
-    let mut virt_page = start;
-    while virt_page < end {
+    for virt_page in range.step_by(PAGE_SIZE) {
         let init_p1_table = init_p4_table
-            .create_next_table(p4_index(virt_page))?
-            .create_next_table(p3_index(virt_page))?
-            .create_next_table(p2_index(virt_page))?;
+            .create_next_table(p4_index(virt_page), false)?
+            .create_next_table(p3_index(virt_page), false)?
+            .create_next_table(p2_index(virt_page), false)?;
 
         let boot_p1_table = boot_p4_table
             .next_table(p4_index(virt_page))
@@ -126,8 +181,6 @@ unsafe fn copy_boot_mapping(
 
         init_p1_table[p1_index(virt_page)] =
             page_entry::RawPresentPte::from_frame_and_flags(boot_p1_entry.frame(), flags).into();
-
-        virt_page += PAGE_SIZE;
     }
 
     Ok(())
@@ -147,27 +200,23 @@ unsafe fn prepare_identity_mapping(init_p4_table: &mut PageTable<L4>) -> Result<
         todo!("This would be much easier if we supported 1gib pages");
     } else {
         // Identity map the first 4gib of physical address space. This will take a bunch of pages
-        // but should all fit in a single PML4 entry
-        assert_eq!(
-            p4_index(IDENTITY_MAP_REGION + 0xffff_ffff),
-            IDENTITY_MAP_PML4,
-            "Identity map region does not fit in a single PML4 entry"
-        );
-
-        let p3_table = init_p4_table.create_next_table(p4_index(IDENTITY_MAP_REGION))?;
-        let mut va_pos = IDENTITY_MAP_REGION;
-        let va_limit = IDENTITY_MAP_REGION + IDENTITY_MAP_SIZE;
+        // but should all fit in a single PML4 entry, which pre_init has checked. It goes where the
+        // bootloader's was, so that phys_to_virt works the same after the switch.
+        let identity_map_region = phys_offset();
+        let p3_table = init_p4_table.create_next_table(p4_index(identity_map_region), false)?;
+        let mut va_pos = identity_map_region;
+        let va_limit = identity_map_region + IDENTITY_MAP_SIZE;
 
         let mut current_p3_index = p3_index(va_pos);
-        let mut current_p2_table = p3_table.create_next_table(current_p3_index)?;
+        let mut current_p2_table = p3_table.create_next_table(current_p3_index, false)?;
 
         while va_pos < va_limit {
             if p3_index(va_pos) != current_p3_index {
                 current_p3_index = p3_index(va_pos);
-                current_p2_table = p3_table.create_next_table(current_p3_index)?;
+                current_p2_table = p3_table.create_next_table(current_p3_index, false)?;
             }
 
-            let phys_pos = va_pos - IDENTITY_MAP_REGION;
+            let phys_pos = va_pos - identity_map_region;
             let frame = Frame::containing_address(phys_pos);
 
             current_p2_table[p2_index(va_pos)] = page_entry::RawPresentPte::from_frame_and_flags(
@@ -185,9 +234,33 @@ unsafe fn prepare_identity_mapping(init_p4_table: &mut PageTable<L4>) -> Result<
     Ok(())
 }
 
-pub fn phys_to_virt_addr(phys_addr: usize, length: usize) -> usize {
+/// Where the identity map of physical memory starts
+pub fn phys_offset() -> usize {
+    PHYS_OFFSET.load(Ordering::Relaxed)
+}
+
+// The identity map gets a PML4 entry to itself, in the kernel half, and is mapped with huge pages
+fn validate_phys_offset(offset: usize) -> Result<()> {
+    let pml4 = p4_index(offset);
+    let shares_pml4 = pml4 == KERNEL_PML4 || pml4 == KERNEL_DATA_PML4;
+    let fits = offset
+        .checked_add(IDENTITY_MAP_SIZE - 1)
+        .map_or(false, |last| p4_index(last) == pml4);
+
+    if pml4 < FIRST_KERNEL_PML4 || shares_pml4 || offset % HUGE_PAGE_SIZE != 0 || !fits {
+        Err(MemoryError::InvalidRegion)
+    } else {
+        Ok(())
+    }
+}
+
+fn offset_phys_to_virt(offset: usize, phys_addr: usize, length: usize) -> usize {
     assert!(phys_addr + length < IDENTITY_MAP_SIZE);
-    phys_addr + IDENTITY_MAP_REGION
+    phys_addr + offset
+}
+
+pub fn phys_to_virt_addr(phys_addr: usize, length: usize) -> usize {
+    offset_phys_to_virt(phys_offset(), phys_addr, length)
 }
 
 pub fn phys_to_virt<T>(phys_addr: usize) -> *const T {
@@ -199,47 +272,43 @@ pub fn phys_to_virt_mut<T>(phys_addr: usize) -> *mut T {
 }
 
 pub unsafe fn pre_init(boot_info: &BootInfo) {
-    assert_eq!(
-        boot_info.physical_memory_offset as usize, IDENTITY_MAP_REGION,
-        "Bootloader has not mapped identity memory in the right place"
-    );
+    let offset = boot_info.physical_memory_offset as usize;
+    if validate_phys_offset(offset).is_err() {
+        panic!(
+            "Bootloader mapped physical memory at unusable address {:#x}",
+            offset
+        );
+    }
+    PHYS_OFFSET.store(offset, Ordering::Relaxed);
 }
 
-pub unsafe fn init(cpuid: usize) -> usize {
-    extern "C" {
-        static __kernel_start: u8;
-        static __text_start: u8;
-        static __text_end: u8;
-        static __rodata_start: u8;
-        static __rodata_end: u8;
-        static __data_start: u8;
-        static __data_end: u8;
-        static __tdata_start: u8;
-        static __tdata_end: u8;
-        static __tbss_start: u8;
-        static __tbss_end: u8;
-        static __bss_start: u8;
-        static __bss_end: u8;
-        static __kernel_end: u8;
-    };
+// Where the bootloader puts the stack that kstart runs on, set by kernel-stack-address in Cargo.toml
+const BOOT_STACK_ADDRESS: usize = 0x1000_0000;
+const BOOT_STACK_PAGES: usize = 8;
 
-    let kernel_start = page_align_down(&__kernel_start as *const u8 as usize);
-    let kernel_end = page_align_up(&__kernel_end as *const u8 as usize);
+/// The boot stack, less its guard page. Its frames are reclaimed once the BSP has left it.
+pub fn boot_stack_range() -> Range<usize> {
+    let start = BOOT_STACK_ADDRESS + PAGE_SIZE;
+    start..start + BOOT_STACK_PAGES * PAGE_SIZE
+}
 
-    assert_eq!(
-        p4_index(kernel_start),
-        KERNEL_PML4,
-        "Kernel is not in kernel PML4 region"
-    );
-    assert_eq!(
-        p4_index(kernel_end),
-        KERNEL_PML4,
-        "Kernel is not in kernel PML4 region"
-    );
+/// Unmap the boot stack, so that its frames can be reclaimed. copy_boot_mapping never counted them
+/// as mapped, so the entries are just cleared rather than unmapped.
+pub unsafe fn unmap_boot_stack() {
+    let mut page_table = lock_page_table();
+    for page in boot_stack_range().step_by(PAGE_SIZE) {
+        if let Some(pte) = page_table.get_pte_mut_for_address(page) {
+            pte.store(page_entry::RawNotPresentPte::unused());
+        }
+    }
+    page_table.flush_all();
+}
 
-    // Allow for the "guard page" on the stack
-    let boot_stack_start = 0x10000000 + PAGE_SIZE;
-    let boot_stack_end = boot_stack_start + (PAGE_SIZE * 8);
+pub unsafe fn init(cpuid: usize) -> usize {
+    // This checks that the kernel is where we expect it
+    kernel_layout::kernel_range();
+
+    let boot_stack = boot_stack_range();
 
     // How do we get hold of the bootloader page table. Fortunately, the bootloader identity maps
     // enough physical memory that we can access it directly like this.
@@ -251,54 +320,44 @@ pub unsafe fn init(cpuid: usize) -> usize {
     let init_page_table = &mut *phys_to_virt_mut(init_page_table_phys.physical_address());
 
     prepare_identity_mapping(init_page_table).expect("Failed to initialize identity mapping");
+    hyperspace::prepare(init_page_table).expect("Failed to initialize hyperspace");
 
     copy_boot_mapping(
         bootloader_page_table,
         init_page_table,
-        &__text_start as *const u8 as usize,
-        &__text_end as *const u8 as usize,
+        kernel_layout::text_range(),
         page_entry::PresentPageFlags::GLOBAL,
     )
     .expect("Failed to create initial mapping");
     copy_boot_mapping(
         bootloader_page_table,
         init_page_table,
-        &__rodata_start as *const u8 as usize,
-        &__rodata_end as *const u8 as usize,
+        kernel_layout::rodata_range(),
         page_entry::PresentPageFlags::GLOBAL | page_entry::PresentPageFlags::NO_EXECUTE,
     )
     .expect("Failed to create initial mapping");
     copy_boot_mapping(
         bootloader_page_table,
         init_page_table,
-        &__data_start as *const u8 as usize,
-        &__data_end as *const u8 as usize,
+        kernel_layout::data_range(),
         page_entry::PresentPageFlags::GLOBAL
             | page_entry::PresentPageFlags::NO_EXECUTE
             | page_entry::PresentPageFlags::WRITABLE,
     )
     .expect("Failed to create initial mapping");
+    // initialize_tcb only ever reads the TLS template, as each CPU's copy is made elsewhere, so it
+    // is read only from the start rather than once every CPU has been through init
     copy_boot_mapping(
         bootloader_page_table,
         init_page_table,
-        &__tdata_start as *const u8 as usize,
-        &__tdata_end as *const u8 as usize,
+        kernel_layout::tls_template_range(),
         page_entry::PresentPageFlags::GLOBAL | page_entry::PresentPageFlags::NO_EXECUTE,
     )
     .expect("Failed to create initial mapping");
     copy_boot_mapping(
         bootloader_page_table,
         init_page_table,
-        &__tbss_start as *const u8 as usize,
-        &__tbss_end as *const u8 as usize,
-        page_entry::PresentPageFlags::GLOBAL | page_entry::PresentPageFlags::NO_EXECUTE,
-    )
-    .expect("Failed to create initial mapping");
-    copy_boot_mapping(
-        bootloader_page_table,
-        init_page_table,
-        &__bss_start as *const u8 as usize,
-        &__bss_end as *const u8 as usize,
+        kernel_layout::bss_range(),
         page_entry::PresentPageFlags::GLOBAL
             | page_entry::PresentPageFlags::NO_EXECUTE
             | page_entry::PresentPageFlags::WRITABLE,
@@ -307,14 +366,15 @@ pub unsafe fn init(cpuid: usize) -> usize {
     copy_boot_mapping(
         bootloader_page_table,
         init_page_table,
-        boot_stack_start,
-        boot_stack_end,
+        boot_stack,
         page_entry::PresentPageFlags::NO_EXECUTE | page_entry::PresentPageFlags::WRITABLE,
     )
     .expect("Failed to create initial mapping");
 
     // Switch to the page table
     controlregs::cr3_write(init_page_table_phys.physical_address() as u64);
+    KERNEL_CR3.store(init_page_table_phys.physical_address(), Ordering::Relaxed);
+    enable_paging_features();
 
     // Initialize the region manager
     heap_region::init(KERNEL_HEAP_BASE, KERNEL_HEAP_LIMIT);
@@ -323,23 +383,112 @@ pub unsafe fn init(cpuid: usize) -> usize {
 }
 
 pub unsafe fn init_ap(cpu_id: usize) -> usize {
-    // The only thing we need to do for an AP is to initialize its TCB
-    // memory
+    // The trampoline turns these on already, but make sure every CPU agrees with the BSP
+    enable_paging_features();
+
+    // The only other thing we need to do for an AP is to initialize its TCB memory
     initialize_tcb(cpu_id).expect("Failed to initialize tcb for CPU")
 }
 
-unsafe fn initialize_tcb(_cpuid: usize) -> Result<usize> {
-    extern "C" {
-        static mut __tdata_start: u8;
-        static mut __tdata_end: u8;
-        static mut __tbss_start: u8;
-        static mut __tbss_end: u8;
+/// Unmap pages of the kernel image that are no longer needed and hand their frames to physmem.
+/// Returns the number of frames that were recovered.
+pub unsafe fn release_kernel_image_pages(start: usize, size: usize) -> usize {
+    assert_eq!(start % PAGE_SIZE, 0, "Kernel image pages must be page aligned");
+    assert_eq!(size % PAGE_SIZE, 0, "Kernel image pages must be page aligned");
+
+    let mut page_table = lock_page_table();
+    let mut flusher = MapperFlushAll::new();
+    let mut recovered = 0;
+
+    for page in (start..start + size).step_by(PAGE_SIZE) {
+        let frame = page_table
+            .get_pte_for_address(page)
+            .and_then(|pte| pte.present().ok())
+            .map(|present_pte| present_pte.frame())
+            .expect("Kernel image page is not mapped");
+
+        flusher.consume(
+            page_table
+                .unmap(page, false)
+                .expect("Kernel image page is not canonical"),
+        );
+        if physmem::reclaim_frame(frame) {
+            recovered += 1;
+        }
     }
 
-    let tcb_start_addr = &__tdata_start as *const _ as usize;
-    let tcb_end_addr = &__tbss_end as *const _ as usize;
-    let per_cpu_size = page_align_up(tcb_end_addr - tcb_start_addr);
-    let tbss_offset = &__tbss_start as *const _ as usize - tcb_start_addr;
+    flusher.flush(&page_table);
+    recovered
+}
+
+/// Find a page in the kernel image's PML4 entry that is writable and executable at the same time.
+/// Nothing should be, so anything this finds is a mistake in the linker script or the mappings.
+pub fn find_wx_page(p4: &PageTable<L4>) -> Option<usize> {
+    use core::convert::TryFrom;
+
+    let is_wx = |pte: page_entry::RawPte| {
+        pte.present().map_or(false, |pte| {
+            pte.flags().contains(PresentPageFlags::WRITABLE)
+                && !pte.flags().contains(PresentPageFlags::NO_EXECUTE)
+        })
+    };
+    let is_huge_wx =
+        |pte: page_entry::RawPte| pte.present().map_or(false, |pte| pte.is_huge()) && is_wx(pte);
+    let index = |index: usize| PageTableIndex::try_from(index).unwrap();
+
+    let base = 0xffff_0000_0000_0000 | usize::from(KERNEL_PML4) << 39;
+    let p3 = p4.next_table(KERNEL_PML4)?;
+    for p3_index in 0..512 {
+        let p3_addr = base | p3_index << 30;
+        if is_huge_wx(p3[index(p3_index)]) {
+            return Some(p3_addr);
+        }
+
+        let p2 = match p3.next_table(index(p3_index)) {
+            Some(p2) => p2,
+            None => continue,
+        };
+        for p2_index in 0..512 {
+            let p2_addr = p3_addr | p2_index << 21;
+            if is_huge_wx(p2[index(p2_index)]) {
+                return Some(p2_addr);
+            }
+
+            let p1 = match p2.next_table(index(p2_index)) {
+                Some(p1) => p1,
+                None => continue,
+            };
+            for (p1_index, pte) in p1.iter().enumerate() {
+                if is_wx(*pte) {
+                    return Some(p2_addr | p1_index << 12);
+                }
+            }
+        }
+    }
+
+    None
+}
+
+/// Panic if any of the kernel image is mapped writable and executable
+pub unsafe fn verify_wx() {
+    if let Some(page) = find_wx_page(lock_page_table().p4()) {
+        panic!("Kernel page {:#x} is writable and executable", page);
+    }
+}
+
+// The page tables use GLOBAL for kernel mappings and leave read only sections without WRITABLE,
+// but neither means anything unless it is switched on. Without WP, ring 0 can write to any page.
+unsafe fn enable_paging_features() {
+    use controlregs::{Cr0, Cr4};
+
+    controlregs::cr0_write(controlregs::cr0() | Cr0::CR0_WRITE_PROTECT);
+    controlregs::cr4_write(controlregs::cr4() | Cr4::CR4_ENABLE_GLOBAL_PAGES);
+}
+
+unsafe fn initialize_tcb(_cpuid: usize) -> Result<usize> {
+    let tls_template = kernel_layout::tls_template_range();
+    let per_cpu_size = page_align_up(tls_template.len());
+    let tbss_offset = kernel_layout::tls_data_size();
 
     let slot_region = allocate_region(per_cpu_size / PAGE_SIZE)?;
     let slot_start_addr = slot_region.start();
@@ -353,7 +502,7 @@ unsafe fn initialize_tcb(_cpuid: usize) -> Result<usize> {
     }
 
     core::ptr::copy(
-        &__tdata_start as *const u8,
+        tls_template.start as *const u8,
         slot_start_addr as *mut u8,
         tbss_offset,
     );
@@ -368,3 +517,129 @@ unsafe fn initialize_tcb(_cpuid: usize) -> Result<usize> {
 
     Ok(tcb_offset)
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::interrupts::exceptions::try_write_byte;
+
+    static READ_ONLY_BYTE: u8 = 0x5a;
+
+    #[test_case]
+    fn physical_memory_offset_comes_from_the_bootloader() {
+        assert_eq!(validate_phys_offset(phys_offset()), Ok(()));
+        assert_eq!(phys_to_virt::<u8>(0x1234) as usize, phys_offset() + 0x1234);
+
+        // Anywhere else that the bootloader could have picked works the same way
+        let offset = 0xffff_9000_0000_0000;
+        assert_eq!(validate_phys_offset(offset), Ok(()));
+        assert_eq!(offset_phys_to_virt(offset, 0x1234, 8), offset + 0x1234);
+        assert_eq!(
+            offset_phys_to_virt(offset, IDENTITY_MAP_SIZE - PAGE_SIZE, PAGE_SIZE - 1),
+            offset + IDENTITY_MAP_SIZE - PAGE_SIZE
+        );
+    }
+
+    #[test_case]
+    fn unusable_physical_memory_offsets_are_rejected() {
+        let pml4_size = 1 << 39;
+        for offset in [
+            0x0000_4000_0000_0000,
+            0xffff_9000_0000_1000,
+            0xffff_9000_0000_0000 + pml4_size - IDENTITY_MAP_SIZE / 2,
+            0xffff_8000_0000_0000,
+            KERNEL_HEAP_BASE,
+        ]
+        .iter()
+        {
+            assert_eq!(
+                validate_phys_offset(*offset),
+                Err(MemoryError::InvalidRegion),
+                "{:#x}",
+                offset
+            );
+        }
+    }
+
+    #[test_case]
+    fn kernel_cannot_write_read_only_pages() {
+        unsafe {
+            assert!(controlregs::cr0().contains(controlregs::Cr0::CR0_WRITE_PROTECT));
+            assert!(controlregs::cr4().contains(controlregs::Cr4::CR4_ENABLE_GLOBAL_PAGES));
+
+            let read_only = &READ_ONLY_BYTE as *const u8 as *mut u8;
+            assert!(kernel_layout::rodata_range().contains(&(read_only as usize)));
+
+            assert!(!try_write_byte(read_only, 0xa5), "Write to rodata did not fault");
+            assert_eq!(core::ptr::read_volatile(read_only), 0x5a);
+
+            let mut writable = 0u8;
+            assert!(try_write_byte(&mut writable, 0xa5));
+            assert_eq!(core::ptr::read_volatile(&writable), 0xa5);
+        }
+    }
+
+    #[test_case]
+    fn full_flushes_leave_global_pages_on() {
+        use controlregs::Cr4;
+
+        // A heap page is GLOBAL, so only a flush that gets rid of global entries will do
+        let region = allocate_region(1).expect("Failed to allocate region");
+        let page_table = unsafe { lock_page_table() };
+        let flags = page_table
+            .get_pte_for_address(region.start())
+            .and_then(|pte| pte.present().ok())
+            .expect("Heap page is not mapped")
+            .flags();
+        assert!(flags.contains(page_entry::PresentPageFlags::GLOBAL));
+
+        let flushes = FLUSH_ALL_COUNT.load(Ordering::SeqCst);
+        page_table.flush_all();
+        assert_eq!(FLUSH_ALL_COUNT.load(Ordering::SeqCst), flushes + 1);
+        assert!(unsafe { controlregs::cr4() }.contains(Cr4::CR4_ENABLE_GLOBAL_PAGES));
+    }
+
+    #[test_case]
+    fn tls_template_is_read_only() {
+        let template = kernel_layout::tls_template_range();
+        assert!(template.start < template.end);
+
+        unsafe {
+            let template_byte = template.start as *mut u8;
+            let original = core::ptr::read_volatile(template_byte);
+            assert!(
+                !try_write_byte(template_byte, !original),
+                "Write to the TLS template did not fault"
+            );
+            assert_eq!(core::ptr::read_volatile(template_byte), original);
+        }
+    }
+
+    #[test_case]
+    fn kernel_mappings_are_never_writable_and_executable() {
+        assert_eq!(find_wx_page(unsafe { lock_page_table() }.p4()), None);
+    }
+
+    #[test_case]
+    fn writable_and_executable_page_is_found() {
+        // The kernel image starts well after the beginning of its PML4 entry
+        const WX_PAGE: usize = 0xffff_8000_0000_0000;
+        assert!(WX_PAGE < kernel_layout::kernel_range().start);
+
+        let frame = physmem::allocate_kernel_frame().expect("Failed to allocate test frame");
+        let mut page_table = unsafe { lock_page_table() };
+        page_table
+            .map_to(WX_PAGE, frame, PresentPageFlags::WRITABLE)
+            .expect("Failed to map test page")
+            .flush(&page_table);
+
+        let found = find_wx_page(page_table.p4());
+        page_table
+            .unmap(WX_PAGE, true)
+            .expect("Failed to unmap test page")
+            .flush(&page_table);
+
+        assert_eq!(found, Some(WX_PAGE));
+        assert_eq!(find_wx_page(page_table.p4()), None);
+    }
+}
@@ -2,6 +2,7 @@ use crate::physmem::Frame;
 use bitflags::bitflags;
 use core::convert::{TryFrom, TryInto};
 use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
 use num_derive::{FromPrimitive, ToPrimitive};
 use num_traits::FromPrimitive;
 
@@ -38,6 +39,22 @@ impl RawPte {
         self.flags().contains(RawPageFlags::PRESENT)
     }
 
+    pub fn is_region_header(&self) -> bool {
+        match self.present() {
+            Ok(present_pte) => present_pte
+                .flags()
+                .contains(PresentPageFlags::REGION_HEADER),
+            Err(_) => self
+                .not_present()
+                .map(|not_present_pte| {
+                    not_present_pte
+                        .flags()
+                        .contains(NotPresentPageFlags::REGION_HEADER)
+                })
+                .unwrap_or(false),
+        }
+    }
+
     pub fn present(self) -> core::result::Result<RawPresentPte, InvalidPteError> {
         self.try_into()
     }
@@ -45,6 +62,24 @@ impl RawPte {
     pub fn not_present(self) -> core::result::Result<RawNotPresentPte, InvalidPteError> {
         self.try_into()
     }
+
+    // Entries in a live table can be read by the CPU's page walker, or another CPU's, at any time,
+    // so they are only ever changed with a single 64 bit store
+    fn as_atomic(&mut self) -> &AtomicU64 {
+        unsafe { &*(self as *mut Self as *const AtomicU64) }
+    }
+
+    /// Replace the entry in one store. The address, flags and present bit all change together, and
+    /// anything written before it, such as the contents of a new table, is visible to whoever sees
+    /// the new entry.
+    pub fn store(&mut self, new_pte: impl Into<RawPte>) {
+        self.as_atomic().store(new_pte.into().0, Ordering::Release);
+    }
+
+    /// Replace the entry in one store and return what it was
+    pub fn swap(&mut self, new_pte: impl Into<RawPte>) -> Self {
+        Self(self.as_atomic().swap(new_pte.into().0, Ordering::AcqRel))
+    }
 }
 
 impl fmt::Debug for RawPte {
@@ -84,7 +119,7 @@ bitflags! {
         /// Indicates that the mapping is present in all address spaces, so it isn't flushed from
         /// the TLB on an address space switch.
         const GLOBAL =          1 << 8;
-        /// Available to the OS, can be used to store additional data, e.g. custom flags.
+        /// Marks the first page of a region allocated by the region manager.
         const REGION_HEADER =   1 << 9;
         /// Available to the OS, can be used to store additional data, e.g. custom flags.
         const BIT_10 =          1 << 10;
@@ -186,8 +221,8 @@ pub enum NotPresentPageType {
 
 bitflags! {
     pub struct NotPresentPageFlags: u64 {
-        /// Available to the OS, can be used to store additional data, e.g. custom flags.
-        const BIT_9 =           1 << 9;
+        /// Marks the first page of a region allocated by the region manager.
+        const REGION_HEADER =   1 << 9;
         /// Available to the OS, can be used to store additional data, e.g. custom flags.
         const BIT_10 =          1 << 10;
         /// Available to the OS, can be used to store additional data, e.g. custom flags.
@@ -255,7 +290,7 @@ impl RawNotPresentPte {
     }
 
     pub fn page_type(&self) -> NotPresentPageType {
-        NotPresentPageType::from_u8(((self.0 >> Self::TYPE_SHIFT) & Self::TYPE_BITS) as u8)
+        NotPresentPageType::from_u8(((self.0 & Self::TYPE_BITS) >> Self::TYPE_SHIFT) as u8)
             .expect("Invalid PTE type")
     }
 
@@ -313,7 +348,13 @@ impl KernelStackGuardPagePte {
 
 impl From<KernelStackGuardPagePte> for RawNotPresentPte {
     fn from(_: KernelStackGuardPagePte) -> Self {
-        RawNotPresentPte::from_type(NotPresentPageType::GuardPage)
+        // The guard page is the first page of the kernel stack region, so it is also the header
+        RawNotPresentPte::from_type_flags_frame_and_counter(
+            NotPresentPageType::GuardPage,
+            NotPresentPageFlags::REGION_HEADER,
+            Frame::containing_address(0),
+            0,
+        )
     }
 }
 
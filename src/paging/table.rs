@@ -1,6 +1,6 @@
 use super::page_entry::{PresentPageFlags, RawNotPresentPte, RawPresentPte, RawPte};
-use super::Result;
 use super::{phys_to_virt, phys_to_virt_mut};
+use super::{MemoryError, Result};
 use crate::physmem;
 use crate::physmem::Frame;
 use core::convert::{Infallible, TryFrom};
@@ -106,6 +106,13 @@ pub const fn p4_index(va: usize) -> PageTableIndex {
     PageTableIndex::new_truncate((va >> 12 >> 9 >> 9 >> 9) as u16)
 }
 
+/// Whether bits 48 to 63 of va are all copies of bit 47. The indexes above ignore those bits, so
+/// anything else would look like a different address.
+pub const fn is_canonical(va: usize) -> bool {
+    let top_bits = va >> 47;
+    top_bits == 0 || top_bits == 0x1_ffff
+}
+
 pub trait PageTableLevel {}
 
 pub trait HierarchyLevel: PageTableLevel {
@@ -157,16 +164,36 @@ impl<L: PageTableLevel> PageTable<L> {
 
     pub fn zero(&mut self) {
         for entry in self.iter_mut() {
-            *entry = RawPte::unused();
+            entry.store(RawPte::unused());
         }
     }
 }
 
 impl<L: 'static + HierarchyLevel> PageTable<L> {
+    /// Get the table that index points to, creating it if there isn't one. The entry for it is only
+    /// user accessible if user_accessible is set, as it must be when a user page will be under it,
+    /// and an existing entry is made user accessible if it needs to be.
     pub fn create_next_table<'a>(
         &'a mut self,
         index: PageTableIndex,
+        user_accessible: bool,
     ) -> Result<&'a mut PageTable<L::NextLevel>> {
+        let user_flag = if user_accessible {
+            PresentPageFlags::USER_ACCESSIBLE
+        } else {
+            PresentPageFlags::empty()
+        };
+
+        if let Ok(present_pte) = self[index].present() {
+            if !present_pte.is_huge() && !present_pte.flags().contains(user_flag) {
+                self[index].store(RawPresentPte::from_frame_flags_and_counter(
+                    present_pte.frame(),
+                    present_pte.flags() | user_flag,
+                    present_pte.counter(),
+                ));
+            }
+        }
+
         if self.next_table_frame(index).is_none() {
             assert!(
                 !self[index]
@@ -175,13 +202,19 @@ impl<L: 'static + HierarchyLevel> PageTable<L> {
                     .unwrap_or(false),
                 "Huge page not supported"
             );
-            let new_page_table = physmem::allocate_kernel_frame()
-                .expect("Failed to allocate frame in boot_create_next_table");
-            self[index] = RawPresentPte::from_frame_and_flags(
+            // Running out of memory here isn't fatal, it just means the mapping can't be made
+            let new_page_table =
+                physmem::allocate_kernel_frame().ok_or(MemoryError::OutOfMemory)?;
+
+            // Whatever the frame held last would look like entries, so clear it before it is
+            // linked in. Kernel frames are always in the identity map.
+            let address = new_page_table.physical_address();
+            unsafe { &mut *phys_to_virt_mut::<PageTable<L::NextLevel>>(address) }.zero();
+
+            self[index].store(RawPresentPte::from_frame_and_flags(
                 new_page_table,
-                PresentPageFlags::WRITABLE | PresentPageFlags::USER_ACCESSIBLE,
-            )
-            .into();
+                PresentPageFlags::WRITABLE | user_flag,
+            ));
         }
 
         Ok(self.next_table_mut(index).unwrap())
@@ -201,9 +234,11 @@ impl<L: 'static + HierarchyLevel> PageTable<L> {
     }
 
     pub fn next_table_frame(&self, index: PageTableIndex) -> Option<Frame> {
+        // A huge page maps memory directly, so there is no next table to follow
         self[index]
             .present()
             .ok()
+            .filter(|present_pte| !present_pte.is_huge())
             .map(|present_pte| present_pte.frame())
     }
 }
@@ -222,7 +257,7 @@ impl PageTable<L1> {
 
         // We should only be doing this for not present pages
         assert!(!pte.is_present());
-        self.0[usize::from(index)] = new_pte.into();
+        self[index].store(new_pte);
     }
 }
 
@@ -239,3 +274,44 @@ impl<L: PageTableLevel> IndexMut<PageTableIndex> for PageTable<L> {
         &mut self.0[usize::from(index)]
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test_case]
+    fn new_tables_start_empty() {
+        let parent_frame = physmem::allocate_kernel_frame().expect("Failed to allocate frame");
+        let parent =
+            unsafe { &mut *phys_to_virt_mut::<PageTable<L2>>(parent_frame.physical_address()) };
+        parent.zero();
+
+        let stale = physmem::allocate_kernel_frame().expect("Failed to allocate frame");
+        unsafe {
+            core::ptr::write_bytes(
+                phys_to_virt_mut::<u8>(stale.physical_address()),
+                0xff,
+                physmem::PAGE_SIZE,
+            )
+        };
+
+        // With nothing else free, the new table has to be the stale frame
+        let index = PageTableIndex::new_truncate(7);
+        let (table_frame, all_unused) = {
+            let _exhausted = physmem::ExhaustedKernelFrames::new();
+            physmem::deallocate_frame(stale);
+            let all_unused = parent
+                .create_next_table(index, false)
+                .expect("Failed to create table")
+                .iter()
+                .all(RawPte::is_unused);
+            (parent.next_table_frame(index), all_unused)
+        };
+
+        physmem::deallocate_frame(parent.next_table_frame(index).unwrap());
+        physmem::deallocate_frame(parent_frame);
+
+        assert_eq!(table_frame, Some(stale));
+        assert!(all_unused);
+    }
+}
@@ -0,0 +1,188 @@
+// Lets the test runner carry on after a test panics. catch_panic saves the callee saved registers,
+// the stack pointer and the flags before running the test, and the panic handler restores them,
+// which puts us back in catch_panic as if the test had returned. Nothing is unwound, so whatever
+// the test had allocated is leaked and any locks it held stay locked. Only the lock order tracking
+// is put back as it was.
+
+use crate::intel_asm;
+use crate::lock_order;
+use crate::scheduler::current_task;
+use alloc::string::String;
+use core::fmt::{self, Write};
+use core::panic::PanicInfo;
+use core::ptr;
+use core::sync::atomic::{AtomicPtr, Ordering};
+
+// rbx, rbp, r12-r15, rflags, the return address and the stack pointer
+const JUMP_BUFFER_SIZE: usize = 9;
+
+#[repr(C)]
+struct JumpBuffer([usize; JUMP_BUFFER_SIZE]);
+
+// Call func(data), returning 1 in al if it returned. recovery_jump returns from here again with 0
+// in al instead.
+intel_asm!(
+    ".global recovery_call\n",
+    ".type recovery_call, @function\n",
+    ".section .text.recovery_call, \"ax\", @progbits\n",
+    "recovery_call:\n",
+    "mov [rdi], rbx\n",
+    "mov [rdi + 8], rbp\n",
+    "mov [rdi + 16], r12\n",
+    "mov [rdi + 24], r13\n",
+    "mov [rdi + 32], r14\n",
+    "mov [rdi + 40], r15\n",
+    "pushfq\n",
+    "pop qword ptr [rdi + 48]\n",
+    "mov rax, [rsp]\n",
+    "mov [rdi + 56], rax\n",
+    "lea rax, [rsp + 8]\n",
+    "mov [rdi + 64], rax\n",
+    // Keep the stack aligned for the call
+    "sub rsp, 8\n",
+    "mov rdi, rdx\n",
+    "call rsi\n",
+    "add rsp, 8\n",
+    "mov eax, 1\n",
+    "ret\n",
+    ".size recovery_call, . - recovery_call\n",
+    ".global recovery_jump\n",
+    ".type recovery_jump, @function\n",
+    "recovery_jump:\n",
+    "mov rbx, [rdi]\n",
+    "mov rbp, [rdi + 8]\n",
+    "mov r12, [rdi + 16]\n",
+    "mov r13, [rdi + 24]\n",
+    "mov r14, [rdi + 32]\n",
+    "mov r15, [rdi + 40]\n",
+    "mov rsp, [rdi + 64]\n",
+    "push qword ptr [rdi + 48]\n",
+    "popfq\n",
+    "xor eax, eax\n",
+    "jmp qword ptr [rdi + 56]\n",
+    ".size recovery_jump, . - recovery_jump\n",
+    ".text\n",
+);
+
+extern "C" {
+    fn recovery_call(buffer: *mut JumpBuffer, func: extern "C" fn(*mut u8), data: *mut u8) -> bool;
+    fn recovery_jump(buffer: *const JumpBuffer) -> !;
+}
+
+// The start of the message of a panic that expect_panic caught. The panic handler writes it, so it
+// is a fixed buffer rather than a String, and anything that doesn't fit is dropped.
+const MESSAGE_SIZE: usize = 256;
+
+struct PanicMessage {
+    bytes: [u8; MESSAGE_SIZE],
+    len: usize,
+}
+
+impl fmt::Write for PanicMessage {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let len = s.len().min(MESSAGE_SIZE - self.len);
+        self.bytes[self.len..self.len + len].copy_from_slice(&s.as_bytes()[..len]);
+        self.len += len;
+        Ok(())
+    }
+}
+
+struct RecoveryPoint {
+    buffer: JumpBuffer,
+    pid: usize,
+    // Only expect_panic has somewhere to put the message, and its panics aren't reported
+    message: Option<PanicMessage>,
+}
+
+// The innermost catch_panic that is running. Only the task that set it can jump back to it.
+static RECOVERY_POINT: AtomicPtr<RecoveryPoint> = AtomicPtr::new(ptr::null_mut());
+
+extern "C" fn call_once<F: FnOnce()>(data: *mut u8) {
+    let func = unsafe { &mut *(data as *mut Option<F>) };
+    func.take().unwrap()();
+}
+
+// Run func with point as the innermost recovery point, and return false if it panicked
+fn run_recoverable<F: FnOnce()>(point: &mut RecoveryPoint, func: F) -> bool {
+    let mut func = Some(func);
+    let held_locks = lock_order::held_count();
+    let previous = RECOVERY_POINT.swap(point, Ordering::SeqCst);
+    let returned = unsafe {
+        recovery_call(
+            &mut point.buffer,
+            call_once::<F>,
+            &mut func as *mut Option<F> as *mut u8,
+        )
+    };
+    RECOVERY_POINT.store(previous, Ordering::SeqCst);
+
+    // The lock order tokens that func held were never dropped, so stop tracking them. Otherwise
+    // they would look like locks that are still held to everything that runs after.
+    if !returned {
+        lock_order::forget_held_after(held_locks);
+    }
+
+    returned
+}
+
+/// Run func, and return false if it panicked instead of returning. Panics only come back here if
+/// the panic handler calls recover, and only from the task that called this.
+pub fn catch_panic<F: FnOnce()>(func: F) -> bool {
+    let mut point = RecoveryPoint {
+        buffer: JumpBuffer([0; JUMP_BUFFER_SIZE]),
+        pid: current_task().pid(),
+        message: None,
+    };
+
+    run_recoverable(&mut point, func)
+}
+
+/// Run func, which should panic, and return what the panic handler would have printed, or None if
+/// func returned instead. The panic isn't reported, so this is for tests that check that something
+/// panics. Long messages are cut short.
+pub fn expect_panic<F: FnOnce()>(func: F) -> Option<String> {
+    let mut point = RecoveryPoint {
+        buffer: JumpBuffer([0; JUMP_BUFFER_SIZE]),
+        pid: current_task().pid(),
+        message: Some(PanicMessage {
+            bytes: [0; MESSAGE_SIZE],
+            len: 0,
+        }),
+    };
+
+    if run_recoverable(&mut point, func) {
+        return None;
+    }
+
+    let message = point.message.unwrap();
+    Some(String::from_utf8_lossy(&message.bytes[..message.len]).into_owned())
+}
+
+// The recovery point that a panic on the current task would go back to, if there is one
+unsafe fn current_point() -> Option<&'static mut RecoveryPoint> {
+    let point = RECOVERY_POINT.load(Ordering::SeqCst);
+    if !point.is_null() && (*point).pid == current_task().pid() {
+        Some(&mut *point)
+    } else {
+        None
+    }
+}
+
+/// Called from the panic handler before it reports the panic. If the panicking task is inside
+/// expect_panic, this records the message and goes back there, and doesn't return.
+pub unsafe fn recover_expected(info: &PanicInfo) {
+    if let Some(point) = current_point() {
+        if let Some(message) = point.message.as_mut() {
+            let _ = write!(message, "{}", info);
+            recovery_jump(&point.buffer);
+        }
+    }
+}
+
+/// Called from the panic handler. If the panicking task is inside catch_panic, this goes back
+/// there and doesn't return.
+pub unsafe fn recover() {
+    if let Some(point) = current_point() {
+        recovery_jump(&point.buffer);
+    }
+}
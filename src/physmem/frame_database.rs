@@ -1,7 +1,9 @@
 use super::{page_align_down, Frame, FrameAllocator, LockedFrameAllocator, PAGE_SIZE};
 use crate::init_mutex::InitMutex;
+use alloc::boxed::Box;
 use alloc::vec;
 use bootloader::bootinfo::{MemoryRegion, MemoryRegionType};
+use core::ops::{Deref, DerefMut};
 
 fn set_bit(bitmask: &mut [u8], index: usize, value: bool) {
     let index_byte = index / 8;
@@ -126,12 +128,52 @@ fn find_available_limit_frame<'a>(
     available_limit_frame
 }
 
+// Arrays with an entry for each frame in a region. The low region's are set up before there is a
+// heap, so they are static. The others are on the heap, and are freed along with their region.
+enum FrameArray<T: 'static> {
+    Static(&'static mut [T]),
+    Heap(Box<[T]>),
+}
+
+impl<T> Deref for FrameArray<T> {
+    type Target = [T];
+
+    fn deref(&self) -> &[T] {
+        match self {
+            FrameArray::Static(array) => array,
+            FrameArray::Heap(array) => array,
+        }
+    }
+}
+
+impl<T> DerefMut for FrameArray<T> {
+    fn deref_mut(&mut self) -> &mut [T] {
+        match self {
+            FrameArray::Static(array) => array,
+            FrameArray::Heap(array) => array,
+        }
+    }
+}
+
+// How many of the most recently freed frames each region remembers. Handing those out again first
+// means that a frame that was just freed, which is likely still in the cache, is the next one to
+// be used. The bitmask still says which frames are free, so a remembered frame is only a hint and
+// is checked before it is used.
+const RECENT_FRAMES: usize = 8;
+
 pub struct PageFrameRegion {
     start_frame: usize,
     limit_frame: usize,
     free_frames: usize,
     used_frames: usize,
-    bitmask: &'static mut [u8],
+    bitmask: FrameArray<u8>,
+    // How many page table entries map each frame, so that a frame mapped in several places is only
+    // freed when the last of them goes
+    mapping_counts: FrameArray<u32>,
+    // A stack of frame indexes, which overwrites the oldest entry when it is full
+    recent: [usize; RECENT_FRAMES],
+    recent_top: usize,
+    recent_count: usize,
 }
 
 impl PageFrameRegion {
@@ -140,16 +182,34 @@ impl PageFrameRegion {
         limit_frame: usize,
         memory_map: impl IntoIterator<Item = &'a MemoryRegion>,
         bitmask: &'static mut [u8],
+        mapping_counts: &'static mut [u32],
+    ) -> Self {
+        Self::with_arrays(
+            start_frame,
+            limit_frame,
+            memory_map,
+            FrameArray::Static(bitmask),
+            FrameArray::Static(mapping_counts),
+        )
+    }
+
+    fn with_arrays<'a>(
+        start_frame: usize,
+        limit_frame: usize,
+        memory_map: impl IntoIterator<Item = &'a MemoryRegion>,
+        mut bitmask: FrameArray<u8>,
+        mut mapping_counts: FrameArray<u32>,
     ) -> Self {
         let mut free_frames = 0;
         bitmask.fill(0);
+        mapping_counts.fill(0);
 
         for region in filter_memory_map(start_frame, limit_frame, memory_map, usable) {
             let free_span_start_frame = (region.base / PAGE_SIZE).max(start_frame) - start_frame;
             let free_span_end_frame = (region.limit / PAGE_SIZE).min(limit_frame) - start_frame;
 
             for free_frame in free_span_start_frame..free_span_end_frame {
-                set_bit(bitmask, free_frame, true);
+                set_bit(&mut bitmask, free_frame, true);
                 free_frames += 1;
             }
         }
@@ -160,6 +220,10 @@ impl PageFrameRegion {
             free_frames,
             used_frames: 0,
             bitmask,
+            mapping_counts,
+            recent: [0; RECENT_FRAMES],
+            recent_top: 0,
+            recent_count: 0,
         }
     }
 
@@ -175,15 +239,46 @@ impl PageFrameRegion {
         let bitmask_bytes = (bitmask_frames + 7) / 8;
 
         let bitmask = vec![0; bitmask_bytes].into_boxed_slice();
-        Self::new(
+        let mapping_counts = vec![0; bitmask_frames].into_boxed_slice();
+        Self::with_arrays(
             start_frame,
             limit_frame,
             memory_map,
-            alloc::boxed::Box::leak(bitmask),
+            FrameArray::Heap(bitmask),
+            FrameArray::Heap(mapping_counts),
         )
     }
 
-    pub fn reclaim<'a>(&mut self, memory_map: impl IntoIterator<Item = &'a MemoryRegion> + Clone) {
+    fn push_recent(&mut self, frame_index: usize) {
+        if RECENT_FRAMES == 0 {
+            return;
+        }
+
+        self.recent[self.recent_top] = frame_index;
+        self.recent_top = (self.recent_top + 1) % RECENT_FRAMES;
+        self.recent_count = (self.recent_count + 1).min(RECENT_FRAMES);
+    }
+
+    // Take the most recently freed frame that is still free
+    fn pop_recent(&mut self) -> Option<usize> {
+        while self.recent_count > 0 {
+            self.recent_top = (self.recent_top + RECENT_FRAMES - 1) % RECENT_FRAMES;
+            self.recent_count -= 1;
+
+            let frame_index = self.recent[self.recent_top];
+            if get_bit(&self.bitmask, frame_index) {
+                return Some(frame_index);
+            }
+        }
+
+        None
+    }
+
+    pub fn reclaim<'a>(
+        &mut self,
+        memory_map: impl IntoIterator<Item = &'a MemoryRegion> + Clone,
+    ) -> usize {
+        let free_frames_before = self.free_frames;
         for region in filter_memory_map(self.start_frame, self.limit_frame, memory_map, reclaimable)
         {
             let free_span_start_frame =
@@ -193,14 +288,72 @@ impl PageFrameRegion {
 
             for free_frame in free_span_start_frame..free_span_end_frame {
                 assert!(
-                    get_bit(self.bitmask, free_frame) == false,
+                    get_bit(&self.bitmask, free_frame) == false,
                     "Reclaiming frame that is already marked free: {:#x}",
                     free_frame
                 );
-                set_bit(self.bitmask, free_frame, true);
+                set_bit(&mut self.bitmask, free_frame, true);
                 self.free_frames += 1;
             }
         }
+
+        self.free_frames - free_frames_before
+    }
+}
+
+impl PageFrameRegion {
+    /// Add a single frame that was never free, like part of the kernel image, to the free pool.
+    /// Returns false if the frame is outside the range that this region tracks.
+    pub fn reclaim_frame(&mut self, frame: Frame) -> bool {
+        if !self.contains_frame(frame) || frame.index() - self.start_frame >= self.bitmask.len() * 8
+        {
+            return false;
+        }
+
+        let frame_index = frame.index() - self.start_frame;
+        assert!(
+            get_bit(&self.bitmask, frame_index) == false,
+            "Reclaiming frame that is already marked free: {:?}",
+            frame
+        );
+        set_bit(&mut self.bitmask, frame_index, true);
+        self.free_frames += 1;
+        true
+    }
+}
+
+impl PageFrameRegion {
+    // Frames past the end of the array, which are never handed out, aren't counted
+    fn mapping_count_mut(&mut self, frame: Frame) -> Option<&mut u32> {
+        if self.contains_frame(frame) {
+            self.mapping_counts
+                .get_mut(frame.index() - self.start_frame)
+        } else {
+            None
+        }
+    }
+
+    pub fn mapping_count(&mut self, frame: Frame) -> usize {
+        self.mapping_count_mut(frame)
+            .map_or(0, |count| *count as usize)
+    }
+
+    /// Returns the number of mappings there now are
+    pub fn add_mapping(&mut self, frame: Frame) -> usize {
+        self.mapping_count_mut(frame).map_or(0, |count| {
+            *count = count
+                .checked_add(1)
+                .expect("Frame mapping count overflowed");
+            *count as usize
+        })
+    }
+
+    /// Returns the number of mappings left
+    pub fn remove_mapping(&mut self, frame: Frame) -> usize {
+        self.mapping_count_mut(frame).map_or(0, |count| {
+            *count = count.saturating_sub(1);
+            *count as usize
+        })
     }
 }
 
@@ -214,7 +367,13 @@ impl LockedFrameAllocator for PageFrameRegion {
     }
 
     fn allocate_frame(&mut self) -> Option<Frame> {
-        if let Some((byte_index, byte)) = self
+        if let Some(frame_index) = self.pop_recent() {
+            set_bit(&mut self.bitmask, frame_index, false);
+            self.free_frames -= 1;
+            self.used_frames += 1;
+
+            Some(Frame::from_index(frame_index + self.start_frame))
+        } else if let Some((byte_index, byte)) = self
             .bitmask
             .iter_mut()
             .enumerate()
@@ -227,7 +386,7 @@ impl LockedFrameAllocator for PageFrameRegion {
             // is bigger than the region. That can't happen though because we would never have set that bit to one
             debug_assert!(frame_index < self.limit_frame);
 
-            set_bit(self.bitmask, frame_index, false);
+            set_bit(&mut self.bitmask, frame_index, false);
             self.free_frames -= 1;
             self.used_frames += 1;
 
@@ -241,9 +400,10 @@ impl LockedFrameAllocator for PageFrameRegion {
         assert!(self.contains_frame(frame), "Frame is not from this region");
 
         let frame_index = frame.index() - self.start_frame;
-        set_bit(self.bitmask, frame_index, true);
+        set_bit(&mut self.bitmask, frame_index, true);
         self.free_frames += 1;
         self.used_frames -= 1;
+        self.push_recent(frame_index);
     }
 
     fn contains_frame(&self, frame: Frame) -> bool {
@@ -282,12 +442,19 @@ pub fn early_init<'a, T: IntoIterator<Item = &'a MemoryRegion>>(memory_map: T) {
         const LOW_REGION_BITMASK_BYTES: usize = (LOW_REGION_FRAMES + 7) / 8;
         static mut LOW_REGION_BITMASK: [u8; LOW_REGION_BITMASK_BYTES] =
             [0; LOW_REGION_BITMASK_BYTES];
+        static mut LOW_REGION_MAPPING_COUNTS: [u32; LOW_REGION_FRAMES] = [0; LOW_REGION_FRAMES];
 
         // We need an unsafe here because we're using a mutable static, but it is safe because the init mutex
         // guarantees this function will only be called once
-        PageFrameRegion::new(UNUSED_LOW_FRAMES, LOW_REGION_FRAMES, memory_map, unsafe {
-            &mut LOW_REGION_BITMASK
-        })
+        unsafe {
+            PageFrameRegion::new(
+                UNUSED_LOW_FRAMES,
+                LOW_REGION_FRAMES,
+                memory_map,
+                &mut LOW_REGION_BITMASK,
+                &mut LOW_REGION_MAPPING_COUNTS,
+            )
+        }
     }
 
     LOW_REGION.init(make_early_memory_map(memory_map));
@@ -306,10 +473,92 @@ pub fn init_post_paging<'a>(memory_map: impl IntoIterator<Item = &'a MemoryRegio
     ));
 }
 
-pub fn init_reclaim<'a>(memory_map: impl IntoIterator<Item = &'a MemoryRegion> + Clone) {
-    LOW_REGION.lock().reclaim(memory_map.clone());
-    NORMAL_REGION.lock().reclaim(memory_map.clone());
-    HIGH_REGION.lock().reclaim(memory_map);
+// Run func on the region that tracks frame. Returns None if no region covers the frame, or the
+// region it would be in hasn't been set up yet.
+fn with_region<R>(frame: Frame, func: impl FnOnce(&mut PageFrameRegion) -> R) -> Option<R> {
+    [&LOW_REGION, &NORMAL_REGION, &HIGH_REGION]
+        .iter()
+        .filter_map(|region| region.try_lock())
+        .find(|region| region.contains_frame(frame))
+        .map(|mut region| func(&mut region))
+}
+
+pub fn mapping_count(frame: Frame) -> usize {
+    with_region(frame, |region| region.mapping_count(frame)).unwrap_or(0)
+}
+
+pub fn add_mapping(frame: Frame) -> usize {
+    with_region(frame, |region| region.add_mapping(frame)).unwrap_or(0)
+}
+
+pub fn remove_mapping(frame: Frame) -> usize {
+    with_region(frame, |region| region.remove_mapping(frame)).unwrap_or(0)
+}
+
+pub fn reclaim_frame(frame: Frame) -> bool {
+    LOW_REGION.lock().reclaim_frame(frame)
+        || NORMAL_REGION.lock().reclaim_frame(frame)
+        || HIGH_REGION.lock().reclaim_frame(frame)
+}
+
+/// Whether init_reclaim would free frame
+pub fn is_reclaimable<'a>(
+    memory_map: impl IntoIterator<Item = &'a MemoryRegion>,
+    frame: Frame,
+) -> bool {
+    filter_memory_map(frame.index(), frame.index() + 1, memory_map, reclaimable)
+        .next()
+        .is_some()
+}
+
+pub fn init_reclaim<'a>(memory_map: impl IntoIterator<Item = &'a MemoryRegion> + Clone) -> usize {
+    let reclaimed = LOW_REGION.lock().reclaim(memory_map.clone())
+        + NORMAL_REGION.lock().reclaim(memory_map.clone())
+        + HIGH_REGION.lock().reclaim(memory_map);
+
+    #[cfg(test)]
+    test::BOOT_FRAMES_RECLAIMED.store(reclaimed, core::sync::atomic::Ordering::SeqCst);
+
+    reclaimed
+}
+
+/// A set of regions like the kernel's, built from any memory map. The kernel's own regions are
+/// handing out frames for the whole run, so tests that want to check how a memory map turns into
+/// free frames use one of these instead.
+#[cfg(test)]
+pub struct FrameDatabase {
+    regions: [PageFrameRegion; 3],
+}
+
+#[cfg(test)]
+impl FrameDatabase {
+    pub fn new<'a>(memory_map: impl IntoIterator<Item = &'a MemoryRegion> + Clone) -> Self {
+        Self {
+            regions: [
+                PageFrameRegion::alloc(UNUSED_LOW_FRAMES, LOW_REGION_FRAMES, memory_map.clone()),
+                PageFrameRegion::alloc(LOW_REGION_FRAMES, NORMAL_REGION_FRAMES, memory_map.clone()),
+                PageFrameRegion::alloc(NORMAL_REGION_FRAMES, HIGH_REGION_FRAMES, memory_map),
+            ],
+        }
+    }
+
+    /// Throw the regions away, freeing their bitmasks, and build them again from memory_map
+    pub fn reset_for_test<'a>(
+        &mut self,
+        memory_map: impl IntoIterator<Item = &'a MemoryRegion> + Clone,
+    ) {
+        *self = Self::new(memory_map);
+    }
+
+    pub fn free_frames(&self) -> usize {
+        self.regions.iter().map(|region| region.free_frames()).sum()
+    }
+
+    pub fn allocate_frame(&mut self) -> Option<Frame> {
+        self.regions
+            .iter_mut()
+            .find_map(|region| region.allocate_frame())
+    }
 }
 
 impl<T: LockedFrameAllocator> FrameAllocator for InitMutex<T> {
@@ -339,3 +588,142 @@ impl<T: LockedFrameAllocator> FrameAllocator for InitMutex<T> {
             .unwrap_or(false)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::paging::{boot_stack_range, lock_page_table, phys_to_virt_mut};
+    use alloc::vec::Vec;
+    use bootloader::bootinfo::FrameRange;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    pub static BOOT_FRAMES_RECLAIMED: AtomicUsize = AtomicUsize::new(0);
+
+    const CANARY: u64 = 0xdead_beef_cafe_f00d;
+
+    fn usable(start: usize, end: usize) -> MemoryRegion {
+        MemoryRegion {
+            range: FrameRange::new(start as u64, end as u64),
+            region_type: MemoryRegionType::Usable,
+        }
+    }
+
+    #[test_case]
+    fn reset_databases_only_use_the_new_map() {
+        let map_a = [
+            usable(0x20_0000, 0x30_0000),
+            usable(0x1_0010_0000, 0x1_0020_0000),
+        ];
+        let map_b = [
+            usable(0x10_0000, 0x10_8000),
+            usable(0x1_0000_0000, 0x1_0000_4000),
+        ];
+
+        let mut database = FrameDatabase::new(map_a.iter());
+        assert_eq!(database.free_frames(), 0x200);
+
+        database.reset_for_test(map_b.iter());
+        assert_eq!(database.free_frames(), 12);
+
+        let mut allocated = 0;
+        while let Some(frame) = database.allocate_frame() {
+            let address = frame.physical_address() as u64;
+            assert!(
+                map_b
+                    .iter()
+                    .any(|region| address >= region.range.start_addr()
+                        && address < region.range.end_addr()),
+                "{:?} is not usable in the new map",
+                frame
+            );
+            allocated += 1;
+        }
+        assert_eq!(allocated, 12);
+    }
+
+    #[test_case]
+    fn freed_frames_are_reused_first() {
+        let memory_map = [usable(0x10_0000, 0x10_8000)];
+        let mut region = PageFrameRegion::alloc(0, LOW_REGION_FRAMES, memory_map.iter());
+
+        let frames: Vec<_> = (0..4).map(|_| region.allocate_frame().unwrap()).collect();
+        region.deallocate_frame(frames[0]);
+        region.deallocate_frame(frames[2]);
+
+        // The lowest free frame is frames[0], but frames[2] was freed more recently
+        assert_eq!(region.allocate_frame(), Some(frames[2]));
+        assert_eq!(region.allocate_frame(), Some(frames[0]));
+        assert_eq!(region.free_frames(), 4);
+    }
+
+    #[test_case]
+    fn mapping_counts_are_per_frame() {
+        let memory_map = [usable(0x10_0000, 0x10_8000)];
+        let mut region = PageFrameRegion::alloc(0, LOW_REGION_FRAMES, memory_map.iter());
+        let frames: Vec<_> = (0..8).map(|_| region.allocate_frame().unwrap()).collect();
+
+        // Far more mappings, of far more frames, than would fit in the counter bits of a PTE
+        const MAPPINGS: usize = 10_000;
+        for frame in frames.iter() {
+            for _ in 0..MAPPINGS {
+                region.add_mapping(*frame);
+            }
+        }
+        region.remove_mapping(frames[0]);
+
+        assert_eq!(region.mapping_count(frames[0]), MAPPINGS - 1);
+        assert_eq!(region.mapping_count(frames[7]), MAPPINGS);
+        for _ in 1..MAPPINGS {
+            region.remove_mapping(frames[0]);
+        }
+        assert_eq!(region.remove_mapping(frames[0]), 0);
+        assert_eq!(region.mapping_count(frames[0]), 0);
+
+        // Frames outside the region aren't counted
+        let outside = Frame::containing_address(0x20_0000);
+        region.add_mapping(outside);
+        assert_eq!(region.mapping_count(outside), 0);
+    }
+
+    #[test_case]
+    fn reclaimed_frames_can_be_reused() {
+        let frame = crate::physmem::allocate_kernel_frame().expect("Failed to allocate frame");
+        let memory_map = [MemoryRegion {
+            range: FrameRange::new(
+                frame.physical_address() as u64,
+                (frame.physical_address() + PAGE_SIZE) as u64,
+            ),
+            region_type: MemoryRegionType::Bootloader,
+        }];
+        assert!(is_reclaimable(memory_map.iter(), frame));
+
+        // A region that only covers the frame, which the bootloader is still using
+        let mut region =
+            PageFrameRegion::alloc(frame.index(), frame.index() + 1, memory_map.iter());
+        let contents = unsafe { &mut *phys_to_virt_mut::<u64>(frame.physical_address()) };
+        *contents = CANARY;
+        assert_eq!(region.free_frames(), 0);
+        assert_eq!(region.allocate_frame(), None);
+        assert_eq!(*contents, CANARY);
+
+        assert_eq!(region.reclaim(memory_map.iter()), 1);
+        assert_eq!(region.free_frames(), 1);
+        assert_eq!(region.allocate_frame(), Some(frame));
+        assert_eq!(region.allocate_frame(), None);
+
+        crate::physmem::deallocate_frame(frame);
+    }
+
+    #[test_case]
+    fn boot_memory_is_reclaimed_once_unmapped() {
+        // The boot stack is a region of the memory map of its own, so its frames are in the count
+        let boot_stack = boot_stack_range();
+        assert!(BOOT_FRAMES_RECLAIMED.load(Ordering::SeqCst) >= boot_stack.len() / PAGE_SIZE);
+
+        // Nothing can still write to those frames through the boot stack
+        let page_table = unsafe { lock_page_table() };
+        assert!(boot_stack
+            .step_by(PAGE_SIZE)
+            .all(|page| page_table.translate(page).is_none()));
+    }
+}
@@ -0,0 +1,161 @@
+// The memory map from the bootloader is trusted to describe each physical address at most once.
+// The frame database builds its bitmasks from whichever regions are of the types it wants, so if
+// a usable region overlapped a reserved one, the frames they share would be handed out anyway.
+// This checks a copy of the map before anything is built from it.
+
+use bootloader::bootinfo::{FrameRange, MemoryRegion, MemoryRegionType};
+use core::ops::Deref;
+
+/// The most regions the bootloader's memory map can hold
+pub const MAX_MEMORY_REGIONS: usize = 64;
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum MemoryMapError {
+    /// The regions starting at these two addresses both cover the same memory
+    Overlap(u64, u64),
+}
+
+/// A copy of the boot memory map. It needs no heap, so it can be made and validated before the
+/// frame database is built from it.
+pub struct MemoryMapCopy {
+    regions: [MemoryRegion; MAX_MEMORY_REGIONS],
+    len: usize,
+}
+
+impl MemoryMapCopy {
+    pub fn new(memory_map: &[MemoryRegion]) -> Self {
+        assert!(
+            memory_map.len() <= MAX_MEMORY_REGIONS,
+            "Too many memory regions"
+        );
+
+        let empty = MemoryRegion {
+            range: FrameRange::new(0, 0),
+            region_type: MemoryRegionType::Empty,
+        };
+        let mut regions = [empty; MAX_MEMORY_REGIONS];
+        regions[..memory_map.len()].copy_from_slice(memory_map);
+        Self {
+            regions,
+            len: memory_map.len(),
+        }
+    }
+
+    /// Validate the copy with validate_memory_map, leaving just the regions that are left
+    pub fn validate(&mut self) -> Result<(), MemoryMapError> {
+        self.len = validate_memory_map(&mut self.regions[..self.len])?;
+        Ok(())
+    }
+}
+
+impl Deref for MemoryMapCopy {
+    type Target = [MemoryRegion];
+
+    fn deref(&self) -> &[MemoryRegion] {
+        &self.regions[..self.len]
+    }
+}
+
+/// Sort the memory map by address and merge neighbouring regions of the same type, returning how
+/// many regions are left at the front of it. Fails if any regions overlap, in which case the map is
+/// left sorted but not merged. This runs before thread locals work, so it can't log.
+pub fn validate_memory_map(memory_map: &mut [MemoryRegion]) -> Result<usize, MemoryMapError> {
+    if !memory_map
+        .windows(2)
+        .all(|pair| pair[0].range.start_addr() <= pair[1].range.start_addr())
+    {
+        crate::serial_println!("Memory map is not sorted");
+        memory_map.sort_unstable_by_key(|region| region.range.start_addr());
+    }
+
+    if let Some(pair) = memory_map
+        .windows(2)
+        .find(|pair| pair[0].range.end_addr() > pair[1].range.start_addr())
+    {
+        return Err(MemoryMapError::Overlap(
+            pair[0].range.start_addr(),
+            pair[1].range.start_addr(),
+        ));
+    }
+
+    let mut len = 0;
+    for index in 0..memory_map.len() {
+        let region = memory_map[index];
+        let adjacent = len > 0
+            && memory_map[len - 1].range.end_addr() == region.range.start_addr()
+            && memory_map[len - 1].region_type == region.region_type;
+        if adjacent {
+            memory_map[len - 1].range.end_frame_number = region.range.end_frame_number;
+        } else {
+            memory_map[len] = region;
+            len += 1;
+        }
+    }
+
+    Ok(len)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloc::vec;
+
+    fn region(start: u64, end: u64, region_type: MemoryRegionType) -> MemoryRegion {
+        MemoryRegion {
+            range: FrameRange::new(start, end),
+            region_type,
+        }
+    }
+
+    #[test_case]
+    fn overlapping_regions_are_reported() {
+        let mut memory_map = vec![
+            region(0x10_0000, 0x20_0000, MemoryRegionType::Usable),
+            region(0x1f_0000, 0x30_0000, MemoryRegionType::Reserved),
+        ];
+
+        assert_eq!(
+            validate_memory_map(&mut memory_map),
+            Err(MemoryMapError::Overlap(0x10_0000, 0x1f_0000))
+        );
+    }
+
+    #[test_case]
+    fn copies_are_validated_in_place() {
+        let memory_map = [
+            region(0x20_0000, 0x30_0000, MemoryRegionType::Usable),
+            region(0x10_0000, 0x20_0000, MemoryRegionType::Usable),
+            region(0x30_0000, 0x40_0000, MemoryRegionType::Reserved),
+        ];
+
+        let mut copy = MemoryMapCopy::new(&memory_map);
+        assert_eq!(copy.validate(), Ok(()));
+        assert_eq!(
+            *copy,
+            [
+                region(0x10_0000, 0x30_0000, MemoryRegionType::Usable),
+                region(0x30_0000, 0x40_0000, MemoryRegionType::Reserved),
+            ]
+        );
+    }
+
+    #[test_case]
+    fn neighbouring_regions_of_a_type_are_merged() {
+        let mut memory_map = vec![
+            region(0x20_0000, 0x30_0000, MemoryRegionType::Usable),
+            region(0x10_0000, 0x20_0000, MemoryRegionType::Usable),
+            region(0x30_0000, 0x40_0000, MemoryRegionType::Reserved),
+            region(0x40_0000, 0x50_0000, MemoryRegionType::Usable),
+        ];
+
+        assert_eq!(validate_memory_map(&mut memory_map), Ok(3));
+        assert_eq!(
+            memory_map[..3],
+            vec![
+                region(0x10_0000, 0x30_0000, MemoryRegionType::Usable),
+                region(0x30_0000, 0x40_0000, MemoryRegionType::Reserved),
+                region(0x40_0000, 0x50_0000, MemoryRegionType::Usable),
+            ]
+        );
+    }
+}
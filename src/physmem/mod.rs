@@ -1,7 +1,11 @@
 use bootloader::bootinfo::MemoryRegion;
 use core::fmt;
+use spin::Mutex;
 
 mod frame_database;
+mod memory_map;
+
+pub use memory_map::{validate_memory_map, MemoryMapCopy, MemoryMapError};
 
 pub const PAGE_SIZE: usize = 4096;
 
@@ -19,10 +23,44 @@ pub fn early_init<'a>(memory_map: impl IntoIterator<Item = &'a MemoryRegion>) {
 
 pub fn init_post_paging<'a>(memory_map: impl IntoIterator<Item = &'a MemoryRegion> + Clone) {
     frame_database::init_post_paging(memory_map);
+
+    let mut reserve = RESERVED_FRAMES.lock();
+    for slot in reserve.iter_mut() {
+        *slot = Some(allocate_kernel_frame().expect("Failed to allocate reserved frames"));
+    }
+}
+
+/// Free the memory that the bootloader used for its page tables, its stack and the boot info, and
+/// return how many frames that was. By now the kernel's own page table has to be active, the BSP
+/// has to have left the boot stack and unmapped it, and anything needed from the boot info has to
+/// have been copied out of it.
+pub fn init_reclaim<'a>(memory_map: impl IntoIterator<Item = &'a MemoryRegion> + Clone) -> usize {
+    let stack_pointer = x86::bits64::registers::rsp() as usize;
+    let boot_stack = crate::paging::boot_stack_range();
+    assert!(
+        !boot_stack.contains(&stack_pointer),
+        "Reclaiming boot memory while running on the boot stack"
+    );
+    assert!(
+        unsafe { crate::paging::lock_page_table() }
+            .translate(boot_stack.start)
+            .is_none(),
+        "Reclaiming boot memory while the boot stack is still mapped"
+    );
+
+    let page_table = Frame::containing_address(unsafe { x86::controlregs::cr3() } as usize);
+    assert!(
+        !frame_database::is_reclaimable(memory_map.clone(), page_table),
+        "Reclaiming boot memory while the boot page table is active"
+    );
+
+    frame_database::init_reclaim(memory_map)
 }
 
-pub fn init_reclaim<'a>(memory_map: impl IntoIterator<Item = &'a MemoryRegion> + Clone) {
-    frame_database::init_reclaim(memory_map);
+/// Give the allocator a frame that it has never owned, such as one from the kernel image. Returns
+/// false if the frame is outside the memory that the allocator tracks.
+pub fn reclaim_frame(frame: Frame) -> bool {
+    frame_database::reclaim_frame(frame)
 }
 
 #[derive(Clone, Copy, PartialEq, Eq)]
@@ -64,7 +102,82 @@ pub fn used_frames() -> usize {
         + frame_database::HIGH_REGION.used_frames()
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RegionStats {
+    pub name: &'static str,
+    pub free_frames: usize,
+    pub used_frames: usize,
+}
+
+/// How full each of the frame allocator's regions is, lowest first
+pub fn region_stats() -> [RegionStats; 3] {
+    let stats = |name, region: &dyn FrameAllocator| RegionStats {
+        name,
+        free_frames: region.free_frames(),
+        used_frames: region.used_frames(),
+    };
+
+    [
+        stats("low", &frame_database::LOW_REGION),
+        stats("normal", &frame_database::NORMAL_REGION),
+        stats("high", &frame_database::HIGH_REGION),
+    ]
+}
+
+// Lets tests make frame allocations fail on demand, to get at the error handling paths without
+// having to really run out of memory
+#[cfg(test)]
+static INJECTED_FAILURE_SKIP: core::sync::atomic::AtomicUsize =
+    core::sync::atomic::AtomicUsize::new(0);
+#[cfg(test)]
+static INJECTED_FAILURE_COUNT: core::sync::atomic::AtomicUsize =
+    core::sync::atomic::AtomicUsize::new(0);
+
+/// Make the next n frame allocations fail
+#[cfg(test)]
+pub fn fail_next_n_allocations(n: usize) {
+    fail_allocations_after(0, n);
+}
+
+/// Let skip frame allocations succeed, then make the n after that fail
+#[cfg(test)]
+pub fn fail_allocations_after(skip: usize, n: usize) {
+    use core::sync::atomic::Ordering;
+    INJECTED_FAILURE_COUNT.store(0, Ordering::SeqCst);
+    INJECTED_FAILURE_SKIP.store(skip, Ordering::SeqCst);
+    INJECTED_FAILURE_COUNT.store(n, Ordering::SeqCst);
+}
+
+#[cfg(test)]
+fn inject_failure() -> bool {
+    use core::sync::atomic::Ordering;
+
+    let decrement = |count: usize| count.checked_sub(1);
+    if INJECTED_FAILURE_COUNT.load(Ordering::SeqCst) == 0 {
+        false
+    } else if INJECTED_FAILURE_SKIP
+        .fetch_update(Ordering::SeqCst, Ordering::SeqCst, decrement)
+        .is_ok()
+    {
+        false
+    } else {
+        INJECTED_FAILURE_COUNT
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, decrement)
+            .is_ok()
+    }
+}
+
+#[cfg(not(test))]
+#[inline(always)]
+fn inject_failure() -> bool {
+    false
+}
+
 pub fn allocate_kernel_frame() -> Option<Frame> {
+    if inject_failure() {
+        return None;
+    }
+
     // For kernel allocations we do not try the high region because it isn't mapped and delivers frames
     // that are useless to the kernel
     frame_database::NORMAL_REGION
@@ -73,13 +186,68 @@ pub fn allocate_kernel_frame() -> Option<Frame> {
 }
 
 pub fn allocate_user_frame() -> Option<Frame> {
+    if inject_failure() {
+        return None;
+    }
+
     frame_database::HIGH_REGION
         .allocate_frame()
         .or_else(|| frame_database::NORMAL_REGION.allocate_frame())
         .or_else(|| frame_database::LOW_REGION.allocate_frame())
 }
 
+// A few kernel frames are held back for the memory manager's own bookkeeping, so that it can still
+// finish what it is doing once everything else has run out. Without them, running out of memory
+// could leave the region manager unable to free the memory that would get us out of trouble.
+const RESERVED_FRAME_COUNT: usize = 8;
+
+static RESERVED_FRAMES: Mutex<[Option<Frame>; RESERVED_FRAME_COUNT]> =
+    Mutex::new([None; RESERVED_FRAME_COUNT]);
+
+/// Allocate a frame from the emergency reserve. This is only for the memory manager's internal
+/// structures, and only once allocate_kernel_frame has failed.
+pub fn allocate_reserved_frame() -> Option<Frame> {
+    RESERVED_FRAMES
+        .lock()
+        .iter_mut()
+        .find(|slot| slot.is_some())
+        .and_then(|slot| slot.take())
+}
+
+pub fn reserved_frames() -> usize {
+    RESERVED_FRAMES
+        .lock()
+        .iter()
+        .filter(|slot| slot.is_some())
+        .count()
+}
+
+// Top the reserve back up from a frame that is being freed. Returns the frame if it isn't needed.
+fn refill_reserve(frame: Frame) -> Option<Frame> {
+    // Reserved frames have to be usable as kernel frames, which high frames aren't
+    if frame_database::HIGH_REGION.contains_frame(frame) {
+        return Some(frame);
+    }
+
+    match RESERVED_FRAMES
+        .lock()
+        .iter_mut()
+        .find(|slot| slot.is_none())
+    {
+        Some(slot) => {
+            *slot = Some(frame);
+            None
+        }
+        None => Some(frame),
+    }
+}
+
 pub fn deallocate_frame(frame: Frame) {
+    let frame = match refill_reserve(frame) {
+        Some(frame) => frame,
+        None => return,
+    };
+
     if frame_database::LOW_REGION.contains_frame(frame) {
         frame_database::LOW_REGION.deallocate_frame(frame)
     } else if frame_database::NORMAL_REGION.contains_frame(frame) {
@@ -89,6 +257,22 @@ pub fn deallocate_frame(frame: Frame) {
     }
 }
 
+/// How many page table entries map the frame. Frames that the allocator doesn't track, like MMIO,
+/// always have a count of zero.
+pub fn mapping_count(frame: Frame) -> usize {
+    frame_database::mapping_count(frame)
+}
+
+/// Record that a page table entry now maps the frame, and return how many do
+pub fn add_mapping(frame: Frame) -> usize {
+    frame_database::add_mapping(frame)
+}
+
+/// Record that a page table entry no longer maps the frame, and return how many still do
+pub fn remove_mapping(frame: Frame) -> usize {
+    frame_database::remove_mapping(frame)
+}
+
 pub trait LockedFrameAllocator {
     fn free_frames(&self) -> usize;
     fn used_frames(&self) -> usize;
@@ -108,3 +292,60 @@ pub trait FrameAllocator {
 
     fn contains_frame(&self, frame: Frame) -> bool;
 }
+
+/// Holds every frame that allocate_kernel_frame could return, for tests that need the kernel to be
+/// out of memory. The frames are chained together through their first word, so holding them doesn't
+/// need any memory, and they are all released on drop.
+#[cfg(test)]
+pub struct ExhaustedKernelFrames {
+    head: Option<Frame>,
+    count: usize,
+}
+
+#[cfg(test)]
+impl ExhaustedKernelFrames {
+    pub fn new() -> Self {
+        let mut ret = Self {
+            head: None,
+            count: 0,
+        };
+
+        // Kernel frames are always in the identity map
+        while let Some(frame) = allocate_kernel_frame() {
+            let next = ret.head.map_or(usize::MAX, |head| head.physical_address());
+            unsafe {
+                *crate::paging::phys_to_virt_mut::<usize>(frame.physical_address()) = next;
+            }
+            ret.head = Some(frame);
+            ret.count += 1;
+        }
+
+        ret
+    }
+
+    pub fn count(&self) -> usize {
+        self.count
+    }
+
+    /// Give a single frame back, so that exactly one allocation can succeed
+    pub fn release_one(&mut self) -> Option<Frame> {
+        let frame = self.head?;
+        let next = unsafe { *crate::paging::phys_to_virt::<usize>(frame.physical_address()) };
+        self.head = if next == usize::MAX {
+            None
+        } else {
+            Some(Frame::containing_address(next))
+        };
+        self.count -= 1;
+
+        deallocate_frame(frame);
+        Some(frame)
+    }
+}
+
+#[cfg(test)]
+impl Drop for ExhaustedKernelFrames {
+    fn drop(&mut self) {
+        while self.release_one().is_some() {}
+    }
+}
@@ -1,3 +1,4 @@
+use crate::fpu::{self, FpuState};
 use alloc::boxed::Box;
 
 #[derive(Debug)]
@@ -12,6 +13,11 @@ pub struct ArchContext {
     r15: usize,
     rsp: usize,
     rbp: usize,
+    // The kernel always runs with this CPU's thread local block in fs, and a task's own fs base
+    // only matters in ring 3, where the way into the kernel saves it. Only gs needs switching.
+    gs_base: usize,
+    // The assembly only knows about the fields above, so anything else has to go after them
+    fpu: Option<Box<FpuState>>,
 }
 
 impl ArchContext {
@@ -26,9 +32,20 @@ impl ArchContext {
             r15: 0,
             rsp: 0,
             rbp: 0,
+            gs_base: 0,
+            fpu: None,
         }
     }
 
+    /// Give the task somewhere to keep its FPU state. A task without one can't use the FPU.
+    pub fn allocate_fpu_state(&mut self) {
+        self.fpu = Some(box FpuState::new());
+    }
+
+    pub fn fpu_state(&self) -> Option<&FpuState> {
+        self.fpu.as_deref()
+    }
+
     pub fn set_page_table(&mut self, cr3: usize) {
         self.cr3 = cr3;
     }
@@ -37,6 +54,14 @@ impl ArchContext {
         self.cr3
     }
 
+    pub fn set_gs_base(&mut self, gs_base: usize) {
+        self.gs_base = gs_base;
+    }
+
+    pub fn gs_base(&self) -> usize {
+        self.gs_base
+    }
+
     pub fn set_stack(&mut self, rsp: usize) {
         self.rsp = rsp;
     }
@@ -65,6 +90,10 @@ impl ArchContext {
     }
 
     pub unsafe fn switch_to(&mut self, next: &mut ArchContext) {
+        #[cfg(debug_assertions)]
+        crate::paging::verify_cr3_kernel_shared(next.cr3);
+
+        fpu::switch_out(self.fpu.as_deref_mut());
         do_switch(self, next);
     }
 }
@@ -108,6 +137,15 @@ crate::function!(do_switch(current: &mut ArchContext, next: &mut ArchContext) =>
         mov [rdi+8*8], rbp
         mov rbp, [rsi+8*8]
 
+        // The kernel doesn't use gs, so the gs base can be switched straight away
+        mov ecx, 0xc0000101
+        rdmsr
+        mov [rdi+9*8], eax
+        mov [rdi+9*8+4], edx
+        mov eax, [rsi+9*8]
+        mov edx, [rsi+9*8+4]
+        wrmsr
+
         // At this point the context switch is complete, but we need to tell the scheduler to complete its job
         call complete_task_switch
 
@@ -126,7 +164,9 @@ trait TrampolineLaunch {
 
 impl<F: FnOnce() -> !> TrampolineLaunch for LaunchTrampolineStruct<F> {
     fn do_call(self: Box<Self>) -> ! {
-        (self.func)()
+        // The task may exit, and never come back to free the box, so it has to go first
+        let Self { func } = *self;
+        func()
     }
 }
 
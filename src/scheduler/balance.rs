@@ -0,0 +1,99 @@
+// Spreading ready tasks over the CPUs. All CPUs share the ready lists, so a task without affinity
+// runs on whichever CPU reschedules first. The catch is that a CPU in its idle loop only looks at
+// the ready lists when it is woken, so without this, tasks spawned on a busy CPU would queue up
+// behind each other there while the other CPUs sat idle. Every so often, if tasks are waiting, an
+// idle CPU is woken for each of them to come and take it.
+
+use super::idle::{idle_state, kick_cpu, MAX_CPUS};
+use super::TASK_DIRECTORY;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+const BALANCE_INTERVAL_NS: u64 = 2_000_000;
+
+static LAST_BALANCE_NS: AtomicU64 = AtomicU64::new(0);
+
+/// Wake an idle CPU for each ready task that can run anywhere. Returns how many were woken.
+pub fn balance() -> usize {
+    let waiting = TASK_DIRECTORY.migratable_ready_count();
+    let this_cpu = crate::cpu_id();
+
+    let mut woken = 0;
+    for cpu_id in (0..MAX_CPUS).filter(|cpu_id| *cpu_id != this_cpu) {
+        if woken == waiting {
+            break;
+        }
+
+        let state = idle_state(cpu_id);
+        if state.is_idling() && !state.needs_resched().load(Ordering::SeqCst) {
+            kick_cpu(cpu_id);
+            woken += 1;
+        }
+    }
+
+    woken
+}
+
+/// Balance if nothing has for a while. The timer tick calls this, but tasks run with interrupts
+/// disabled, so a busy CPU doesn't see its ticks and reschedule calls this too.
+pub fn balance_if_due() {
+    let now_ns = match crate::devices::now_ns() {
+        Some(now_ns) => now_ns,
+        None => return,
+    };
+
+    let last_ns = LAST_BALANCE_NS.load(Ordering::Relaxed);
+    if now_ns.wrapping_sub(last_ns) >= BALANCE_INTERVAL_NS
+        && LAST_BALANCE_NS
+            .compare_exchange(last_ns, now_ns, Ordering::SeqCst, Ordering::Relaxed)
+            .is_ok()
+    {
+        balance();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::scheduler::{exit, reschedule, sleep, spawn, TaskReference};
+    use alloc::sync::Arc;
+    use alloc::vec::Vec;
+    use core::sync::atomic::AtomicBool;
+    use core::time::Duration;
+
+    const BALANCE_TASKS: usize = 4;
+
+    // Exits with a bit for each CPU that it ran on
+    fn busy_task(stop: Arc<AtomicBool>) -> ! {
+        let mut cpus = 0;
+        while !stop.load(Ordering::SeqCst) {
+            cpus |= 1 << crate::cpu_id();
+            crate::devices::clock::spin_wait_ns(500_000);
+            reschedule();
+        }
+
+        core::mem::drop(stop);
+        exit(cpus)
+    }
+
+    #[test_case]
+    fn ready_tasks_spread_to_idle_cpus() {
+        let this_cpu = crate::cpu_id();
+        if !(0..MAX_CPUS).any(|cpu_id| cpu_id != this_cpu && idle_state(cpu_id).is_idling()) {
+            return;
+        }
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let tasks: Vec<TaskReference> = (0..BALANCE_TASKS)
+            .map(|_| {
+                let stop = stop.clone();
+                unsafe { spawn(None, move || busy_task(stop)) }.expect("Failed to spawn task")
+            })
+            .collect();
+
+        sleep(Duration::from_millis(50));
+        stop.store(true, Ordering::SeqCst);
+        let cpus = tasks.iter().fold(0, |cpus, task| cpus | task.join());
+
+        assert!(cpus.count_ones() > 1, "Tasks only ran on CPUs {:#x}", cpus);
+    }
+}
@@ -0,0 +1,131 @@
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use x86::cpuid::CpuId;
+
+// CPU ids are local APIC ids, which are below this on anything we run on
+pub const MAX_CPUS: usize = 64;
+
+// MONITOR watches a whole cache line, so each CPU's state gets a line of its own and writes to one
+// CPU's flag don't wake the others
+#[repr(align(64))]
+pub struct IdleState {
+    needs_resched: AtomicBool,
+    idling: AtomicBool,
+    uses_mwait: AtomicBool,
+    wakeups: AtomicUsize,
+}
+
+impl IdleState {
+    const fn new() -> Self {
+        Self {
+            needs_resched: AtomicBool::new(false),
+            idling: AtomicBool::new(false),
+            uses_mwait: AtomicBool::new(false),
+            wakeups: AtomicUsize::new(0),
+        }
+    }
+
+    /// Set when another CPU wants this one to look for a task to run
+    pub fn needs_resched(&self) -> &AtomicBool {
+        &self.needs_resched
+    }
+
+    /// Whether this CPU is in its idle loop
+    pub fn is_idling(&self) -> bool {
+        self.idling.load(Ordering::SeqCst)
+    }
+
+    pub fn uses_mwait(&self) -> bool {
+        self.uses_mwait.load(Ordering::SeqCst)
+    }
+
+    /// How many times this CPU has woken from its idle wait
+    pub fn wakeups(&self) -> usize {
+        self.wakeups.load(Ordering::SeqCst)
+    }
+
+    pub fn enter_idle(&self, uses_mwait: bool) {
+        self.uses_mwait.store(uses_mwait, Ordering::SeqCst);
+        self.idling.store(true, Ordering::SeqCst);
+    }
+
+    pub fn leave_idle(&self) {
+        self.idling.store(false, Ordering::SeqCst);
+    }
+
+    pub fn count_wakeup(&self) {
+        self.wakeups.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+const IDLE_STATE_INIT: IdleState = IdleState::new();
+static IDLE_STATES: [IdleState; MAX_CPUS] = [IDLE_STATE_INIT; MAX_CPUS];
+
+pub fn idle_state(cpu_id: usize) -> &'static IdleState {
+    &IDLE_STATES[cpu_id]
+}
+
+pub fn mwait_supported() -> bool {
+    CpuId::new()
+        .get_feature_info()
+        .map_or(false, |info| info.has_monitor_mwait())
+}
+
+/// Ask a CPU to look for something to run. A CPU waiting in MWAIT wakes as soon as its flag is
+/// written, but one waiting in HLT only notices at its next interrupt.
+pub fn wake_cpu(cpu_id: usize) {
+    idle_state(cpu_id)
+        .needs_resched
+        .store(true, Ordering::SeqCst);
+}
+
+/// Wake a CPU, and make sure that it notices straight away by sending it an IPI if it waits in HLT
+pub fn kick_cpu(cpu_id: usize) {
+    wake_cpu(cpu_id);
+    if cpu_id != crate::cpu_id() && !idle_state(cpu_id).uses_mwait() {
+        crate::ipi::ipi_cpu(crate::ipi::IpiKind::Reschedule, cpu_id);
+    }
+}
+
+/// Get an idle CPU to pick up a task that has just become ready, which has to be its own CPU if it
+/// has one
+pub fn wake_idle_cpu(cpu_id: Option<usize>) {
+    let cpu_id = cpu_id.or_else(|| (0..MAX_CPUS).find(|cpu| idle_state(*cpu).is_idling()));
+    if let Some(cpu_id) = cpu_id {
+        kick_cpu(cpu_id);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::interrupts::pause;
+
+    #[test_case]
+    fn idle_cpu_wakes_when_its_flag_is_set() {
+        let this_cpu = crate::cpu_id();
+        let other_cpu =
+            match (0..MAX_CPUS).find(|cpu| *cpu != this_cpu && idle_state(*cpu).is_idling()) {
+                Some(other_cpu) => other_cpu,
+                // Nothing else is idle, so there is nothing to wake
+                None => return,
+            };
+
+        let other_state = idle_state(other_cpu);
+        assert_eq!(other_state.uses_mwait(), mwait_supported());
+
+        // Only MWAIT wakes on the write, a CPU in HLT waits for its next interrupt
+        if !other_state.uses_mwait() {
+            return;
+        }
+
+        wake_cpu(other_cpu);
+
+        // The other CPU clears the flag once it has woken up and seen it
+        let mut spins = 0;
+        while other_state.needs_resched().load(Ordering::SeqCst) {
+            assert!(spins < 100_000_000, "CPU {} did not wake up", other_cpu);
+            spins += 1;
+            pause();
+        }
+    }
+}
@@ -1,17 +1,31 @@
 mod arch_context;
+mod balance;
+mod idle;
+mod preempt;
 mod reschedule;
 mod task;
 
+use crate::devices::now_ns;
+use crate::devices::timer::{add_sleep_timer, cancel_sleep_timer, deadline_passed};
+use crate::loader::{self, LoaderError, TlsTemplate};
 use crate::paging;
+use core::time::Duration;
 
 pub(self) use arch_context::ArchContext;
-pub use reschedule::{current_task, reschedule};
-pub use task::{Pid, TaskControl, TaskDirectory, TaskReference, TASK_DIRECTORY};
+pub use balance::{balance, balance_if_due};
+pub use idle::{idle_state, mwait_supported, wake_cpu, wake_idle_cpu, IdleState, MAX_CPUS};
+pub use preempt::{preempt_user_task, preemption_point, slice_expired, TIME_SLICE_NS};
+pub use reschedule::{block_current, current_pid, current_task, reschedule, restore_fpu_state};
+pub use task::{
+    Pid, Task, TaskControl, TaskDirectory, TaskPriority, TaskReference, TaskState, TASK_DIRECTORY,
+};
 
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
 pub enum SchedulerError {
     MemoryError(paging::MemoryError),
     OutOfPids,
+    InvalidCpu,
+    LoaderError(LoaderError),
 }
 
 impl From<paging::MemoryError> for SchedulerError {
@@ -20,6 +34,12 @@ impl From<paging::MemoryError> for SchedulerError {
     }
 }
 
+impl From<LoaderError> for SchedulerError {
+    fn from(loader_error: LoaderError) -> Self {
+        Self::LoaderError(loader_error)
+    }
+}
+
 pub type Result<T> = core::result::Result<T, SchedulerError>;
 
 pub unsafe fn init(
@@ -32,15 +52,77 @@ pub unsafe fn init(
     Ok(idle_task)
 }
 
-pub unsafe fn spawn(func: impl FnOnce() -> !) -> Result<TaskReference> {
-    let ret = task::Task::spawn()?;
+/// Spawn a task. It runs in the kernel page table unless it is given an address space of its own,
+/// in which case the context switch loads that instead.
+pub unsafe fn spawn(
+    address_space: Option<paging::AddressSpace>,
+    func: impl FnOnce() -> !,
+) -> Result<TaskReference> {
+    spawn_task(address_space, None, TaskPriority::Normal, 0, func)
+}
+
+/// Spawn a task that goes on to enter ring 3 in an address space of its own. If the program has
+/// thread locals, its block is mapped into the address space here, and the task's fs base points
+/// at it from the start.
+pub unsafe fn spawn_user(
+    mut address_space: paging::AddressSpace,
+    tls: Option<&TlsTemplate>,
+    func: impl FnOnce() -> !,
+) -> Result<TaskReference> {
+    let user_fs_base = match tls {
+        Some(template) => loader::setup_user_tls(&mut address_space, template)?,
+        None => 0,
+    };
+
+    spawn_task(
+        Some(address_space),
+        None,
+        TaskPriority::Normal,
+        user_fs_base,
+        func,
+    )
+}
+
+/// Spawn a kernel task that only ever runs on one CPU
+pub unsafe fn spawn_on(cpu_id: usize, func: impl FnOnce() -> !) -> Result<TaskReference> {
+    spawn_with_priority(TaskPriority::Normal, Some(cpu_id), func)
+}
+
+/// Spawn a kernel task at priority, on one CPU if cpu_id is given. Only the idle tasks run at idle
+/// priority.
+pub unsafe fn spawn_with_priority(
+    priority: TaskPriority,
+    cpu_id: Option<usize>,
+    func: impl FnOnce() -> !,
+) -> Result<TaskReference> {
+    assert_ne!(priority, TaskPriority::Idle, "Only idle tasks can be idle");
+    if cpu_id.map_or(false, |cpu_id| !crate::init::cpu_online(cpu_id)) {
+        return Err(SchedulerError::InvalidCpu);
+    }
+
+    spawn_task(None, cpu_id, priority, 0, func)
+}
+
+unsafe fn spawn_task(
+    address_space: Option<paging::AddressSpace>,
+    cpu_id: Option<usize>,
+    priority: TaskPriority,
+    user_fs_base: usize,
+    func: impl FnOnce() -> !,
+) -> Result<TaskReference> {
+    // Spawning is as good a time as any to free the tasks that have exited
+    TASK_DIRECTORY.reap_exited();
+
+    let cr3 = address_space
+        .as_ref()
+        .map_or_else(paging::kernel_cr3, |address_space| address_space.cr3());
+    let ret = task::Task::spawn(address_space, cpu_id, priority, user_fs_base)?;
 
     let arch_context = {
         let mut arch_context = ArchContext::new();
         arch_context.set_stack(ret.stack_top());
-
-        // TODOTODOTODO
-        arch_context.set_page_table(x86::controlregs::cr3() as usize);
+        arch_context.set_page_table(cr3);
+        arch_context.allocate_fpu_state();
         arch_context.push_system_task_startup(func);
 
         arch_context
@@ -49,3 +131,340 @@ pub unsafe fn spawn(func: impl FnOnce() -> !) -> Result<TaskReference> {
     ret.clone().make_runnable(arch_context);
     Ok(ret)
 }
+
+/// Stop the current task for good. Anything joining it gets exit_code, and the task is freed once
+/// it has been switched away from and nothing else refers to it.
+pub fn exit(exit_code: isize) -> ! {
+    unsafe { crate::interrupts::disable() };
+
+    {
+        let task = current_task();
+        task.set_exited(exit_code);
+        task.remove_from_directory();
+    }
+
+    unsafe { reschedule::exit_current() }
+}
+
+/// How many tasks there are, including the idle tasks
+pub fn task_count() -> usize {
+    TASK_DIRECTORY.task_count()
+}
+
+/// Block the current task for at least duration. With no clock to time it, this just gives other
+/// tasks a chance to run.
+pub fn sleep(duration: Duration) {
+    let deadline_ns = match now_ns() {
+        Some(now_ns) => now_ns.wrapping_add(duration.as_nanos() as u64),
+        None => return reschedule(),
+    };
+
+    // Anything else can wake the task too, so keep going until the deadline has really passed. The
+    // timer is cancelled each time, so that it can't wake the task later on, when it is blocked on
+    // something else.
+    while !deadline_passed(now_ns().unwrap(), deadline_ns) {
+        let task = current_task();
+        let sleeper = task.clone();
+        block_current(move || add_sleep_timer(sleeper, deadline_ns));
+        cancel_sleep_timer(&task);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::paging::{lock_page_table, new_address_space, PresentPageFlags};
+    use crate::physmem;
+    use crate::sync::Mutex;
+    use alloc::sync::Arc;
+    use alloc::vec::Vec;
+    use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+    // Both tasks map their own page here
+    const PRIVATE_PAGE: usize = 0x0000_6500_0000_0000;
+
+    fn is_mapped(page: usize) -> bool {
+        unsafe { lock_page_table() }
+            .get_pte_for_address(page)
+            .map_or(false, |pte| pte.is_present())
+    }
+
+    // Exits with what it sees in its private page once both tasks have written to theirs
+    fn private_page_task(marker: u64, pages_written: Arc<AtomicUsize>) -> ! {
+        assert!(!is_mapped(PRIVATE_PAGE), "Private page is already mapped");
+
+        let frame = physmem::allocate_user_frame().expect("Failed to allocate private page");
+        {
+            let mut page_table = unsafe { lock_page_table() };
+            page_table
+                .map_to(
+                    PRIVATE_PAGE,
+                    frame,
+                    PresentPageFlags::WRITABLE | PresentPageFlags::NO_EXECUTE,
+                )
+                .expect("Failed to map private page")
+                .flush(&page_table);
+        }
+
+        unsafe { core::ptr::write_volatile(PRIVATE_PAGE as *mut u64, marker) };
+        pages_written.fetch_add(1, Ordering::SeqCst);
+
+        // Only look at the page again once the other task has written to its own
+        while pages_written.load(Ordering::SeqCst) < 2 {
+            reschedule();
+        }
+        core::mem::drop(pages_written);
+
+        let contents = unsafe { core::ptr::read_volatile(PRIVATE_PAGE as *const u64) };
+        exit(contents as isize)
+    }
+
+    const BASE_SWITCHES: usize = 4;
+
+    // Exits with how many times it came back from a switch without its gs base, or with anything
+    // but this CPU's thread local block in fs
+    fn segment_base_task(gs_base: u64) -> ! {
+        use x86::msr::{rdmsr, wrmsr, IA32_FS_BASE, IA32_GS_BASE, IA32_KERNEL_GSBASE};
+
+        unsafe { wrmsr(IA32_GS_BASE, gs_base) };
+
+        let mut mismatches = 0;
+        for _ in 0..BASE_SWITCHES {
+            reschedule();
+
+            let (fs_base, current_gs_base, kernel_fs_base) = unsafe {
+                (
+                    rdmsr(IA32_FS_BASE),
+                    rdmsr(IA32_GS_BASE),
+                    rdmsr(IA32_KERNEL_GSBASE),
+                )
+            };
+            if fs_base != kernel_fs_base || current_gs_base != gs_base {
+                mismatches += 1;
+            }
+        }
+
+        exit(mismatches)
+    }
+
+    #[test_case]
+    fn tasks_keep_their_own_gs_base() {
+        const GS_BASES: [u64; 2] = [0x3000_0000, 0x4000_0000];
+
+        let tasks: Vec<TaskReference> = GS_BASES
+            .iter()
+            .cloned()
+            .map(|gs_base| {
+                unsafe { spawn(None, move || segment_base_task(gs_base)) }
+                    .expect("Failed to spawn task")
+            })
+            .collect();
+
+        for task in tasks.iter() {
+            assert_eq!(task.join(), 0);
+        }
+    }
+
+    #[test_case]
+    fn tasks_in_separate_address_spaces_are_isolated() {
+        const MARKERS: [u64; 2] = [0x1111_1111_1111_1111, 0x2222_2222_2222_2222];
+
+        let pages_written = Arc::new(AtomicUsize::new(0));
+        let tasks: Vec<TaskReference> = MARKERS
+            .iter()
+            .cloned()
+            .map(|marker| {
+                let address_space = new_address_space().expect("Failed to create address space");
+                let pages_written = pages_written.clone();
+                unsafe {
+                    spawn(Some(address_space), move || {
+                        private_page_task(marker, pages_written)
+                    })
+                }
+                .expect("Failed to spawn task")
+            })
+            .collect();
+
+        let contents: Vec<u64> = tasks.iter().map(|task| task.join() as u64).collect();
+        assert_eq!(contents, MARKERS);
+        assert!(
+            !is_mapped(PRIVATE_PAGE),
+            "Private page is mapped in the kernel"
+        );
+    }
+
+    #[test_case]
+    fn sleeping_task_wakes_after_its_deadline() {
+        const SLEEP_NS: u64 = 5_000_000;
+
+        let start = match now_ns() {
+            Some(start) => start,
+            None => return,
+        };
+        sleep(Duration::from_nanos(SLEEP_NS));
+        let slept = now_ns().unwrap() - start;
+
+        assert!(slept >= SLEEP_NS, "Slept for {}ns", slept);
+        assert_eq!(current_task().state(), TaskState::Running);
+    }
+
+    // Keeps running until the test tells it to stop
+    fn accounting_task(busy: bool, stop: Arc<AtomicBool>) -> ! {
+        while !stop.load(Ordering::SeqCst) {
+            if busy {
+                crate::devices::clock::spin_wait_ns(1_000_000);
+                reschedule();
+            } else {
+                sleep(Duration::from_millis(1));
+            }
+        }
+
+        core::mem::drop(stop);
+        exit(0)
+    }
+
+    #[test_case]
+    fn busy_tasks_accrue_more_cpu_time() {
+        if now_ns().is_none() {
+            return;
+        }
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let busy_stop = stop.clone();
+        let busy = unsafe { spawn(None, move || accounting_task(true, busy_stop)) }
+            .expect("Failed to spawn task");
+        let lazy_stop = stop.clone();
+        let lazy = unsafe { spawn(None, move || accounting_task(false, lazy_stop)) }
+            .expect("Failed to spawn task");
+
+        sleep(Duration::from_millis(50));
+        stop.store(true, Ordering::SeqCst);
+        busy.join();
+        lazy.join();
+
+        assert!(busy.switch_count() > 0 && lazy.switch_count() > 0);
+        assert!(
+            busy.cpu_time_ns() > 4 * lazy.cpu_time_ns(),
+            "Busy task ran for {}ns, lazy task for {}ns",
+            busy.cpu_time_ns(),
+            lazy.cpu_time_ns()
+        );
+    }
+
+    const PINNED_CPU: usize = 1;
+    const PINNED_RUNS: usize = 8;
+
+    #[test_case]
+    fn pinned_task_only_runs_on_its_cpu() {
+        if !crate::init::cpu_online(PINNED_CPU) {
+            return;
+        }
+
+        // The task exits with a bit for each CPU that it ran on
+        let task = unsafe {
+            spawn_on(PINNED_CPU, || {
+                let mut cpus = 0;
+                for _ in 0..PINNED_RUNS {
+                    cpus |= 1 << crate::cpu_id();
+                    reschedule();
+                }
+                exit(cpus)
+            })
+        }
+        .expect("Failed to spawn pinned task");
+
+        assert_eq!(task.join(), 1 << PINNED_CPU);
+    }
+
+    #[test_case]
+    fn every_task_is_listed() {
+        const TASKS: usize = 3;
+
+        // The tasks block on the gate until the listing has been taken
+        let gate = Arc::new(Mutex::new(()));
+        let gate_guard = gate.lock();
+        let started = Arc::new(AtomicUsize::new(0));
+
+        let count_before = task_count();
+        let tasks: Vec<TaskReference> = (0..TASKS)
+            .map(|_| {
+                let gate = gate.clone();
+                let started = started.clone();
+                unsafe {
+                    spawn(None, move || {
+                        started.fetch_add(1, Ordering::SeqCst);
+                        core::mem::drop(started);
+                        core::mem::drop(gate.lock());
+                        core::mem::drop(gate);
+                        exit(0)
+                    })
+                }
+                .expect("Failed to spawn task")
+            })
+            .collect();
+        while started.load(Ordering::SeqCst) < TASKS {
+            sleep(Duration::from_millis(1));
+        }
+
+        let mut listed = Vec::new();
+        let mut idle_tasks = 0;
+        TASK_DIRECTORY.for_each_task(|pid, state, priority, _| {
+            listed.push((pid, state));
+            if priority == TaskPriority::Idle {
+                idle_tasks += 1;
+            }
+        });
+
+        assert!(task_count() >= count_before + TASKS);
+        assert!(idle_tasks >= crate::cpu_count());
+        // The tasks have started, so they are blocked, or just about to be
+        for task in tasks.iter() {
+            let state = listed
+                .iter()
+                .find(|(listed_pid, _)| *listed_pid == task.pid())
+                .map(|(_, state)| *state);
+            assert!(
+                state == Some(TaskState::Blocked) || state == Some(TaskState::Running),
+                "Task {} is {:?}",
+                task.pid(),
+                state
+            );
+        }
+        assert!(listed.contains(&(current_task().pid(), TaskState::Running)));
+
+        core::mem::drop(gate_guard);
+        for task in tasks.iter() {
+            task.join();
+        }
+    }
+
+    #[test_case]
+    fn exited_task_is_joined_and_unlisted() {
+        let count_before = task_count();
+        let task = unsafe { spawn(None, || exit(7)) }.expect("Failed to spawn task");
+
+        assert_eq!(task.join(), 7);
+        assert_eq!(task.exit_code(), Some(7));
+        assert_eq!(task_count(), count_before);
+
+        let mut listed = false;
+        TASK_DIRECTORY.for_each_task(|pid, _, _, _| listed |= pid == task.pid());
+        assert!(!listed, "Task {} is still listed", task.pid());
+    }
+
+    #[test_case]
+    fn tasks_cannot_be_pinned_to_missing_cpus() {
+        let missing_cpu = (0..MAX_CPUS)
+            .find(|cpu_id| !crate::init::cpu_online(*cpu_id))
+            .unwrap_or(MAX_CPUS);
+
+        assert_eq!(
+            unsafe { spawn_on(missing_cpu, || loop {}) }.map(|_| ()),
+            Err(SchedulerError::InvalidCpu)
+        );
+        assert_eq!(
+            current_task().set_affinity(missing_cpu),
+            Err(SchedulerError::InvalidCpu)
+        );
+    }
+}
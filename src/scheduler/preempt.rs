@@ -0,0 +1,213 @@
+// Time slices. A task that isn't idle gets TIME_SLICE_NS of the CPU each time it is switched to,
+// measured in TSC ticks. The local APIC timer is armed for the end of the slice, directly in TSC
+// units if it has a deadline mode, or otherwise ticking periodically at the slice length. A task in
+// ring 3 runs with interrupts enabled, so the timer takes the CPU away from it. Kernel tasks run
+// with interrupts disabled, so the timer can't, and they check whether their slice is over at
+// their preemption points instead.
+
+use crate::devices::local_apic::{local_apic_access, tsc_deadline_supported};
+use crate::devices::tsc;
+use x86::time::rdtsc;
+
+pub const TIME_SLICE_NS: u64 = 10_000_000;
+
+// When this CPU's current slice ends, or zero if it has no slice
+#[thread_local]
+static mut SLICE_END_TSC: u64 = 0;
+
+/// Start a slice for the task being switched to. The idle task doesn't get one, because anything
+/// that becomes ready takes over from it anyway.
+pub(super) unsafe fn start_slice(idle: bool) {
+    let local_apic = local_apic_access();
+    let slice_ticks = match tsc::ns_to_ticks(TIME_SLICE_NS) {
+        Some(slice_ticks) if !idle => slice_ticks,
+        _ => {
+            SLICE_END_TSC = 0;
+            local_apic.stop_timer();
+            return;
+        }
+    };
+
+    SLICE_END_TSC = rdtsc() + slice_ticks;
+    if tsc_deadline_supported() {
+        local_apic.arm_deadline(SLICE_END_TSC);
+    } else {
+        // The timer is calibrated before anything is scheduled
+        let armed = local_apic.arm_periodic((TIME_SLICE_NS / 1_000_000) as u32);
+        assert!(armed, "Local APIC timer isn't calibrated");
+    }
+}
+
+/// Whether the current task has used up its slice
+pub fn slice_expired() -> bool {
+    unsafe { SLICE_END_TSC != 0 && rdtsc() >= SLICE_END_TSC }
+}
+
+/// Let another task run if the current one has used up its slice
+pub fn preemption_point() {
+    if slice_expired() {
+        super::reschedule();
+    }
+}
+
+/// Called by the local APIC timer when it interrupts ring 3. The task came in on its own kernel
+/// stack, so it can be switched away from here, and it goes back to ring 3 when it is switched
+/// back to.
+pub fn preempt_user_task() {
+    if slice_expired() {
+        super::reschedule();
+
+        // Nothing else was ready, so the task carries on with a slice of its own
+        if slice_expired() {
+            unsafe { start_slice(false) };
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::intel_asm;
+    use crate::paging::{hyperspace, new_address_space, PresentPageFlags, PAGE_SIZE};
+    use crate::physmem::{self, Frame};
+    use crate::scheduler::{sleep, spawn_task, TaskPriority, TaskReference};
+    use crate::syscall::enter_user_mode;
+    use core::slice;
+    use core::time::Duration;
+
+    // Spin until the word that rdi points at is set, without ever entering the kernel, and then
+    // exit
+    intel_asm!(
+        ".global spin_test_stub\n",
+        ".global spin_test_stub_end\n",
+        ".section .text.spin_test_stub, \"ax\", @progbits\n",
+        "spin_test_stub:\n",
+        "cmp qword ptr [rdi], 0\n",
+        "je spin_test_stub\n",
+        "mov eax, 2\n",
+        "xor edi, edi\n",
+        "syscall\n",
+        "ud2\n",
+        "spin_test_stub_end:\n",
+        ".text\n",
+    );
+
+    extern "C" {
+        static spin_test_stub: u8;
+        static spin_test_stub_end: u8;
+    }
+
+    const CODE_PAGE: usize = 0x40_0000;
+    const DATA_PAGE: usize = 0x40_1000;
+
+    #[test_case]
+    fn slices_expire_after_the_slice_length() {
+        if tsc::ns_to_ticks(TIME_SLICE_NS).is_none() {
+            crate::skip_test("no tsc clock");
+            return;
+        }
+
+        unsafe { start_slice(false) };
+        assert!(!slice_expired());
+
+        crate::devices::clock::spin_wait_ns(TIME_SLICE_NS);
+        assert!(slice_expired());
+
+        unsafe { start_slice(false) };
+        assert!(!slice_expired());
+    }
+
+    // Start a task spinning in ring 3 on cpu_id, until the first word of stop_frame is set
+    fn spawn_spinner(cpu_id: usize, stop_frame: Frame) -> TaskReference {
+        let code = unsafe {
+            let start = &spin_test_stub as *const u8;
+            let end = &spin_test_stub_end as *const u8;
+            slice::from_raw_parts(start, end as usize - start as usize)
+        };
+
+        let code_frame = physmem::allocate_user_frame().expect("Failed to allocate code frame");
+        hyperspace::with_frame(code_frame, |page| unsafe {
+            page.copy_from_nonoverlapping(code.as_ptr(), code.len())
+        })
+        .expect("Failed to copy test code");
+        hyperspace::zero_frame(stop_frame).expect("Failed to zero data frame");
+
+        let mut address_space = new_address_space().expect("Failed to create address space");
+        {
+            let mut mapper = unsafe { address_space.mapper() };
+            let flush = mapper
+                .map_to(CODE_PAGE, code_frame, PresentPageFlags::USER_ACCESSIBLE)
+                .expect("Failed to map code page");
+            unsafe { flush.ignore() };
+            let flush = mapper
+                .map_to(
+                    DATA_PAGE,
+                    stop_frame,
+                    PresentPageFlags::USER_ACCESSIBLE
+                        | PresentPageFlags::WRITABLE
+                        | PresentPageFlags::NO_EXECUTE,
+                )
+                .expect("Failed to map data page");
+            unsafe { flush.ignore() };
+        }
+
+        unsafe {
+            spawn_task(
+                Some(address_space),
+                Some(cpu_id),
+                TaskPriority::Normal,
+                0,
+                || enter_user_mode(CODE_PAGE, DATA_PAGE + PAGE_SIZE, DATA_PAGE),
+            )
+        }
+        .expect("Failed to spawn spinner")
+    }
+
+    #[test_case]
+    fn the_timer_preempts_ring_3() {
+        // Each spinner only gets the CPU back if the other one is preempted
+        const SWITCHES: u64 = 4;
+        const TIMEOUT_NS: u64 = 100 * TIME_SLICE_NS;
+
+        if tsc::ns_to_ticks(TIME_SLICE_NS).is_none() {
+            crate::skip_test("no tsc clock");
+            return;
+        }
+
+        let cpu_id = crate::cpu_id();
+        let frames = [
+            physmem::allocate_user_frame().expect("Failed to allocate data frame"),
+            physmem::allocate_user_frame().expect("Failed to allocate data frame"),
+        ];
+        let spinners = [
+            spawn_spinner(cpu_id, frames[0]),
+            spawn_spinner(cpu_id, frames[1]),
+        ];
+
+        let deadline_ns = tsc::now_ns().unwrap() + TIMEOUT_NS;
+        while tsc::now_ns().unwrap() < deadline_ns
+            && spinners
+                .iter()
+                .any(|spinner| spinner.switch_count() < SWITCHES)
+        {
+            sleep(Duration::from_millis(1));
+        }
+        let switch_counts = [spinners[0].switch_count(), spinners[1].switch_count()];
+
+        for frame in frames.iter() {
+            hyperspace::with_frame(*frame, |page| unsafe {
+                (page as *mut u64).write_volatile(1)
+            })
+            .expect("Failed to stop spinner");
+        }
+        for spinner in spinners.iter() {
+            assert_eq!(spinner.join(), 0);
+        }
+
+        assert!(
+            switch_counts.iter().all(|count| *count >= SWITCHES),
+            "Spinners were only switched to {:?} times",
+            switch_counts
+        );
+    }
+}
@@ -1,5 +1,5 @@
 use super::arch_context::ArchContext;
-use super::{TaskControl, TaskReference, TASK_DIRECTORY};
+use super::{Pid, TaskControl, TaskPriority, TaskReference, TASK_DIRECTORY};
 use alloc::boxed::Box;
 
 struct CurrentTask {
@@ -48,16 +48,80 @@ impl CurrentTask {
 
     unsafe fn complete_task_switch(&mut self) {
         assert!(!self.old.is_none(), "Task switch is not in progress");
+        debug_assert!(!crate::interrupts::are_enabled());
+
+        // Anything the new task does in ring 3 comes back into the kernel on its own stack
+        crate::gdt::set_kernel_stack_top(self.current_task().stack_top());
+        super::preempt::start_slice(self.current_task().priority() == TaskPriority::Idle);
 
         let old_task = self.old.take().unwrap();
-        old_task.make_ready()
+        if let Some(now_ns) = crate::devices::now_ns() {
+            old_task.task().account_switch_out(now_ns);
+            self.current_task().account_switch_in(now_ns);
+        }
+        old_task.switch_out()
+    }
+
+    pub unsafe fn block(&mut self, arm_wakeup: impl FnOnce()) {
+        debug_assert!(
+            !crate::interrupts::are_enabled(),
+            "Blocking with interrupts enabled"
+        );
+
+        let task = self.current_task();
+        assert_ne!(task.priority(), TaskPriority::Idle, "Idle task can't block");
+
+        // The wakeup is armed after the task is marked as blocked, so that it can't come too early
+        // to wake it. If it comes before the switch is done, the task just goes back on the ready
+        // list.
+        task.set_blocked();
+        core::mem::drop(task);
+        arm_wakeup();
+
+        // This CPU's idle task can always run here, so there is something to switch to
+        let next_task = TASK_DIRECTORY
+            .find_next_task(None)
+            .expect("No task to switch to");
+        let (old_ctxt, new_ctxt) = self.prepare_task_switch(next_task);
+        old_ctxt.switch_to(new_ctxt);
+    }
+
+    unsafe fn exit(&mut self) -> ! {
+        debug_assert!(!crate::interrupts::are_enabled());
+
+        // The task is already marked as exited, so switching away puts it on the exited list
+        let next_task = TASK_DIRECTORY
+            .find_next_task(None)
+            .expect("No task to switch to");
+        let (old_ctxt, new_ctxt) = self.prepare_task_switch(next_task);
+        old_ctxt.switch_to(new_ctxt);
+
+        unreachable!("Switched back to a task that has exited");
     }
 
     pub unsafe fn reschedule(&mut self) {
         // Reschedule is called at opportune times to reschedule tasks, but the current task continues to be
         // runnable. You should not be holding any kernel locks when you call this (i.e. running at passive level
         // should we get as far as that)
-        if let Some(next_task) = TASK_DIRECTORY.find_next_task(Some(current_task().priority())) {
+        debug_assert!(
+            !crate::interrupts::are_enabled(),
+            "Rescheduling with interrupts enabled"
+        );
+
+        // A task that has been pinned to another CPU has to give this one up, even if only the
+        // idle task can take over
+        let task = self.current_task();
+        let must_move = task
+            .affinity()
+            .map_or(false, |cpu_id| cpu_id != crate::cpu_id());
+        let min_priority = if must_move {
+            None
+        } else {
+            Some(task.priority())
+        };
+        core::mem::drop(task);
+
+        if let Some(next_task) = TASK_DIRECTORY.find_next_task(min_priority) {
             // Now we can get the pointer to the outgoing task and the incoming task arch contexts.
 
             // Pulling off this task switch is tricky. Problems - firstly, there is no way to do this atomically
@@ -72,9 +136,11 @@ impl CurrentTask {
             // once we remove it, we must complete a task switch
             let (old_ctxt, new_ctxt) = CURRENT_TASK.prepare_task_switch(next_task);
 
+            // This also loads the new task's page table if it is different from ours
             old_ctxt.switch_to(new_ctxt);
 
-            todo!()
+            // We only get here once another task has switched back to us, and that switch has
+            // already been completed, so there is nothing left to do
         } // otherwise, nothing currently ready to switch to so stay where we are
     }
 }
@@ -83,6 +149,34 @@ pub fn current_task() -> TaskReference {
     unsafe { CURRENT_TASK.current_task() }
 }
 
+/// The pid of the task running on this CPU, if there is one yet. Unlike current_task this takes no
+/// reference to the task, so it can be used from an NMI.
+pub fn current_pid() -> Option<Pid> {
+    unsafe {
+        CURRENT_TASK
+            .current
+            .as_ref()
+            .map(|task_control| task_control.pid())
+    }
+}
+
+/// Give the FPU to the current task, after it trapped on its first FPU instruction since it was
+/// switched to. Returns false if there is no task that can use the FPU.
+pub unsafe fn restore_fpu_state() -> bool {
+    let fpu_state = CURRENT_TASK
+        .current
+        .as_mut()
+        .and_then(|task_control| task_control.arch_context().fpu_state());
+
+    match fpu_state {
+        Some(fpu_state) => {
+            crate::fpu::switch_in(fpu_state);
+            true
+        }
+        None => false,
+    }
+}
+
 pub(super) unsafe fn set_initial_task(task_control: Box<TaskControl>) {
     assert!(CURRENT_TASK.switch_running_task(task_control).is_none());
 }
@@ -91,11 +185,26 @@ pub(super) unsafe fn set_initial_task(task_control: Box<TaskControl>) {
 static mut CURRENT_TASK: CurrentTask = CurrentTask::new();
 
 pub fn reschedule() {
+    super::balance_if_due();
+
     unsafe {
         CURRENT_TASK.reschedule();
     }
 }
 
+/// Switch away from the current task until something wakes it. arm_wakeup is called once the task
+/// is marked as blocked, to set up whatever will wake it.
+pub fn block_current(arm_wakeup: impl FnOnce()) {
+    unsafe {
+        CURRENT_TASK.block(arm_wakeup);
+    }
+}
+
+/// Switch away from the current task, which has exited, for the last time
+pub(super) unsafe fn exit_current() -> ! {
+    CURRENT_TASK.exit()
+}
+
 #[no_mangle]
 unsafe extern "C" fn complete_task_switch() {
     CURRENT_TASK.complete_task_switch()
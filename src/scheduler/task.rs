@@ -1,11 +1,15 @@
 use super::arch_context::ArchContext;
 use super::{reschedule, reschedule::set_initial_task, Result, SchedulerError};
+use crate::devices::timer::SleepTimer;
 use crate::paging;
+use crate::sync::{IrqMutex, MutexWait, WaitQueue};
 use alloc::boxed::Box;
 use alloc::collections::btree_map::BTreeMap;
 use alloc::sync::Arc;
+use alloc::vec::Vec;
 use bitflags::bitflags;
 use core::cell::UnsafeCell;
+use core::sync::atomic::{spin_loop_hint, AtomicU64, AtomicUsize, Ordering};
 use intrusive_collections::intrusive_adapter;
 use intrusive_collections::{LinkedList, LinkedListLink};
 use spin::{Mutex, RwLock};
@@ -21,16 +25,27 @@ pub enum TaskState {
     New,
     Ready,
     Running,
+    Blocked,
+    // Never runs again. It is freed once nothing refers to it.
+    Exited,
 }
 
 #[derive(Debug, PartialEq, Eq, Clone, Copy, PartialOrd, Ord)]
 #[repr(usize)]
 pub enum TaskPriority {
     Idle = 0,
-    Normal = 1,
+    Low = 1,
+    Normal = 2,
+    High = 3,
 }
 
-const PRIORITIES_COUNT: usize = 2;
+const PRIORITIES_COUNT: usize = 4;
+const PRIORITIES: [TaskPriority; PRIORITIES_COUNT] = [
+    TaskPriority::Idle,
+    TaskPriority::Low,
+    TaskPriority::Normal,
+    TaskPriority::High,
+];
 
 pub type Pid = usize;
 
@@ -39,7 +54,9 @@ const MAX_PID: Pid = 0x0007_ffff_ffff_ffff;
 
 struct TaskDirectoryData {
     process_map: BTreeMap<Pid, TaskReference>,
-    ready_lists: [LinkedList<TaskListAdapter>; 2],
+    ready_lists: [LinkedList<TaskListAdapter>; PRIORITIES_COUNT],
+    // Control blocks of tasks that have exited and been switched away from, waiting to be freed
+    exited: LinkedList<TaskListAdapter>,
     next_pid: Pid,
     next_system_pid: Pid,
 }
@@ -48,7 +65,13 @@ impl TaskDirectoryData {
     const fn new() -> Self {
         Self {
             process_map: BTreeMap::new(),
-            ready_lists: [LinkedList::new(TaskListAdapter::NEW), LinkedList::new(TaskListAdapter::NEW)],
+            ready_lists: [
+                LinkedList::new(TaskListAdapter::NEW),
+                LinkedList::new(TaskListAdapter::NEW),
+                LinkedList::new(TaskListAdapter::NEW),
+                LinkedList::new(TaskListAdapter::NEW),
+            ],
+            exited: LinkedList::new(TaskListAdapter::NEW),
             next_pid: 0,
             next_system_pid: 0xffff_ffff_ffff_ffff,
         }
@@ -96,10 +119,20 @@ impl TaskDirectoryData {
         let task = Arc::new(Task {
             pid,
             arch_context: ContextWrapper(UnsafeCell::new(ArchContext::new())),
+            accounting: TaskAccounting::new(),
+            sleep_timer: Mutex::new(SleepTimer::new()),
+            waiting_for_users: AtomicUsize::new(0),
+            exit: IrqMutex::new(TaskExit {
+                exit_code: None,
+                joiners: WaitQueue::new(),
+            }),
             inner: RwLock::new(TaskData {
                 _pid: pid,
                 state: TaskState::New,
                 init,
+                parked: None,
+                priority_boosts: [0; PRIORITIES_COUNT],
+                waiting_for: None,
             }),
         });
         self.process_map.insert(pid, task.clone());
@@ -111,7 +144,7 @@ impl TaskDirectoryData {
             let task_inner = task_control.task.inner.read();
             assert_eq!(task_inner.state, TaskState::Ready);
 
-            task_inner.init.priority as usize
+            task_inner.priority() as usize
         };
 
         self.ready_lists[priority_index].push_back(task_control);
@@ -138,6 +171,47 @@ impl TaskDirectoryData {
         // We didn't find a higher priority task
         None
     }
+
+    // Move a ready task to the list for its priority, if it has changed. A task that isn't on a
+    // ready list already picks up its priority when it is next put on one.
+    fn requeue(&mut self, pid: Pid) {
+        for priority_index in 0..PRIORITIES_COUNT {
+            let mut pos = self.ready_lists[priority_index].front_mut();
+            while let Some(task_control) = pos.get() {
+                if task_control.task.pid != pid {
+                    pos.move_next();
+                    continue;
+                }
+
+                if task_control.task.priority() as usize != priority_index {
+                    let task_control = pos.remove().unwrap();
+                    self.add_to_ready_list(task_control);
+                }
+                return;
+            }
+        }
+    }
+
+    fn task_summaries(&self) -> Vec<(Pid, TaskState, TaskPriority, u64)> {
+        self.process_map
+            .iter()
+            .map(|(pid, task)| {
+                let (state, priority) = {
+                    let inner = task.inner.read();
+                    (inner.state, inner.priority())
+                };
+                (*pid, state, priority, task.cpu_time_ns())
+            })
+            .collect()
+    }
+
+    fn migratable_ready_count(&self) -> usize {
+        self.ready_lists
+            .iter()
+            .flat_map(|list| list.iter())
+            .filter(|task_control| task_control.task.inner.read().init.cpu_id.is_none())
+            .count()
+    }
 }
 
 pub struct TaskDirectory {
@@ -160,7 +234,13 @@ impl TaskDirectory {
     }
 
     pub(super) fn add_to_ready_list(&self, task_control: Box<TaskControl>) {
-        self.data.lock().add_to_ready_list(task_control)
+        let cpu_id = task_control.task.affinity();
+        self.data.lock().add_to_ready_list(task_control);
+
+        // Only its own CPU can pick up a task with affinity, so make sure that it looks
+        if let Some(cpu_id) = cpu_id.filter(|cpu_id| *cpu_id != crate::cpu_id()) {
+            super::idle::kick_cpu(cpu_id);
+        }
     }
 
     pub(super) fn find_next_task(
@@ -169,21 +249,84 @@ impl TaskDirectory {
     ) -> Option<Box<TaskControl>> {
         self.data.lock().find_next_task(current_priority)
     }
+
+    fn requeue(&self, pid: Pid) {
+        self.data.lock().requeue(pid);
+    }
+
+    /// Call f with the pid, state, priority and CPU time of every task, in pid order. They are
+    /// copied out with the directory locked and f is called after it is unlocked, so f can do what
+    /// it likes, but the tasks may have moved on by then.
+    pub fn for_each_task(&self, mut f: impl FnMut(Pid, TaskState, TaskPriority, u64)) {
+        // Taking a task's lock with the directory locked is the same order as adding it to a ready
+        // list, and nothing takes the directory lock with a task locked
+        let summaries = self.data.lock().task_summaries();
+        for (pid, state, priority, cpu_time_ns) in summaries {
+            f(pid, state, priority, cpu_time_ns);
+        }
+    }
+
+    pub fn task_count(&self) -> usize {
+        self.data.lock().process_map.len()
+    }
+
+    fn remove_task(&self, pid: Pid) {
+        self.data.lock().process_map.remove(&pid);
+    }
+
+    fn add_exited(&self, task_control: Box<TaskControl>) {
+        self.data.lock().exited.push_back(task_control);
+    }
+
+    /// Free the tasks that have exited since the last call. Freeing a task frees its stack, which
+    /// can't be done in the middle of the context switch away from it, so it is left until here.
+    pub(super) fn reap_exited(&self) {
+        let exited = core::mem::replace(
+            &mut self.data.lock().exited,
+            LinkedList::new(TaskListAdapter::NEW),
+        );
+        core::mem::drop(exited);
+    }
+
+    /// How many ready tasks could run on any CPU
+    pub fn migratable_ready_count(&self) -> usize {
+        self.data.lock().migratable_ready_count()
+    }
 }
 
 pub static TASK_DIRECTORY: TaskDirectory = TaskDirectory::new();
 
 pub struct TaskInit {
-    _flags: TaskFlags,
+    flags: TaskFlags,
     kernel_stack: paging::KernelStack,
+    // The task's arch context points at this, so it lives as long as the task does
+    _address_space: Option<paging::AddressSpace>,
     cpu_id: Option<usize>,
     priority: TaskPriority,
+    user_fs_base: usize,
 }
 
 pub struct TaskData {
     _pid: Pid,
     state: TaskState,
     init: TaskInit,
+    // A blocked task's control block waits here once it has been switched away from
+    parked: Option<Box<TaskControl>>,
+    // How many boosts of each priority the task has, one for each lock it holds that a higher
+    // priority task is waiting for
+    priority_boosts: [usize; PRIORITIES_COUNT],
+    // The mutex the task is waiting for, which any boost it gets is passed on to
+    waiting_for: Option<MutexWait>,
+}
+
+impl TaskData {
+    fn priority(&self) -> TaskPriority {
+        let boost = PRIORITIES
+            .iter()
+            .rev()
+            .find(|priority| self.priority_boosts[**priority as usize] != 0);
+        boost.map_or(self.init.priority, |boost| (*boost).max(self.init.priority))
+    }
 }
 
 pub struct TaskControl {
@@ -199,6 +342,10 @@ impl TaskControl {
         self.task.clone()
     }
 
+    pub fn pid(&self) -> Pid {
+        self.task.pid()
+    }
+
     pub fn arch_context<'a>(&'a mut self) -> &'a mut ArchContext {
         &mut self.arch_context
     }
@@ -214,6 +361,24 @@ impl TaskControl {
 
         TASK_DIRECTORY.add_to_ready_list(self);
     }
+
+    /// Put a task that has just been switched away from wherever it goes next. A blocked task is
+    /// parked until something wakes it, and anything else goes back on the ready list.
+    pub fn switch_out(self: Box<Self>) {
+        let task = self.task();
+        let mut lock = task.inner.write();
+        match lock.state {
+            TaskState::Blocked => lock.parked = Some(self),
+            TaskState::Exited => {
+                core::mem::drop(lock);
+                TASK_DIRECTORY.add_exited(self);
+            }
+            _ => {
+                core::mem::drop(lock);
+                self.make_ready();
+            }
+        }
+    }
 }
 
 struct ContextWrapper(UnsafeCell<ArchContext>);
@@ -221,10 +386,40 @@ struct ContextWrapper(UnsafeCell<ArchContext>);
 unsafe impl Send for ContextWrapper {}
 unsafe impl Sync for ContextWrapper {}
 
+// Only the CPU that switches a task in or out updates these, so they are kept outside the task's
+// lock and the context switch never waits for it
+struct TaskAccounting {
+    total_run_ns: AtomicU64,
+    switch_count: AtomicU64,
+    // Zero until the task has been switched to
+    last_scheduled_ns: AtomicU64,
+}
+
+impl TaskAccounting {
+    const fn new() -> Self {
+        Self {
+            total_run_ns: AtomicU64::new(0),
+            switch_count: AtomicU64::new(0),
+            last_scheduled_ns: AtomicU64::new(0),
+        }
+    }
+}
+
+// Kept apart from the rest of the task, so that joining tasks can wait on it
+struct TaskExit {
+    exit_code: Option<isize>,
+    joiners: WaitQueue,
+}
+
 pub struct Task {
     pid: Pid,
     inner: RwLock<TaskData>,
+    exit: IrqMutex<TaskExit>,
     arch_context: ContextWrapper,
+    accounting: TaskAccounting,
+    sleep_timer: Mutex<SleepTimer>,
+    // How many boosts are being passed on through the mutex the task is waiting for
+    waiting_for_users: AtomicUsize,
 }
 
 pub type TaskReference = Arc<Task>;
@@ -237,24 +432,37 @@ impl Task {
         TASK_DIRECTORY.create_task(
             true,
             TaskInit {
-                _flags: TaskFlags::NO_TERMINATE,
+                flags: TaskFlags::NO_TERMINATE,
                 kernel_stack: kernel_stack,
+                _address_space: None,
                 cpu_id: Some(cpu_id),
                 priority: TaskPriority::Idle,
+                user_fs_base: 0,
             },
         )
     }
 
-    pub(super) fn spawn() -> Result<TaskReference> {
+    pub(super) fn remove_from_directory(&self) {
+        TASK_DIRECTORY.remove_task(self.pid);
+    }
+
+    pub(super) fn spawn(
+        address_space: Option<paging::AddressSpace>,
+        cpu_id: Option<usize>,
+        priority: TaskPriority,
+        user_fs_base: usize,
+    ) -> Result<TaskReference> {
         let kernel_stack = paging::allocate_kernel_stack(paging::DEFAULT_KERNEL_STACK_PAGES)?;
 
         TASK_DIRECTORY.create_task(
             false,
             TaskInit {
-                _flags: TaskFlags::empty(),
+                flags: TaskFlags::empty(),
                 kernel_stack,
-                cpu_id: None,
-                priority: TaskPriority::Normal,
+                _address_space: address_space,
+                cpu_id,
+                priority,
+                user_fs_base,
             },
         )
     }
@@ -263,6 +471,11 @@ impl Task {
         self.pid
     }
 
+    /// The timer that wakes the task when it sleeps, which the timer wheel links it through
+    pub fn sleep_timer(&self) -> &Mutex<SleepTimer> {
+        &self.sleep_timer
+    }
+
     pub fn state(&self) -> TaskState {
         self.inner.read().state
     }
@@ -273,14 +486,230 @@ impl Task {
         guard.state = TaskState::Running;
     }
 
+    /// Mark the current task as blocked, so that it is parked rather than made ready when it is
+    /// next switched away from
+    pub(super) fn set_blocked(&self) {
+        let mut guard = self.inner.write();
+        assert_eq!(guard.state, TaskState::Running);
+        guard.state = TaskState::Blocked;
+    }
+
+    /// Make a blocked task ready to run again. Returns false if it wasn't blocked.
+    pub fn wake(&self) -> bool {
+        let (control, cpu_id) = {
+            let mut guard = self.inner.write();
+            if guard.state != TaskState::Blocked {
+                return false;
+            }
+
+            match guard.parked.take() {
+                Some(control) => {
+                    guard.state = TaskState::Ready;
+                    (control, guard.init.cpu_id)
+                }
+
+                // It hasn't been switched away from yet, and it is made ready when it is, just as
+                // if it had never blocked
+                None => {
+                    guard.state = TaskState::Running;
+                    return true;
+                }
+            }
+        };
+
+        TASK_DIRECTORY.add_to_ready_list(control);
+        super::idle::wake_idle_cpu(cpu_id);
+        true
+    }
+
+    pub(super) fn account_switch_in(&self, now_ns: u64) {
+        self.accounting
+            .last_scheduled_ns
+            .store(now_ns, Ordering::Relaxed);
+        self.accounting.switch_count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(super) fn account_switch_out(&self, now_ns: u64) {
+        let last_scheduled_ns = self.accounting.last_scheduled_ns.load(Ordering::Relaxed);
+        if last_scheduled_ns != 0 {
+            self.accounting
+                .total_run_ns
+                .fetch_add(now_ns.saturating_sub(last_scheduled_ns), Ordering::Relaxed);
+        }
+    }
+
+    /// How long the task has spent running, up to the last time it was switched away from
+    pub fn cpu_time_ns(&self) -> u64 {
+        self.accounting.total_run_ns.load(Ordering::Relaxed)
+    }
+
+    /// How many times the task has been switched to
+    pub fn switch_count(&self) -> u64 {
+        self.accounting.switch_count.load(Ordering::Relaxed)
+    }
+
+    /// The CPU that the task is pinned to, if it is
+    pub fn affinity(&self) -> Option<usize> {
+        self.inner.read().init.cpu_id
+    }
+
+    /// Pin the task to a CPU. A ready task moves there straight away, and a running one the next
+    /// time it reschedules. Idle tasks stay on their own CPUs.
+    pub fn set_affinity(&self, cpu_id: usize) -> Result<()> {
+        if !crate::init::cpu_online(cpu_id) {
+            return Err(SchedulerError::InvalidCpu);
+        }
+
+        let state = {
+            let mut guard = self.inner.write();
+            if guard.init.priority == TaskPriority::Idle {
+                return Err(SchedulerError::InvalidCpu);
+            }
+
+            guard.init.cpu_id = Some(cpu_id);
+            guard.state
+        };
+
+        // The ready lists are shared, so the task is already where the new CPU will look for it
+        if state == TaskState::Ready && cpu_id != crate::cpu_id() {
+            super::idle::kick_cpu(cpu_id);
+        }
+
+        Ok(())
+    }
+
+    /// The priority the task is scheduled at, including any boost
     pub fn priority(&self) -> TaskPriority {
+        self.inner.read().priority()
+    }
+
+    /// The priority the task was spawned with
+    pub fn base_priority(&self) -> TaskPriority {
         self.inner.read().init.priority
     }
 
+    /// Schedule the task at priority or higher until this boost is removed. This is for priority
+    /// inheritance, so that a task holding a lock runs at the priority of whoever wants it. Boosts
+    /// are counted, so each lock can add and remove its own.
+    pub fn add_priority_boost(&self, priority: TaskPriority) {
+        self.update_priority_boosts(|boosts| boosts[priority as usize] += 1);
+    }
+
+    /// Take away a boost that add_priority_boost gave the task
+    pub fn remove_priority_boost(&self, priority: TaskPriority) {
+        self.update_priority_boosts(|boosts| {
+            assert_ne!(
+                boosts[priority as usize], 0,
+                "Removing a boost that isn't there"
+            );
+            boosts[priority as usize] -= 1;
+        });
+    }
+
+    fn update_priority_boosts(&self, update: impl FnOnce(&mut [usize; PRIORITIES_COUNT])) {
+        let (state, changed) = {
+            let mut guard = self.inner.write();
+            let old_priority = guard.priority();
+            update(&mut guard.priority_boosts);
+            (guard.state, guard.priority() != old_priority)
+        };
+
+        if changed && state == TaskState::Ready {
+            TASK_DIRECTORY.requeue(self.pid);
+        }
+    }
+
+    /// Record the mutex the task is about to wait for, or that it has stopped waiting. Once it has
+    /// stopped, this waits until nothing is still passing a boost on through the mutex, so that the
+    /// mutex can go away.
+    pub fn set_waiting_for(&self, mutex: Option<MutexWait>) {
+        let stopped = mutex.is_none();
+        self.inner.write().waiting_for = mutex;
+
+        if stopped {
+            while self.waiting_for_users.load(Ordering::SeqCst) != 0 {
+                spin_loop_hint();
+            }
+        }
+    }
+
+    /// Call func with the mutex the task is waiting for, if there is one. The task can't stop
+    /// waiting for it until func returns. The task isn't kept locked while func runs, since func
+    /// locks the mutex, and whoever holds that might be waking the task.
+    pub fn with_waiting_for<R>(&self, func: impl FnOnce(Option<&MutexWait>) -> R) -> R {
+        let mutex = {
+            let guard = self.inner.read();
+            if guard.waiting_for.is_some() {
+                self.waiting_for_users.fetch_add(1, Ordering::SeqCst);
+            }
+            guard.waiting_for
+        };
+
+        let ret = func(mutex.as_ref());
+        if mutex.is_some() {
+            self.waiting_for_users.fetch_sub(1, Ordering::SeqCst);
+        }
+        ret
+    }
+
     pub fn stack_top(&self) -> usize {
         self.inner.read().init.kernel_stack.stack_top()
     }
 
+    pub fn stack_peak_usage(&self) -> usize {
+        self.inner.read().init.kernel_stack.peak_usage()
+    }
+
+    /// What the task passed to exit, if it has exited
+    pub fn exit_code(&self) -> Option<isize> {
+        self.exit.lock().exit_code
+    }
+
+    /// Wait for the task to exit, and return its exit code
+    pub fn join(&self) -> isize {
+        assert_ne!(
+            self.pid,
+            super::current_task().pid(),
+            "Task can't join itself"
+        );
+
+        loop {
+            if let Some(exit_code) = self.exit_code() {
+                return exit_code;
+            }
+
+            WaitQueue::wait(&self.exit, |exit| match exit.exit_code {
+                Some(_) => None,
+                None => Some(&mut exit.joiners),
+            });
+        }
+    }
+
+    /// The fs base that the task runs with in ring 3, which points at its thread local block. It is
+    /// fixed when the task is spawned. The kernel always runs with this CPU's thread locals, so this
+    /// is only loaded on the way out to ring 3.
+    pub fn user_fs_base(&self) -> usize {
+        self.inner.read().init.user_fs_base
+    }
+
+    /// Mark the running task as exited, and wake anything joining it. It is put on the exited list
+    /// rather than a ready list when it is next switched away from.
+    pub(super) fn set_exited(&self, exit_code: isize) {
+        {
+            let mut guard = self.inner.write();
+            assert!(
+                !guard.init.flags.contains(TaskFlags::NO_TERMINATE),
+                "Task can't exit"
+            );
+            assert_eq!(guard.state, TaskState::Running);
+            guard.state = TaskState::Exited;
+        }
+
+        let mut exit = self.exit.lock();
+        exit.exit_code = Some(exit_code);
+        exit.joiners.wake_all();
+    }
+
     pub unsafe fn arch_context_ptr(&self) -> *mut ArchContext {
         self.arch_context.0.get()
     }
@@ -1,22 +1,70 @@
+use crate::io_port::PortRange;
+use crate::sync::IrqMutex;
 use lazy_static::lazy_static;
-use spin::Mutex;
 use uart_16550::SerialPort;
 
+const COM1: u16 = 0x3f8;
+const UART_PORTS: u16 = 8;
+
+const DATA: u16 = 0;
+const MODEM_CONTROL: u16 = 4;
+const SCRATCH: u16 = 7;
+
+const MODEM_CONTROL_LOOPBACK: u8 = 0x1e;
+const SCRATCH_PATTERNS: [u8; 2] = [0x5a, 0xa5];
+const LOOPBACK_PATTERN: u8 = 0xae;
+
 lazy_static! {
-    pub static ref SERIAL1: Mutex<SerialPort> = {
-        let mut serial_port = unsafe { SerialPort::new(0x3F8) };
-        serial_port.init();
-        Mutex::new(serial_port)
-    };
+    // Log records are printed from interrupt handlers too. None if there is no UART at COM1, in
+    // which case everything printed to serial is dropped.
+    pub static ref SERIAL1: IrqMutex<Option<SerialPort>> =
+        IrqMutex::new(unsafe { init_port(COM1) });
+}
+
+// Check that something which looks like a 16550 answers at base. An empty port range reads back as
+// all ones whatever was written, so it fails both checks.
+unsafe fn probe(base: u16) -> bool {
+    let ports = PortRange::new(base, UART_PORTS);
+    let scratch_works = SCRATCH_PATTERNS.iter().all(|pattern| {
+        ports.write(SCRATCH, *pattern);
+        ports.read::<u8>(SCRATCH) == *pattern
+    });
+    if !scratch_works {
+        return false;
+    }
+
+    // In loopback mode the UART receives whatever it sends, and nothing goes out on the wire
+    let modem_control = ports.read::<u8>(MODEM_CONTROL);
+    ports.write(MODEM_CONTROL, MODEM_CONTROL_LOOPBACK);
+    ports.write(DATA, LOOPBACK_PATTERN);
+    let looped_back = ports.read::<u8>(DATA) == LOOPBACK_PATTERN;
+    ports.write(MODEM_CONTROL, modem_control);
+
+    looped_back
+}
+
+/// Set up the UART at base, if there is one
+pub unsafe fn init_port(base: u16) -> Option<SerialPort> {
+    let mut serial_port = SerialPort::new(base);
+    serial_port.init();
+    if probe(base) {
+        Some(serial_port)
+    } else {
+        None
+    }
+}
+
+/// Whether there is a serial port for serial_print! to go to
+pub fn is_present() -> bool {
+    SERIAL1.lock().is_some()
 }
 
 #[doc(hidden)]
 pub fn _print(args: ::core::fmt::Arguments) {
     use core::fmt::Write;
-    SERIAL1
-        .lock()
-        .write_fmt(args)
-        .expect("Printing to serial failed");
+    if let Some(serial) = SERIAL1.lock().as_mut() {
+        serial.write_fmt(args).expect("Printing to serial failed");
+    }
 }
 
 /// Prints to the host through the serial interface.
@@ -35,3 +83,40 @@ macro_rules! serial_println {
     ($fmt:expr, $($arg:tt)*) => ($crate::serial_print!(
         concat!($fmt, "\n"), $($arg)*));
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // QEMU only has COM1 unless it is asked for more
+    const COM4: u16 = 0x2e8;
+
+    #[test_case]
+    fn missing_uart_is_not_used() {
+        assert!(unsafe { init_port(COM4) }.is_none());
+    }
+
+    #[test_case]
+    fn com1_passes_its_probe() {
+        assert!(is_present());
+
+        // Hold the lock so nothing is printed while COM1 is in loopback, and only assert once it
+        // is dropped, so that a failure can be printed
+        let ports = PortRange::new(COM1, UART_PORTS);
+        let (probed, modem_control_kept, written) = {
+            let mut serial = SERIAL1.lock();
+            let modem_control = ports.read::<u8>(MODEM_CONTROL);
+            let probed = unsafe { probe(COM1) };
+            let modem_control_kept = ports.read::<u8>(MODEM_CONTROL) == modem_control;
+            let written = serial
+                .as_mut()
+                .map(|serial| core::fmt::Write::write_str(serial, ""));
+            (probed, modem_control_kept, written)
+        };
+
+        assert!(probed);
+        // The probe takes COM1 back out of loopback, so whatever is written goes out again
+        assert!(modem_control_kept);
+        assert_eq!(written, Some(Ok(())));
+    }
+}
@@ -0,0 +1,221 @@
+// A kernel shell on the keyboard. It reads a line at a time and runs a few commands for looking at
+// the state of the kernel, printing to the screen and the serial port.
+
+use crate::devices::ps2_keyboard::{self, Key};
+use crate::physmem::{self, PAGE_SIZE};
+use crate::scheduler::TASK_DIRECTORY;
+use crate::util::StackString;
+use alloc::string::String;
+use core::fmt::{self, Write};
+
+const PROMPT: &str = "> ";
+const MAX_LINE: usize = 80;
+
+/// Everything the shell prints goes to the screen and the serial port both
+struct Console;
+
+impl Write for Console {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        crate::vga_buffer::_print(format_args!("{}", s));
+        crate::serial::_print(format_args!("{}", s));
+        Ok(())
+    }
+}
+
+pub struct Shell {
+    line: String,
+}
+
+impl Shell {
+    pub fn new() -> Self {
+        Self {
+            line: String::new(),
+        }
+    }
+
+    /// Take a key press, echoing it to out, and run the line when it is finished
+    pub fn feed(&mut self, key: Key, out: &mut impl Write) -> fmt::Result {
+        match key {
+            Key::Enter => {
+                writeln!(out)?;
+                let line = core::mem::take(&mut self.line);
+                run_command(line.trim(), out)?;
+                out.write_str(PROMPT)
+            }
+
+            Key::Backspace => {
+                // The console can't move back, so show what is left of the line instead
+                if self.line.pop().is_some() {
+                    write!(out, "\n{}{}", PROMPT, self.line)?;
+                }
+                Ok(())
+            }
+
+            Key::Char(c) if self.line.len() < MAX_LINE => {
+                self.line.push(c);
+                out.write_char(c)
+            }
+
+            _ => Ok(()),
+        }
+    }
+}
+
+fn meminfo(out: &mut impl Write) -> fmt::Result {
+    let free_frames = physmem::free_frames();
+    let used_frames = physmem::used_frames();
+    writeln!(
+        out,
+        "free frames: {} ({} KiB)",
+        free_frames,
+        free_frames * PAGE_SIZE / 1024
+    )?;
+    writeln!(
+        out,
+        "used frames: {} ({} KiB)",
+        used_frames,
+        used_frames * PAGE_SIZE / 1024
+    )?;
+
+    for region in physmem::region_stats().iter() {
+        writeln!(
+            out,
+            "  {:<6} free {:>8} used {:>8}",
+            region.name, region.free_frames, region.used_frames
+        )?;
+    }
+    Ok(())
+}
+
+fn cpus(out: &mut impl Write) -> fmt::Result {
+    write!(out, "{} CPUs online:", crate::cpu_count())?;
+    for cpu_id in (0..crate::scheduler::MAX_CPUS).filter(|id| crate::init::cpu_online(*id)) {
+        write!(out, " {}", cpu_id)?;
+    }
+    writeln!(out)
+}
+
+fn ps(out: &mut impl Write) -> fmt::Result {
+    writeln!(
+        out,
+        "{:>18} {:<8} {:<8} {:>12}",
+        "PID", "STATE", "PRIORITY", "CPU us"
+    )?;
+
+    let mut result = Ok(());
+    TASK_DIRECTORY.for_each_task(|pid, state, priority, cpu_time_ns| {
+        if result.is_ok() {
+            result = writeln!(
+                out,
+                "{:>#18x} {:<8} {:<8} {:>12}",
+                pid,
+                StackString::<16>::from_args(format_args!("{:?}", state)),
+                StackString::<16>::from_args(format_args!("{:?}", priority)),
+                cpu_time_ns / 1000
+            );
+        }
+    });
+    result
+}
+
+fn uptime(out: &mut impl Write) -> fmt::Result {
+    match crate::devices::now_ns() {
+        Some(now_ns) => writeln!(
+            out,
+            "up {}.{:03}s",
+            now_ns / 1_000_000_000,
+            now_ns / 1_000_000 % 1000
+        ),
+        None => writeln!(out, "no clock"),
+    }
+}
+
+/// Run one line of input
+pub fn run_command(line: &str, out: &mut impl Write) -> fmt::Result {
+    match line {
+        "" => Ok(()),
+        "meminfo" => meminfo(out),
+        "cpus" => cpus(out),
+        "ps" => ps(out),
+        "uptime" => uptime(out),
+        "help" => writeln!(out, "commands: meminfo cpus ps uptime help"),
+        _ => writeln!(out, "unknown command: {}", line),
+    }
+}
+
+/// The shell task. It sleeps until there are keys to read.
+pub fn run() -> ! {
+    let mut shell = Shell::new();
+    let _ = Console.write_str(PROMPT);
+
+    loop {
+        let event = ps2_keyboard::wait_event();
+        if event.pressed {
+            let _ = shell.feed(event.key, &mut Console);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn type_line(shell: &mut Shell, line: &str) -> String {
+        let mut out = String::new();
+        for c in line.chars() {
+            let key = if c == '\n' { Key::Enter } else { Key::Char(c) };
+            shell.feed(key, &mut out).unwrap();
+        }
+        out
+    }
+
+    // The number after label, on the line that starts with it
+    fn number_after(out: &str, label: &str) -> usize {
+        out.lines()
+            .find_map(|line| line.strip_prefix(label))
+            .and_then(|rest| rest.split_whitespace().next())
+            .and_then(|number| number.parse().ok())
+            .unwrap_or_else(|| panic!("No {} in {:?}", label, out))
+    }
+
+    #[test_case]
+    fn meminfo_reports_frame_counts() {
+        let out = type_line(&mut Shell::new(), "meminfo\n");
+
+        let free_frames = number_after(&out, "free frames:");
+        let used_frames = number_after(&out, "used frames:");
+        assert!(free_frames > 0 && used_frames > 0, "{}", out);
+
+        // Other CPUs are allocating too, so only expect the total to be about right
+        let total = physmem::free_frames() + physmem::used_frames();
+        let reported = free_frames + used_frames;
+        assert!(reported > total / 2 && reported < total * 2, "{}", out);
+        assert!(out.ends_with(PROMPT));
+    }
+
+    #[test_case]
+    fn lines_are_edited_before_they_run() {
+        let mut shell = Shell::new();
+        let mut out = String::new();
+        for key in [Key::Char('c'), Key::Char('x'), Key::Backspace].iter() {
+            shell.feed(*key, &mut out).unwrap();
+        }
+        let out = type_line(&mut shell, "pus\n");
+        assert!(out.contains("CPUs online: 0"), "{}", out);
+
+        let out = type_line(&mut shell, "frobnicate\n");
+        assert!(out.contains("unknown command: frobnicate"), "{}", out);
+    }
+
+    #[test_case]
+    fn ps_lists_the_current_task() {
+        let out = type_line(&mut Shell::new(), "ps\n");
+        let pid = alloc::format!("{:#x}", crate::scheduler::current_task().pid());
+        assert!(
+            out.lines()
+                .any(|line| line.trim_start().starts_with(&pid) && line.contains("Running")),
+            "{}",
+            out
+        );
+    }
+}
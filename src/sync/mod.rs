@@ -0,0 +1,183 @@
+// Locks for state that interrupt handlers share with tasks. A handler that spins on a lock held by
+// the task it interrupted would never get it back, so these keep interrupts masked on this CPU for
+// as long as the lock is held. Other CPUs can still take the lock from their handlers, because the
+// holder will carry on and release it.
+
+mod mutex;
+mod rwlock;
+mod wait_queue;
+
+use crate::interrupts::are_enabled;
+use core::mem::ManuallyDrop;
+use core::ops::{Deref, DerefMut};
+use spin::{Mutex as SpinMutex, MutexGuard as SpinMutexGuard};
+
+pub use mutex::{Mutex, MutexGuard, MutexWait};
+pub use rwlock::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+pub use wait_queue::WaitQueue;
+
+pub struct IrqMutex<T> {
+    inner: SpinMutex<T>,
+}
+
+/// Interrupts stay disabled until this is dropped, and then go back to how they were before the
+/// lock was taken
+pub struct IrqMutexGuard<'a, T> {
+    guard: ManuallyDrop<SpinMutexGuard<'a, T>>,
+    interrupts_enabled: bool,
+}
+
+impl<T> IrqMutex<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            inner: SpinMutex::new(value),
+        }
+    }
+
+    pub fn lock(&self) -> IrqMutexGuard<T> {
+        let interrupts_enabled = are_enabled();
+        unsafe { crate::interrupts::disable() };
+
+        IrqMutexGuard {
+            guard: ManuallyDrop::new(self.inner.lock()),
+            interrupts_enabled,
+        }
+    }
+
+    pub fn try_lock(&self) -> Option<IrqMutexGuard<T>> {
+        let interrupts_enabled = are_enabled();
+        unsafe { crate::interrupts::disable() };
+
+        match self.inner.try_lock() {
+            Some(guard) => Some(IrqMutexGuard {
+                guard: ManuallyDrop::new(guard),
+                interrupts_enabled,
+            }),
+
+            None => {
+                if interrupts_enabled {
+                    unsafe { crate::interrupts::enable() };
+                }
+                None
+            }
+        }
+    }
+}
+
+impl<T> Deref for IrqMutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T> DerefMut for IrqMutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+impl<T> Drop for IrqMutexGuard<'_, T> {
+    fn drop(&mut self) {
+        // The lock has to be released before an interrupt can come in and want it
+        unsafe {
+            ManuallyDrop::drop(&mut self.guard);
+            if self.interrupts_enabled {
+                crate::interrupts::enable();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::devices::local_apic::local_apic_access;
+    use crate::idt;
+    use crate::interrupts::pause;
+    use core::sync::atomic::{AtomicBool, Ordering};
+
+    // Nothing else uses this vector
+    const TEST_VECTOR: u8 = 0xe0;
+
+    // A fixed interrupt to this CPU, using the self destination shorthand
+    const SELF_IPI: u32 = 1 << 18 | 1 << 14;
+
+    static TEST_LOCK: IrqMutex<usize> = IrqMutex::new(0);
+    static CONTENDED: AtomicBool = AtomicBool::new(false);
+
+    // If this handler spun on the lock while the test held it, the CPU would hang, so it records
+    // whether it would have had to
+    crate::interrupt!(irq_mutex_test_interrupt, || {
+        match TEST_LOCK.try_lock() {
+            Some(mut count) => *count += 1,
+            None => CONTENDED.store(true, Ordering::SeqCst),
+        }
+
+        local_apic_access().eoi();
+    });
+
+    #[test_case]
+    fn interrupts_wait_for_the_lock() {
+        let were_enabled = are_enabled();
+        unsafe {
+            idt::set_handler(TEST_VECTOR, Some(irq_mutex_test_interrupt));
+            crate::interrupts::enable();
+        }
+
+        {
+            let count = TEST_LOCK.lock();
+            assert!(!are_enabled());
+
+            local_apic_access().set_icr(0, SELF_IPI | u32::from(TEST_VECTOR));
+            for _ in 0..1000 {
+                pause();
+            }
+            assert_eq!(*count, 0);
+        }
+
+        // The interrupt was held pending, and comes in as soon as the guard is dropped
+        assert!(are_enabled());
+        for _ in 0..1000 {
+            if *TEST_LOCK.lock() != 0 {
+                break;
+            }
+            pause();
+        }
+
+        unsafe {
+            crate::interrupts::disable();
+            idt::set_handler(TEST_VECTOR, None);
+            if were_enabled {
+                crate::interrupts::enable();
+            }
+        }
+
+        assert_eq!(*TEST_LOCK.lock(), 1);
+        assert!(!CONTENDED.load(Ordering::SeqCst));
+    }
+
+    #[test_case]
+    fn nested_guards_restore_the_outer_state() {
+        let lock = IrqMutex::new(());
+        let were_enabled = are_enabled();
+        unsafe { crate::interrupts::enable() };
+
+        {
+            let _outer = lock.lock();
+            {
+                let inner = IrqMutex::new(());
+                let _inner = inner.lock();
+            }
+            assert!(!are_enabled());
+            assert!(lock.try_lock().is_none());
+            assert!(!are_enabled());
+        }
+        assert!(are_enabled());
+
+        if !were_enabled {
+            unsafe { crate::interrupts::disable() };
+        }
+    }
+}
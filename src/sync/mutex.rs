@@ -0,0 +1,376 @@
+// A mutex that blocks the task waiting for it instead of spinning, for state that only tasks use.
+// While a task is waiting, the owner runs at the waiter's priority if that is higher, so a low
+// priority owner can't be kept off the CPU by tasks in between and hold up the waiter indefinitely.
+// Each lock keeps track of the boost it gave its owner, so an owner holding several contended
+// locks keeps the boosts of the others when it lets go of one. If the owner is itself waiting for
+// another lock, the boost is passed on to that lock's owner, and so on down the chain.
+
+use super::{IrqMutex, IrqMutexGuard, WaitQueue};
+use crate::scheduler::{self, TaskPriority, TaskReference};
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use core::ptr::NonNull;
+
+struct MutexState {
+    owner: Option<TaskReference>,
+    // The boost that the owner has from this lock's waiters
+    boost: Option<TaskPriority>,
+    waiters: WaitQueue,
+}
+
+impl MutexState {
+    // Give the owner boost from this lock in place of whatever it had from it before
+    fn set_boost(&mut self, boost: Option<TaskPriority>) {
+        if boost == self.boost {
+            return;
+        }
+
+        let owner = self.owner.as_ref().expect("Boosting a mutex with no owner");
+        if let Some(old_boost) = self.boost {
+            owner.remove_priority_boost(old_boost);
+        }
+        if let Some(boost) = boost {
+            owner.add_priority_boost(boost);
+        }
+        self.boost = boost;
+    }
+
+    // Boost the owner to at least priority, and pass it on to the owner of whatever lock the owner
+    // is waiting for
+    fn raise_boost(&mut self, priority: TaskPriority) {
+        if self.boost.map_or(false, |boost| boost >= priority) {
+            return;
+        }
+
+        self.set_boost(Some(priority));
+        if let Some(owner) = self.owner.as_ref() {
+            owner.with_waiting_for(|mutex| {
+                if let Some(mutex) = mutex {
+                    mutex.lock().raise_boost(priority);
+                }
+            });
+        }
+    }
+}
+
+/// The mutex that a task is waiting for, kept in the task for passing boosts down the chain of
+/// owners. It is only followed through Task::with_waiting_for, which keeps the task from returning
+/// and letting the mutex go away.
+#[derive(Clone, Copy)]
+pub struct MutexWait(NonNull<IrqMutex<MutexState>>);
+
+unsafe impl Send for MutexWait {}
+unsafe impl Sync for MutexWait {}
+
+impl MutexWait {
+    fn lock(&self) -> IrqMutexGuard<MutexState> {
+        unsafe { self.0.as_ref() }.lock()
+    }
+}
+
+pub struct Mutex<T> {
+    state: IrqMutex<MutexState>,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Send for Mutex<T> {}
+unsafe impl<T: Send> Sync for Mutex<T> {}
+
+pub struct MutexGuard<'a, T> {
+    mutex: &'a Mutex<T>,
+}
+
+impl<T> Mutex<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            state: IrqMutex::new(MutexState {
+                owner: None,
+                boost: None,
+                waiters: WaitQueue::new(),
+            }),
+            data: UnsafeCell::new(value),
+        }
+    }
+
+    pub fn lock(&self) -> MutexGuard<T> {
+        loop {
+            if let Some(guard) = self.try_lock() {
+                return guard;
+            }
+
+            let task = scheduler::current_task();
+            let priority = task.priority();
+            WaitQueue::wait(&self.state, |state| {
+                // If it was unlocked before this task got to wait, there's nothing to wait for
+                state.owner.as_ref()?;
+                task.set_waiting_for(Some(MutexWait(NonNull::from(&self.state))));
+                state.raise_boost(priority);
+                Some(&mut state.waiters)
+            });
+            task.set_waiting_for(None);
+        }
+    }
+
+    pub fn try_lock(&self) -> Option<MutexGuard<T>> {
+        let mut state = self.state.lock();
+        if state.owner.is_some() {
+            return None;
+        }
+
+        // Whoever is still waiting passes their priority on to the new owner
+        state.owner = Some(scheduler::current_task());
+        let boost = state.waiters.highest_priority();
+        state.set_boost(boost);
+        Some(MutexGuard { mutex: self })
+    }
+
+    /// The task holding the lock, if there is one
+    pub fn owner(&self) -> Option<TaskReference> {
+        self.state.lock().owner.clone()
+    }
+
+    fn unlock(&self) {
+        let mut state = self.state.lock();
+        assert!(state.owner.is_some(), "Unlocking a mutex that isn't locked");
+
+        // Only the boost from this lock goes, and any from other locks the owner holds stays
+        state.set_boost(None);
+        state.owner = None;
+
+        // The waiter still has to take the lock like anyone else
+        state.waiters.wake_one();
+    }
+}
+
+impl<T> Deref for MutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.data.get() }
+    }
+}
+
+impl<T> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.data.get() }
+    }
+}
+
+impl<T> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        self.mutex.unlock();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::devices::{clock, now_ns};
+    use crate::scheduler::{exit, reschedule, sleep, spawn_with_priority, TaskPriority};
+    use alloc::sync::Arc;
+    use core::sync::atomic::{AtomicBool, Ordering};
+    use core::time::Duration;
+
+    // All three tasks share one CPU, so the scheduler's priorities are all that decide who runs
+    const INVERSION_CPU: usize = 1;
+
+    // Long enough that the high priority task would be left waiting for it without the boost
+    const MEDIUM_SPIN_NS: u64 = 200_000_000;
+
+    struct Inversion {
+        lock: Mutex<usize>,
+        low_locked: AtomicBool,
+        high_waiting: AtomicBool,
+        high_locked: AtomicBool,
+    }
+
+    // Exits with its priority at the point the high priority task was waiting for the lock
+    fn low_task(inversion: Arc<Inversion>) -> ! {
+        let priority = {
+            let mut guard = inversion.lock.lock();
+            inversion.low_locked.store(true, Ordering::SeqCst);
+
+            // Only runs again once something lets it, and then only lets go once the high
+            // priority task is waiting
+            while !inversion.high_waiting.load(Ordering::SeqCst) {
+                reschedule();
+            }
+            *guard += 1;
+            scheduler::current_task().priority()
+        };
+
+        core::mem::drop(inversion);
+        exit(priority as isize)
+    }
+
+    // Exits with 1 if it gave up waiting for the high priority task to get the lock
+    fn medium_task(inversion: Arc<Inversion>) -> ! {
+        let start = now_ns().unwrap();
+        let mut timed_out = false;
+        while !inversion.high_locked.load(Ordering::SeqCst) {
+            if now_ns().unwrap() - start > MEDIUM_SPIN_NS {
+                timed_out = true;
+                break;
+            }
+            clock::spin_wait_ns(100_000);
+            reschedule();
+        }
+
+        core::mem::drop(inversion);
+        exit(timed_out as isize)
+    }
+
+    fn high_task(inversion: Arc<Inversion>) -> ! {
+        inversion.high_waiting.store(true, Ordering::SeqCst);
+        let guard = inversion.lock.lock();
+        assert_eq!(*guard, 1);
+        inversion.high_locked.store(true, Ordering::SeqCst);
+        core::mem::drop(guard);
+
+        core::mem::drop(inversion);
+        exit(0)
+    }
+
+    fn wait_for(flag: &AtomicBool) {
+        while !flag.load(Ordering::SeqCst) {
+            sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test_case]
+    fn lock_owner_inherits_the_waiters_priority() {
+        if !crate::init::cpu_online(INVERSION_CPU) {
+            crate::skip_test("no spare cpu");
+            return;
+        }
+        if now_ns().is_none() {
+            crate::skip_test("no clock");
+            return;
+        }
+
+        let inversion = Arc::new(Inversion {
+            lock: Mutex::new(0),
+            low_locked: AtomicBool::new(false),
+            high_waiting: AtomicBool::new(false),
+            high_locked: AtomicBool::new(false),
+        });
+        let spawn = |priority, func: fn(Arc<Inversion>) -> !| {
+            let inversion = inversion.clone();
+            unsafe { spawn_with_priority(priority, Some(INVERSION_CPU), move || func(inversion)) }
+                .expect("Failed to spawn task")
+        };
+
+        // Normal priority tasks that are already running could keep a low priority task from ever
+        // starting, so it only drops to low priority once it has the lock
+        let low = spawn(TaskPriority::Low, low_task);
+        low.add_priority_boost(TaskPriority::Normal);
+        wait_for(&inversion.low_locked);
+        low.remove_priority_boost(TaskPriority::Normal);
+        assert_eq!(
+            inversion.lock.owner().map(|owner| owner.pid()),
+            Some(low.pid())
+        );
+
+        let medium = spawn(TaskPriority::Normal, medium_task);
+        let high = spawn(TaskPriority::High, high_task);
+        let medium_timed_out = medium.join();
+
+        assert!(inversion.high_locked.load(Ordering::SeqCst));
+        assert_eq!(
+            medium_timed_out, 0,
+            "High priority task waited behind the medium one"
+        );
+        assert_eq!(high.join(), 0);
+        assert_eq!(low.join(), TaskPriority::High as isize);
+        assert_eq!(low.priority(), TaskPriority::Low);
+        assert!(inversion.lock.owner().is_none());
+    }
+
+    #[test_case]
+    fn uncontended_lock_and_unlock() {
+        let mutex = Mutex::new(5);
+        {
+            let mut guard = mutex.lock();
+            *guard += 1;
+            assert!(mutex.try_lock().is_none());
+            assert_eq!(
+                mutex.owner().map(|owner| owner.pid()),
+                Some(scheduler::current_task().pid())
+            );
+        }
+
+        assert_eq!(*mutex.try_lock().unwrap(), 6);
+        assert!(mutex.owner().is_none());
+    }
+
+    #[test_case]
+    fn boosts_are_kept_for_each_lock() {
+        let task = scheduler::current_task();
+        assert!(task.base_priority() < TaskPriority::High);
+        let first = Mutex::new(());
+        let second = Mutex::new(());
+        let first_guard = first.lock();
+        let second_guard = second.lock();
+
+        // As if a high priority task were waiting for the first lock
+        first.state.lock().raise_boost(TaskPriority::High);
+        assert_eq!(task.priority(), TaskPriority::High);
+
+        // Letting go of the other lock leaves the boost alone
+        core::mem::drop(second_guard);
+        assert_eq!(task.priority(), TaskPriority::High);
+        core::mem::drop(first_guard);
+        assert_eq!(task.priority(), task.base_priority());
+    }
+
+    struct Chain {
+        outer: Mutex<()>,
+        inner: Mutex<()>,
+        middle_locked: AtomicBool,
+    }
+
+    // Takes the inner lock, and then waits for the outer one while holding it
+    fn middle_task(chain: Arc<Chain>) -> ! {
+        {
+            let _inner = chain.inner.lock();
+            chain.middle_locked.store(true, Ordering::SeqCst);
+            let _outer = chain.outer.lock();
+        }
+
+        core::mem::drop(chain);
+        exit(0)
+    }
+
+    #[test_case]
+    fn boosts_pass_down_a_chain_of_owners() {
+        let task = scheduler::current_task();
+        assert!(task.base_priority() < TaskPriority::High);
+        let chain = Arc::new(Chain {
+            outer: Mutex::new(()),
+            inner: Mutex::new(()),
+            middle_locked: AtomicBool::new(false),
+        });
+
+        let outer = chain.outer.lock();
+        let middle = {
+            let chain = chain.clone();
+            unsafe { spawn_with_priority(task.base_priority(), None, move || middle_task(chain)) }
+                .expect("Failed to spawn task")
+        };
+        wait_for(&chain.middle_locked);
+        while !middle.with_waiting_for(|mutex| mutex.is_some()) {
+            sleep(Duration::from_millis(1));
+        }
+
+        // As if a high priority task were waiting for the inner lock. The middle task can't let go
+        // of that until it gets the outer lock, so this task gets the boost as well.
+        chain.inner.state.lock().raise_boost(TaskPriority::High);
+        assert_eq!(middle.priority(), TaskPriority::High);
+        assert_eq!(task.priority(), TaskPriority::High);
+
+        core::mem::drop(outer);
+        assert_eq!(task.priority(), task.base_priority());
+        assert_eq!(middle.join(), 0);
+        assert_eq!(middle.priority(), middle.base_priority());
+    }
+}
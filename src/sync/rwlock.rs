@@ -0,0 +1,269 @@
+// A reader-writer lock that blocks tasks instead of spinning. Any number of readers can hold it at
+// once, or one writer. Writers come first: once a writer is waiting, new readers wait behind it, so
+// a steady stream of readers can't keep it out for ever.
+
+use super::{IrqMutex, WaitQueue};
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+
+struct RwLockState {
+    readers: usize,
+    writer: bool,
+    waiting_writers: usize,
+    waiters: WaitQueue,
+}
+
+impl RwLockState {
+    fn can_read(&self) -> bool {
+        !self.writer && self.waiting_writers == 0
+    }
+
+    fn can_write(&self) -> bool {
+        !self.writer && self.readers == 0
+    }
+}
+
+pub struct RwLock<T> {
+    state: IrqMutex<RwLockState>,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Send for RwLock<T> {}
+unsafe impl<T: Send + Sync> Sync for RwLock<T> {}
+
+pub struct RwLockReadGuard<'a, T> {
+    lock: &'a RwLock<T>,
+}
+
+pub struct RwLockWriteGuard<'a, T> {
+    lock: &'a RwLock<T>,
+}
+
+impl<T> RwLock<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            state: IrqMutex::new(RwLockState {
+                readers: 0,
+                writer: false,
+                waiting_writers: 0,
+                waiters: WaitQueue::new(),
+            }),
+            data: UnsafeCell::new(value),
+        }
+    }
+
+    pub fn read(&self) -> RwLockReadGuard<T> {
+        loop {
+            {
+                let mut state = self.state.lock();
+                if state.can_read() {
+                    state.readers += 1;
+                    return RwLockReadGuard { lock: self };
+                }
+            }
+
+            WaitQueue::wait(&self.state, |state| {
+                if state.can_read() {
+                    None
+                } else {
+                    Some(&mut state.waiters)
+                }
+            });
+        }
+    }
+
+    pub fn write(&self) -> RwLockWriteGuard<T> {
+        self.state.lock().waiting_writers += 1;
+        loop {
+            {
+                let mut state = self.state.lock();
+                if state.can_write() {
+                    state.waiting_writers -= 1;
+                    state.writer = true;
+                    return RwLockWriteGuard { lock: self };
+                }
+            }
+
+            WaitQueue::wait(&self.state, |state| {
+                if state.can_write() {
+                    None
+                } else {
+                    Some(&mut state.waiters)
+                }
+            });
+        }
+    }
+
+    // Readers and writers wait on the same queue, so wake them all and let them sort out who goes
+    // next between them
+    fn read_unlock(&self) {
+        let mut state = self.state.lock();
+        state.readers -= 1;
+        if state.readers == 0 {
+            state.waiters.wake_all();
+        }
+    }
+
+    fn write_unlock(&self) {
+        let mut state = self.state.lock();
+        state.writer = false;
+        state.waiters.wake_all();
+    }
+}
+
+impl<T> Deref for RwLockReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T> Drop for RwLockReadGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.read_unlock();
+    }
+}
+
+impl<T> Deref for RwLockWriteGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T> DerefMut for RwLockWriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<T> Drop for RwLockWriteGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.write_unlock();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::devices::{clock, now_ns};
+    use crate::scheduler::{exit, reschedule, sleep, spawn, TaskReference};
+    use alloc::sync::Arc;
+    use alloc::vec::Vec;
+    use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use core::time::Duration;
+
+    const READERS: usize = 3;
+    const WRITES: u64 = 20;
+    const WRITER_TIMEOUT_NS: u64 = 2_000_000_000;
+
+    struct Shared {
+        data: RwLock<[u64; 8]>,
+        stop_reading: AtomicBool,
+        reads: AtomicUsize,
+        concurrent_readers: AtomicUsize,
+        writer_done: AtomicBool,
+    }
+
+    // Readers hold the lock for a while and come straight back for it, so that without writer
+    // preference there would almost always be a reader in the way. Exits with how many reads were
+    // torn.
+    fn reader_task(shared: Arc<Shared>) -> ! {
+        let mut torn_reads = 0;
+        while !shared.stop_reading.load(Ordering::SeqCst) {
+            {
+                let data = shared.data.read();
+                shared.concurrent_readers.fetch_add(1, Ordering::SeqCst);
+                clock::spin_wait_ns(50_000);
+                if data.iter().any(|value| *value != data[0]) {
+                    torn_reads += 1;
+                }
+
+                shared.concurrent_readers.fetch_sub(1, Ordering::SeqCst);
+            }
+            shared.reads.fetch_add(1, Ordering::SeqCst);
+            reschedule();
+        }
+
+        core::mem::drop(shared);
+        exit(torn_reads)
+    }
+
+    fn writer_task(shared: Arc<Shared>) -> ! {
+        for write in 1..=WRITES {
+            let mut data = shared.data.write();
+            assert_eq!(shared.concurrent_readers.load(Ordering::SeqCst), 0);
+
+            // Slowly, so a reader that got in would see a mix of old and new values
+            for value in data.iter_mut() {
+                *value = write;
+                clock::spin_wait_ns(10_000);
+            }
+        }
+
+        shared.writer_done.store(true, Ordering::SeqCst);
+        core::mem::drop(shared);
+        exit(0)
+    }
+
+    #[test_case]
+    fn writer_gets_in_between_readers() {
+        let start = match now_ns() {
+            Some(start) => start,
+            None => return,
+        };
+
+        let shared = Arc::new(Shared {
+            data: RwLock::new([0; 8]),
+            stop_reading: AtomicBool::new(false),
+            reads: AtomicUsize::new(0),
+            concurrent_readers: AtomicUsize::new(0),
+            writer_done: AtomicBool::new(false),
+        });
+        let spawn_task = |func: fn(Arc<Shared>) -> !| {
+            let shared = shared.clone();
+            unsafe { spawn(None, move || func(shared)) }.expect("Failed to spawn task")
+        };
+
+        let readers: Vec<TaskReference> = (0..READERS).map(|_| spawn_task(reader_task)).collect();
+        while shared.reads.load(Ordering::SeqCst) < READERS {
+            sleep(Duration::from_millis(1));
+        }
+        let writer = spawn_task(writer_task);
+
+        while !shared.writer_done.load(Ordering::SeqCst)
+            && now_ns().unwrap() - start < WRITER_TIMEOUT_NS
+        {
+            sleep(Duration::from_millis(1));
+        }
+        let starved = !shared.writer_done.load(Ordering::SeqCst);
+
+        // A starved writer still gets in once the readers have gone
+        shared.stop_reading.store(true, Ordering::SeqCst);
+        let torn_reads: isize = readers.iter().map(|reader| reader.join()).sum();
+        writer.join();
+
+        assert!(!starved, "Writer was starved");
+        assert_eq!(*shared.data.read(), [WRITES; 8]);
+        assert_eq!(torn_reads, 0);
+        assert!(shared.reads.load(Ordering::SeqCst) >= READERS);
+    }
+
+    #[test_case]
+    fn readers_share_and_writers_do_not() {
+        let lock = RwLock::new(1);
+        {
+            let first = lock.read();
+            let second = lock.read();
+            assert_eq!(*first + *second, 2);
+            assert_eq!(lock.state.lock().readers, 2);
+        }
+
+        *lock.write() += 1;
+        let state = lock.state.lock();
+        assert!(state.can_write() && state.can_read());
+        assert_eq!(unsafe { *lock.data.get() }, 2);
+    }
+}
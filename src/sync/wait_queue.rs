@@ -0,0 +1,83 @@
+use super::IrqMutex;
+use crate::scheduler::{self, TaskPriority, TaskReference};
+use alloc::vec::Vec;
+
+/// Tasks blocked until something changes in the state that the queue is kept with. The queue lives
+/// inside that state's IrqMutex, so a task deciding to wait and another task making the change and
+/// waking the queue can't miss each other.
+pub struct WaitQueue {
+    waiters: Vec<TaskReference>,
+}
+
+impl WaitQueue {
+    pub const fn new() -> Self {
+        Self {
+            waiters: Vec::new(),
+        }
+    }
+
+    /// Block the current task on the queue that must_wait returns. must_wait is called with state
+    /// locked once the task is marked as blocked, and if it returns None, the task doesn't wait
+    /// after all. Either way the caller should check again whatever it was waiting for.
+    pub fn wait<S>(state: &IrqMutex<S>, must_wait: impl FnOnce(&mut S) -> Option<&mut WaitQueue>) {
+        let task = scheduler::current_task();
+        scheduler::block_current(move || {
+            let mut state = state.lock();
+            match must_wait(&mut state) {
+                Some(queue) => queue.waiters.push(task),
+                None => {
+                    task.wake();
+                }
+            }
+        });
+    }
+
+    /// Take a task off the queue without waking it, for when something else woke it first. Returns
+    /// false if it wasn't waiting here.
+    pub fn remove(&mut self, task: &TaskReference) -> bool {
+        let pid = task.pid();
+        let index = self.waiters.iter().position(|waiter| waiter.pid() == pid);
+        match index {
+            Some(index) => {
+                self.waiters.remove(index);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.waiters.is_empty()
+    }
+
+    /// The priority of the highest priority task on the queue
+    pub fn highest_priority(&self) -> Option<TaskPriority> {
+        self.waiters.iter().map(|waiter| waiter.priority()).max()
+    }
+
+    /// Wake the highest priority task, the one that has waited longest if there is a tie. Returns
+    /// false if there was nobody waiting.
+    pub fn wake_one(&mut self) -> bool {
+        let index = (0..self.waiters.len()).max_by_key(|index| {
+            // max_by_key picks the last of equals, so reverse the index to get the first
+            (self.waiters[*index].priority(), usize::MAX - index)
+        });
+
+        match index {
+            Some(index) => {
+                self.waiters.remove(index).wake();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Wake everything on the queue, and return how many there were
+    pub fn wake_all(&mut self) -> usize {
+        let count = self.waiters.len();
+        for task in self.waiters.drain(..) {
+            task.wake();
+        }
+        count
+    }
+}
@@ -0,0 +1,136 @@
+// The way in and out of ring 3. The syscall instruction leaves the user's rip in rcx and rflags in
+// r11 and doesn't switch stacks, so the entry stub finds the kernel stack in the TSS and builds the
+// same frame that an interrupt from ring 3 would have left, with the registers on top of it. That
+// makes it an InterruptStack, and the way out is an iretq rather than sysret, which would need the
+// user segments to sit in a different order in the GDT.
+
+use crate::gdt::{self, USER_CODE_SELECTOR, USER_DATA_SELECTOR};
+use crate::interrupts::InterruptStack;
+use crate::scheduler::current_task;
+use x86::msr::{self, IA32_EFER, IA32_FMASK, IA32_LSTAR, IA32_STAR};
+
+const EFER_SYSCALL_ENABLE: u64 = 1;
+
+// Interrupts, direction and trap flags, which are cleared on the way in
+const SYSCALL_FLAG_MASK: u64 = 0x700;
+
+// Interrupts on, and the reserved bit that is always set. Interrupts from ring 3 load the kernel's
+// thread locals on the way in, just like syscalls.
+const USER_RFLAGS: usize = 0x202;
+
+// The entry stub can't use the selector constants directly, so it reads them from here
+#[no_mangle]
+static SYSCALL_USER_CS: usize = USER_CODE_SELECTOR;
+#[no_mangle]
+static SYSCALL_USER_SS: usize = USER_DATA_SELECTOR;
+
+// Where the entry stub keeps the user's stack pointer while it switches to the kernel stack
+#[no_mangle]
+#[thread_local]
+static mut SYSCALL_USER_RSP: usize = 0;
+
+crate::function!(syscall_entry => {
+    // The gs base is this CPU's thread local block until the second swapgs, which is how we find
+    // the TSS. Interrupts are masked until the iretq, but NMIs and machine checks are not, so their
+    // entry paths check for a rip between here and syscall_gs_restored and take the thread local
+    // block from the gs base instead of the kernel gs base.
+    "swapgs\n",
+    "mov gs:[SYSCALL_USER_RSP@TPOFF], rsp\n",
+    "mov rsp, gs:[TSS@TPOFF + 4]\n",
+    // The iretq frame
+    "push qword ptr [rip + SYSCALL_USER_SS]\n",
+    "push qword ptr gs:[SYSCALL_USER_RSP@TPOFF]\n",
+    "swapgs\n",
+    ".global syscall_gs_restored\n",
+    "syscall_gs_restored:\n",
+    "push r11\n",
+    "push qword ptr [rip + SYSCALL_USER_CS]\n",
+    "push rcx\n",
+
+    "push rax\n",
+    crate::push_scratch!(),
+    crate::push_preserved!(),
+
+    // The fs slot holds the user's fs base, and the kernel runs with this CPU's thread local block,
+    // which the kernel gs base MSR holds
+    "mov ecx, 0xc0000100\n",
+    "rdmsr\n",
+    "shl rdx, 32\n",
+    "or rax, rdx\n",
+    "push rax\n",
+    "mov ecx, 0xc0000102\n",
+    "rdmsr\n",
+    "mov ecx, 0xc0000100\n",
+    "wrmsr\n",
+
+    "mov rdi, rsp\n",
+    "call __syscall_handler\n",
+
+    "mov ecx, 0xc0000100\n",
+    "mov eax, [rsp]\n",
+    "mov edx, [rsp + 4]\n",
+    "wrmsr\n",
+    "add rsp, 8\n",
+    crate::pop_preserved!(),
+    crate::pop_scratch!(),
+    "iretq\n",
+});
+
+#[no_mangle]
+unsafe extern "C" fn __syscall_handler(stack: *mut InterruptStack) {
+    let stack = &mut *stack;
+    let result = super::handle_syscall(
+        stack.rax(),
+        stack.rdi(),
+        stack.rsi(),
+        stack.rdx(),
+        stack.r10(),
+        stack.r8(),
+        stack.r9(),
+    );
+    stack.set_rax(result as usize);
+
+    crate::scheduler::preemption_point();
+}
+
+/// Let this CPU take syscalls
+pub unsafe fn init() {
+    let kernel_code = (gdt::GDT_KERNEL_CODE << 3) as u64;
+
+    msr::wrmsr(IA32_EFER, msr::rdmsr(IA32_EFER) | EFER_SYSCALL_ENABLE);
+    msr::wrmsr(IA32_STAR, kernel_code << 32);
+    msr::wrmsr(IA32_LSTAR, syscall_entry as usize as u64);
+    msr::wrmsr(IA32_FMASK, SYSCALL_FLAG_MASK);
+}
+
+/// Drop the current task into ring 3 at rip, with its stack at rsp, arg in rdi and its own fs base.
+/// The task comes back into the kernel on the top of its kernel stack, so nothing below here is
+/// needed again.
+pub unsafe fn enter_user_mode(rip: usize, rsp: usize, arg: usize) -> ! {
+    let task = current_task();
+    let fs_base = task.user_fs_base();
+    gdt::set_kernel_stack_top(task.stack_top());
+    core::mem::drop(task);
+
+    // Thread locals are gone once the user's fs base is loaded, so it is the last thing before the
+    // iretq
+    asm!(
+        "push {ss}",
+        "push {rsp}",
+        "push {rflags}",
+        "push {cs}",
+        "push {rip}",
+        "mov ecx, 0xc0000100",
+        "wrmsr",
+        "iretq",
+        ss = in(reg) USER_DATA_SELECTOR,
+        rsp = in(reg) rsp,
+        rflags = in(reg) USER_RFLAGS,
+        cs = in(reg) USER_CODE_SELECTOR,
+        rip = in(reg) rip,
+        in("rdi") arg,
+        in("eax") fs_base as u32,
+        in("edx") (fs_base >> 32) as u32,
+        options(noreturn)
+    );
+}
@@ -0,0 +1,284 @@
+// Futexes, for user mode to block on a word of its memory until another task changes it. Waiters
+// are keyed by the physical address of the word, so tasks that map the same memory at different
+// addresses, or in different address spaces, still find each other.
+
+use super::user_copy::validate_user_pages;
+use super::{Result, SyscallError};
+use crate::paging::{lock_page_table, phys_to_virt, PresentPageFlags};
+use crate::physmem::{self, Frame};
+use crate::scheduler::{current_task, TaskReference};
+use crate::sync::{IrqMutex, WaitQueue};
+use alloc::vec::Vec;
+use core::mem::size_of;
+use core::sync::atomic::{AtomicU32, Ordering};
+
+const BUCKET_COUNT: usize = 64;
+
+// The futexes that hash to one bucket, with the physical address of each
+struct FutexBucket {
+    futexes: Vec<(usize, WaitQueue)>,
+}
+
+impl FutexBucket {
+    fn queue(&mut self, key: usize) -> &mut WaitQueue {
+        let index = match self.futexes.iter().position(|(k, _)| *k == key) {
+            Some(index) => index,
+            None => {
+                self.futexes.push((key, WaitQueue::new()));
+                self.futexes.len() - 1
+            }
+        };
+        &mut self.futexes[index].1
+    }
+
+    // Take a task off a futex's queue without waking it, and drop the queue if it was the last
+    fn remove_waiter(&mut self, key: usize, task: &TaskReference) {
+        if let Some(index) = self.futexes.iter().position(|(k, _)| *k == key) {
+            let queue = &mut self.futexes[index].1;
+            queue.remove(task);
+            if queue.is_empty() {
+                self.futexes.swap_remove(index);
+            }
+        }
+    }
+}
+
+const EMPTY_BUCKET: IrqMutex<FutexBucket> = IrqMutex::new(FutexBucket {
+    futexes: Vec::new(),
+});
+static FUTEX_BUCKETS: [IrqMutex<FutexBucket>; BUCKET_COUNT] = [EMPTY_BUCKET; BUCKET_COUNT];
+
+fn bucket(key: usize) -> &'static IrqMutex<FutexBucket> {
+    // Futexes are 4 byte aligned, so the bottom bits don't tell them apart
+    &FUTEX_BUCKETS[(key / size_of::<u32>()) % BUCKET_COUNT]
+}
+
+// The physical address of a user's futex word. The frame counts as mapped for as long as the key
+// is held, so if the page is unmapped in the meantime the frame can't be freed and reused for
+// something else that would then share the key.
+struct FutexKey {
+    key: usize,
+    // Frames that the allocator doesn't track, like MMIO, are never freed, so aren't pinned
+    pinned: bool,
+}
+
+impl FutexKey {
+    fn new(addr: usize) -> Result<Self> {
+        if addr % size_of::<u32>() != 0 {
+            return Err(SyscallError::InvalidArgument);
+        }
+        validate_user_pages(addr, size_of::<u32>(), PresentPageFlags::empty())?;
+
+        // It may have been unmapped since it was checked. The page table stays locked until the
+        // frame is pinned, so it can't be unmapped in between.
+        let page_table = unsafe { lock_page_table() };
+        let key = page_table.translate(addr).ok_or(SyscallError::BadAddress)?;
+        let frame = Frame::containing_address(key);
+        let pinned = physmem::mapping_count(frame) > 0;
+        if pinned {
+            physmem::add_mapping(frame);
+        }
+        Ok(Self { key, pinned })
+    }
+}
+
+impl Drop for FutexKey {
+    fn drop(&mut self) {
+        let frame = Frame::containing_address(self.key);
+        if self.pinned && physmem::remove_mapping(frame) == 0 {
+            physmem::deallocate_frame(frame);
+        }
+    }
+}
+
+// Read the word through the physical mapping, so there's no fault to handle
+fn futex_value(key: usize) -> u32 {
+    unsafe { &*phys_to_virt::<AtomicU32>(key) }.load(Ordering::SeqCst)
+}
+
+/// Block until the futex at addr is woken, as long as it still holds expected. The value is checked
+/// with the futex's bucket locked, so a task that changes it and then wakes the futex can't slip
+/// in between the check and the wait.
+pub fn futex_wait(addr: usize, expected: u32) -> Result<isize> {
+    let futex = FutexKey::new(addr)?;
+    let key = futex.key;
+
+    let mut changed = false;
+    WaitQueue::wait(bucket(key), |bucket| {
+        if futex_value(key) != expected {
+            changed = true;
+            None
+        } else {
+            Some(bucket.queue(key))
+        }
+    });
+
+    if changed {
+        return Err(SyscallError::TryAgain);
+    }
+
+    // futex_wake takes the task off the queue, so if it is still there something else woke it.
+    // Callers check the value again anyway, so that is just an early return.
+    bucket(key).lock().remove_waiter(key, &current_task());
+    Ok(0)
+}
+
+/// Wake up to count tasks waiting on the futex at addr, and return how many there were
+pub fn futex_wake(addr: usize, count: usize) -> Result<isize> {
+    let futex = FutexKey::new(addr)?;
+    let key = futex.key;
+
+    let mut bucket = bucket(key).lock();
+    let index = match bucket.futexes.iter().position(|(k, _)| *k == key) {
+        Some(index) => index,
+        None => return Ok(0),
+    };
+
+    let queue = &mut bucket.futexes[index].1;
+    let mut woken = 0;
+    while woken < count && queue.wake_one() {
+        woken += 1;
+    }
+
+    if queue.is_empty() {
+        bucket.futexes.swap_remove(index);
+    }
+    Ok(woken as isize)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::paging::PAGE_SIZE;
+    use crate::physmem;
+    use crate::scheduler::{exit, sleep, spawn, TaskState};
+    use core::time::Duration;
+
+    const USER_PAGE: usize = 0x0000_6600_0002_0000;
+    const FUTEX: usize = USER_PAGE + 0x40;
+
+    fn map_user_page() {
+        let frame = physmem::allocate_user_frame().expect("Failed to allocate test frame");
+        let mut page_table = unsafe { lock_page_table() };
+        page_table
+            .map_to(
+                USER_PAGE,
+                frame,
+                PresentPageFlags::USER_ACCESSIBLE
+                    | PresentPageFlags::WRITABLE
+                    | PresentPageFlags::NO_EXECUTE,
+            )
+            .expect("Failed to map test page")
+            .flush(&page_table);
+    }
+
+    fn unmap_user_page() {
+        let mut page_table = unsafe { lock_page_table() };
+        page_table
+            .unmap(USER_PAGE, true)
+            .expect("Failed to unmap test page")
+            .flush(&page_table);
+    }
+
+    // Exits with what futex_wait returned, the way the syscall would
+    fn waiter_task() -> ! {
+        let result = match futex_wait(FUTEX, 0) {
+            Ok(value) => value,
+            Err(error) => -error.errno(),
+        };
+        exit(result)
+    }
+
+    #[test_case]
+    fn waiter_is_woken() {
+        map_user_page();
+        let futex = unsafe { &*(FUTEX as *const AtomicU32) };
+        futex.store(0, Ordering::SeqCst);
+
+        let waiter = unsafe { spawn(None, || waiter_task()) }.expect("Failed to spawn waiter");
+        while waiter.state() != TaskState::Blocked {
+            sleep(Duration::from_millis(1));
+        }
+
+        // Blocked doesn't mean it is on the futex's queue yet, so keep trying until it is
+        let mut woken = 0;
+        for _ in 0..1000 {
+            assert_eq!(waiter.exit_code(), None);
+            woken = futex_wake(FUTEX, 2).unwrap();
+            if woken != 0 {
+                break;
+            }
+            sleep(Duration::from_millis(1));
+        }
+        let result = waiter.join();
+        let nobody_left = futex_wake(FUTEX, 1);
+
+        unmap_user_page();
+
+        assert_eq!(woken, 1);
+        assert_eq!(result, 0);
+        assert_eq!(nobody_left, Ok(0));
+    }
+
+    fn is_queued(key: usize) -> bool {
+        bucket(key)
+            .lock()
+            .futexes
+            .iter()
+            .any(|(k, queue)| *k == key && !queue.is_empty())
+    }
+
+    #[test_case]
+    fn stray_wake_leaves_the_queue_and_unpins() {
+        map_user_page();
+        let futex = unsafe { &*(FUTEX as *const AtomicU32) };
+        futex.store(0, Ordering::SeqCst);
+        let key = unsafe { lock_page_table() }.translate(FUTEX).unwrap();
+        let frame = Frame::containing_address(key);
+
+        let waiter = unsafe { spawn(None, || waiter_task()) }.expect("Failed to spawn waiter");
+        while !is_queued(key) {
+            sleep(Duration::from_millis(1));
+        }
+        let pinned_count = physmem::mapping_count(frame);
+
+        // Something other than futex_wake wakes it, like a stale timer would
+        waiter.wake();
+        let result = waiter.join();
+        let still_queued = is_queued(key);
+        let unpinned_count = physmem::mapping_count(frame);
+
+        unmap_user_page();
+
+        assert_eq!(result, 0);
+        assert!(!still_queued, "Waiter was left on the queue");
+        assert_eq!((pinned_count, unpinned_count), (2, 1));
+    }
+
+    #[test_case]
+    fn changed_value_returns_at_once() {
+        map_user_page();
+        unsafe { (FUTEX as *mut u32).write_volatile(7) };
+        let result = futex_wait(FUTEX, 6);
+        unmap_user_page();
+
+        assert_eq!(result, Err(SyscallError::TryAgain));
+    }
+
+    #[test_case]
+    fn futex_address_is_checked() {
+        assert_eq!(
+            futex_wait(USER_PAGE + PAGE_SIZE, 0),
+            Err(SyscallError::BadAddress)
+        );
+        assert_eq!(
+            futex_wake(0xffff_8000_0000_0000, 1),
+            Err(SyscallError::BadAddress)
+        );
+
+        map_user_page();
+        let unaligned = futex_wake(FUTEX + 1, 1);
+        unmap_user_page();
+        assert_eq!(unaligned, Err(SyscallError::InvalidArgument));
+    }
+}
@@ -0,0 +1,274 @@
+// Syscalls from ring 3. The number goes in rax and up to six arguments in rdi, rsi, rdx, r10, r8
+// and r9, as on Linux, and the result comes back in rax. Errors are returned as a negative errno.
+
+mod entry;
+mod futex;
+mod user_copy;
+
+use crate::scheduler::{self, current_task, reschedule};
+use crate::serial::SERIAL1;
+use num_derive::FromPrimitive;
+use num_traits::FromPrimitive;
+
+pub use entry::{enter_user_mode, init};
+pub use futex::{futex_wait, futex_wake};
+pub use user_copy::{copy_from_user, copy_to_user};
+
+/// Everything below here belongs to user mode
+pub const USER_ADDRESS_LIMIT: usize = 0x0000_8000_0000_0000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, FromPrimitive)]
+#[repr(usize)]
+pub enum Syscall {
+    Write = 1,
+    Exit = 2,
+    GetPid = 3,
+    Yield = 4,
+    FutexWait = 5,
+    FutexWake = 6,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyscallError {
+    BadFileDescriptor,
+    TryAgain,
+    BadAddress,
+    InvalidArgument,
+    UnknownSyscall,
+}
+
+impl SyscallError {
+    pub fn errno(&self) -> isize {
+        match self {
+            Self::BadFileDescriptor => 9,
+            Self::TryAgain => 11,
+            Self::BadAddress => 14,
+            Self::InvalidArgument => 22,
+            Self::UnknownSyscall => 38,
+        }
+    }
+}
+
+pub type Result<T> = core::result::Result<T, SyscallError>;
+
+const STDOUT: usize = 1;
+const STDERR: usize = 2;
+
+// How much of a write is copied in from the user at a time
+const WRITE_CHUNK_SIZE: usize = 64;
+
+/// Check that a buffer passed in by the user lies entirely in the user half
+pub fn validate_user_buffer(addr: usize, len: usize) -> Result<()> {
+    match addr.checked_add(len) {
+        Some(end) if end <= USER_ADDRESS_LIMIT => Ok(()),
+        _ => Err(SyscallError::BadAddress),
+    }
+}
+
+fn sys_write(fd: usize, buffer: usize, len: usize) -> Result<isize> {
+    if fd != STDOUT && fd != STDERR {
+        return Err(SyscallError::BadFileDescriptor);
+    }
+    validate_user_buffer(buffer, len)?;
+
+    let mut chunk = [0; WRITE_CHUNK_SIZE];
+    for offset in (0..len).step_by(WRITE_CHUNK_SIZE) {
+        let chunk = &mut chunk[..WRITE_CHUNK_SIZE.min(len - offset)];
+        copy_from_user(chunk, buffer + offset)?;
+
+        if let Some(serial) = SERIAL1.lock().as_mut() {
+            for byte in chunk.iter() {
+                serial.send(*byte);
+            }
+        }
+    }
+
+    Ok(len as isize)
+}
+
+fn sys_exit(exit_code: isize) -> ! {
+    scheduler::exit(exit_code)
+}
+
+/// Run syscall nr with the arguments from the user's registers
+pub fn handle_syscall(
+    nr: usize,
+    a1: usize,
+    a2: usize,
+    a3: usize,
+    _a4: usize,
+    _a5: usize,
+    _a6: usize,
+) -> isize {
+    let result = match Syscall::from_usize(nr) {
+        Some(Syscall::Write) => sys_write(a1, a2, a3),
+        Some(Syscall::Exit) => sys_exit(a1 as isize),
+        Some(Syscall::GetPid) => Ok(current_task().pid() as isize),
+        Some(Syscall::Yield) => {
+            reschedule();
+            Ok(0)
+        }
+        Some(Syscall::FutexWait) => futex_wait(a1, a2 as u32),
+        Some(Syscall::FutexWake) => futex_wake(a1, a2),
+        None => Err(SyscallError::UnknownSyscall),
+    };
+
+    match result {
+        Ok(value) => value,
+        Err(error) => -error.errno(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::intel_asm;
+    use crate::paging::{hyperspace, new_address_space, PresentPageFlags, PAGE_SIZE};
+    use crate::physmem;
+    use crate::scheduler::spawn;
+    use core::slice;
+
+    // The stub is copied into a page of its own, so it can only address its data through rbx,
+    // which starts as the data page. It leaves each result in the data page, in RESULT_SLOTS order.
+    intel_asm!(
+        ".global syscall_test_stub\n",
+        ".global syscall_test_stub_end\n",
+        ".section .text.syscall_test_stub, \"ax\", @progbits\n",
+        "syscall_test_stub:\n",
+        "mov rbx, rdi\n",
+        "mov eax, 3\n",
+        "syscall\n",
+        "mov [rbx], rax\n",
+        "mov eax, 1\n",
+        "mov edi, 1\n",
+        "lea rsi, [rbx + 256]\n",
+        "mov rdx, [rbx + 248]\n",
+        "syscall\n",
+        "mov [rbx + 8], rax\n",
+        "mov eax, 1\n",
+        "mov edi, 1\n",
+        "mov rsi, 0xffff800000000000\n",
+        "mov edx, 8\n",
+        "syscall\n",
+        "mov [rbx + 16], rax\n",
+        "mov eax, 4\n",
+        "syscall\n",
+        "mov [rbx + 24], rax\n",
+        "mov eax, 99\n",
+        "syscall\n",
+        "mov [rbx + 32], rax\n",
+        "mov eax, 2\n",
+        "mov edi, 42\n",
+        "syscall\n",
+        "ud2\n",
+        "syscall_test_stub_end:\n",
+        ".text\n",
+    );
+
+    extern "C" {
+        static syscall_test_stub: u8;
+        static syscall_test_stub_end: u8;
+    }
+
+    // getpid, write, write from a kernel address, yield and an unknown syscall
+    const RESULT_SLOTS: usize = 5;
+    const MESSAGE_LENGTH_OFFSET: usize = 248;
+    const MESSAGE_OFFSET: usize = 256;
+    const MESSAGE: &[u8] = b"Hello from ring 3\n";
+
+    const USER_CODE_PAGE: usize = 0x0000_6600_0000_0000;
+    const USER_DATA_PAGE: usize = USER_CODE_PAGE + PAGE_SIZE;
+
+    #[test_case]
+    fn user_buffers_must_be_in_the_user_half() {
+        assert_eq!(validate_user_buffer(0x1000, 0x1000), Ok(()));
+        assert_eq!(validate_user_buffer(USER_ADDRESS_LIMIT - 8, 8), Ok(()));
+        assert_eq!(
+            validate_user_buffer(USER_ADDRESS_LIMIT - 8, 9),
+            Err(SyscallError::BadAddress)
+        );
+        assert_eq!(
+            validate_user_buffer(0xffff_8000_0000_0000, 8),
+            Err(SyscallError::BadAddress)
+        );
+        assert_eq!(
+            validate_user_buffer(usize::MAX, 2),
+            Err(SyscallError::BadAddress)
+        );
+    }
+
+    #[test_case]
+    fn ring_3_makes_syscalls() {
+        let code = unsafe {
+            let start = &syscall_test_stub as *const u8;
+            let end = &syscall_test_stub_end as *const u8;
+            slice::from_raw_parts(start, end as usize - start as usize)
+        };
+
+        let code_frame = physmem::allocate_user_frame().expect("Failed to allocate code frame");
+        let data_frame = physmem::allocate_user_frame().expect("Failed to allocate data frame");
+        hyperspace::with_frame(code_frame, |page| unsafe {
+            page.copy_from_nonoverlapping(code.as_ptr(), code.len())
+        })
+        .expect("Failed to copy test code");
+        hyperspace::zero_frame(data_frame).expect("Failed to zero data frame");
+        hyperspace::with_frame(data_frame, |page| unsafe {
+            (page.add(MESSAGE_LENGTH_OFFSET) as *mut usize).write(MESSAGE.len());
+            page.add(MESSAGE_OFFSET)
+                .copy_from_nonoverlapping(MESSAGE.as_ptr(), MESSAGE.len());
+        })
+        .expect("Failed to write test message");
+
+        // The task gets an address space of its own, which frees the pages when it goes. We keep
+        // hold of the data frame, so that we can see what was recorded after that.
+        let mut address_space = new_address_space().expect("Failed to create address space");
+        {
+            let mut mapper = unsafe { address_space.mapper() };
+            let flush = mapper
+                .map_to(
+                    USER_CODE_PAGE,
+                    code_frame,
+                    PresentPageFlags::USER_ACCESSIBLE,
+                )
+                .expect("Failed to map code page");
+            unsafe { flush.ignore() };
+            let flush = mapper
+                .map_to(
+                    USER_DATA_PAGE,
+                    data_frame,
+                    PresentPageFlags::USER_ACCESSIBLE
+                        | PresentPageFlags::WRITABLE
+                        | PresentPageFlags::NO_EXECUTE,
+                )
+                .expect("Failed to map data page");
+            unsafe { flush.ignore() };
+        }
+
+        // The stack grows down from the end of the data page
+        let task = unsafe {
+            spawn(Some(address_space), || {
+                enter_user_mode(USER_CODE_PAGE, USER_DATA_PAGE + PAGE_SIZE, USER_DATA_PAGE)
+            })
+        }
+        .expect("Failed to spawn user task");
+        let exit_code = task.join();
+
+        let mut results = [0isize; RESULT_SLOTS];
+        hyperspace::with_frame(data_frame, |page| unsafe {
+            (page as *const isize).copy_to_nonoverlapping(results.as_mut_ptr(), RESULT_SLOTS)
+        })
+        .expect("Failed to read data page");
+
+        assert_eq!(exit_code, 42);
+        assert_eq!(
+            results,
+            [
+                task.pid() as isize,
+                MESSAGE.len() as isize,
+                -SyscallError::BadAddress.errno(),
+                0,
+                -SyscallError::UnknownSyscall.errno(),
+            ]
+        );
+    }
+}
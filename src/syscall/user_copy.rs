@@ -0,0 +1,142 @@
+// Copying to and from user buffers. The range is checked against the page tables first, but another
+// CPU can unmap a page while we copy, so the copy itself is also allowed to fault. A fault on the
+// copy goes to the exception table, which makes the copy report failure instead.
+
+use super::{validate_user_buffer, Result, SyscallError};
+use crate::paging::{lock_page_table, page_align_down, PresentPageFlags, PAGE_SIZE};
+use crate::{exception_table_entry, intel_asm};
+
+// Copy rdx bytes from rsi to rdi, returning 1 in al if it faulted or 0 if it worked
+intel_asm!(
+    ".global user_copy\n",
+    ".type user_copy, @function\n",
+    ".section .text.user_copy, \"ax\", @progbits\n",
+    "user_copy:\n",
+    "mov rcx, rdx\n",
+    "xor eax, eax\n",
+    "user_copy_access:\n",
+    "rep movsb\n",
+    "ret\n",
+    "user_copy_fault:\n",
+    "mov eax, 1\n",
+    "ret\n",
+    exception_table_entry!("user_copy_access", "user_copy_fault"),
+    ".size user_copy, . - user_copy\n",
+    ".text\n",
+);
+
+extern "C" {
+    fn user_copy(dst: *mut u8, src: *const u8, len: usize) -> bool;
+}
+
+// Check that every page of the buffer is mapped for the user, and writable if we are going to write
+// to it. Kernel mappings in the user half don't count.
+pub(super) fn validate_user_pages(addr: usize, len: usize, flags: PresentPageFlags) -> Result<()> {
+    validate_user_buffer(addr, len)?;
+    if len == 0 {
+        return Ok(());
+    }
+
+    let flags = flags | PresentPageFlags::USER_ACCESSIBLE;
+    let page_table = unsafe { lock_page_table() };
+    for page in (page_align_down(addr)..addr + len).step_by(PAGE_SIZE) {
+        page_table
+            .get_pte_for_address(page)
+            .and_then(|pte| pte.present().ok())
+            .filter(|pte| pte.flags().contains(flags))
+            .ok_or(SyscallError::BadAddress)?;
+    }
+
+    Ok(())
+}
+
+/// Fill dst from the user buffer at user_src
+pub fn copy_from_user(dst: &mut [u8], user_src: usize) -> Result<()> {
+    validate_user_pages(user_src, dst.len(), PresentPageFlags::empty())?;
+
+    match unsafe { user_copy(dst.as_mut_ptr(), user_src as *const u8, dst.len()) } {
+        false => Ok(()),
+        true => Err(SyscallError::BadAddress),
+    }
+}
+
+/// Copy src to the user buffer at user_dst
+pub fn copy_to_user(user_dst: usize, src: &[u8]) -> Result<()> {
+    validate_user_pages(user_dst, src.len(), PresentPageFlags::WRITABLE)?;
+
+    match unsafe { user_copy(user_dst as *mut u8, src.as_ptr(), src.len()) } {
+        false => Ok(()),
+        true => Err(SyscallError::BadAddress),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::physmem;
+
+    const USER_PAGE: usize = 0x0000_6600_0001_0000;
+
+    fn map_user_page(flags: PresentPageFlags) {
+        let frame = physmem::allocate_user_frame().expect("Failed to allocate test frame");
+        let mut page_table = unsafe { lock_page_table() };
+        page_table
+            .map_to(
+                USER_PAGE,
+                frame,
+                flags | PresentPageFlags::USER_ACCESSIBLE | PresentPageFlags::NO_EXECUTE,
+            )
+            .expect("Failed to map test page")
+            .flush(&page_table);
+    }
+
+    fn unmap_user_page() {
+        let mut page_table = unsafe { lock_page_table() };
+        page_table
+            .unmap(USER_PAGE, true)
+            .expect("Failed to unmap test page")
+            .flush(&page_table);
+    }
+
+    #[test_case]
+    fn copies_to_and_from_a_user_buffer() {
+        map_user_page(PresentPageFlags::WRITABLE);
+
+        let message = *b"copied through user memory";
+        let mut copy = [0; 26];
+        let address = USER_PAGE + PAGE_SIZE - 10;
+        let result = copy_to_user(address, &message[..10])
+            .and_then(|()| copy_from_user(&mut copy[..10], address));
+
+        // The whole buffer runs off the end of the page
+        let overrun = copy_from_user(&mut copy, address);
+
+        unmap_user_page();
+
+        assert_eq!(result, Ok(()));
+        assert_eq!(copy[..10], message[..10]);
+        assert_eq!(overrun, Err(SyscallError::BadAddress));
+    }
+
+    #[test_case]
+    fn unmapped_and_read_only_buffers_are_rejected() {
+        let mut buffer = [0; 8];
+        assert_eq!(
+            copy_from_user(&mut buffer, USER_PAGE),
+            Err(SyscallError::BadAddress)
+        );
+
+        map_user_page(PresentPageFlags::empty());
+        let result = copy_to_user(USER_PAGE, &buffer);
+        unmap_user_page();
+        assert_eq!(result, Err(SyscallError::BadAddress));
+    }
+
+    #[test_case]
+    fn faulting_copy_recovers() {
+        // Skip the checks, so that the copy itself faults
+        let mut buffer = [0; 8];
+        let faulted = unsafe { user_copy(buffer.as_mut_ptr(), USER_PAGE as *const u8, 8) };
+        assert!(faulted);
+    }
+}
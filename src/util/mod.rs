@@ -0,0 +1,224 @@
+// Small helpers that don't belong to any one part of the kernel
+
+use crate::paging;
+use core::fmt::{self, Write};
+use core::ops::Deref;
+
+const HEXDUMP_LINE_BYTES: usize = 16;
+
+// One line of a hexdump, in the same layout as hexdump -C: the address, the bytes in two groups of
+// eight, and then the bytes again as ASCII. A short last line is padded so the ASCII lines up.
+struct HexdumpLine<'a> {
+    addr: usize,
+    bytes: &'a [u8],
+}
+
+impl<'a> fmt::Display for HexdumpLine<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:016x} ", self.addr)?;
+        for index in 0..HEXDUMP_LINE_BYTES {
+            if index % 8 == 0 {
+                f.write_str(" ")?;
+            }
+            match self.bytes.get(index) {
+                Some(byte) => write!(f, "{:02x} ", byte)?,
+                None => f.write_str("   ")?,
+            }
+        }
+
+        f.write_str(" |")?;
+        for byte in self.bytes {
+            let printable = byte.is_ascii_graphic() || *byte == b' ';
+            f.write_char(if printable { *byte as char } else { '.' })?;
+        }
+        f.write_str("|")
+    }
+}
+
+/// Log bytes as a hexdump, labelling each line with the address it would have if the bytes started
+/// at base_addr. Nothing is allocated, so this works when memory is the problem being debugged.
+pub fn hexdump(bytes: &[u8], base_addr: usize) {
+    for (index, line) in bytes.chunks(HEXDUMP_LINE_BYTES).enumerate() {
+        let line = HexdumpLine {
+            addr: base_addr + index * HEXDUMP_LINE_BYTES,
+            bytes: line,
+        };
+        crate::info!("{}", line);
+    }
+}
+
+/// Hexdump physical memory through the identity map, labelled with its physical addresses. Reading
+/// device memory can have side effects, so this is only for memory that is safe to read.
+pub unsafe fn hexdump_phys(phys_addr: usize, len: usize) {
+    let virt_addr = paging::phys_to_virt_addr(phys_addr, len);
+    hexdump(
+        core::slice::from_raw_parts(virt_addr as *const u8, len),
+        phys_addr,
+    );
+}
+
+/// The byte checksum that ACPI and a lot of firmware tables use: the bytes of a valid table,
+/// including its checksum byte, add up to zero
+pub fn checksum8(bytes: &[u8]) -> u8 {
+    bytes.iter().fold(0, |sum, byte| sum.wrapping_add(*byte))
+}
+
+/// A string formatted into a buffer of N bytes, for the allocator, the paging code and interrupt
+/// handlers, which can't allocate. Writes that don't fit are cut short at a character boundary
+/// rather than failing, and is_truncated says whether that happened.
+pub struct StackString<const N: usize> {
+    buffer: [u8; N],
+    len: usize,
+    truncated: bool,
+}
+
+impl<const N: usize> StackString<N> {
+    pub const fn new() -> Self {
+        Self {
+            buffer: [0; N],
+            len: 0,
+            truncated: false,
+        }
+    }
+
+    /// Format args into a new string, as format! would
+    pub fn from_args(args: fmt::Arguments) -> Self {
+        let mut ret = Self::new();
+        let _ = ret.write_fmt(args);
+        ret
+    }
+
+    pub fn as_str(&self) -> &str {
+        // Only whole characters are ever copied in
+        unsafe { core::str::from_utf8_unchecked(&self.buffer[..self.len]) }
+    }
+
+    pub fn is_truncated(&self) -> bool {
+        self.truncated
+    }
+
+    pub fn clear(&mut self) {
+        self.len = 0;
+        self.truncated = false;
+    }
+}
+
+impl<const N: usize> Write for StackString<N> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut length = s.len().min(N - self.len);
+        while !s.is_char_boundary(length) {
+            length -= 1;
+        }
+
+        self.buffer[self.len..self.len + length].copy_from_slice(&s.as_bytes()[..length]);
+        self.len += length;
+        self.truncated |= length < s.len();
+        Ok(())
+    }
+}
+
+impl<const N: usize> Deref for StackString<N> {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        self.as_str()
+    }
+}
+
+impl<const N: usize> fmt::Display for StackString<N> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.pad(self.as_str())
+    }
+}
+
+impl<const N: usize> fmt::Debug for StackString<N> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(self.as_str(), f)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloc::vec::Vec;
+
+    #[test_case]
+    fn hexdump_matches_hexdump_c() {
+        let mut bytes: Vec<u8> = b"Hello, hexdump!\n".iter().copied().collect();
+        bytes.extend(0..20u8);
+
+        // Other CPUs can log while this runs, so only look at the hexdump's own lines
+        let captured = crate::log::capture(|| hexdump(&bytes, 0xffff_8000_0000_1ff0));
+        let lines: Vec<&str> = captured
+            .lines()
+            .filter_map(|line| line.splitn(2, "] ").nth(1))
+            .filter(|line| line.starts_with("ffff80000000"))
+            .collect();
+
+        assert_eq!(
+            lines,
+            [
+                "ffff800000001ff0  48 65 6c 6c 6f 2c 20 68  65 78 64 75 6d 70 21 0a  |Hello, hexdump!.|",
+                "ffff800000002000  00 01 02 03 04 05 06 07  08 09 0a 0b 0c 0d 0e 0f  |................|",
+                "ffff800000002010  10 11 12 13                                       |....|",
+            ]
+        );
+    }
+
+    #[test_case]
+    fn checksum8_wraps() {
+        assert_eq!(checksum8(&[]), 0);
+        assert_eq!(checksum8(&[0x01, 0x02, 0x03]), 0x06);
+        assert_eq!(checksum8(&[0xff, 0x02]), 0x01);
+        assert_eq!(checksum8(&[0x80, 0x70, 0x10]), 0x00);
+    }
+
+    #[test_case]
+    fn stack_string_fits_exactly() {
+        let mut string = StackString::<5>::new();
+        string.write_str("hello").unwrap();
+        assert_eq!(string.as_str(), "hello");
+        assert!(!string.is_truncated());
+
+        string.clear();
+        assert_eq!(&*string, "");
+    }
+
+    #[test_case]
+    fn stack_string_truncates_when_full() {
+        let mut string = StackString::<5>::new();
+        write!(string, "hello {}", "world").unwrap();
+        assert_eq!(string.as_str(), "hello");
+        assert!(string.is_truncated());
+
+        // A character that doesn't fit is left out rather than split
+        let string = StackString::<3>::from_args(format_args!("a\u{e9}\u{e9}"));
+        assert_eq!(string.as_str(), "a\u{e9}");
+        assert!(string.is_truncated());
+    }
+
+    #[test_case]
+    fn stack_string_works_with_write() {
+        let mut string = StackString::<32>::new();
+        write!(string, "{:#x} {:>4}", 0x1234, 7).unwrap();
+        let padded = StackString::<8>::from_args(format_args!("{}", "ab"));
+        write!(string, "|{:<5}|", padded).unwrap();
+        assert_eq!(string.as_str(), "0x1234    7|ab   |");
+        assert!(!string.is_truncated());
+    }
+
+    #[test_case]
+    fn hexdump_phys_labels_lines_with_physical_addresses() {
+        let frame = crate::physmem::allocate_kernel_frame().expect("Failed to allocate frame");
+        let addr = frame.physical_address();
+        unsafe { core::ptr::write_bytes(paging::phys_to_virt_mut::<u8>(addr), b'A', 4) };
+
+        let captured = crate::log::capture(|| unsafe { hexdump_phys(addr, 4) });
+        crate::physmem::deallocate_frame(frame);
+
+        assert!(captured.contains(&alloc::format!(
+            "{:016x}  41 41 41 41                                       |AAAA|\n",
+            addr
+        )));
+    }
+}
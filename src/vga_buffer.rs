@@ -1,3 +1,4 @@
+use crate::io_port::{Io, Port};
 use crate::paging::phys_to_virt_mut;
 use core::fmt;
 use lazy_static::lazy_static;
@@ -46,18 +47,83 @@ struct ScreenChar {
 const BUFFER_HEIGHT: usize = 25;
 const BUFFER_WIDTH: usize = 80;
 
+// One bit per row
+const ALL_ROWS_DIRTY: u32 = (1 << BUFFER_HEIGHT) - 1;
+
+const CRTC_CURSOR_LOCATION_HIGH: u8 = 0x0e;
+const CRTC_CURSOR_LOCATION_LOW: u8 = 0x0f;
+
+struct Crtc {
+    index: Port<u8, 0x3d4>,
+    data: Port<u8, 0x3d5>,
+}
+
+impl Crtc {
+    #[cfg(test)]
+    fn read(&mut self, register: u8) -> u8 {
+        self.index.write(register);
+        self.data.read()
+    }
+
+    fn write(&mut self, register: u8, value: u8) {
+        self.index.write(register);
+        self.data.write(value);
+    }
+
+    /// Where the cursor is, in characters from the top left of the screen
+    #[cfg(test)]
+    fn cursor_location(&mut self) -> u16 {
+        u16::from(self.read(CRTC_CURSOR_LOCATION_HIGH)) << 8
+            | u16::from(self.read(CRTC_CURSOR_LOCATION_LOW))
+    }
+
+    fn set_cursor_location(&mut self, location: u16) {
+        self.write(CRTC_CURSOR_LOCATION_HIGH, (location >> 8) as u8);
+        self.write(CRTC_CURSOR_LOCATION_LOW, location as u8);
+    }
+}
+
 #[repr(transparent)]
 struct Buffer {
     chars: [[Volatile<ScreenChar>; BUFFER_WIDTH]; BUFFER_HEIGHT],
 }
 
+/// Text is written to a copy of the screen in RAM, and only the rows that changed are copied to
+/// the VGA buffer when it is flushed. The VGA buffer is slow to access, and scrolling it in place
+/// means reading it back as well. The hardware cursor is moved to where the next character goes
+/// on each flush.
 pub struct Writer {
+    row_position: usize,
     column_position: usize,
     color_code: ColorCode,
+    shadow: [[ScreenChar; BUFFER_WIDTH]; BUFFER_HEIGHT],
+    dirty_rows: u32,
     buffer: &'static mut Buffer,
+    crtc: Crtc,
 }
 
 impl Writer {
+    fn new(buffer: &'static mut Buffer) -> Self {
+        let color_code = ColorCode::new(Color::Yellow, Color::Black);
+        let blank = ScreenChar {
+            ascii_character: b' ',
+            color_code,
+        };
+        // Start at the bottom, so whatever the bootloader left on the screen scrolls up out of the way
+        Self {
+            row_position: BUFFER_HEIGHT - 1,
+            column_position: 0,
+            color_code,
+            shadow: [[blank; BUFFER_WIDTH]; BUFFER_HEIGHT],
+            dirty_rows: ALL_ROWS_DIRTY,
+            buffer,
+            crtc: Crtc {
+                index: Port::new(),
+                data: Port::new(),
+            },
+        }
+    }
+
     pub fn write_byte(&mut self, byte: u8) {
         match byte {
             b'\n' => self.new_line(),
@@ -66,14 +132,14 @@ impl Writer {
                     self.new_line();
                 }
 
-                let row = BUFFER_HEIGHT - 1;
+                let row = self.row_position;
                 let col = self.column_position;
 
-                let color_code = self.color_code;
-                self.buffer.chars[row][col].write(ScreenChar {
+                self.shadow[row][col] = ScreenChar {
                     ascii_character: byte,
-                    color_code,
-                });
+                    color_code: self.color_code,
+                };
+                self.dirty_rows |= 1 << row;
                 self.column_position += 1;
             }
         }
@@ -88,14 +154,68 @@ impl Writer {
         }
     }
 
-    fn new_line(&mut self) {
-        for row in 1..BUFFER_HEIGHT {
-            for col in 0..BUFFER_WIDTH {
-                let character = self.buffer.chars[row][col].read();
-                self.buffer.chars[row - 1][col].write(character);
+    /// Move to row and col, where the next character will be written
+    pub fn set_cursor(&mut self, row: usize, col: usize) {
+        assert!(
+            row < BUFFER_HEIGHT && col < BUFFER_WIDTH,
+            "Cursor position {},{} is off the screen",
+            row,
+            col
+        );
+        self.row_position = row;
+        self.column_position = col;
+        self.update_cursor();
+    }
+
+    /// Blank the screen and move to the top left
+    pub fn clear(&mut self) {
+        for row in 0..BUFFER_HEIGHT {
+            self.clear_row(row);
+        }
+        self.set_cursor(0, 0);
+        self.flush();
+    }
+
+    fn update_cursor(&mut self) {
+        // After the last column the cursor stays there until the next character wraps the line
+        let col = self.column_position.min(BUFFER_WIDTH - 1);
+        self.crtc
+            .set_cursor_location((self.row_position * BUFFER_WIDTH + col) as u16);
+    }
+
+    /// Copy the rows that have changed since the last flush to the VGA buffer, and move the cursor.
+    /// Returns how many rows were copied.
+    pub fn flush(&mut self) -> usize {
+        let mut copied = 0;
+        for row in 0..BUFFER_HEIGHT {
+            if self.dirty_rows & 1 << row == 0 {
+                continue;
             }
+
+            // Volatile<ScreenChar> is transparent, so the row can go across in one go
+            unsafe {
+                core::ptr::copy_nonoverlapping(
+                    self.shadow[row].as_ptr(),
+                    self.buffer.chars[row].as_mut_ptr() as *mut ScreenChar,
+                    BUFFER_WIDTH,
+                );
+            }
+            copied += 1;
+        }
+
+        self.dirty_rows = 0;
+        self.update_cursor();
+        copied
+    }
+
+    fn new_line(&mut self) {
+        if self.row_position < BUFFER_HEIGHT - 1 {
+            self.row_position += 1;
+        } else {
+            self.shadow.copy_within(1.., 0);
+            self.clear_row(BUFFER_HEIGHT - 1);
+            self.dirty_rows = ALL_ROWS_DIRTY;
         }
-        self.clear_row(BUFFER_HEIGHT - 1);
         self.column_position = 0;
     }
 
@@ -104,9 +224,8 @@ impl Writer {
             ascii_character: b' ',
             color_code: self.color_code,
         };
-        for col in 0..BUFFER_WIDTH {
-            self.buffer.chars[row][col].write(blank);
-        }
+        self.shadow[row] = [blank; BUFFER_WIDTH];
+        self.dirty_rows |= 1 << row;
     }
 }
 
@@ -118,11 +237,8 @@ impl fmt::Write for Writer {
 }
 
 lazy_static! {
-    pub static ref WRITER: Mutex<Writer> = Mutex::new(Writer {
-        column_position: 0,
-        color_code: ColorCode::new(Color::Yellow, Color::Black),
-        buffer: unsafe { &mut *phys_to_virt_mut(0xb8000) },
-    });
+    pub static ref WRITER: Mutex<Writer> =
+        Mutex::new(Writer::new(unsafe { &mut *phys_to_virt_mut(0xb8000) }));
 }
 
 #[macro_export]
@@ -136,10 +252,22 @@ macro_rules! println {
     ($($arg:tt)*) => ($crate::print!("{}\n", format_args!($($arg)*)));
 }
 
+/// Move the console to row and col, where the next character will be printed
+pub fn set_cursor(row: usize, col: usize) {
+    WRITER.lock().set_cursor(row, col);
+}
+
+/// Blank the console and move to the top left
+pub fn clear() {
+    WRITER.lock().clear();
+}
+
 #[doc(hidden)]
 pub fn _print(args: fmt::Arguments) {
     use core::fmt::Write;
-    WRITER.lock().write_fmt(args).unwrap();
+    let mut writer = WRITER.lock();
+    writer.write_fmt(args).unwrap();
+    writer.flush();
 }
 
 #[test_case]
@@ -154,12 +282,69 @@ fn test_println_many() {
     }
 }
 
+#[test_case]
+fn test_flush_copies_dirty_rows() {
+    use core::fmt::Write;
+
+    let mut writer = WRITER.lock();
+    for row in 0..BUFFER_HEIGHT {
+        writeln!(writer, "test_flush_copies_dirty_rows line {}", row).unwrap();
+    }
+    assert_eq!(writer.flush(), BUFFER_HEIGHT);
+    assert_eq!(writer.flush(), 0);
+
+    // Writing within a line only touches that row, and scrolling touches them all
+    write!(writer, "partial").unwrap();
+    assert_eq!(writer.flush(), 1);
+    writeln!(writer).unwrap();
+    assert_eq!(writer.flush(), BUFFER_HEIGHT);
+
+    for row in 0..BUFFER_HEIGHT {
+        for col in 0..BUFFER_WIDTH {
+            assert_eq!(
+                writer.buffer.chars[row][col].read(),
+                writer.shadow[row][col]
+            );
+        }
+    }
+    let partial = writer.shadow[BUFFER_HEIGHT - 2]
+        .iter()
+        .take(7)
+        .map(|screen_char| screen_char.ascii_character);
+    assert!(partial.eq(b"partial".iter().copied()));
+}
+
 #[test_case]
 fn test_println_output() {
+    use core::fmt::Write;
+
     let s = "Some test string that fits on a single line";
-    println!("{}", s);
+    let mut writer = WRITER.lock();
+    writeln!(writer, "{}", s).unwrap();
+    writer.flush();
     for (i, c) in s.chars().enumerate() {
-        let screen_char = WRITER.lock().buffer.chars[BUFFER_HEIGHT - 2][i].read();
+        let screen_char = writer.buffer.chars[writer.row_position - 1][i].read();
         assert_eq!(char::from(screen_char.ascii_character), c);
     }
 }
+
+#[test_case]
+fn test_cursor_follows_output() {
+    use core::fmt::Write;
+
+    let mut writer = WRITER.lock();
+    writer.clear();
+    assert_eq!(writer.crtc.cursor_location(), 0);
+    let blank = |c: &ScreenChar| c.ascii_character == b' ';
+    assert!(writer.shadow.iter().flatten().all(blank));
+
+    writer.set_cursor(3, 10);
+    write!(writer, "abc").unwrap();
+    writer.flush();
+    assert_eq!(writer.crtc.cursor_location(), 3 * 80 + 13);
+    assert_eq!(writer.buffer.chars[3][10].read().ascii_character, b'a');
+
+    writeln!(writer).unwrap();
+    writer.flush();
+    assert_eq!(writer.crtc.cursor_location(), 4 * 80);
+}
@@ -0,0 +1,97 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_kern::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+// The same kernel with the HPET left alone, so that the PIT is the clock, as it is on machines
+// that have no HPET
+
+use bootloader::BootInfo;
+use core::time::Duration;
+use rust_kern::devices::clock::{self, TimerSource};
+use rust_kern::devices::{hpet, now_ns, pit, tsc};
+use rust_kern::init::BootOptions;
+use rust_kern::interrupts;
+use rust_kern::scheduler::sleep;
+
+#[test_case]
+fn the_pit_is_the_clock() {
+    assert_eq!(clock::timer_source(), Some(TimerSource::Pit));
+    assert!(hpet::monotonic_ns().is_none());
+}
+
+#[test_case]
+fn the_clock_runs_on_the_pit() {
+    // Sleep rather than spin, since the PIT's clock needs the BSP to take its interrupts
+    let start = clock::monotonic_ns().unwrap();
+    sleep(Duration::from_millis(2));
+    assert!(clock::monotonic_ns().unwrap() >= start + 2_000_000);
+    assert!(now_ns().is_some());
+}
+
+#[test_case]
+fn ticks_come_at_the_programmed_rate() {
+    const HZ: u32 = 100;
+    const WAIT_NS: u64 = 200_000_000;
+
+    let were_enabled = interrupts::are_enabled();
+    unsafe {
+        assert!(pit::set_frequency(HZ));
+        interrupts::enable();
+    }
+
+    // Spin on channel 2 rather than sleep, so the wait doesn't depend on the ticks it counts
+    let start = pit::ticks();
+    pit::spin_wait_ns(WAIT_NS);
+    let counted = pit::ticks() - start;
+
+    unsafe {
+        if !were_enabled {
+            interrupts::disable();
+        }
+        assert!(pit::set_frequency(clock::DEFAULT_TICK_HZ));
+    }
+
+    let expected = WAIT_NS * u64::from(HZ) / 1_000_000_000;
+    assert!(
+        counted >= expected * 3 / 4 && counted <= expected + 1,
+        "Counted {} ticks, expected {}",
+        counted,
+        expected
+    );
+}
+
+#[test_case]
+fn the_tsc_is_calibrated_against_the_pit() {
+    const WAIT_NS: u64 = 20_000_000;
+
+    if tsc::now_ns().is_none() {
+        rust_kern::skip_test("no invariant tsc");
+        return;
+    }
+
+    let (start_ns, end_ns, waited_ns) = pit::measure_wait_ns(WAIT_NS, || tsc::now_ns().unwrap());
+    let tsc_ns = end_ns - start_ns;
+    assert!(
+        tsc_ns >= waited_ns * 19 / 20 && tsc_ns <= waited_ns * 21 / 20,
+        "The TSC saw {}ns when the PIT saw {}ns",
+        tsc_ns,
+        waited_ns
+    );
+}
+
+fn idle_loop() -> ! {
+    todo!("BIG IDLE: This would be the idle loop")
+}
+
+fn run_tests() -> ! {
+    test_main();
+    idle_loop();
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn _start(boot_info: &'static BootInfo) -> ! {
+    let options = BootOptions { ignore_hpet: true };
+    rust_kern::init::kstart_with_options(boot_info, options, run_tests)
+}